@@ -24,8 +24,7 @@ fn main() {
             "The source layers of the overlay").required();
         ap.parse_args_or_exit();
     }
-    match libmount::Overlay::readonly(lowerdirs.iter().map(|x| x.as_ref()),
-                                      target)
+    match libmount::Overlay::readonly_paths(&lowerdirs, target)
         .mount()
     {
         Ok(()) => {}
@@ -32,12 +32,12 @@ fn main() {
         ap.parse_args_or_exit();
     }
     let mut mnt = libmount::Tmpfs::new(target);
-    if let Some(x) = size { mnt = mnt.size_bytes(x); };
+    if let Some(x) = size { mnt.set_size_bytes(x); };
     if let Some(ref x) = mode {
-        mnt = mnt.mode(u32::from_str_radix(x, 8).expect("valid octal mode"));
+        mnt.set_mode(u32::from_str_radix(x, 8).expect("valid octal mode"));
     }
-    if let Some(x) = uid { mnt = mnt.uid(x); }
-    if let Some(x) = gid { mnt = mnt.gid(x); }
+    if let Some(x) = uid { mnt.set_uid(x); }
+    if let Some(x) = gid { mnt.set_gid(x); }
     match mnt.mount() {
         Ok(()) => {}
         Err(e) => {
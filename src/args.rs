@@ -0,0 +1,110 @@
+use std::fmt;
+use std::ffi::{CStr, CString};
+
+use nix::mount::{MsFlags, mount};
+
+use util::format_ms_flags;
+
+
+/// Exact arguments of the `mount()` system call
+///
+/// This is available from the failed operation with `Error::mount_args()`
+/// (or `OSError::mount_args()`) for debugging. `Display` formats it like
+/// `strace` does, so the failing call can be reproduced by hand. Nothing
+/// is stored on the success path, the arguments are recomputed from the
+/// operation when requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountArgs {
+    source: Option<CString>,
+    target: CString,
+    fstype: Option<CString>,
+    flags: MsFlags,
+    data: Option<Vec<u8>>,
+}
+
+impl MountArgs {
+    pub(crate) fn new(source: Option<&CStr>, target: &CStr,
+        fstype: Option<&CStr>, flags: MsFlags, data: Option<&[u8]>)
+        -> MountArgs
+    {
+        MountArgs {
+            source: source.map(|x| x.to_owned()),
+            target: target.to_owned(),
+            fstype: fstype.map(|x| x.to_owned()),
+            flags,
+            data: data.map(|x| x.to_vec()),
+        }
+    }
+
+    /// The `source` argument (`None` is passed as `NULL`)
+    pub fn source(&self) -> Option<&CStr> {
+        self.source.as_ref().map(|x| &x[..])
+    }
+    /// The `target` argument
+    pub fn target(&self) -> &CStr {
+        &self.target
+    }
+    /// The `filesystemtype` argument (`None` is passed as `NULL`)
+    pub fn fstype(&self) -> Option<&CStr> {
+        self.fstype.as_ref().map(|x| &x[..])
+    }
+    /// The `mountflags` argument
+    pub fn flags(&self) -> MsFlags {
+        self.flags
+    }
+    /// The `data` argument (`None` is passed as `NULL`)
+    pub fn data(&self) -> Option<&[u8]> {
+        self.data.as_ref().map(|x| &x[..])
+    }
+
+    pub(crate) fn call(&self) -> ::nix::Result<()> {
+        mount(self.source(), self.target(), self.fstype(), self.flags,
+              self.data())
+    }
+}
+
+fn write_arg(fmt: &mut fmt::Formatter, value: Option<&[u8]>)
+    -> fmt::Result
+{
+    match value {
+        Some(value) => write!(fmt, "{:?}", String::from_utf8_lossy(value)),
+        None => write!(fmt, "NULL"),
+    }
+}
+
+impl fmt::Display for MountArgs {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "mount(")?;
+        write_arg(fmt, self.source().map(|x| x.to_bytes()))?;
+        write!(fmt, ", ")?;
+        write_arg(fmt, Some(self.target.to_bytes()))?;
+        write!(fmt, ", ")?;
+        write_arg(fmt, self.fstype().map(|x| x.to_bytes()))?;
+        write!(fmt, ", {}, ", format_ms_flags(self.flags))?;
+        write_arg(fmt, self.data())?;
+        write!(fmt, ")")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+
+    use nix::mount::MsFlags;
+
+    use super::MountArgs;
+
+    #[test]
+    fn test_display() {
+        let target = CString::new("/mnt").unwrap();
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let args = MountArgs::new(Some(&tmpfs), &target, Some(&tmpfs),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV, Some(b"size=1024"));
+        assert_eq!(args.to_string(), "mount(\"tmpfs\", \"/mnt\", \"tmpfs\", \
+            MS_NOSUID|MS_NODEV, \"size=1024\")");
+        let args = MountArgs::new(None, &target, None, MsFlags::MS_REMOUNT,
+            None);
+        assert_eq!(args.to_string(),
+            "mount(NULL, \"/mnt\", NULL, MS_REMOUNT, NULL)");
+    }
+}
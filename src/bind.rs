@@ -1,14 +1,42 @@
+use std::io;
 use std::fmt;
 use std::ffi::{CStr, CString, OsStr};
+use std::fs::OpenOptions;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 
+use libc::mode_t;
 use nix::mount::{MsFlags, mount};
 
-use {OSError, Error};
-use util::{path_to_cstring, as_path};
-use explain::{Explainable, exists, user};
-use remount::Remount;
+use {OSError, Error, Propagation};
+use error::ignore_errors;
+use lock;
+use util::{path_to_cstring, as_path, format_ms_flags};
+use explain::{Explainable, PathRole, exists, target_exists, user};
+use explain::warn_if_nonempty;
+use remount::{Remount, get_mountpoint_flags};
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum BindError {
+        InvalidFlags(flags: MsFlags) {
+            display("Flags {:?} can't be applied to a bind mount", flags)
+        }
+    }
+}
+
+/// Flags which can be changed by remounting a bind mount
+const BIND_REMOUNT_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_RDONLY.bits() |
+    MsFlags::MS_NOSUID.bits() |
+    MsFlags::MS_NODEV.bits() |
+    MsFlags::MS_NOEXEC.bits() |
+    MsFlags::MS_NOATIME.bits() |
+    MsFlags::MS_NODIRATIME.bits() |
+    MsFlags::MS_RELATIME.bits() |
+    MsFlags::MS_STRICTATIME.bits());
 
 
 /// A mount bind definition
@@ -22,6 +50,21 @@ pub struct BindMount {
     target: CString,
     recursive: bool,
     readonly: bool,
+    flags: MsFlags,
+    create_target_file: Option<mode_t>,
+    warn_nonempty: bool,
+    lock: Option<PathBuf>,
+    propagation: Option<(Propagation, bool)>,
+}
+
+/// The outcome of the bind mount returned by `bare_mount_reported()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindReport {
+    /// Whether additional remount was done to make the mount readonly
+    pub remounted: bool,
+    /// The flags of the target mount point as reported by the kernel after
+    /// the whole operation
+    pub flags: MsFlags,
 }
 
 impl BindMount {
@@ -36,11 +79,16 @@ impl BindMount {
             target: path_to_cstring(target.as_ref()),
             recursive: true,
             readonly: false,
+            flags: MsFlags::empty(),
+            create_target_file: None,
+            warn_nonempty: false,
+            lock: None,
+            propagation: None,
         }
     }
     /// Toggle recursion
     pub fn recursive(mut self, flag: bool) -> BindMount {
-        self.recursive = flag;
+        self.set_recursive(flag);
         self
     }
     /// If set to `true` makes bind-mount readonly
@@ -52,30 +100,151 @@ impl BindMount {
     ///    is done
     /// 3. If set to `false` is option is no-op (does **not** remount `rw`)
     pub fn readonly(mut self, flag: bool) -> BindMount {
+        self.set_readonly(flag);
+        self
+    }
+    /// Set per-mount flags applied by remount after bind
+    ///
+    /// This works like `readonly()` but for a set of flags at once. Only
+    /// `MS_RDONLY`, `MS_NOSUID`, `MS_NODEV`, `MS_NOEXEC` and atime flags
+    /// are allowed, mount fails before doing anything if there are others.
+    /// Flags that are not in the set are left as is (i.e. inherited from
+    /// the source mount point).
+    pub fn with_flags(mut self, flags: MsFlags) -> BindMount {
+        self.set_flags(flags);
+        self
+    }
+    /// Create an empty target file with `mode` if it doesn't exist
+    ///
+    /// This is for binding a single file (like `/etc/resolv.conf`) where
+    /// the target must be a file too. Existing target is left untouched
+    /// (it's not truncated). Note `mode` is subject to umask.
+    pub fn create_target_file(mut self, mode: mode_t) -> BindMount {
+        self.set_create_target_file(mode);
+        self
+    }
+    /// Log a warning if target is a non-empty directory
+    ///
+    /// Its contents are hidden by the mount. This is only a `log::warn!`,
+    /// the mount is done anyway.
+    pub fn warn_nonempty_target(mut self) -> BindMount {
+        self.set_warn_nonempty_target();
+        self
+    }
+    /// Hold an exclusive `flock` on `lockfile` while mounting
+    ///
+    /// The file is created if it doesn't exist. This serializes concurrent
+    /// mounters which use the same lock file. The lock is held until the
+    /// operation finishes, including the remount done for `readonly()` and
+    /// `with_flags()`, and it's released on error too.
+    pub fn with_lock<P: AsRef<Path>>(mut self, lockfile: P) -> BindMount {
+        self.set_lock(lockfile);
+        self
+    }
+    /// Change propagation type of the new mount right after binding
+    ///
+    /// With `recursive` set, all the submounts (brought by a recursive
+    /// bind) are changed too, which is usually what you want for a
+    /// container: `rbind` followed by `make-rslave` or `make-rprivate`.
+    /// This makes an additional `mount` call, and if it fails the bind
+    /// mount is left in place.
+    pub fn propagation(mut self, propagation: Propagation, recursive: bool)
+        -> BindMount
+    {
+        self.set_propagation(propagation, recursive);
+        self
+    }
+
+    /// Toggle recursion (in-place version of `recursive`)
+    pub fn set_recursive(&mut self, flag: bool) -> &mut BindMount {
+        self.recursive = flag;
+        self
+    }
+    /// Toggle readonly (in-place version of `readonly`)
+    pub fn set_readonly(&mut self, flag: bool) -> &mut BindMount {
         self.readonly = flag;
         self
     }
+    /// Set per-mount flags (in-place version of `with_flags`)
+    pub fn set_flags(&mut self, flags: MsFlags) -> &mut BindMount {
+        self.flags = flags;
+        self
+    }
+    /// Create an empty target file if it doesn't exist
+    /// (in-place version of `create_target_file`)
+    pub fn set_create_target_file(&mut self, mode: mode_t) -> &mut BindMount {
+        self.create_target_file = Some(mode);
+        self
+    }
+    /// Log a warning if target is a non-empty directory
+    /// (in-place version of `warn_nonempty_target`)
+    pub fn set_warn_nonempty_target(&mut self) -> &mut BindMount {
+        self.warn_nonempty = true;
+        self
+    }
+    /// Hold a lock while mounting (in-place version of `with_lock`)
+    pub fn set_lock<P: AsRef<Path>>(&mut self, lockfile: P) -> &mut BindMount {
+        self.lock = Some(lockfile.as_ref().to_path_buf());
+        self
+    }
+    /// Change propagation type after binding
+    /// (in-place version of `propagation`)
+    pub fn set_propagation(&mut self, propagation: Propagation,
+        recursive: bool)
+        -> &mut BindMount
+    {
+        self.propagation = Some((propagation, recursive));
+        self
+    }
 
     /// Execute a bind mount
     pub fn bare_mount(self) -> Result<(), OSError> {
-        let mut flags = MsFlags::MS_BIND;
-        if self.recursive {
-            flags = flags | MsFlags::MS_REC;
+        let _lock = match lock::acquire(&self.lock) {
+            Ok(lock) => lock,
+            Err(e) => return Err(OSError::from_io(e, Box::new(self))),
+        };
+        if !BIND_REMOUNT_FLAGS.contains(self.flags) {
+            let invalid = self.flags - BIND_REMOUNT_FLAGS;
+            return Err(OSError::from_bind(
+                BindError::InvalidFlags(invalid), Box::new(self)));
+        }
+        if let Some(mode) = self.create_target_file {
+            let result = OpenOptions::new()
+                .write(true).create_new(true).mode(mode)
+                .open(as_path(&self.target));
+            match result {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(OSError::from_io(e, Box::new(self))),
+            }
+        }
+        if self.warn_nonempty {
+            warn_if_nonempty(as_path(&self.target));
         }
         if let Err(err) = mount(
             Some(&*self.source),
             &*self.target,
             None::<&CStr>,
-            flags,
+            self.mount_flags(),
             None::<&CStr>,
         ) {
             return Err(OSError::from_nix(err, Box::new(self)));
         }
-        if self.readonly {
-            try!(Remount::new(OsStr::from_bytes(self.target.as_bytes()))
-                .bind(true)
-                .readonly(true)
-                .bare_remount());
+        if let Some(flags) = self.propagation_flags() {
+            if let Err(err) = mount(None::<&CStr>, &*self.target,
+                                    None::<&CStr>, flags, None::<&CStr>)
+            {
+                return Err(OSError::from_nix(err, Box::new(self)));
+            }
+        }
+        if self.readonly || !self.flags.is_empty() {
+            let mut remount = Remount::new(
+                OsStr::from_bytes(self.target.as_bytes()));
+            remount.set_bind(true).set_flags(self.flags);
+            if self.readonly {
+                remount.set_readonly(true);
+            }
+            remount.bare_remount()?;
         }
         Ok(())
     }
@@ -84,6 +253,53 @@ impl BindMount {
     pub fn mount(self) -> Result<(), Error> {
         self.bare_mount().map_err(OSError::explain)
     }
+
+    /// Mount ignoring errors of the specified kinds (like `nofail` in fstab)
+    ///
+    /// Returns `Ok(true)` if mounted and `Ok(false)` if mount failed with
+    /// one of the `kinds`.
+    pub fn mount_ignoring(self, kinds: &[io::ErrorKind]) -> Result<bool, Error> {
+        ignore_errors(self.mount(), kinds)
+    }
+
+    /// Execute a bind mount and report what was actually done
+    ///
+    /// This reads mountinfo after mounting to find out effective flags, so
+    /// it's a bit slower than `bare_mount()`. Note if reading mountinfo
+    /// fails, the error is returned while the bind mount is left in place.
+    pub fn bare_mount_reported(self) -> Result<BindReport, OSError> {
+        let remounted = self.readonly || !self.flags.is_empty();
+        let op = self.clone();
+        self.bare_mount()?;
+        match get_mountpoint_flags(as_path(&op.target)) {
+            Ok(flags) => Ok(BindReport { remounted, flags }),
+            Err(e) => Err(OSError::from_remount(e, Box::new(op))),
+        }
+    }
+
+    fn mount_flags(&self) -> MsFlags {
+        let mut flags = MsFlags::MS_BIND;
+        if self.recursive {
+            flags = flags | MsFlags::MS_REC;
+        }
+        flags
+    }
+
+    fn propagation_flags(&self) -> Option<MsFlags> {
+        self.propagation.map(|(propagation, recursive)| {
+            if recursive {
+                MsFlags::MS_REC | propagation.ms_flag()
+            } else {
+                propagation.ms_flag()
+            }
+        })
+    }
+
+    /// Execute a bind mount, report what was done and explain the error
+    /// immediately
+    pub fn mount_reported(self) -> Result<BindReport, Error> {
+        self.bare_mount_reported().map_err(OSError::explain)
+    }
 }
 
 impl fmt::Display for BindMount {
@@ -100,9 +316,178 @@ impl Explainable for BindMount {
     fn explain(&self) -> String {
         [
             format!("source: {}", exists(as_path(&self.source))),
-            format!("target: {}", exists(as_path(&self.target))),
+            format!("target: {}", target_exists(as_path(&self.target))),
+            format!("flags: {}", format_ms_flags(self.mount_flags())),
+            format!("propagation: {}", match self.propagation {
+                Some((prop, true)) => format!("r{}", prop),
+                Some((prop, false)) => prop.to_string(),
+                None => "unchanged".to_string(),
+            }),
             format!("{}", user()),
         ].join(", ")
     }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![
+            (PathRole::Source, as_path(&self.source)),
+            (PathRole::Target, as_path(&self.target)),
+        ]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, create_dir, metadata};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    use nix::mount::MsFlags;
+    use nix::unistd::getuid;
+
+    use {Unmount, Propagation, Tmpfs};
+    use explain::Explainable;
+    use mountinfo::find_mount_point;
+    use util::test::tmp_dir;
+    use super::BindMount;
+
+    #[test]
+    fn test_explain_flags() {
+        let bind = BindMount::new("/nonexistent1", "/nonexistent2");
+        assert!(bind.explain().contains("flags: MS_BIND|MS_REC"));
+        let bind = bind.recursive(false);
+        assert!(bind.explain().contains("flags: MS_BIND,"));
+    }
+
+    #[test]
+    fn test_readonly_report() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("bind_readonly_report");
+        create_dir(dir.join("src")).unwrap();
+        create_dir(dir.join("rw")).unwrap();
+        create_dir(dir.join("ro")).unwrap();
+
+        let report = BindMount::new(dir.join("src"), dir.join("rw"))
+            .mount_reported().unwrap();
+        assert!(!report.remounted);
+        assert!(!report.flags.contains(MsFlags::MS_RDONLY));
+
+        let report = BindMount::new(dir.join("src"), dir.join("ro"))
+            .readonly(true)
+            .mount_reported().unwrap();
+        assert!(report.remounted);
+        assert!(report.flags.contains(MsFlags::MS_RDONLY));
+
+        Unmount::new(dir.join("rw")).unmount().unwrap();
+        Unmount::new(dir.join("ro")).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_flags() {
+        let err = BindMount::new("/nonexistent1", "/nonexistent2")
+            .with_flags(MsFlags::MS_NOEXEC | MsFlags::MS_MOVE)
+            .mount().unwrap_err();
+        assert!(err.to_string().contains(
+            "Flags MS_MOVE can't be applied to a bind mount"));
+    }
+
+    #[test]
+    fn test_with_flags() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("bind_with_flags");
+        create_dir(dir.join("src")).unwrap();
+        create_dir(dir.join("dst")).unwrap();
+
+        let report = BindMount::new(dir.join("src"), dir.join("dst"))
+            .with_flags(MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID)
+            .mount_reported().unwrap();
+        assert!(report.remounted);
+        assert!(report.flags.contains(
+            MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID));
+        assert!(!report.flags.contains(MsFlags::MS_RDONLY));
+
+        Unmount::new(dir.join("dst")).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_create_target_file() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("bind_create_target_file");
+        File::create(dir.join("resolv.conf")).unwrap()
+            .write_all(b"nameserver 127.0.0.1\n").unwrap();
+        create_dir(dir.join("etc")).unwrap();
+        File::create(dir.join("etc/hosts")).unwrap()
+            .write_all(b"keep").unwrap();
+
+        BindMount::new(dir.join("resolv.conf"), dir.join("etc/resolv.conf"))
+            .create_target_file(0o644)
+            .readonly(true)
+            .mount().unwrap();
+        let mut buf = String::new();
+        File::open(dir.join("etc/resolv.conf")).unwrap()
+            .read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "nameserver 127.0.0.1\n");
+        assert!(File::create(dir.join("etc/resolv.conf")).is_err());
+        Unmount::new(dir.join("etc/resolv.conf")).unmount().unwrap();
+        assert!(metadata(dir.join("etc/resolv.conf")).unwrap().is_file());
+        assert_eq!(metadata(dir.join("etc/resolv.conf")).unwrap()
+                   .permissions().mode() & 0o600, 0o600);
+
+        // existing target is not truncated
+        BindMount::new(dir.join("resolv.conf"), dir.join("etc/hosts"))
+            .create_target_file(0o644)
+            .mount().unwrap();
+        Unmount::new(dir.join("etc/hosts")).unmount().unwrap();
+        let mut buf = String::new();
+        File::open(dir.join("etc/hosts")).unwrap()
+            .read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "keep");
+    }
+
+    #[test]
+    fn test_propagation_flags() {
+        let bind = BindMount::new("/nonexistent1", "/nonexistent2");
+        assert_eq!(bind.propagation_flags(), None);
+        assert!(bind.explain().contains("propagation: unchanged"));
+        let bind = bind.propagation(Propagation::Slave, true);
+        assert_eq!(bind.mount_flags(), MsFlags::MS_BIND | MsFlags::MS_REC);
+        assert_eq!(bind.propagation_flags(),
+                   Some(MsFlags::MS_REC | MsFlags::MS_SLAVE));
+        assert!(bind.explain().contains("propagation: rslave"));
+        let bind = bind.propagation(Propagation::Private, false);
+        assert_eq!(bind.propagation_flags(), Some(MsFlags::MS_PRIVATE));
+        assert!(bind.explain().contains("propagation: private"));
+    }
+
+    #[test]
+    fn test_bind_propagation() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("bind_propagation");
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        create_dir(&src).unwrap();
+        create_dir(&dst).unwrap();
+        Tmpfs::new(&src).mount().unwrap();
+        Propagation::from_fd(File::open(&src).unwrap().as_raw_fd())
+            .apply(Propagation::Shared).unwrap();
+        BindMount::new(&src, &dst)
+            .propagation(Propagation::Slave, true)
+            .mount().unwrap();
+        let mnt = find_mount_point(&dst).unwrap().unwrap();
+        assert!(mnt.peer_group().is_none());
+        assert!(mnt.master_group().is_some());
+        Unmount::new(&dst).unmount().unwrap();
+        Unmount::new(&src).unmount().unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::ffi::{CString, CStr};
+use std::path::Path;
+
+use nix::mount::{MsFlags, mount};
+
+use {OSError, Error};
+use util::{path_to_cstring, as_path, format_ms_flags};
+use explain::{Explainable, PathRole, target_exists, user};
+
+
+/// A legacy cgroup (v1) hierarchy mount definition
+///
+/// This is similar to `mount -t cgroup -o cpu,cpuacct none /target`.
+///
+/// By default hierarchy is mounted with nosuid,nodev,noexec
+#[derive(Debug, Clone)]
+pub struct CgroupV1 {
+    target: CString,
+    controllers: Vec<String>,
+    name: Option<String>,
+    noprefix: bool,
+    flags: MsFlags,
+}
+
+impl CgroupV1 {
+    /// New cgroup hierarchy mount with target path and no controllers
+    ///
+    /// Note: when neither controllers nor `name` is set, kernel attaches
+    /// all the controllers which are not bound to other hierarchies yet.
+    pub fn new<P: AsRef<Path>>(path: P) -> CgroupV1 {
+        CgroupV1 {
+            target: path_to_cstring(path.as_ref()),
+            controllers: Vec::new(),
+            name: None,
+            noprefix: false,
+            flags: MsFlags::MS_NOSUID|MsFlags::MS_NODEV|MsFlags::MS_NOEXEC,
+        }
+    }
+    /// Set controllers to attach to the hierarchy (e.g. `cpu`, `cpuacct`)
+    pub fn controllers(mut self, controllers: &[&str]) -> CgroupV1 {
+        self.set_controllers(controllers);
+        self
+    }
+    /// Set name of the hierarchy (`name=` option)
+    ///
+    /// If no controllers are set, hierarchy is mounted with `none` option,
+    /// i.e. without controllers, like systemd does with `none,name=systemd`.
+    pub fn name(mut self, name: &str) -> CgroupV1 {
+        self.set_name(name);
+        self
+    }
+    /// Don't prefix files with controller name (only valid for `cpuset`)
+    pub fn noprefix(mut self, flag: bool) -> CgroupV1 {
+        self.set_noprefix(flag);
+        self
+    }
+
+    /// Set controllers (in-place version of `controllers`)
+    pub fn set_controllers(&mut self, controllers: &[&str]) -> &mut CgroupV1 {
+        self.controllers = controllers.iter().map(|x| x.to_string()).collect();
+        self
+    }
+    /// Set name of the hierarchy (in-place version of `name`)
+    pub fn set_name(&mut self, name: &str) -> &mut CgroupV1 {
+        self.name = Some(name.to_string());
+        self
+    }
+    /// Don't prefix files with controller name
+    /// (in-place version of `noprefix`)
+    pub fn set_noprefix(&mut self, flag: bool) -> &mut CgroupV1 {
+        self.noprefix = flag;
+        self
+    }
+
+    fn format_options(&self) -> Vec<u8> {
+        let mut options = Vec::new();
+        if self.controllers.is_empty() {
+            if self.name.is_some() {
+                options.push("none".to_string());
+            }
+        } else {
+            options.extend(self.controllers.iter().cloned());
+        }
+        if let Some(ref name) = self.name {
+            options.push(format!("name={}", name));
+        }
+        if self.noprefix {
+            options.push("noprefix".to_string());
+        }
+        options.join(",").into_bytes()
+    }
+
+    /// Mount the cgroup hierarchy
+    pub fn bare_mount(self) -> Result<(), OSError> {
+        let options = self.format_options();
+        mount(
+            Some(CStr::from_bytes_with_nul(b"none\0").unwrap()),
+            &*self.target,
+            Some(CStr::from_bytes_with_nul(b"cgroup\0").unwrap()),
+            self.flags,
+            Some(&*options)
+        ).map_err(|err| OSError::from_nix(err, Box::new(self)))
+    }
+
+    /// Mount the cgroup hierarchy and explain error immediately
+    pub fn mount(self) -> Result<(), Error> {
+        self.bare_mount().map_err(OSError::explain)
+    }
+}
+
+impl fmt::Display for CgroupV1 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let opts = self.format_options();
+        write!(fmt, "cgroup {} -> {:?}", String::from_utf8_lossy(&opts),
+            as_path(&self.target))
+    }
+}
+
+impl Explainable for CgroupV1 {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", target_exists(as_path(&self.target))),
+            format!("flags: {}", format_ms_flags(self.flags)),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::CgroupV1;
+
+    #[test]
+    fn test_controllers() {
+        let cg = CgroupV1::new("/sys/fs/cgroup/cpu")
+            .controllers(&["cpu", "cpuacct"]);
+        assert_eq!(cg.format_options(), b"cpu,cpuacct");
+        assert_eq!(CgroupV1::new("/sys/fs/cgroup").format_options(), b"");
+    }
+
+    #[test]
+    fn test_named() {
+        let cg = CgroupV1::new("/sys/fs/cgroup/systemd").name("systemd");
+        assert_eq!(cg.format_options(), b"none,name=systemd");
+        let cg = CgroupV1::new("/sys/fs/cgroup/cpu")
+            .controllers(&["cpu"])
+            .name("mine");
+        assert_eq!(cg.format_options(), b"cpu,name=mine");
+    }
+
+    #[test]
+    fn test_noprefix() {
+        let mut cg = CgroupV1::new("/sys/fs/cgroup/cpuset");
+        cg.set_controllers(&["cpuset"]).set_noprefix(true);
+        assert_eq!(cg.format_options(), b"cpuset,noprefix");
+        assert_eq!(format!("{}", cg),
+                   "cgroup cpuset,noprefix -> \"/sys/fs/cgroup/cpuset\"");
+    }
+}
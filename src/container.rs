@@ -0,0 +1,300 @@
+use std::io;
+use std::fmt;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use libc::{self, EEXIST};
+use nix::mount::{MsFlags, MntFlags, mount, umount2};
+
+use {OSError, Error};
+use util::{path_to_cstring, as_path, format_ms_flags};
+use explain::{Explainable, PathRole, target_exists, dir_state, user};
+
+
+/// Standard pseudo filesystems for a container root
+///
+/// Mounts the following under the `root` (creating mount points if needed):
+///
+/// * `proc` at `/proc` (nosuid,nodev,noexec)
+/// * `sysfs` at `/sys` (read-only, nosuid,nodev,noexec)
+/// * `tmpfs` at `/dev` (nosuid,noexec,mode=0755)
+/// * `devpts` at `/dev/pts` (new instance, nosuid,noexec), with
+///   `/dev/ptmx` symlinked to `pts/ptmx`
+/// * `tmpfs` at `/tmp` (nosuid,nodev,mode=01777)
+///
+/// The root filesystem is considered untrusted: symlinks inside it are never
+/// followed when creating or mounting on the mount points, so e.g. a `dev ->
+/// /` symlink makes the mount fail instead of mounting over host's root.
+///
+/// Tmpfs is used for `/dev` instead of `devtmpfs` because the latter is
+/// shared with the host and can't be mounted in user namespace. Device nodes
+/// should be bind-mounted from the host if needed.
+///
+/// If any of the mounts fails, the ones already mounted are unmounted, so
+/// the root is left as it was (except created mount point directories).
+#[derive(Debug, Clone)]
+pub struct ContainerMounts {
+    root: PathBuf,
+}
+
+/// A single step of the `ContainerMounts`
+#[derive(Debug, Clone)]
+struct PseudoMount {
+    fstype: &'static [u8],
+    root: PathBuf,
+    path: &'static str,
+    target: CString,
+    flags: MsFlags,
+    options: &'static [u8],
+}
+
+impl ContainerMounts {
+    /// Create a set of mounts for the container at `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> ContainerMounts {
+        ContainerMounts {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn steps(&self) -> Vec<PseudoMount> {
+        let step = |fstype: &'static [u8], path: &'static str,
+                    flags: MsFlags, options: &'static [u8]|
+        {
+            PseudoMount {
+                fstype,
+                root: self.root.clone(),
+                path,
+                target: path_to_cstring(&self.root.join(path)),
+                flags,
+                options,
+            }
+        };
+        let nosuid = MsFlags::MS_NOSUID;
+        let nodev = MsFlags::MS_NODEV;
+        let noexec = MsFlags::MS_NOEXEC;
+        vec![
+            step(b"proc\0", "proc", nosuid|nodev|noexec, b"\0"),
+            step(b"sysfs\0", "sys", MsFlags::MS_RDONLY|nosuid|nodev|noexec,
+                 b"\0"),
+            step(b"tmpfs\0", "dev", nosuid|noexec, b"mode=0755\0"),
+            step(b"devpts\0", "dev/pts", nosuid|noexec,
+                 b"newinstance,ptmxmode=0666,mode=0620\0"),
+            step(b"tmpfs\0", "tmp", nosuid|nodev, b"mode=01777\0"),
+        ]
+    }
+
+    /// Mount all the filesystems
+    pub fn bare_mount(self) -> Result<(), OSError> {
+        let mut mounted = Vec::new();
+        for step in self.steps() {
+            let target = step.target.clone();
+            match step.mount() {
+                Ok(()) => mounted.push(target),
+                Err(e) => {
+                    for target in mounted.iter().rev() {
+                        umount2(&**target,
+                                MntFlags::MNT_DETACH | UMOUNT_NOFOLLOW).ok();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Mount all the filesystems and explain error immediately
+    pub fn mount(self) -> Result<(), Error> {
+        self.bare_mount().map_err(OSError::explain)
+    }
+}
+
+/// `UMOUNT_NOFOLLOW` flag, nix doesn't have it
+const UMOUNT_NOFOLLOW: MntFlags = unsafe {
+    MntFlags::from_bits_unchecked(libc::UMOUNT_NOFOLLOW)
+};
+
+impl PseudoMount {
+    fn mount(self) -> Result<(), OSError> {
+        let dir = match open_beneath(&self.root, self.path) {
+            Ok(dir) => dir,
+            Err(e) => return Err(OSError::from_io(e, Box::new(self))),
+        };
+        // mounting on the magic link mounts on the directory it refers to
+        let fd_path = CString::new(
+            format!("/proc/self/fd/{}", dir.as_raw_fd())).unwrap();
+        let fstype = CStr::from_bytes_with_nul(self.fstype).unwrap();
+        let options = CStr::from_bytes_with_nul(self.options).unwrap();
+        if let Err(err) = mount(Some(fstype), &*fd_path, Some(fstype),
+                                self.flags, Some(options))
+        {
+            return Err(OSError::from_nix(err, Box::new(self)));
+        }
+        if self.fstype == b"devpts\0" {
+            if let Err(e) = self.link_ptmx() {
+                umount2(&*self.target,
+                        MntFlags::MNT_DETACH | UMOUNT_NOFOLLOW).ok();
+                return Err(OSError::from_io(e, Box::new(self)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Symlinks `ptmx` next to the `pts` directory to `pts/ptmx`
+    fn link_ptmx(&self) -> io::Result<()> {
+        let parent = Path::new(self.path).parent()
+            .and_then(|p| p.to_str()).unwrap_or("");
+        let dir = open_beneath(&self.root, parent)?;
+        let res = unsafe {
+            libc::symlinkat(b"pts/ptmx\0".as_ptr() as *const _,
+                dir.as_raw_fd(), b"ptmx\0".as_ptr() as *const _)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Opens the directory `path` relative to `root` without following symlinks
+///
+/// Missing directories are created. Any symlink on the way makes the call
+/// fail with `ENOTDIR`, so the result is always inside the `root`.
+fn open_beneath(root: &Path, path: &str) -> io::Result<File> {
+    let mut dir = File::open(root)?;
+    for name in path.split('/').filter(|x| !x.is_empty()) {
+        if name == ".." {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "parent directory is not allowed in the path"));
+        }
+        let name = CString::new(name).unwrap();
+        let res = unsafe {
+            libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755)
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(EEXIST) {
+                return Err(err);
+            }
+        }
+        let fd = unsafe {
+            libc::openat(dir.as_raw_fd(), name.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW
+                | libc::O_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        dir = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(dir)
+}
+
+impl fmt::Display for ContainerMounts {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "container mounts at {:?}", self.root)
+    }
+}
+
+impl fmt::Display for PseudoMount {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let fstype = &self.fstype[..self.fstype.len()-1];
+        let options = &self.options[..self.options.len()-1];
+        write!(fmt, "{} {} -> {:?}",
+            String::from_utf8_lossy(fstype),
+            String::from_utf8_lossy(options),
+            as_path(&self.target))
+    }
+}
+
+impl Explainable for PseudoMount {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", target_exists(as_path(&self.target))),
+            format!("target: {}", dir_state(as_path(&self.target))),
+            format!("flags: {}", format_ms_flags(self.flags)),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs::{File, OpenOptions, create_dir};
+    use std::os::unix::fs::symlink;
+
+    use nix::unistd::getuid;
+
+    use Unmount;
+    use mountinfo::mounts_under;
+    use util::test::tmp_dir;
+    use super::ContainerMounts;
+
+    #[test]
+    fn test_steps() {
+        let steps = ContainerMounts::new("/root").steps().iter()
+            .map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(steps, vec![
+            "proc  -> \"/root/proc\"",
+            "sysfs  -> \"/root/sys\"",
+            "tmpfs mode=0755 -> \"/root/dev\"",
+            "devpts newinstance,ptmxmode=0666,mode=0620 -> \"/root/dev/pts\"",
+            "tmpfs mode=01777 -> \"/root/tmp\"",
+        ]);
+    }
+
+    #[test]
+    fn test_container_mounts() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("container_mounts");
+        ContainerMounts::new(&dir).mount().unwrap();
+        let fstypes = mounts_under(&dir).unwrap().into_iter()
+            .map(|m| m.fstype.into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(fstypes, ["proc", "sysfs", "tmpfs", "devpts", "tmpfs"]
+                   .iter().map(OsStr::new).collect::<Vec<_>>());
+        assert!(dir.join("proc/self").exists());
+        OpenOptions::new().read(true).write(true)
+            .open(dir.join("dev/ptmx")).unwrap();
+        Unmount::recursive(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_symlink_escape() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("container_mounts_escape");
+        let outside = tmp_dir("container_mounts_outside");
+        create_dir(dir.join("root")).unwrap();
+        symlink(&outside, dir.join("root/tmp")).unwrap();
+        let err = ContainerMounts::new(dir.join("root")).mount()
+            .unwrap_err();
+        assert!(err.to_string().starts_with("tmpfs mode=01777 -> "));
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+        assert_eq!(mounts_under(&outside).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_rollback() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("container_mounts_rollback");
+        // mount point for /dev can't be created
+        File::create(dir.join("dev")).unwrap();
+        let err = ContainerMounts::new(&dir).mount().unwrap_err();
+        assert!(err.to_string().starts_with("tmpfs mode=0755 -> "));
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+    }
+}
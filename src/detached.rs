@@ -0,0 +1,372 @@
+use std::io;
+use std::fmt;
+use std::ffi::{CStr, CString};
+use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
+use std::path::Path;
+
+use libc::{c_int, c_uint, fcntl, AT_FDCWD, FD_CLOEXEC, F_GETFD, F_SETFD};
+use nix::unistd::close;
+use nix::mount::MsFlags;
+
+use {OSError, Error, Propagation};
+use util::{path_to_cstring, as_path};
+use explain::{Explainable, PathRole, target_exists, dir_state, user};
+use mountinfo::mount_id_of_fd;
+use newapi::{mount_attrs, AttachCalls, CreateCalls, Kernel, MountAttr};
+use newapi::{SETATTR_EMPTY_PATH, MOVE_MOUNT_F_EMPTY_PATH};
+use newapi::{FSOPEN_CLOEXEC, FSMOUNT_CLOEXEC};
+use newapi::{FSCONFIG_SET_FLAG, FSCONFIG_SET_STRING, FSCONFIG_SET_FD};
+use newapi::FSCONFIG_CMD_CREATE;
+
+
+/// A mount which is not attached to any place in the filesystem tree yet
+///
+/// Created by the new mount API (linux 5.2+). The mount is referenced only
+/// by the file descriptor, so it can be passed to other processes or
+/// attached later with `attach_to()`. If it's dropped without attaching,
+/// the file descriptor is closed and the filesystem is unmounted.
+#[derive(Debug)]
+pub struct DetachedMount {
+    fd: RawFd,
+    description: String,
+    propagation: Option<Propagation>,
+}
+
+/// An operation of attaching detached mount (used for error reporting)
+#[derive(Debug)]
+struct Attach {
+    description: String,
+    target: CString,
+}
+
+impl DetachedMount {
+    pub(crate) fn from_raw_fd(fd: RawFd, description: String)
+        -> DetachedMount
+    {
+        DetachedMount {
+            fd,
+            description,
+            propagation: None,
+        }
+    }
+
+    /// Set propagation type of the mount right after attaching it
+    ///
+    /// This uses `mount_setattr` which is available in linux 5.12+. Note
+    /// if setting propagation fails, the mount is left attached.
+    pub fn propagation(mut self, propagation: Propagation) -> DetachedMount {
+        self.set_propagation(propagation);
+        self
+    }
+
+    /// Set propagation type (in-place version of `propagation`)
+    pub fn set_propagation(&mut self, propagation: Propagation)
+        -> &mut DetachedMount
+    {
+        self.propagation = Some(propagation);
+        self
+    }
+
+    /// Attach the mount at `target` path (using `move_mount`)
+    pub fn bare_attach_to<P: AsRef<Path>>(self, target: P)
+        -> Result<(), OSError>
+    {
+        let target = path_to_cstring(target.as_ref());
+        // the mount is kept alive by the mount tree after `move_mount`
+        self.attach(&target, &Kernel)
+            .map_err(|err| OSError::from_nix(err, Box::new(Attach {
+                description: self.description.clone(),
+                target,
+            })))
+    }
+
+    fn attach<C: AttachCalls>(&self, target: &CStr, calls: &C)
+        -> ::nix::Result<()>
+    {
+        let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+        calls.move_mount(self.fd, empty,
+            AT_FDCWD, target, MOVE_MOUNT_F_EMPTY_PATH)?;
+        if let Some(propagation) = self.propagation {
+            calls.mount_setattr(self.fd, empty, SETATTR_EMPTY_PATH,
+                &MountAttr::propagation(propagation))?;
+        }
+        Ok(())
+    }
+
+    /// Attach the mount at `target` path and explain the error immediately
+    pub fn attach_to<P: AsRef<Path>>(self, target: P) -> Result<(), Error> {
+        self.bare_attach_to(target).map_err(OSError::explain)
+    }
+}
+
+/// A parameter of the filesystem context (see `create_detached_with`)
+#[derive(Debug, Clone)]
+pub(crate) enum FsParam {
+    Flag(CString),
+    String(CString, CString),
+    Fd(CString, RawFd),
+}
+
+/// A step of `create_detached_with` which has failed
+#[derive(Debug)]
+pub(crate) enum CreateStep<'a> {
+    Open,
+    Set(&'a CStr),
+    Create,
+    Mount,
+}
+
+/// Create a detached mount with comma-separated `options`
+///
+/// Options are passed one by one with `fsconfig`, so unlike `mount()` commas
+/// can't be escaped.
+pub fn create_detached(fstype: &CStr, options: &[u8], flags: MsFlags)
+    -> ::nix::Result<DetachedMount>
+{
+    let params = parse_params(options)?;
+    let fd = create_detached_with(fstype, &params, mount_attrs(flags),
+                                  &Kernel)
+        .map_err(|(_, err)| err)?;
+    Ok(DetachedMount::from_raw_fd(fd,
+        format!("detached {}", fstype.to_string_lossy())))
+}
+
+fn parse_params(options: &[u8]) -> ::nix::Result<Vec<FsParam>> {
+    let cstring = |x: &[u8]| CString::new(x)
+        .map_err(|_| ::nix::Error::InvalidPath);
+    options.split(|&c| c == b',').filter(|x| !x.is_empty()).map(|opt| {
+        let mut pair = opt.splitn(2, |&c| c == b'=');
+        let key = cstring(pair.next().unwrap())?;
+        match pair.next() {
+            Some(value) => Ok(FsParam::String(key, cstring(value)?)),
+            None => Ok(FsParam::Flag(key)),
+        }
+    }).collect()
+}
+
+/// Create a filesystem with `params` and a detached mount of it
+///
+/// Returns the file descriptor of the mount, or the step which has failed
+/// along with the error. The filesystem context is closed in any case.
+pub(crate) fn create_detached_with<'a, C: CreateCalls>(fstype: &CStr,
+    params: &'a [FsParam], attr_flags: c_uint, calls: &C)
+    -> Result<RawFd, (CreateStep<'a>, ::nix::Error)>
+{
+    let fs = calls.fsopen(fstype, FSOPEN_CLOEXEC)
+        .map_err(|err| (CreateStep::Open, err))?;
+    let result = configure(fs, params, calls).and_then(|()| {
+        calls.fsmount(fs, FSMOUNT_CLOEXEC, attr_flags)
+            .map_err(|err| (CreateStep::Mount, err))
+    });
+    calls.close(fs).ok();
+    result
+}
+
+fn configure<'a, C: CreateCalls>(fs: RawFd, params: &'a [FsParam], calls: &C)
+    -> Result<(), (CreateStep<'a>, ::nix::Error)>
+{
+    for param in params {
+        let (key, res) = match *param {
+            FsParam::Flag(ref key) => (key, calls.fsconfig(fs,
+                FSCONFIG_SET_FLAG, Some(key), None, 0)),
+            FsParam::String(ref key, ref value) => (key,
+                calls.fsconfig(fs, FSCONFIG_SET_STRING,
+                    Some(key), Some(value), 0)),
+            FsParam::Fd(ref key, fd) => (key, calls.fsconfig(fs,
+                FSCONFIG_SET_FD, Some(key), None, fd)),
+        };
+        res.map_err(|err| (CreateStep::Set(key), err))?;
+    }
+    calls.fsconfig(fs, FSCONFIG_CMD_CREATE, None, None, 0)
+        .map_err(|err| (CreateStep::Create, err))
+}
+
+impl DetachedMount {
+    /// Returns kernel-assigned id of the mount (`mount_id` in mountinfo)
+    pub fn mount_id(&self) -> io::Result<u64> {
+        mount_id_of_fd(self)
+    }
+
+    /// Toggle close-on-exec flag of the file descriptor
+    ///
+    /// Detached mounts are always created with `O_CLOEXEC`. Clear it to
+    /// deliberately pass the mount to a child process via `exec` (the child
+    /// gets the same fd number, see `as_raw_fd()`).
+    pub fn cloexec(mut self, flag: bool) -> io::Result<DetachedMount> {
+        self.set_cloexec(flag)?;
+        Ok(self)
+    }
+
+    /// Toggle close-on-exec flag (in-place version of `cloexec`)
+    pub fn set_cloexec(&mut self, flag: bool)
+        -> io::Result<&mut DetachedMount>
+    {
+        let flags = self.fd_flags()?;
+        let flags = if flag {
+            flags | FD_CLOEXEC
+        } else {
+            flags & !FD_CLOEXEC
+        };
+        if unsafe { fcntl(self.fd, F_SETFD, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(self)
+    }
+
+    /// Returns `true` if the file descriptor is closed on `exec`
+    pub fn is_cloexec(&self) -> io::Result<bool> {
+        Ok(self.fd_flags()? & FD_CLOEXEC != 0)
+    }
+
+    fn fd_flags(&self) -> io::Result<c_int> {
+        match unsafe { fcntl(self.fd, F_GETFD) } {
+            -1 => Err(io::Error::last_os_error()),
+            flags => Ok(flags),
+        }
+    }
+}
+
+impl AsRawFd for DetachedMount {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for DetachedMount {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        ::std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for DetachedMount {
+    fn drop(&mut self) {
+        close(self.fd).ok();
+    }
+}
+
+impl fmt::Display for Attach {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "attach {} -> {:?}",
+            self.description, as_path(&self.target))
+    }
+}
+
+impl Explainable for Attach {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", target_exists(as_path(&self.target))),
+            format!("target: {}", dir_state(as_path(&self.target))),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::ffi::{CStr, CString};
+    use std::fs::File;
+    use std::os::unix::io::{RawFd, AsRawFd, IntoRawFd};
+
+    use libc::{c_uint, fcntl, AT_FDCWD, FD_CLOEXEC, F_GETFD};
+    use nix::Result;
+    use nix::mount::MsFlags;
+    use nix::unistd::getuid;
+
+    use Propagation;
+    use newapi::{AttachCalls, MountAttr};
+    use newapi::{MOVE_MOUNT_F_EMPTY_PATH, SETATTR_EMPTY_PATH};
+    use super::{DetachedMount, create_detached};
+
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<String>>);
+
+    impl AttachCalls for Recorder {
+        fn move_mount(&self, from_dfd: RawFd, from_path: &CStr,
+            to_dfd: RawFd, to_path: &CStr, flags: c_uint)
+            -> Result<()>
+        {
+            assert_eq!(to_dfd, AT_FDCWD);
+            assert_eq!(flags, MOVE_MOUNT_F_EMPTY_PATH);
+            self.0.borrow_mut().push(format!("move_mount({}, {:?}, {:?})",
+                from_dfd, from_path, to_path));
+            Ok(())
+        }
+        fn mount_setattr(&self, dfd: RawFd, path: &CStr, flags: c_uint,
+            attr: &MountAttr)
+            -> Result<()>
+        {
+            assert_eq!(flags, SETATTR_EMPTY_PATH);
+            self.0.borrow_mut().push(format!("mount_setattr({}, {:?}, {:x})",
+                dfd, path, attr.propagation));
+            Ok(())
+        }
+    }
+
+    fn fake_mount() -> DetachedMount {
+        DetachedMount {
+            fd: -1,
+            description: "detached tmpfs".to_string(),
+            propagation: None,
+        }
+    }
+
+    #[test]
+    fn test_cloexec() {
+        let fd = File::open("/dev/null").unwrap().into_raw_fd();
+        let mnt = DetachedMount::from_raw_fd(fd, "/dev/null".to_string());
+        assert!(mnt.is_cloexec().unwrap());
+        let mnt = mnt.cloexec(false).unwrap();
+        assert!(!mnt.is_cloexec().unwrap());
+        assert_eq!(unsafe { fcntl(fd, F_GETFD) } & FD_CLOEXEC, 0);
+        let mnt = mnt.cloexec(true).unwrap();
+        assert_eq!(unsafe { fcntl(fd, F_GETFD) } & FD_CLOEXEC, FD_CLOEXEC);
+        drop(mnt);
+    }
+
+    #[test]
+    fn test_detached_cloexec() {
+        if !getuid().is_root() {
+            return;
+        }
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let mut mnt = create_detached(&tmpfs, b"size=1m", MsFlags::empty())
+            .unwrap();
+        assert!(mnt.is_cloexec().unwrap());
+        mnt.set_cloexec(false).unwrap();
+        let flags = unsafe { fcntl(mnt.as_raw_fd(), F_GETFD) };
+        assert_eq!(flags & FD_CLOEXEC, 0);
+    }
+
+    #[test]
+    fn test_attach_calls() {
+        let calls = Recorder::default();
+        let target = CString::new("/mnt").unwrap();
+        let mnt = fake_mount();
+        mnt.attach(&target, &calls).unwrap();
+        assert_eq!(*calls.0.borrow(), vec![
+            "move_mount(-1, \"\", \"/mnt\")".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_attach_propagation() {
+        let calls = Recorder::default();
+        let target = CString::new("/mnt").unwrap();
+        let mnt = fake_mount().propagation(Propagation::Private);
+        mnt.attach(&target, &calls).unwrap();
+        assert_eq!(*calls.0.borrow(), vec![
+            "move_mount(-1, \"\", \"/mnt\")".to_string(),
+            format!("mount_setattr(-1, \"\", {:x})",
+                    MsFlags::MS_PRIVATE.bits()),
+        ]);
+    }
+}
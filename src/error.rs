@@ -1,30 +1,345 @@
 use std::io;
 use std::fmt;
+use std::path::Path;
 use std::error::Error as StdError;
 
-use {OSError, Error, MountError};
+use libc::{c_int, EACCES, EPERM, EBUSY, ENOENT, EROFS, ENODEV, ENOSYS};
+use libc::{EINVAL, ENOSPC};
+use nix::unistd::getuid;
+
+use {OSError, Error, MountError, MountArgs};
+use explain::{mac_state, unprivileged_userns_enabled};
+use explain::{missing_capabilities, mount_limit};
+use explain::{PathRole, exists, mount_point};
 use remount::RemountError;
+use unmount::UnmountError;
+use fsmount::FsMountError;
+use modify::{SwapError, RecreateError};
+use timeout::TimeoutError;
+use util::supports_open_tree;
 
 impl OSError {
     /// Convert error to the one providing extra useful information
     pub fn explain(self) -> Error {
-        let text = self.1.explain();
-        match self.0 {
-            MountError::Io(e) => Error(self.1, e, text),
+        let text = self.1.explain_errno(self.raw_os_error());
+        let category = self.category();
+        let (err, text) = match self.0 {
+            MountError::Io(e) => {
+                let mut text = text;
+                if let Some(EACCES) | Some(EPERM) = e.raw_os_error() {
+                    if let Some(mac) = mac_state() {
+                        text = format!("{}, mac: {}", text, mac);
+                    }
+                }
+                if e.raw_os_error() == Some(EPERM) && !getuid().is_root()
+                    && !unprivileged_userns_enabled()
+                {
+                    text = format!("{}, userns: disabled", text);
+                }
+                if e.raw_os_error() == Some(ENOSPC) && self.1.creates_mount() {
+                    if let Some(limit) = mount_limit() {
+                        text = format!("{}, mount-limit: {}", text, limit);
+                    }
+                }
+                (e, text)
+            },
             MountError::Remount(RemountError::Io(msg, io_err)) => {
-                Error(self.1, io_err, format!("{}, {}", msg, text))
+                (io_err, format!("{}, {}", msg, text))
             },
             MountError::Remount(err) => {
                 let text = format!("{}, {}", &err, text);
                 let err = Box::new(err);
-                Error(self.1,
-                      io::Error::new(io::ErrorKind::InvalidData, err),
-                      text)
+                (io::Error::new(io::ErrorKind::InvalidData, err), text)
+            },
+            MountError::Unmount(UnmountError::Io(msg, io_err)) => {
+                (io_err, format!("{}, {}", msg, text))
+            },
+            MountError::Unmount(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::other(err), text)
+            },
+            MountError::Bind(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::new(io::ErrorKind::InvalidInput, err), text)
+            },
+            MountError::Pivot(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::new(io::ErrorKind::InvalidInput, err), text)
+            },
+            MountError::Tmpfs(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::new(io::ErrorKind::InvalidInput, err), text)
+            },
+            MountError::FsMount(FsMountError::Io(msg, io_err)) => {
+                (io_err, format!("{}, {}", msg, text))
+            },
+            MountError::FsMount(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::new(io::ErrorKind::InvalidInput, err), text)
+            },
+            MountError::Swap(SwapError::Io(msg, io_err)) => {
+                (io_err, format!("{}, {}", msg, text))
+            },
+            MountError::Swap(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::other(err), text)
             },
+            MountError::Recreate(RecreateError::Io(msg, io_err)) => {
+                (io_err, format!("{}, {}", msg, text))
+            },
+            MountError::Recreate(err) => {
+                let text = format!("{}, {}", &err, text);
+                // keep the errno of the failed `new` operation
+                let io_err = match err.raw_os_error() {
+                    Some(errno) => io::Error::from_raw_os_error(errno),
+                    None => io::Error::new(io::ErrorKind::InvalidInput,
+                                           Box::new(err)),
+                };
+                (io_err, text)
+            },
+            MountError::Timeout(err) => {
+                let text = format!("{}, {}", &err, text);
+                let kind = match err {
+                    TimeoutError::MountTimeout(..) => io::ErrorKind::TimedOut,
+                    TimeoutError::ThreadPanicked => io::ErrorKind::Other,
+                };
+                (io::Error::new(kind, Box::new(err)), text)
+            },
+            MountError::Overlay(err) => {
+                let text = format!("{}, {}", &err, text);
+                let err = Box::new(err);
+                (io::Error::new(io::ErrorKind::InvalidInput, err), text)
+            },
+        };
+        // `ENOSYS` only comes from the new mount API, which has no fallback
+        let text = if err.raw_os_error() == Some(ENOSYS)
+            && !supports_open_tree()
+        {
+            format!("{}, kernel: older than 5.2", text)
+        } else {
+            text
+        };
+        Error(self.1, err, text, category)
+    }
+
+    fn category(&self) -> ErrorCategory {
+        use self::ErrorCategory::*;
+        let paths = self.1.paths();
+        let probe = |role, check: &dyn Fn(&Path) -> bool| {
+            paths.iter().any(|&(r, path)| r == role && check(path))
+        };
+        let missing = |path: &Path| exists(path) == "missing";
+        match self.raw_os_error() {
+            Some(ENOENT) => {
+                if probe(PathRole::Target, &missing) {
+                    TargetMissing
+                } else if probe(PathRole::Source, &missing) {
+                    SourceMissing
+                } else {
+                    Other
+                }
+            }
+            Some(EACCES) | Some(EPERM) => PermissionDenied,
+            Some(EBUSY) => Busy,
+            Some(ENODEV) | Some(ENOSYS) => UnsupportedFilesystem,
+            Some(EINVAL) => {
+                if probe(PathRole::MountPoint,
+                         &|path| mount_point(path) == "not-a-mountpoint")
+                {
+                    NotAMountpoint
+                } else {
+                    InvalidConfiguration
+                }
+            }
+            Some(_) => Other,
+            None => match self.0 {
+                MountError::Remount(RemountError::UnknownMountPoint(_)) => {
+                    NotAMountpoint
+                }
+                MountError::Bind(_) | MountError::Pivot(_) |
+                MountError::Tmpfs(_) | MountError::Overlay(_) |
+                MountError::Recreate(RecreateError::WrongTarget(_)) |
+                MountError::FsMount(FsMountError::NulByte(_)) => {
+                    InvalidConfiguration
+                }
+                _ => Other,
+            },
+        }
+    }
+}
+
+/// A category of the error which is stable across library versions
+///
+/// Unlike error messages (and errno values, which are ambiguous for mount)
+/// this is suitable for handling errors programmatically, e.g. to pass
+/// them across FFI boundary or to serialize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    /// Target path (mount point) doesn't exist
+    TargetMissing,
+    /// Source path (or device) doesn't exist
+    SourceMissing,
+    /// Operation is not permitted (`EACCES` or `EPERM`)
+    PermissionDenied,
+    /// Mount point or device is busy (`EBUSY`)
+    Busy,
+    /// Filesystem type or system call isn't supported by the kernel
+    UnsupportedFilesystem,
+    /// Options are invalid, either rejected by the library or by the kernel
+    InvalidConfiguration,
+    /// The path is expected to be a mount point but it isn't
+    NotAMountpoint,
+    /// Any other error
+    Other,
+}
+
+macro_rules! errno_predicates {
+    ($typ:ident) => {
+        impl $typ {
+            /// Mount point or device is busy (`EBUSY`)
+            pub fn is_busy(&self) -> bool {
+                self.raw_os_error() == Some(EBUSY)
+            }
+            /// Operation is not permitted (`EACCES` or `EPERM`)
+            pub fn is_permission_denied(&self) -> bool {
+                match self.raw_os_error() {
+                    Some(EACCES) | Some(EPERM) => true,
+                    _ => false,
+                }
+            }
+            /// Some path or device doesn't exist (`ENOENT`)
+            pub fn is_not_found(&self) -> bool {
+                self.raw_os_error() == Some(ENOENT)
+            }
+            /// Filesystem is read-only (`EROFS`)
+            pub fn is_read_only(&self) -> bool {
+                self.raw_os_error() == Some(EROFS)
+            }
+            /// Filesystem type or system call isn't supported by the kernel
+            /// (`ENODEV` or `ENOSYS`)
+            pub fn is_unsupported(&self) -> bool {
+                match self.raw_os_error() {
+                    Some(ENODEV) | Some(ENOSYS) => true,
+                    _ => false,
+                }
+            }
+            /// Filesystem is probably already mounted at the target
+            ///
+            /// This is a heuristic: kernel returns `EBUSY` when the same
+            /// block device is already mounted, but `EBUSY` is also returned
+            /// for other reasons. So this is the same as `is_busy()` for now,
+            /// but communicates the intention better.
+            pub fn is_already_mounted(&self) -> bool {
+                self.is_busy()
+            }
         }
     }
 }
 
+impl OSError {
+    /// Returns the OS error code, if the error was returned by the kernel
+    ///
+    /// Validation errors made by the library itself have no code.
+    pub fn raw_os_error(&self) -> Option<c_int> {
+        match self.0 {
+            MountError::Io(ref e) => e.raw_os_error(),
+            MountError::Remount(RemountError::Io(_, ref e)) => {
+                e.raw_os_error()
+            }
+            MountError::Unmount(UnmountError::Io(_, ref e)) => {
+                e.raw_os_error()
+            }
+            MountError::FsMount(FsMountError::Io(_, ref e)) => {
+                e.raw_os_error()
+            }
+            MountError::Swap(SwapError::Io(_, ref e)) => {
+                e.raw_os_error()
+            }
+            MountError::Recreate(ref err) => err.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    /// Returns the arguments of the failed `mount()` call (if any)
+    ///
+    /// See `Explainable::mount_args`.
+    pub fn mount_args(&self) -> Option<MountArgs> {
+        self.1.mount_args()
+    }
+
+    /// Returns capabilities which the process lacks to do the operation
+    ///
+    /// This is only non-empty for `EPERM` (which means `CAP_SYS_ADMIN` is
+    /// required) and `EACCES` (`CAP_DAC_OVERRIDE`), if the capability isn't
+    /// in the effective set of the current process. Note that in a user
+    /// namespace the capability is in the set, but the operation may still
+    /// be denied if the mount namespace is owned by another user namespace.
+    pub fn missing_capabilities(&self) -> Vec<&'static str> {
+        missing_capabilities(self.raw_os_error())
+    }
+}
+
+impl Error {
+    /// Returns the OS error code, if the error was returned by the kernel
+    ///
+    /// Validation errors made by the library itself have no code.
+    pub fn raw_os_error(&self) -> Option<c_int> {
+        self.1.raw_os_error()
+    }
+
+    /// Returns the explanation, i.e. the part of the message in parenthesis
+    ///
+    /// It's computed when the error is created, so calling this doesn't
+    /// probe the filesystem again.
+    pub fn explanation(&self) -> &str {
+        &self.2
+    }
+
+    /// Returns the arguments of the failed `mount()` call (if any)
+    ///
+    /// Use it to log the exact system call, e.g.
+    /// `mount("tmpfs", "/mnt", "tmpfs", MS_NOSUID, "size=1024")`.
+    pub fn mount_args(&self) -> Option<MountArgs> {
+        self.0.mount_args()
+    }
+
+    /// Returns capabilities which the process lacks to do the operation
+    ///
+    /// See `OSError::missing_capabilities`.
+    pub fn missing_capabilities(&self) -> Vec<&'static str> {
+        missing_capabilities(self.raw_os_error())
+    }
+
+    /// Returns the category of the error
+    ///
+    /// The category is derived from the errno and the probes made when
+    /// the error was explained, e.g. `ENOENT` with missing target path is
+    /// `TargetMissing`.
+    pub fn category(&self) -> ErrorCategory {
+        self.3
+    }
+}
+
+errno_predicates!(OSError);
+errno_predicates!(Error);
+
+/// Turns the error of one of the `kinds` into `Ok(false)`
+pub fn ignore_errors(result: Result<(), Error>, kinds: &[io::ErrorKind])
+    -> Result<bool, Error>
+{
+    match result {
+        Ok(()) => Ok(true),
+        Err(ref e) if kinds.contains(&e.1.kind()) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 impl fmt::Display for OSError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "{}: {}", self.1, self.0)
@@ -32,7 +347,7 @@ impl fmt::Display for OSError {
 }
 
 impl StdError for OSError {
-    fn cause(&self) -> Option<&StdError> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         Some(&self.0)
     }
     fn description(&self) -> &str {
@@ -47,10 +362,178 @@ impl fmt::Display for Error {
 }
 
 impl StdError for Error {
-    fn cause(&self) -> Option<&StdError> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         Some(&self.1)
     }
     fn description(&self) -> &str {
        self.1.description()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::error::Error as StdError;
+
+    use libc::{ENOENT, EPERM, EACCES, EBUSY, EROFS, ENODEV, ENOSYS, EINVAL};
+    use libc::ENOSPC;
+    use nix::errno::Errno;
+
+    use {Error, OSError, Tmpfs, Remount, RemountError};
+    use {BindMount, Move, Explainable};
+    use util::test::tmp_dir;
+    use explain::mount_limit;
+    use super::{ignore_errors, ErrorCategory};
+
+    fn source_chain(err: &StdError) -> Vec<String> {
+        let mut chain = vec![err.to_string()];
+        let mut cur = err.source();
+        while let Some(err) = cur {
+            chain.push(err.to_string());
+            cur = err.source();
+        }
+        chain
+    }
+
+    fn assert_send_sync<T: StdError + Send + Sync + 'static>() {}
+
+    fn failed_mount(errno: i32) -> Result<(), Error> {
+        Err(Error(Box::new(Tmpfs::new("/nonexistent")),
+                  io::Error::from_raw_os_error(errno),
+                  String::from("target: missing"),
+                  ErrorCategory::Other))
+    }
+
+    #[test]
+    fn test_ignore_errors() {
+        assert_eq!(ignore_errors(Ok(()), &[io::ErrorKind::NotFound]).unwrap(),
+                   true);
+        assert_eq!(ignore_errors(failed_mount(ENOENT),
+                                 &[io::ErrorKind::NotFound]).unwrap(),
+                   false);
+        let err = ignore_errors(failed_mount(EPERM),
+                                &[io::ErrorKind::NotFound]).unwrap_err();
+        assert_eq!(err.1.raw_os_error(), Some(EPERM));
+        assert!(ignore_errors(failed_mount(ENOENT), &[]).is_err());
+    }
+
+    #[test]
+    fn test_explanation() {
+        let err = failed_mount(ENOENT).unwrap_err();
+        assert_eq!(err.explanation(), "target: missing");
+        assert!(err.to_string().ends_with(
+            &format!("({})", err.explanation())));
+    }
+
+    #[test]
+    fn test_mount_limit_explanation() {
+        let os_err = |errno| OSError::from_io(
+            io::Error::from_raw_os_error(errno),
+            Box::new(Tmpfs::new("/nonexistent")));
+        let err = os_err(ENOSPC).explain();
+        // /proc/sys/fs/mount-max is missing on old kernels
+        if mount_limit().is_some() {
+            assert!(err.explanation().contains(", mount-limit: "));
+            assert!(err.explanation().ends_with(" used"));
+        } else {
+            assert!(!err.explanation().contains("mount-limit"));
+        }
+        let err = os_err(ENOENT).explain();
+        assert!(!err.explanation().contains("mount-limit"));
+        let err = OSError::from_io(io::Error::from_raw_os_error(ENOSPC),
+            Box::new(Remount::new("/nonexistent"))).explain();
+        assert!(!err.explanation().contains("mount-limit"));
+    }
+
+    #[test]
+    fn test_category() {
+        use super::ErrorCategory::*;
+        let err = |errno, op: Box<dyn Explainable>| {
+            OSError::from_io(io::Error::from_raw_os_error(errno), op)
+                .explain().category()
+        };
+        let dir = tmp_dir("error_category");
+        assert_eq!(err(ENOENT, Box::new(BindMount::new(&dir, "/nonexistent"))),
+                   TargetMissing);
+        assert_eq!(err(ENOENT, Box::new(BindMount::new("/nonexistent", &dir))),
+                   SourceMissing);
+        assert_eq!(err(ENOENT, Box::new(Remount::new("/nonexistent"))),
+                   TargetMissing);
+        assert_eq!(err(ENOENT, Box::new(BindMount::new(&dir, &dir))), Other);
+        assert_eq!(err(EPERM, Box::new(Tmpfs::new(&dir))), PermissionDenied);
+        assert_eq!(err(EACCES, Box::new(Tmpfs::new(&dir))), PermissionDenied);
+        assert_eq!(err(EBUSY, Box::new(Tmpfs::new(&dir))), Busy);
+        assert_eq!(err(ENODEV, Box::new(Tmpfs::new(&dir))),
+                   UnsupportedFilesystem);
+        assert_eq!(err(EINVAL, Box::new(Move::new(&dir, "/nonexistent"))),
+                   NotAMountpoint);
+        assert_eq!(err(EINVAL, Box::new(Tmpfs::new(&dir))),
+                   InvalidConfiguration);
+        assert_eq!(err(EROFS, Box::new(Tmpfs::new(&dir))), Other);
+
+        let err = Tmpfs::new("/nonexistent").size_fraction_of_ram(2.0)
+            .mount().unwrap_err();
+        assert_eq!(err.category(), InvalidConfiguration);
+        let err = Remount::new("/nonexistent").readonly(true)
+            .remount().unwrap_err();
+        assert_eq!(err.category(), NotAMountpoint);
+    }
+
+    #[test]
+    fn test_predicates() {
+        let err = |errno| failed_mount(errno).unwrap_err();
+        assert!(err(EBUSY).is_busy());
+        assert!(err(EBUSY).is_already_mounted());
+        assert!(err(EACCES).is_permission_denied());
+        assert!(err(EPERM).is_permission_denied());
+        assert!(err(ENOENT).is_not_found());
+        assert!(err(EROFS).is_read_only());
+        assert!(err(ENODEV).is_unsupported());
+        assert!(err(ENOSYS).is_unsupported());
+
+        let e = err(EINVAL);
+        assert_eq!(e.raw_os_error(), Some(EINVAL));
+        assert!(!e.is_busy() && !e.is_permission_denied() &&
+                !e.is_not_found() && !e.is_read_only() &&
+                !e.is_unsupported() && !e.is_already_mounted());
+    }
+
+    #[test]
+    fn test_os_error_predicates() {
+        let err = OSError::from_nix(::nix::Error::Sys(Errno::EROFS),
+                                    Box::new(Tmpfs::new("/nonexistent")));
+        assert!(err.is_read_only());
+        assert!(!err.is_busy());
+        assert_eq!(err.raw_os_error(), Some(EROFS));
+        assert!(err.explain().is_read_only());
+
+        let err = Tmpfs::new("/nonexistent").raw_data("x".repeat(1 << 20))
+            .bare_mount().unwrap_err();
+        assert_eq!(err.raw_os_error(), None);
+        assert!(!err.is_permission_denied());
+    }
+
+    #[test]
+    fn test_source_chain() {
+        assert_send_sync::<OSError>();
+        assert_send_sync::<Error>();
+        let remount_error = || OSError::from_remount(
+            RemountError::Io("Can't read mountinfo".to_string(),
+                             io::Error::from_raw_os_error(EACCES)),
+            Box::new(Remount::new("/mnt")));
+        let err = remount_error();
+        let chain = source_chain(&err);
+        assert_eq!(chain.len(), 4);
+        assert!(chain[2].starts_with("Can't read mountinfo: "));
+        let root = err.source().and_then(|e| e.source())
+            .and_then(|e| e.source()).unwrap();
+        assert_eq!(root.downcast_ref::<io::Error>().unwrap().raw_os_error(),
+                   Some(EACCES));
+
+        let err = remount_error().explain();
+        let root = err.source().unwrap();
+        assert_eq!(root.downcast_ref::<io::Error>().unwrap().raw_os_error(),
+                   Some(EACCES));
+        assert!(root.source().is_none());
+    }
+}
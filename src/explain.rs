@@ -1,42 +1,573 @@
 use std::io::Read;
-use std::fs::File;
+use std::fs::{File, symlink_metadata, metadata, read_dir, read_link};
 use std::fmt::{Display, Debug};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::Path;
 
+use libc::{c_int, EACCES, EPERM};
+use nix::sys::stat::{major, minor};
+use nix::sys::utsname::uname;
 use nix::unistd::getuid;
 
+use MountArgs;
+use mountinfo::{Parser, read_self_mountinfo, count, mount_max};
+use util::canonicalize_lenient;
 
-pub trait Explainable: Display + Debug {
+
+/// An operation which can explain why it has failed
+///
+/// This is implemented by all the mount operations of the library, and can
+/// be implemented by user types to construct `OSError` using
+/// `OSError::from_nix_error`. It's `Send + Sync` so that errors can be
+/// passed between threads and wrapped by `anyhow`-like libraries.
+pub trait Explainable: Display + Debug + Send + Sync {
+    /// Probe the environment and describe what might be wrong
+    ///
+    /// This is called only after the operation has failed. The result is
+    /// a comma-separated list of short facts, like `target: missing`.
     fn explain(&self) -> String;
+
+    /// Same as `explain()` but may add facts specific to the error code
+    ///
+    /// This is what `OSError::explain()` calls. By default it returns the
+    /// result of `explain()`.
+    fn explain_errno(&self, errno: Option<c_int>) -> String {
+        let _ = errno;
+        self.explain()
+    }
+
+    /// Arguments of the `mount()` call made by the operation
+    ///
+    /// Returns `None` by default, and for operations which don't call
+    /// `mount()` or have failed before calling it.
+    fn mount_args(&self) -> Option<MountArgs> {
+        None
+    }
+
+    /// Paths the operation works on, along with their role
+    ///
+    /// These are probed by `OSError::explain()` to find out the category
+    /// of the error (e.g. `ENOENT` for missing target is `TargetMissing`).
+    /// Returns an empty list by default.
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        Vec::new()
+    }
+
+    /// Whether the operation creates a new mount
+    ///
+    /// For such operations `ENOSPC` is explained by the limit of mounts
+    /// in the namespace. Returns `false` by default.
+    fn creates_mount(&self) -> bool {
+        false
+    }
+}
+
+/// Role of the path in the operation (see `Explainable::paths`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathRole {
+    /// Source of the mount (or of the move)
+    Source,
+    /// Mount point or the path which the operation changes
+    Target,
+    /// Path which must already be a mount point
+    MountPoint,
 }
 
+/// Checks path existence without following the symlink in the last component
+///
+/// Mounting over a symlink (or symlink as a source) often behaves
+/// differently than users expect, so we report symlinks explicitly.
 pub fn exists(path: &Path) -> &'static str {
-    if path.exists() {
-        "exists"
-    } else {
-        "missing"
+    match symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_symlink() => {
+            if path.exists() {
+                "exists (symlink)"
+            } else {
+                "exists (dangling symlink)"
+            }
+        }
+        Ok(_) => "exists",
+        Err(_) => "missing",
+    }
+}
+
+/// Same as `exists` but also tells where the path breaks if it's missing
+///
+/// E.g. `missing (exists up to /a)` for `/a/b/c` when there is no `/a/b`.
+pub fn target_exists(path: &Path) -> String {
+    match exists(path) {
+        "missing" => match deepest_existing(path) {
+            Some(ancestor) => {
+                format!("missing (exists up to {})", ancestor.display())
+            }
+            None => "missing".to_string(),
+        },
+        state => state.to_string(),
+    }
+}
+
+fn deepest_existing(path: &Path) -> Option<&Path> {
+    path.ancestors().skip(1)
+        .find(|p| !p.as_os_str().is_empty() && symlink_metadata(p).is_ok())
+}
+
+/// Checks whether path is a directory and whether it's empty
+pub fn dir_state(path: &Path) -> &'static str {
+    match metadata(path) {
+        Ok(ref meta) if meta.is_dir() => {
+            match read_dir(path) {
+                Ok(mut items) => {
+                    if items.next().is_none() {
+                        "empty-dir"
+                    } else {
+                        "non-empty-dir"
+                    }
+                }
+                Err(_) => "unreadable-dir",
+            }
+        }
+        Ok(_) => "not-a-dir",
+        Err(_) => "missing",
+    }
+}
+
+/// Logs a warning if `target` is a non-empty directory
+///
+/// Files in the directory become inaccessible while something is mounted
+/// over it, which is occasionally a mistake.
+pub fn warn_if_nonempty(target: &Path) {
+    if dir_state(target) == "non-empty-dir" {
+        warn!("Mounting over non-empty directory {:?}, \
+               its contents will be hidden", target);
+    }
+}
+
+/// Checks whether path is a mount point in current mount namespace
+pub fn mount_point(path: &Path) -> &'static str {
+    let path = canonicalize_lenient(path);
+    match read_self_mountinfo() {
+        Ok(content) => {
+            let found = Parser::new(&content)
+                .filter_map(|x| x.ok())
+                .any(|x| Path::new(&x.mount_point) == path);
+            if found { "mountpoint" } else { "not-a-mountpoint" }
+        }
+        Err(_) => "unknown",
+    }
+}
+
+/// Checks whether path is a block device and whether it's already mounted
+///
+/// Returns `already-mounted-at /x` if the device is mounted somewhere in
+/// current mount namespace (only the first mount point is reported).
+pub fn block_device(path: &Path) -> String {
+    let meta = metadata(path).ok()
+        .map(|m| (m.file_type().is_block_device(), m.rdev()));
+    let mountinfo = read_self_mountinfo().ok();
+    block_device_from(meta, mountinfo.as_ref().map(|x| &x[..]))
+}
+
+fn block_device_from(meta: Option<(bool, u64)>, mountinfo: Option<&[u8]>)
+    -> String
+{
+    match meta {
+        None => "missing".to_string(),
+        Some((false, _)) => "not-a-block-device".to_string(),
+        Some((true, rdev)) => {
+            let (major, minor) = (major(rdev), minor(rdev));
+            mountinfo
+                .and_then(|content| {
+                    Parser::new(content)
+                        .filter_map(|x| x.ok())
+                        .find(|x| x.major == major &&
+                                  x.minor == minor)
+                })
+                .map(|x| format!("already-mounted-at {}",
+                                 Path::new(&x.mount_point).display()))
+                .unwrap_or_else(|| "block-device".to_string())
+        }
+    }
+}
+
+/// Returns `None` if uid_map can't be read
+pub fn initial_user_namespace() -> Option<bool> {
+    let mut buf = String::with_capacity(100);
+    match File::open("/proc/self/uid_map")
+          .and_then(|mut f| f.read_to_string(&mut buf))
+    {
+        Ok(_) => Some(buf == "         0          0 4294967295\n"),
+        Err(_) => None,
+    }
+}
+
+/// Checks whether we are in the same mount namespace as the init process
+///
+/// Returns `unknown` if namespace of the init can't be read, which is
+/// usually the case for regular users.
+pub fn mount_namespace() -> &'static str {
+    match (read_link("/proc/self/ns/mnt"), read_link("/proc/1/ns/mnt")) {
+        (Ok(ref own), Ok(ref init)) if own == init => "same-as-init",
+        (Ok(_), Ok(_)) => "separate",
+        _ => "unknown",
     }
 }
 
 pub fn user() -> &'static str {
     let uid = getuid();
     if u32::from(uid) == 0 {
-        let mut buf = String::with_capacity(100);
-        match File::open("/proc/self/uid_map")
-              .and_then(|mut f| f.read_to_string(&mut buf))
-        {
-            Ok(_) => {
-                if buf == "         0          0 4294967295\n" {
-                    "superuser"
-                } else {
-                    "mapped-root"
-                }
-            }
-            Err(_) => {
-                "privileged"
-            }
+        match initial_user_namespace() {
+            Some(true) => "superuser",
+            Some(false) => "mapped-root",
+            None => "privileged",
         }
     } else {
         "regular-user"
     }
 }
+
+/// Returns mandatory access control system which may deny the mount
+///
+/// This is reported only for `EACCES` and `EPERM` errors, so it's a hint
+/// rather than a diagnosis.
+pub fn mac_state() -> Option<&'static str> {
+    let read = |path: &str| {
+        let mut buf = String::with_capacity(100);
+        File::open(path).and_then(|mut f| f.read_to_string(&mut buf))
+            .ok().map(|_| buf)
+    };
+    let apparmor_enabled = read("/sys/module/apparmor/parameters/enabled");
+    // with stacked security modules apparmor has its own file
+    let current = read("/proc/self/attr/apparmor/current")
+        .or_else(|| read("/proc/self/attr/current"));
+    let selinux_enforce = read("/sys/fs/selinux/enforce");
+    mac_state_from(apparmor_enabled.as_ref().map(|x| &x[..]),
+                   current.as_ref().map(|x| &x[..]),
+                   selinux_enforce.as_ref().map(|x| &x[..]))
+}
+
+fn mac_state_from(apparmor_enabled: Option<&str>,
+                  current: Option<&str>,
+                  selinux_enforce: Option<&str>)
+    -> Option<&'static str>
+{
+    if apparmor_enabled.map(|x| x.trim()) == Some("Y") {
+        match current.map(|x| x.trim_end_matches('\0').trim()) {
+            Some("unconfined") | Some("") | None => {}
+            Some(_) => return Some("apparmor-confined"),
+        }
+    }
+    if selinux_enforce.map(|x| x.trim()) == Some("1") {
+        return Some("selinux-enforcing");
+    }
+    None
+}
+
+/// Checks why filesystem type `fstype` is not supported (for `ENODEV`)
+///
+/// Returns `registered` if the kernel knows the type (so the error has
+/// another reason), `module-not-loaded (try modprobe X)` if there is a
+/// module with this name for the running kernel, and `unknown` otherwise,
+/// which is usually a typo in the filesystem type.
+pub fn fstype_state(fstype: &str) -> String {
+    let read = |path: &str| {
+        let mut buf = String::with_capacity(4096);
+        File::open(path).and_then(|mut f| f.read_to_string(&mut buf))
+            .ok().map(|_| buf)
+    };
+    let filesystems = read("/proc/filesystems");
+    let modules = read(&format!("/lib/modules/{}/modules.dep",
+                                uname().release()));
+    fstype_state_from(fstype,
+                      filesystems.as_ref().map(|x| &x[..]),
+                      modules.as_ref().map(|x| &x[..]))
+}
+
+fn fstype_state_from(fstype: &str, filesystems: Option<&str>,
+                     modules_dep: Option<&str>)
+    -> String
+{
+    // lines are like `nodev\ttmpfs` or `\text4`
+    let registered = filesystems.map(|x| x.lines().any(|line| {
+        line.split('\t').nth(1).map(|x| x.trim()) == Some(fstype)
+    })).unwrap_or(false);
+    if registered {
+        return "registered".to_string();
+    }
+    // kernel treats dashes and underscores in module names the same
+    let normalize = |name: &str| name.replace('-', "_");
+    let wanted = normalize(fstype);
+    // lines are like `kernel/fs/overlayfs/overlay.ko.zst: dependencies`
+    let module = modules_dep.and_then(|x| x.lines().find(|line| {
+        line.split(':').next()
+            .and_then(|path| path.rsplit('/').next())
+            .and_then(|file| file.split(".ko").next())
+            .map(|name| normalize(name) == wanted)
+            .unwrap_or(false)
+    }));
+    match module {
+        Some(_) => format!("module-not-loaded (try modprobe {})", fstype),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Capability bits (from `include/uapi/linux/capability.h`)
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Returns capabilities which are needed to avoid the error but the
+/// process doesn't have in the effective set
+///
+/// `EPERM` needs `CAP_SYS_ADMIN` (all mount-related calls require it),
+/// `EACCES` is usually caused by directory permissions, which are bypassed
+/// with `CAP_DAC_OVERRIDE`. Returns an empty list for other errors or if
+/// `/proc/self/status` can't be read.
+pub fn missing_capabilities(errno: Option<c_int>) -> Vec<&'static str> {
+    let mut buf = String::with_capacity(2048);
+    let status = File::open("/proc/self/status")
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .ok().map(|_| &buf[..]);
+    missing_capabilities_from(errno, status.and_then(parse_cap_eff))
+}
+
+fn missing_capabilities_from(errno: Option<c_int>, cap_eff: Option<u64>)
+    -> Vec<&'static str>
+{
+    let (name, bit) = match errno {
+        Some(EPERM) => ("CAP_SYS_ADMIN", CAP_SYS_ADMIN),
+        Some(EACCES) => ("CAP_DAC_OVERRIDE", CAP_DAC_OVERRIDE),
+        _ => return Vec::new(),
+    };
+    match cap_eff {
+        Some(caps) if caps & (1 << bit) == 0 => vec![name],
+        _ => Vec::new(),
+    }
+}
+
+/// Finds the `CapEff` field in the contents of `/proc/<pid>/status`
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    status.lines()
+        .find(|line| line.starts_with("CapEff:"))
+        .and_then(|line| {
+            u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+        })
+}
+
+/// Checks whether unprivileged users can create user namespaces
+///
+/// Debian and Ubuntu have `kernel.unprivileged_userns_clone` sysctl, other
+/// distributions usually disable namespaces by setting
+/// `user.max_user_namespaces` to zero. If neither file can be read, user
+/// namespaces are assumed to be enabled.
+pub fn unprivileged_userns_enabled() -> bool {
+    let read = |path: &str| {
+        let mut buf = String::with_capacity(20);
+        File::open(path).and_then(|mut f| f.read_to_string(&mut buf))
+            .ok().map(|_| buf)
+    };
+    let clone = read("/proc/sys/kernel/unprivileged_userns_clone");
+    let max = read("/proc/sys/user/max_user_namespaces");
+    unprivileged_userns_enabled_from(clone.as_ref().map(|x| &x[..]),
+                                     max.as_ref().map(|x| &x[..]))
+}
+
+fn unprivileged_userns_enabled_from(clone: Option<&str>, max: Option<&str>)
+    -> bool
+{
+    clone.map(|x| x.trim()) != Some("0") && max.map(|x| x.trim()) != Some("0")
+}
+
+/// Returns mount count and limit of the current mount namespace
+///
+/// Looks like `12/100000 used`, or `None` if either can't be read.
+pub fn mount_limit() -> Option<String> {
+    match (count(), mount_max()) {
+        (Ok(count), Ok(max)) => Some(mount_limit_from(count, max)),
+        _ => None,
+    }
+}
+
+fn mount_limit_from(count: usize, max: usize) -> String {
+    format!("{}/{} used", count, max)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    use std::fs::create_dir;
+
+    use libc::{EACCES, EBUSY, EPERM};
+
+    use util::test::tmp_dir;
+    use super::{exists, target_exists, dir_state, mount_point};
+    use super::block_device_from;
+    use super::{missing_capabilities_from, parse_cap_eff};
+    use super::{mac_state_from, unprivileged_userns_enabled_from};
+    use super::fstype_state_from;
+    use super::{mount_limit, mount_limit_from};
+
+    #[test]
+    fn test_exists() {
+        let dir = tmp_dir("explain_exists");
+        File::create(dir.join("file")).unwrap();
+        symlink(dir.join("file"), dir.join("link")).unwrap();
+        symlink(dir.join("nowhere"), dir.join("dangling")).unwrap();
+        assert_eq!(exists(&dir.join("file")), "exists");
+        assert_eq!(exists(&dir), "exists");
+        assert_eq!(exists(&dir.join("link")), "exists (symlink)");
+        assert_eq!(exists(&dir.join("dangling")),
+                   "exists (dangling symlink)");
+        assert_eq!(exists(&dir.join("nowhere")), "missing");
+    }
+
+    #[test]
+    fn test_target_exists() {
+        let dir = tmp_dir("explain_target_exists");
+        create_dir(dir.join("a")).unwrap();
+        File::create(dir.join("a/file")).unwrap();
+        assert_eq!(target_exists(&dir.join("a")), "exists");
+        assert_eq!(target_exists(&dir.join("a/b")),
+                   format!("missing (exists up to {})",
+                           dir.join("a").display()));
+        assert_eq!(target_exists(&dir.join("a/b/c/d")),
+                   format!("missing (exists up to {})",
+                           dir.join("a").display()));
+        assert_eq!(target_exists(&dir.join("x/y")),
+                   format!("missing (exists up to {})", dir.display()));
+        // a file in the middle of the path
+        assert_eq!(target_exists(&dir.join("a/file/c")),
+                   format!("missing (exists up to {})",
+                           dir.join("a/file").display()));
+        assert_eq!(target_exists(Path::new("nonexistent-relative/x")),
+                   "missing");
+    }
+
+    #[test]
+    fn test_dir_state() {
+        let dir = tmp_dir("explain_dir_state");
+        create_dir(dir.join("empty")).unwrap();
+        create_dir(dir.join("full")).unwrap();
+        File::create(dir.join("full/file")).unwrap();
+        assert_eq!(dir_state(&dir.join("empty")), "empty-dir");
+        assert_eq!(dir_state(&dir.join("full")), "non-empty-dir");
+        assert_eq!(dir_state(&dir.join("full/file")), "not-a-dir");
+        assert_eq!(dir_state(&dir.join("nowhere")), "missing");
+    }
+
+    #[test]
+    fn test_mount_point() {
+        assert_eq!(mount_point(Path::new("/")), "mountpoint");
+        assert_eq!(mount_point(Path::new("/nonexistent")), "not-a-mountpoint");
+    }
+
+    #[test]
+    fn test_block_device() {
+        let mountinfo = b"\
+            19 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            25 19 8:2 / /home rw,relatime shared:2 - ext4 /dev/sda2 rw\n";
+        let sda2 = (8 << 8) | 2;
+        let sdb1 = (8 << 8) | 17;
+        assert_eq!(block_device_from(None, Some(mountinfo)), "missing");
+        assert_eq!(block_device_from(Some((false, 0)), Some(mountinfo)),
+                   "not-a-block-device");
+        assert_eq!(block_device_from(Some((true, sda2)), Some(mountinfo)),
+                   "already-mounted-at /home");
+        assert_eq!(block_device_from(Some((true, sdb1)), Some(mountinfo)),
+                   "block-device");
+        assert_eq!(block_device_from(Some((true, sda2)), None),
+                   "block-device");
+    }
+
+    #[test]
+    fn test_mac_state() {
+        assert_eq!(mac_state_from(None, None, None), None);
+        assert_eq!(mac_state_from(Some("Y\n"), Some("unconfined\n"), None),
+                   None);
+        assert_eq!(mac_state_from(Some("Y\n"),
+                                  Some("docker-default (enforce)\n"), None),
+                   Some("apparmor-confined"));
+        assert_eq!(mac_state_from(Some("N\n"),
+                                  Some("docker-default (enforce)\n"), None),
+                   None);
+        assert_eq!(mac_state_from(None,
+                                  Some("system_u:system_r:container_t:s0\0"),
+                                  Some("1")),
+                   Some("selinux-enforcing"));
+        assert_eq!(mac_state_from(None,
+                                  Some("system_u:system_r:container_t:s0\0"),
+                                  Some("0")),
+                   None);
+        // just check it doesn't crash on the real system
+        super::mac_state();
+    }
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\n\
+                      CapPrm:\t000001ffffffffff\n\
+                      CapEff:\t000001ffffffffff\n\
+                      CapBnd:\t000001ffffffffff\n";
+        assert_eq!(parse_cap_eff(status), Some(0x1ffffffffff));
+        assert_eq!(parse_cap_eff("CapEff:\t0000000000000000\n"), Some(0));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+        assert_eq!(parse_cap_eff("CapEff:\tgarbage\n"), None);
+    }
+
+    #[test]
+    fn test_missing_capabilities() {
+        let missing = missing_capabilities_from;
+        let all = Some(0x1ffffffffff);
+        // a typical docker container: no CAP_SYS_ADMIN
+        let docker = Some(0x00000000a80425fb);
+        assert_eq!(missing(Some(EPERM), Some(0)), vec!["CAP_SYS_ADMIN"]);
+        assert_eq!(missing(Some(EPERM), docker), vec!["CAP_SYS_ADMIN"]);
+        assert_eq!(missing(Some(EPERM), all), Vec::<&str>::new());
+        assert_eq!(missing(Some(EACCES), Some(0)), vec!["CAP_DAC_OVERRIDE"]);
+        assert_eq!(missing(Some(EACCES), docker), Vec::<&str>::new());
+        assert_eq!(missing(Some(EBUSY), Some(0)), Vec::<&str>::new());
+        assert_eq!(missing(Some(EPERM), None), Vec::<&str>::new());
+        assert_eq!(missing(None, Some(0)), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_unprivileged_userns_enabled() {
+        let enabled = unprivileged_userns_enabled_from;
+        assert!(enabled(None, None));
+        assert!(enabled(Some("1\n"), Some("63487\n")));
+        assert!(enabled(None, Some("63487\n")));
+        assert!(!enabled(Some("0\n"), Some("63487\n")));
+        assert!(!enabled(None, Some("0\n")));
+        assert!(!enabled(Some("1\n"), Some("0\n")));
+        super::unprivileged_userns_enabled();
+    }
+
+    #[test]
+    fn test_fstype_state() {
+        let filesystems = "nodev\tsysfs\nnodev\ttmpfs\n\text4\n";
+        let modules = "kernel/fs/overlayfs/overlay.ko.zst:\n\
+            kernel/fs/fat/vfat.ko.xz: kernel/fs/fat/fat.ko.xz\n\
+            kernel/fs/nls/nls_utf8.ko:\n";
+        let state = |fstype| {
+            fstype_state_from(fstype, Some(filesystems), Some(modules))
+        };
+        assert_eq!(state("ext4"), "registered");
+        assert_eq!(state("tmpfs"), "registered");
+        assert_eq!(state("overlay"),
+                   "module-not-loaded (try modprobe overlay)");
+        assert_eq!(state("vfat"), "module-not-loaded (try modprobe vfat)");
+        assert_eq!(state("nls-utf8"),
+                   "module-not-loaded (try modprobe nls-utf8)");
+        assert_eq!(state("overlayfs"), "unknown");
+        assert_eq!(state("ext"), "unknown");
+        assert_eq!(fstype_state_from("overlay", None, None), "unknown");
+    }
+
+    #[test]
+    fn test_mount_limit() {
+        assert_eq!(mount_limit_from(12, 100000), "12/100000 used");
+        assert_eq!(mount_limit_from(100000, 100000), "100000/100000 used");
+        assert!(mount_limit().unwrap().ends_with(" used"));
+    }
+}
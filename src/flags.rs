@@ -0,0 +1,328 @@
+use std::ffi::OsString;
+
+use nix::mount::MsFlags;
+
+use util::format_ms_flags;
+
+
+/// Flags which select the kind of the `mount()` operation
+const OPERATION_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_BIND.bits() |
+    MsFlags::MS_MOVE.bits() |
+    MsFlags::MS_REMOUNT.bits());
+
+/// Flags which change propagation type
+const PROPAGATION_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_SHARED.bits() |
+    MsFlags::MS_PRIVATE.bits() |
+    MsFlags::MS_SLAVE.bits() |
+    MsFlags::MS_UNBINDABLE.bits());
+
+/// Options of `mount(8)` which are translated into flags, and whether the
+/// option sets (`true`) or clears (`false`) the flag
+const OPTION_FLAGS: &[(&str, MsFlags, bool)] = &[
+    ("ro", MsFlags::MS_RDONLY, true),
+    ("rw", MsFlags::MS_RDONLY, false),
+    ("nosuid", MsFlags::MS_NOSUID, true),
+    ("suid", MsFlags::MS_NOSUID, false),
+    ("nodev", MsFlags::MS_NODEV, true),
+    ("dev", MsFlags::MS_NODEV, false),
+    ("noexec", MsFlags::MS_NOEXEC, true),
+    ("exec", MsFlags::MS_NOEXEC, false),
+    ("sync", MsFlags::MS_SYNCHRONOUS, true),
+    ("async", MsFlags::MS_SYNCHRONOUS, false),
+    ("mand", MsFlags::MS_MANDLOCK, true),
+    ("nomand", MsFlags::MS_MANDLOCK, false),
+    ("dirsync", MsFlags::MS_DIRSYNC, true),
+    ("noatime", MsFlags::MS_NOATIME, true),
+    ("atime", MsFlags::MS_NOATIME, false),
+    ("nodiratime", MsFlags::MS_NODIRATIME, true),
+    ("diratime", MsFlags::MS_NODIRATIME, false),
+    ("relatime", MsFlags::MS_RELATIME, true),
+    ("norelatime", MsFlags::MS_RELATIME, false),
+    ("strictatime", MsFlags::MS_STRICTATIME, true),
+    ("nostrictatime", MsFlags::MS_STRICTATIME, false),
+    ("silent", MsFlags::MS_SILENT, true),
+    ("loud", MsFlags::MS_SILENT, false),
+    ("bind", MsFlags::MS_BIND, true),
+    ("rbind", MsFlags::from_bits_truncate(
+        MsFlags::MS_BIND.bits() | MsFlags::MS_REC.bits()), true),
+    ("remount", MsFlags::MS_REMOUNT, true),
+];
+
+/// Returns the flag of a `mount(8)` option, and whether it's set or cleared
+pub(crate) fn option_flag(name: &str) -> Option<(MsFlags, bool)> {
+    OPTION_FLAGS.iter()
+        .find(|&&(opt, _, _)| opt == name)
+        .map(|&(_, flag, set)| (flag, set))
+}
+
+/// Returns the options of `mount(8)` which set `flags`
+///
+/// Options are in the order of `OPTION_FLAGS`, only the ones setting a
+/// single flag are used (so `MS_BIND|MS_REC` gives `bind`, not `rbind`).
+pub(crate) fn flag_options(flags: MsFlags) -> Vec<&'static str> {
+    OPTION_FLAGS.iter()
+        .filter(|&&(_, flag, set)| {
+            set && flag.bits().count_ones() == 1 && flags.contains(flag)
+        })
+        .map(|&(name, _, _)| name)
+        .collect()
+}
+
+/// Options which are only meaningful for `mount(8)` and fstab
+///
+/// `defaults` is here because all the flags it implies (`rw`, `suid`,
+/// `dev`, `exec`, `async`) are the default state anyway.
+const USERSPACE_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner",
+    "group", "nofail", "_netdev",
+];
+
+quick_error! {
+    /// A combination of `mount()` arguments which kernel rejects or ignores
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum FlagValidationError {
+        /// `MS_MOVE` is combined with `MS_BIND` or `MS_REMOUNT`
+        ConflictingOperations(flags: MsFlags) {
+            display("Flags {} can't be used together", format_ms_flags(*flags))
+        }
+        /// More than one propagation type is set
+        MultiplePropagation(flags: MsFlags) {
+            display("Only one propagation type can be set at a time, \
+                got {}", format_ms_flags(*flags))
+        }
+        /// Propagation flags are combined with other flags
+        PropagationWithFlags(flags: MsFlags) {
+            display("Propagation type can only be combined with MS_REC, \
+                got {}", format_ms_flags(*flags))
+        }
+        /// Filesystem type is passed, but the operation ignores it
+        FstypeIgnored(operation: &'static str) {
+            display("Filesystem type is ignored by {}", operation)
+        }
+        /// Data (options) are passed, but the operation ignores them
+        DataIgnored(operation: &'static str) {
+            display("Options are ignored by {}", operation)
+        }
+        /// A new mount is requested but filesystem type isn't specified
+        MissingFstype {
+            display("Filesystem type is required for a new mount")
+        }
+    }
+}
+
+/// Checks that arguments of `mount()` make sense together
+///
+/// This encodes how the kernel selects the operation by the flags: bind
+/// remount (`MS_REMOUNT|MS_BIND`), remount, bind mount, propagation change,
+/// move, or otherwise a new mount. Combinations which the kernel rejects
+/// with `EINVAL`, as well as arguments which are silently ignored by the
+/// selected operation (which is usually a programming error), are
+/// reported.
+pub fn validate_flags(fstype: Option<&str>, flags: MsFlags, has_data: bool)
+    -> Result<(), FlagValidationError>
+{
+    use self::FlagValidationError::*;
+    let operation = flags & OPERATION_FLAGS;
+    let propagation = flags & PROPAGATION_FLAGS;
+    if operation.contains(MsFlags::MS_MOVE) && operation != MsFlags::MS_MOVE
+    {
+        return Err(ConflictingOperations(operation));
+    }
+    if !propagation.is_empty() {
+        if propagation.bits().count_ones() > 1 {
+            return Err(MultiplePropagation(propagation));
+        }
+        let rest = flags - propagation - MsFlags::MS_REC - MsFlags::MS_SILENT;
+        if !rest.is_empty() {
+            return Err(PropagationWithFlags(flags));
+        }
+    }
+    let name = if operation.contains(MsFlags::MS_REMOUNT) {
+        if operation.contains(MsFlags::MS_BIND) {
+            "bind remount"
+        } else {
+            // data is passed to the filesystem on remount
+            return match fstype {
+                Some(_) => Err(FstypeIgnored("remount")),
+                None => Ok(()),
+            };
+        }
+    } else if operation.contains(MsFlags::MS_BIND) {
+        "bind mount"
+    } else if !propagation.is_empty() {
+        "propagation change"
+    } else if operation.contains(MsFlags::MS_MOVE) {
+        "move"
+    } else {
+        return match fstype {
+            Some(_) => Ok(()),
+            None => Err(MissingFstype),
+        };
+    };
+    if fstype.is_some() {
+        Err(FstypeIgnored(name))
+    } else if has_data {
+        Err(DataIgnored(name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Splits fstab-style options into flags and filesystem-specific data
+///
+/// E.g. `ro,nosuid,mode=0755,size=10%` gives `MS_RDONLY|MS_NOSUID` and
+/// `mode=0755,size=10%`. Later options override earlier ones, so `ro,rw`
+/// gives no flags. Options which only make sense to `mount(8)` itself
+/// (`defaults`, `noauto`, `nofail`, `x-*` and similar) are dropped.
+/// Anything unknown goes to the data, in the original order.
+pub fn parse_mount_options(options: &str) -> (MsFlags, OsString) {
+    let (changes, data) = parse_option_changes(options);
+    let flags = changes.into_iter().fold(MsFlags::empty(),
+        |flags, (flag, set)| if set { flags | flag } else { flags - flag });
+    (flags, data)
+}
+
+/// Same as `parse_mount_options` but returns every flag change in order
+///
+/// This keeps options which clear a flag (like `exec`), so they can
+/// override flags of the existing mount on remount.
+pub(crate) fn parse_option_changes(options: &str)
+    -> (Vec<(MsFlags, bool)>, OsString)
+{
+    let mut changes = Vec::new();
+    let mut data = Vec::new();
+    for opt in options.split(',').map(|x| x.trim()) {
+        if opt.is_empty() || opt.starts_with("x-")
+            || USERSPACE_OPTIONS.contains(&opt)
+        {
+            continue;
+        }
+        match option_flag(opt) {
+            Some(change) => changes.push(change),
+            None => data.push(opt),
+        }
+    }
+    (changes, OsString::from(data.join(",")))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+
+    use nix::mount::MsFlags;
+
+    use super::{validate_flags, parse_mount_options};
+    use super::{option_flag, flag_options};
+    use super::FlagValidationError::*;
+
+    #[test]
+    fn test_valid() {
+        let ok = |fstype, flags, data| {
+            validate_flags(fstype, flags, data).unwrap()
+        };
+        ok(Some("tmpfs"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV, true);
+        ok(None, MsFlags::MS_BIND | MsFlags::MS_REC, false);
+        ok(None, MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY, true);
+        ok(None, MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+           false);
+        ok(None, MsFlags::MS_PRIVATE | MsFlags::MS_REC, false);
+        ok(None, MsFlags::MS_MOVE, false);
+    }
+
+    #[test]
+    fn test_conflicting_operations() {
+        assert_eq!(validate_flags(None, MsFlags::MS_MOVE | MsFlags::MS_BIND,
+                                  false),
+                   Err(ConflictingOperations(
+                       MsFlags::MS_MOVE | MsFlags::MS_BIND)));
+        assert_eq!(validate_flags(None,
+                                  MsFlags::MS_MOVE | MsFlags::MS_REMOUNT,
+                                  false),
+                   Err(ConflictingOperations(
+                       MsFlags::MS_MOVE | MsFlags::MS_REMOUNT)));
+        assert_eq!(ConflictingOperations(MsFlags::MS_MOVE | MsFlags::MS_BIND)
+                   .to_string(),
+                   "Flags MS_BIND|MS_MOVE can't be used together");
+    }
+
+    #[test]
+    fn test_propagation() {
+        let flags = MsFlags::MS_PRIVATE | MsFlags::MS_SHARED;
+        assert_eq!(validate_flags(None, flags, false),
+                   Err(MultiplePropagation(flags)));
+        let flags = MsFlags::MS_PRIVATE | MsFlags::MS_RDONLY;
+        assert_eq!(validate_flags(None, flags, false),
+                   Err(PropagationWithFlags(flags)));
+        let flags = MsFlags::MS_SLAVE | MsFlags::MS_BIND;
+        assert_eq!(validate_flags(None, flags, false),
+                   Err(PropagationWithFlags(flags)));
+        assert_eq!(validate_flags(Some("ext4"), MsFlags::MS_SLAVE, false),
+                   Err(FstypeIgnored("propagation change")));
+        assert_eq!(validate_flags(None, MsFlags::MS_SLAVE, true),
+                   Err(DataIgnored("propagation change")));
+    }
+
+    #[test]
+    fn test_ignored_arguments() {
+        assert_eq!(validate_flags(Some("ext4"), MsFlags::MS_BIND, false),
+                   Err(FstypeIgnored("bind mount")));
+        assert_eq!(validate_flags(None, MsFlags::MS_BIND, true),
+                   Err(DataIgnored("bind mount")));
+        assert_eq!(validate_flags(Some("ext4"), MsFlags::MS_MOVE, false),
+                   Err(FstypeIgnored("move")));
+        assert_eq!(validate_flags(None, MsFlags::MS_MOVE, true),
+                   Err(DataIgnored("move")));
+        assert_eq!(validate_flags(Some("ext4"), MsFlags::MS_REMOUNT, true),
+                   Err(FstypeIgnored("remount")));
+        assert_eq!(validate_flags(None,
+                                  MsFlags::MS_REMOUNT | MsFlags::MS_BIND,
+                                  true),
+                   Err(DataIgnored("bind remount")));
+    }
+
+    #[test]
+    fn test_missing_fstype() {
+        assert_eq!(validate_flags(None, MsFlags::MS_NOSUID, true),
+                   Err(MissingFstype));
+        assert_eq!(validate_flags(None, MsFlags::empty(), false),
+                   Err(MissingFstype));
+    }
+
+    #[test]
+    fn test_parse_mount_options() {
+        let parse = |options| {
+            let (flags, data) = parse_mount_options(options);
+            (flags, data.into_string().unwrap())
+        };
+        assert_eq!(parse("rw,noexec,nosuid,nodev"),
+            (MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+             String::new()));
+        assert_eq!(parse("defaults"), (MsFlags::empty(), String::new()));
+        assert_eq!(parse(""), (MsFlags::empty(), String::new()));
+        assert_eq!(parse("ro,mode=0755,size=10%,noatime"),
+            (MsFlags::MS_RDONLY | MsFlags::MS_NOATIME,
+             "mode=0755,size=10%".to_string()));
+        assert_eq!(parse("ro,rw"), (MsFlags::empty(), String::new()));
+        assert_eq!(parse("noexec,exec,nodev"),
+            (MsFlags::MS_NODEV, String::new()));
+        assert_eq!(parse("defaults,noauto,nofail,x-systemd.automount,\
+                          uid=1000, gid=1000,,"),
+            (MsFlags::empty(), "uid=1000,gid=1000".to_string()));
+        assert_eq!(parse("rbind,ro"),
+            (MsFlags::MS_BIND | MsFlags::MS_REC | MsFlags::MS_RDONLY,
+             String::new()));
+        assert_eq!(parse_mount_options("lowerdir=/a:/b,upperdir=/c").1,
+                   OsStr::new("lowerdir=/a:/b,upperdir=/c"));
+    }
+
+    #[test]
+    fn test_option_table() {
+        assert_eq!(option_flag("loud"), Some((MsFlags::MS_SILENT, false)));
+        assert_eq!(option_flag("nosymfollow"), None);
+        assert_eq!(flag_options(MsFlags::MS_RDONLY | MsFlags::MS_NOSUID |
+                                MsFlags::MS_BIND | MsFlags::MS_REC),
+                   vec!["ro", "nosuid", "bind"]);
+    }
+}
@@ -0,0 +1,435 @@
+use std::io;
+use std::fmt;
+use std::ffi::{CString, OsStr, OsString};
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+
+use libc::{c_int, ENODEV};
+
+use {OSError, Error};
+use explain::{Explainable, user, block_device, fstype_state};
+use detached::{DetachedMount, FsParam, CreateStep, create_detached_with};
+use newapi::{CreateCalls, Kernel};
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FsMountError {
+        Io(msg: String, err: io::Error) {
+            source(err)
+            display("{}: {}", msg, err)
+        }
+        NulByte(what: String) {
+            display("{} contains a NUL byte", what)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Param {
+    Flag(OsString),
+    String(OsString, OsString),
+    Fd(OsString, RawFd),
+}
+
+/// A filesystem configured parameter by parameter (new mount API)
+///
+/// This is a low-level builder for the cases which aren't covered by the
+/// typed builders: every parameter is passed to `fsconfig` as is, in the
+/// order they were added. Unlike `mount()` options, values may contain
+/// commas.
+///
+/// Pseudo-filesystems (like `proc` or `tmpfs`) don't need a source. If
+/// the `source` parameter isn't set for them, the filesystem type is used,
+/// as it's conventionally done with `mount -t proc proc /proc`, so that the
+/// mount source in mountinfo isn't `none`.
+///
+/// The filesystem is created with `create()` which returns a
+/// `DetachedMount`, use `DetachedMount::attach_to()` to attach it. This
+/// requires linux 5.2+, on older kernels `create()` fails with `ENOSYS`.
+#[derive(Debug, Clone)]
+pub struct FsMount {
+    fstype: OsString,
+    params: Vec<Param>,
+}
+
+/// Filesystems which ignore the source (it's only shown in mountinfo)
+const SOURCELESS_FILESYSTEMS: &[&str] = &[
+    "proc", "sysfs", "tmpfs", "devpts", "mqueue", "cgroup", "cgroup2",
+    "debugfs", "tracefs", "securityfs", "configfs", "bpf", "hugetlbfs",
+];
+
+fn to_cstring(value: &OsStr, what: &str) -> Result<CString, FsMountError> {
+    CString::new(value.as_bytes()).map_err(|_| {
+        FsMountError::NulByte(format!("{} {:?}", what, value))
+    })
+}
+
+fn io_error(err: ::nix::Error) -> io::Error {
+    err.as_errno().map_or_else(
+        || io::Error::other(err),
+        io::Error::from)
+}
+
+impl FsMount {
+    /// Start configuring a filesystem of the type `fstype`
+    pub fn new(fstype: &str) -> FsMount {
+        FsMount {
+            fstype: OsString::from(fstype),
+            params: Vec::new(),
+        }
+    }
+
+    /// Add a parameter with a value (`FSCONFIG_SET_STRING`)
+    pub fn string<V: AsRef<OsStr>>(mut self, key: &str, value: V)
+        -> FsMount
+    {
+        self.set_string(key, value);
+        self
+    }
+    /// Add a parameter without a value (`FSCONFIG_SET_FLAG`)
+    pub fn flag(mut self, key: &str) -> FsMount {
+        self.set_flag(key);
+        self
+    }
+    /// Add a parameter referring to a file descriptor (`FSCONFIG_SET_FD`)
+    ///
+    /// The descriptor isn't owned by the builder, it must be kept open
+    /// until `create()` returns.
+    pub fn fd(mut self, key: &str, fd: RawFd) -> FsMount {
+        self.set_fd(key, fd);
+        self
+    }
+
+    /// Add a parameter with a value (in-place version of `string`)
+    pub fn set_string<V: AsRef<OsStr>>(&mut self, key: &str, value: V)
+        -> &mut FsMount
+    {
+        self.params.push(Param::String(OsString::from(key),
+                                       value.as_ref().to_os_string()));
+        self
+    }
+    /// Add a parameter without a value (in-place version of `flag`)
+    pub fn set_flag(&mut self, key: &str) -> &mut FsMount {
+        self.params.push(Param::Flag(OsString::from(key)));
+        self
+    }
+    /// Add a file descriptor parameter (in-place version of `fd`)
+    pub fn set_fd(&mut self, key: &str, fd: RawFd) -> &mut FsMount {
+        self.params.push(Param::Fd(OsString::from(key), fd));
+        self
+    }
+
+    /// Create the filesystem and a detached mount of it
+    pub fn bare_create(self) -> Result<DetachedMount, OSError> {
+        match self.create_with(&Kernel) {
+            Ok(fd) => Ok(DetachedMount::from_raw_fd(fd,
+                format!("detached {}", self.fstype.to_string_lossy()))),
+            Err(e) => Err(OSError::from_fsmount(e, Box::new(self))),
+        }
+    }
+
+    /// Create the filesystem and explain error immediately
+    pub fn create(self) -> Result<DetachedMount, Error> {
+        self.bare_create().map_err(OSError::explain)
+    }
+
+    fn create_with<C: CreateCalls>(&self, calls: &C)
+        -> Result<RawFd, FsMountError>
+    {
+        let fstype = to_cstring(&self.fstype, "filesystem type")?;
+        let params = self.default_source().iter().chain(&self.params)
+            .map(Param::to_fs_param)
+            .collect::<Result<Vec<_>, _>>()?;
+        create_detached_with(&fstype, &params, 0, calls)
+            .map_err(|(step, e)| {
+                let msg = match step {
+                    CreateStep::Open => {
+                        "Cannot open filesystem context".to_string()
+                    }
+                    CreateStep::Set(key) => format!(
+                        "Cannot set parameter {:?}", key.to_string_lossy()),
+                    CreateStep::Create => {
+                        "Cannot create filesystem".to_string()
+                    }
+                    CreateStep::Mount => "Cannot create mount".to_string(),
+                };
+                FsMountError::Io(msg, io_error(e))
+            })
+    }
+
+    fn source(&self) -> Option<&OsStr> {
+        self.params.iter().rev().filter_map(|p| match *p {
+            Param::String(ref key, ref value)
+            if key == "source" => Some(&value[..]),
+            _ => None,
+        }).next()
+    }
+
+    /// Returns the source to set if user hasn't set one
+    fn default_source(&self) -> Option<Param> {
+        let fstype = self.fstype.to_str().unwrap_or("");
+        if self.source().is_none() && SOURCELESS_FILESYSTEMS.contains(&fstype) {
+            Some(Param::String(OsString::from("source"),
+                               self.fstype.clone()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Param {
+    fn to_fs_param(&self) -> Result<FsParam, FsMountError> {
+        let key = |key: &OsStr| to_cstring(key, "parameter name");
+        Ok(match *self {
+            Param::Flag(ref k) => FsParam::Flag(key(k)?),
+            Param::String(ref k, ref value) => FsParam::String(key(k)?,
+                to_cstring(value, &format!("value of {:?}", k))?),
+            Param::Fd(ref k, fd) => FsParam::Fd(key(k)?, fd),
+        })
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Param::Flag(ref key) => {
+                write!(fmt, "{}", key.to_string_lossy())
+            }
+            Param::String(ref key, ref value) => {
+                write!(fmt, "{}={}",
+                    key.to_string_lossy(), value.to_string_lossy())
+            }
+            Param::Fd(ref key, fd) => {
+                write!(fmt, "{}=<fd {}>", key.to_string_lossy(), fd)
+            }
+        }
+    }
+}
+
+impl fmt::Display for FsMount {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "fsmount {}", self.fstype.to_string_lossy())?;
+        let mut sep = " ";
+        for param in &self.params {
+            write!(fmt, "{}{}", sep, param)?;
+            sep = ",";
+        }
+        Ok(())
+    }
+}
+
+impl Explainable for FsMount {
+    fn explain(&self) -> String {
+        let mut info = Vec::new();
+        // only block-device filesystems have a path as a source,
+        // network filesystems have something like `host:/path`
+        if let Some(source) = self.source() {
+            let sourceless = self.fstype.to_str()
+                .map(|x| SOURCELESS_FILESYSTEMS.contains(&x))
+                .unwrap_or(false);
+            if !sourceless && source.as_bytes().starts_with(b"/") {
+                info.push(format!("source: {}",
+                                  block_device(Path::new(source))));
+            }
+        }
+        info.push(user().to_string());
+        info.join(", ")
+    }
+    fn explain_errno(&self, errno: Option<c_int>) -> String {
+        let mut info = vec![self.explain()];
+        if errno == Some(ENODEV) {
+            info.push(format!("fstype: {}",
+                fstype_state(&self.fstype.to_string_lossy())));
+        }
+        info.join(", ")
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::ffi::{CStr, OsStr};
+    use std::os::unix::io::RawFd;
+
+    use libc::{c_int, c_uint, EINVAL, ENODEV, ENOSYS};
+    use nix::Result;
+    use nix::errno::Errno;
+    use nix::unistd::getuid;
+
+    use {OSError, Error, ErrorCategory, Unmount};
+    use mountinfo::mounts_under;
+    use explain::Explainable;
+    use newapi::CreateCalls;
+    use util::test::tmp_dir;
+    use super::FsMount;
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<String>>,
+        fail_key: Option<&'static str>,
+    }
+
+    impl CreateCalls for Recorder {
+        fn fsopen(&self, fstype: &CStr, _flags: c_uint) -> Result<RawFd> {
+            self.calls.borrow_mut().push(format!("fsopen({:?})", fstype));
+            Ok(100)
+        }
+        fn fsconfig(&self, fd: RawFd, cmd: c_uint, key: Option<&CStr>,
+            value: Option<&CStr>, aux: c_int)
+            -> Result<()>
+        {
+            self.calls.borrow_mut().push(format!(
+                "fsconfig({}, {}, {:?}, {:?}, {})",
+                fd, cmd, key, value, aux));
+            match (key, self.fail_key) {
+                (Some(key), Some(fail)) if key.to_bytes() == fail.as_bytes()
+                => Err(::nix::Error::Sys(Errno::EINVAL)),
+                _ => Ok(()),
+            }
+        }
+        fn fsmount(&self, fd: RawFd, _flags: c_uint, _attr_flags: c_uint)
+            -> Result<RawFd>
+        {
+            self.calls.borrow_mut().push(format!("fsmount({})", fd));
+            Ok(-1)
+        }
+        fn close(&self, fd: RawFd) -> Result<()> {
+            self.calls.borrow_mut().push(format!("close({})", fd));
+            Ok(())
+        }
+    }
+
+    fn example() -> FsMount {
+        FsMount::new("ext4")
+            .string("source", "/dev/sda1")
+            .flag("ro")
+            .fd("fd", 7)
+            .string("opt", "a,b")
+    }
+
+    #[test]
+    fn test_sequence() {
+        let calls = Recorder::default();
+        assert_eq!(example().create_with(&calls).unwrap(), -1);
+        assert_eq!(*calls.calls.borrow(), vec![
+            "fsopen(\"ext4\")".to_string(),
+            "fsconfig(100, 1, Some(\"source\"), Some(\"/dev/sda1\"), 0)"
+                .to_string(),
+            "fsconfig(100, 0, Some(\"ro\"), None, 0)".to_string(),
+            "fsconfig(100, 5, Some(\"fd\"), None, 7)".to_string(),
+            "fsconfig(100, 1, Some(\"opt\"), Some(\"a,b\"), 0)".to_string(),
+            "fsconfig(100, 6, None, None, 0)".to_string(),
+            "fsmount(100)".to_string(),
+            "close(100)".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_default_source() {
+        let calls = Recorder::default();
+        FsMount::new("proc").string("hidepid", "2")
+            .create_with(&calls).unwrap();
+        assert_eq!(calls.calls.borrow()[1..3].to_vec(), vec![
+            "fsconfig(100, 1, Some(\"source\"), Some(\"proc\"), 0)"
+                .to_string(),
+            "fsconfig(100, 1, Some(\"hidepid\"), Some(\"2\"), 0)"
+                .to_string(),
+        ]);
+
+        let calls = Recorder::default();
+        FsMount::new("tmpfs").string("source", "shm")
+            .create_with(&calls).unwrap();
+        assert_eq!(calls.calls.borrow()[1],
+            "fsconfig(100, 1, Some(\"source\"), Some(\"shm\"), 0)");
+        assert_eq!(calls.calls.borrow().len(), 5);
+    }
+
+    #[test]
+    fn test_explain_source() {
+        let dir = tmp_dir("fsmount_explain_source");
+        let fact = |fs: FsMount| fs.explain().split(", ").next()
+            .unwrap().to_string();
+        assert_eq!(fact(FsMount::new("ext4").string("source", &dir)),
+                   "source: not-a-block-device");
+        assert_eq!(fact(FsMount::new("ext4")
+                        .string("source", dir.join("nowhere"))),
+                   "source: missing");
+        assert!(!fact(FsMount::new("nfs").string("source", "host:/x"))
+                .starts_with("source:"));
+        assert!(!fact(FsMount::new("tmpfs").string("source", "/dev/shm"))
+                .starts_with("source:"));
+    }
+
+    #[test]
+    fn test_explain_enodev() {
+        let fs = FsMount::new("ext4typo");
+        assert!(!fs.explain_errno(Some(EINVAL)).contains("fstype: "));
+        assert!(fs.explain_errno(Some(ENODEV))
+                .ends_with(", fstype: unknown"));
+    }
+
+    #[test]
+    fn test_proc_without_source() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("fsmount_proc");
+        let mnt = match FsMount::new("proc").create() {
+            Ok(mnt) => mnt,
+            Err(ref e) if e.raw_os_error() == Some(ENOSYS) => return,
+            Err(e) => panic!("{}", e),
+        };
+        mnt.attach_to(&dir).unwrap();
+        let mounts = mounts_under(&dir).unwrap();
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].fstype, OsStr::new("proc"));
+        assert_eq!(mounts[0].mount_source, OsStr::new("proc"));
+        assert!(dir.join("self").exists());
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_config_error() {
+        let calls = Recorder {
+            fail_key: Some("ro"),
+            .. Recorder::default()
+        };
+        let mnt = example();
+        let err = mnt.create_with(&calls).unwrap_err();
+        assert_eq!(*calls.calls.borrow(), vec![
+            "fsopen(\"ext4\")".to_string(),
+            "fsconfig(100, 1, Some(\"source\"), Some(\"/dev/sda1\"), 0)"
+                .to_string(),
+            "fsconfig(100, 0, Some(\"ro\"), None, 0)".to_string(),
+            "close(100)".to_string(),
+        ]);
+        let err = OSError::from_fsmount(err, Box::new(mnt));
+        assert_eq!(err.raw_os_error(), Some(EINVAL));
+        let err: Error = err.explain();
+        assert_eq!(err.raw_os_error(), Some(EINVAL));
+        assert!(err.to_string().starts_with("fsmount ext4 source=/dev/sda1,\
+            ro,fd=<fd 7>,opt=a,b: "));
+        assert!(err.explanation().starts_with(
+            "Cannot set parameter \"ro\", "));
+    }
+
+    #[test]
+    fn test_nul_byte() {
+        let calls = Recorder::default();
+        let mnt = FsMount::new("ext4").string("opt", "a\0b");
+        let err = mnt.create_with(&calls).unwrap_err();
+        assert!(calls.calls.borrow().is_empty());
+        let err: Error = OSError::from_fsmount(err, Box::new(mnt)).explain();
+        assert_eq!(err.raw_os_error(), None);
+        assert_eq!(err.category(), ErrorCategory::InvalidConfiguration);
+        assert!(err.to_string().starts_with(
+            "fsmount ext4 opt=a\0b: value of \"opt\" \"a\\0b\" \
+             contains a NUL byte"), "{}", err);
+        assert!(FsMount::new("ext\04").create_with(&calls).is_err());
+    }
+}
@@ -25,36 +25,111 @@
 extern crate libc;
 extern crate nix;
 #[macro_use] extern crate quick_error;
+#[macro_use] extern crate log;
 
 mod util;
+mod args;
 mod error;
 mod explain;
 mod bind;
 mod overlay;
 mod tmpfs;
+mod cgroup;
+mod container;
 mod modify;
+mod propagation;
+mod pivot;
 mod remount;
+mod unmount;
+mod mountable;
+mod newapi;
+mod detached;
+mod fsmount;
+mod flags;
+mod lock;
+mod mask;
+mod timeout;
 pub mod mountinfo;
+pub mod mounts;
 
 use std::io;
 
-use explain::Explainable;
+pub use explain::{Explainable, PathRole};
+pub use args::MountArgs;
 use remount::RemountError;
-pub use bind::BindMount;
-pub use overlay::Overlay;
-pub use tmpfs::Tmpfs;
-pub use modify::Move;
+use overlay::OverlayError;
+use unmount::UnmountError;
+use bind::BindError;
+use tmpfs::TmpfsError;
+use timeout::TimeoutError;
+use fsmount::FsMountError;
+use pivot::PivotError;
+use modify::{SwapError, RecreateError};
+pub use bind::{BindMount, BindReport};
+pub use overlay::{Overlay, OverlayFeatures, supported_features};
+pub use tmpfs::{Tmpfs, TmpfsResize};
+pub use cgroup::CgroupV1;
+pub use container::ContainerMounts;
+pub use modify::{Move, swap_mounts, bare_swap_mounts};
+pub use modify::{recreate_with, bare_recreate_with};
+pub use propagation::{Propagation, FdPropagation};
+pub use pivot::PivotRoot;
 pub use remount::Remount;
+pub use unmount::{Unmount, ExpiringMount};
+pub use mountable::Mountable;
+pub use detached::DetachedMount;
+pub use fsmount::FsMount;
+pub use flags::{validate_flags, parse_mount_options, FlagValidationError};
+pub use timeout::WithTimeout;
+pub use mask::{mask_path, bare_mask_path};
+pub use error::ErrorCategory;
+pub use util::with_umask;
 
 quick_error! {
     #[derive(Debug)]
     enum MountError {
         Io(err: io::Error) {
-            cause(err)
+            source(err)
             from()
         }
         Remount(err: RemountError) {
-            cause(err)
+            source(err)
+            from()
+        }
+        Overlay(err: OverlayError) {
+            source(err)
+            from()
+        }
+        Unmount(err: UnmountError) {
+            source(err)
+            from()
+        }
+        Bind(err: BindError) {
+            source(err)
+            from()
+        }
+        Tmpfs(err: TmpfsError) {
+            source(err)
+            from()
+        }
+        Timeout(err: TimeoutError) {
+            source(err)
+            from()
+        }
+        FsMount(err: FsMountError) {
+            source(err)
+            from()
+        }
+        Pivot(err: PivotError) {
+            source(err)
+            from()
+        }
+        Swap(err: SwapError) {
+            source(err)
+            from()
+        }
+        Recreate(err: RecreateError) {
+            source(err)
             from()
         }
     }
@@ -75,17 +150,111 @@ quick_error! {
 /// path.
 ///
 #[derive(Debug)]
-pub struct OSError(MountError, Box<Explainable>);
+pub struct OSError(MountError, Box<dyn Explainable>);
 
 impl OSError {
-    fn from_remount(err: RemountError, explain: Box<Explainable>) -> OSError {
+    fn from_remount(err: RemountError, explain: Box<dyn Explainable>)
+        -> OSError
+    {
         OSError(MountError::Remount(err), explain)
     }
 
-    fn from_nix(err: nix::Error, explain: Box<Explainable>) -> OSError {
+    fn from_overlay(err: OverlayError, explain: Box<dyn Explainable>)
+        -> OSError
+    {
+        OSError(MountError::Overlay(err), explain)
+    }
+
+    fn from_unmount(err: UnmountError, explain: Box<dyn Explainable>)
+        -> OSError
+    {
+        OSError(MountError::Unmount(err), explain)
+    }
+
+    fn from_bind(err: BindError, explain: Box<dyn Explainable>) -> OSError {
+        OSError(MountError::Bind(err), explain)
+    }
+
+    fn from_tmpfs(err: TmpfsError, explain: Box<dyn Explainable>) -> OSError {
+        OSError(MountError::Tmpfs(err), explain)
+    }
+
+    fn from_timeout(err: TimeoutError, explain: Box<dyn Explainable>)
+        -> OSError
+    {
+        OSError(MountError::Timeout(err), explain)
+    }
+
+    fn from_fsmount(err: FsMountError, explain: Box<dyn Explainable>)
+        -> OSError
+    {
+        OSError(MountError::FsMount(err), explain)
+    }
+
+    fn from_pivot(err: PivotError, explain: Box<dyn Explainable>) -> OSError {
+        OSError(MountError::Pivot(err), explain)
+    }
+
+    fn from_swap(err: SwapError, explain: Box<dyn Explainable>) -> OSError {
+        OSError(MountError::Swap(err), explain)
+    }
+
+    fn from_recreate(err: RecreateError, explain: Box<dyn Explainable>)
+        -> OSError
+    {
+        OSError(MountError::Recreate(err), explain)
+    }
+
+    fn from_io(err: io::Error, explain: Box<dyn Explainable>) -> OSError {
+        OSError(MountError::Io(err), explain)
+    }
+
+    /// Create an error from the result of the user-defined system call
+    ///
+    /// This is useful for custom mount wrappers which want to report errors
+    /// the same way as the library does.
+    ///
+    /// ```
+    /// extern crate libmount;
+    /// extern crate nix;
+    ///
+    /// use std::fmt;
+    /// use libmount::{OSError, Explainable};
+    ///
+    /// #[derive(Debug)]
+    /// struct MyMount;
+    ///
+    /// impl fmt::Display for MyMount {
+    ///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    ///         write!(f, "my mount")
+    ///     }
+    /// }
+    ///
+    /// impl Explainable for MyMount {
+    ///     fn explain(&self) -> String {
+    ///         "device: busy".to_string()
+    ///     }
+    /// }
+    ///
+    /// # fn main() {
+    /// let nix_err = nix::Error::Sys(nix::errno::Errno::EBUSY);
+    /// let err = OSError::from_nix_error(nix_err, Box::new(MyMount));
+    /// assert_eq!(err.explain().to_string(),
+    ///            "my mount: Device or resource busy (os error 16) \
+    ///             (device: busy)");
+    /// # }
+    /// ```
+    pub fn from_nix_error(err: nix::Error, explain: Box<dyn Explainable>)
+        -> OSError
+    {
+        OSError::from_nix(err, explain)
+    }
+
+    fn from_nix(err: nix::Error, explain: Box<dyn Explainable>) -> OSError {
         OSError(
             MountError::Io(
-                err.as_errno().map_or_else(|| io::Error::new(io::ErrorKind::Other, err), io::Error::from),
+                err.as_errno().map_or_else(|| io::Error::other(err),
+                                           io::Error::from),
             ),
             explain,
         )
@@ -95,7 +264,7 @@ impl OSError {
 /// The error holder which contains as much information about why failure
 /// happens as the library implementors could gain
 ///
-/// This type only provides `Display` for now, but some programmatic interface
-/// is expected in future.
+/// Besides `Display` there are predicates like `is_busy()` and
+/// `is_permission_denied()` for the common error conditions.
 #[derive(Debug)]
-pub struct Error(Box<Explainable>, io::Error, String);
+pub struct Error(Box<dyn Explainable>, io::Error, String, ErrorCategory);
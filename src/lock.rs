@@ -0,0 +1,107 @@
+use std::io;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use libc::{flock, LOCK_EX, LOCK_UN};
+
+
+/// An exclusive `flock` on the file, released when dropped
+///
+/// Used by `with_lock()` of the builders to serialize concurrent mounters.
+/// Dropping happens on the error path too, so the lock is never leaked.
+#[derive(Debug)]
+pub struct FileLock(File);
+
+impl FileLock {
+    /// Open (create if needed) `path` and wait for an exclusive lock on it
+    pub fn exclusive(path: &Path) -> io::Result<FileLock> {
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(false)
+            .open(path)?;
+        loop {
+            if unsafe { flock(file.as_raw_fd(), LOCK_EX) } == 0 {
+                return Ok(FileLock(file));
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Takes the lock set by `with_lock()` of a builder, if there is one
+///
+/// Builders call this before anything else, so validation and the mount
+/// itself are both done under the lock.
+pub fn acquire(lockfile: &Option<PathBuf>) -> io::Result<Option<FileLock>> {
+    match *lockfile {
+        Some(ref path) => FileLock::exclusive(path).map(Some),
+        None => Ok(None),
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // closing the file would release the lock too, unlock explicitly
+        // in case the descriptor was inherited by a child
+        unsafe { flock(self.0.as_raw_fd(), LOCK_UN) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use libc::{flock, LOCK_EX, LOCK_NB};
+
+    use Tmpfs;
+    use util::test::tmp_dir;
+    use super::FileLock;
+
+    fn is_locked(file: &File) -> bool {
+        unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) != 0 }
+    }
+
+    #[test]
+    fn test_serialized() {
+        let lockfile = tmp_dir("lock_serialized").join("lock");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let threads = (0..2).map(|i| {
+            let lockfile = lockfile.clone();
+            let events = events.clone();
+            thread::spawn(move || {
+                let _lock = FileLock::exclusive(&lockfile).unwrap();
+                events.lock().unwrap().push(format!("start {}", i));
+                thread::sleep(Duration::from_millis(50));
+                events.lock().unwrap().push(format!("end {}", i));
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        for pair in events.chunks(2) {
+            assert!(pair[0].starts_with("start "));
+            assert_eq!(pair[1], pair[0].replace("start", "end"));
+        }
+    }
+
+    #[test]
+    fn test_released_on_error() {
+        let dir = tmp_dir("lock_released");
+        let lockfile = dir.join("lock");
+        Tmpfs::new(dir.join("nonexistent")).with_lock(&lockfile)
+            .bare_mount().unwrap_err();
+        assert!(!is_locked(&File::open(&lockfile).unwrap()));
+        let lock = FileLock::exclusive(&lockfile).unwrap();
+        assert!(is_locked(&File::open(&lockfile).unwrap()));
+        drop(lock);
+    }
+}
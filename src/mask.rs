@@ -0,0 +1,213 @@
+use std::fmt;
+use std::ffi::{CStr, CString};
+use std::fs::metadata;
+use std::path::Path;
+
+use nix::mount::{MsFlags, mount};
+
+use {OSError, Error};
+use util::{path_to_cstring, as_path};
+use explain::{Explainable, PathRole, exists, mount_point, user};
+
+
+/// Flags of the masking mounts: nothing can be written or executed there
+const MASK_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_RDONLY.bits() |
+    MsFlags::MS_NOSUID.bits() |
+    MsFlags::MS_NODEV.bits() |
+    MsFlags::MS_NOEXEC.bits());
+
+/// Masking mount of a single path
+#[derive(Debug, Clone)]
+struct Mask {
+    target: CString,
+    kind: Option<Kind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Empty read-only tmpfs over a directory
+    Directory,
+    /// Read-only bind of `/dev/null` over a file
+    File,
+}
+
+/// Make contents of the `target` inaccessible
+///
+/// This is what container runtimes do for paths like `/proc/kcore` or
+/// `/sys/firmware`:
+///
+/// 1. A directory is covered by an empty read-only tmpfs
+/// 2. Anything else (a file, a device) is covered by a read-only bind mount
+///    of `/dev/null`, so reading it returns nothing
+///
+/// Symlinks in `target` are followed. The original contents are still
+/// reachable if the mask is unmounted, so this must be combined with
+/// dropping `CAP_SYS_ADMIN` (or with a user namespace) to be effective.
+pub fn bare_mask_path(target: &Path) -> Result<(), OSError> {
+    let mut mask = Mask {
+        target: path_to_cstring(target),
+        kind: None,
+    };
+    match metadata(target) {
+        Ok(ref meta) if meta.is_dir() => mask.kind = Some(Kind::Directory),
+        Ok(_) => mask.kind = Some(Kind::File),
+        Err(e) => return Err(OSError::from_io(e, Box::new(mask))),
+    }
+    for (source, fstype, flags, data) in mask.steps() {
+        if let Err(err) = mount(Some(source), &*mask.target, fstype, flags,
+                                data)
+        {
+            return Err(OSError::from_nix(err, Box::new(mask)));
+        }
+    }
+    Ok(())
+}
+
+/// Make contents of the `target` inaccessible and explain error immediately
+///
+/// See `bare_mask_path` for the details.
+pub fn mask_path(target: &Path) -> Result<(), Error> {
+    bare_mask_path(target).map_err(OSError::explain)
+}
+
+type Step = (&'static CStr, Option<&'static CStr>, MsFlags,
+             Option<&'static CStr>);
+
+impl Mask {
+    /// Arguments of the `mount()` calls, in order
+    fn steps(&self) -> Vec<Step> {
+        let cstr = |x: &'static [u8]| CStr::from_bytes_with_nul(x).unwrap();
+        match self.kind {
+            Some(Kind::Directory) => vec![
+                (cstr(b"tmpfs\0"), Some(cstr(b"tmpfs\0")), MASK_FLAGS,
+                 Some(cstr(b"size=4k,nr_inodes=1,mode=0555\0"))),
+            ],
+            Some(Kind::File) => vec![
+                (cstr(b"/dev/null\0"), None, MsFlags::MS_BIND, None),
+                // read-only flag is ignored on initial bind, and `nodev`
+                // would make `/dev/null` itself unreadable
+                (cstr(b"none\0"), None,
+                 MsFlags::MS_REMOUNT | MsFlags::MS_BIND
+                 | (MASK_FLAGS - MsFlags::MS_NODEV), None),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Mask {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Some(Kind::Directory) => write!(fmt, "mask directory {:?}",
+                as_path(&self.target)),
+            Some(Kind::File) => write!(fmt, "mask file {:?}",
+                as_path(&self.target)),
+            None => write!(fmt, "mask {:?}", as_path(&self.target)),
+        }
+    }
+}
+
+impl Explainable for Mask {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", exists(as_path(&self.target))),
+            format!("target: {}", mount_point(as_path(&self.target))),
+            format!("/dev/null: {}", exists(Path::new("/dev/null"))),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, OpenOptions, create_dir, read_dir};
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    use libc::EROFS;
+    use nix::mount::MsFlags;
+    use nix::unistd::getuid;
+
+    use Unmount;
+    use mountinfo::find_mount_point;
+    use util::test::tmp_dir;
+    use super::{Mask, Kind, path_to_cstring, bare_mask_path};
+    use super::mask_path;
+
+    fn mask(kind: Kind) -> Mask {
+        Mask {
+            target: path_to_cstring(Path::new("/proc/kcore")),
+            kind: Some(kind),
+        }
+    }
+
+    #[test]
+    fn test_steps() {
+        let steps = mask(Kind::Directory).steps();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].0.to_bytes(), b"tmpfs");
+        assert!(steps[0].2.contains(MsFlags::MS_RDONLY));
+        let steps = mask(Kind::File).steps();
+        assert_eq!(steps.iter().map(|x| x.2).collect::<Vec<_>>(), vec![
+            MsFlags::MS_BIND,
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY |
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        ]);
+        assert_eq!(steps[0].0.to_bytes(), b"/dev/null");
+        assert_eq!(mask(Kind::File).to_string(), "mask file \"/proc/kcore\"");
+    }
+
+    #[test]
+    fn test_missing_target() {
+        let dir = tmp_dir("mask_missing");
+        let err = bare_mask_path(&dir.join("nonexistent")).unwrap_err();
+        assert!(err.to_string().starts_with("mask \""));
+    }
+
+    #[test]
+    fn test_mask_dir() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("mask_dir");
+        let secret = dir.join("secret");
+        create_dir(&secret).unwrap();
+        File::create(secret.join("key")).unwrap();
+        mask_path(&secret).unwrap();
+        assert_eq!(read_dir(&secret).unwrap().count(), 0);
+        let err = File::create(secret.join("new")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EROFS));
+        assert!(find_mount_point(&secret).unwrap().unwrap().is_readonly());
+        Unmount::new(&secret).unmount().unwrap();
+        assert!(secret.join("key").exists());
+    }
+
+    #[test]
+    fn test_mask_file() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("mask_file");
+        let secret = dir.join("secret");
+        File::create(&secret).unwrap().write_all(b"password").unwrap();
+        mask_path(&secret).unwrap();
+        let mut buf = String::new();
+        File::open(&secret).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "");
+        // writes go to /dev/null, so they are discarded
+        OpenOptions::new().write(true).open(&secret).unwrap()
+            .write_all(b"new").unwrap();
+        assert!(find_mount_point(&secret).unwrap().unwrap().is_readonly());
+        Unmount::new(&secret).unmount().unwrap();
+        buf.clear();
+        File::open(&secret).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "password");
+    }
+}
@@ -1,12 +1,20 @@
+use std::io;
 use std::fmt;
 use std::ffi::{CStr, CString};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
-use nix::mount::{MsFlags, mount};
+use libc::{c_int, AT_FDCWD};
+use nix::mount::{MsFlags, MntFlags, mount, umount2};
 
-use {OSError, Error};
-use util::{path_to_cstring, as_path};
-use explain::{Explainable, exists};
+use {OSError, Error, DetachedMount, Mountable};
+use util::{path_to_cstring, as_path, canonicalize_lenient};
+use explain::{Explainable, PathRole, exists, target_exists, dir_state};
+use explain::mount_point;
+use explain::user;
+use newapi::{move_mount, SwapCalls, Kernel};
+use newapi::{OPEN_TREE_CLONE, OPEN_TREE_CLOEXEC, AT_RECURSIVE};
+use newapi::{MOVE_MOUNT_F_EMPTY_PATH, MOVE_MOUNT_BENEATH};
 
 /// A move operation definition
 ///
@@ -18,6 +26,70 @@ pub struct Move {
     target: CString,
 }
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum SwapError {
+        Io(msg: String, err: io::Error) {
+            source(err)
+            display("{}: {}", msg, err)
+        }
+        RollbackFailed(msg: String, err: io::Error, rollback: io::Error) {
+            source(err)
+            display("{}: {}, restoring original mount failed: {}",
+                    msg, err, rollback)
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum RecreateError {
+        Io(msg: String, err: io::Error) {
+            source(err)
+            display("{}: {}", msg, err)
+        }
+        WrongTarget(new: String) {
+            display("{} doesn't create a new mount at the target", new)
+        }
+        NewMountFailed(err: Box<OSError>) {
+            source(err)
+            display("{}, original mount is restored", err)
+        }
+        RollbackFailed(err: Box<OSError>, rollback: io::Error) {
+            source(err)
+            display("{}, restoring original mount failed: {}",
+                    err, rollback)
+        }
+    }
+}
+
+impl RecreateError {
+    /// Errno of the failed system call (of `new` if it has failed)
+    pub(crate) fn raw_os_error(&self) -> Option<c_int> {
+        match *self {
+            RecreateError::Io(_, ref e) => e.raw_os_error(),
+            RecreateError::WrongTarget(..) => None,
+            RecreateError::NewMountFailed(ref e) => e.raw_os_error(),
+            RecreateError::RollbackFailed(ref e, _) => e.raw_os_error(),
+        }
+    }
+}
+
+/// An operation of swapping two mount points (used for error reporting)
+#[derive(Debug)]
+struct Swap {
+    a: CString,
+    b: CString,
+}
+
+/// An operation of replacing a mount with a new one (used for error
+/// reporting)
+#[derive(Debug)]
+struct Recreate {
+    target: CString,
+    new: String,
+}
+
 impl Move {
     /// Create a new Move operation
     pub fn new<A: AsRef<Path>, B: AsRef<Path>>(source: A, target: B) -> Move {
@@ -27,6 +99,16 @@ impl Move {
         }
     }
 
+    /// Path of the mount point to move
+    pub fn source_path(&self) -> &Path {
+        as_path(&self.source)
+    }
+
+    /// Path where mount point will be moved to
+    pub fn target_path(&self) -> &Path {
+        as_path(&self.target)
+    }
+
     /// Execute a move-mountpoint operation
     pub fn bare_move_mountpoint(self)
         -> Result<(), OSError>
@@ -41,6 +123,243 @@ impl Move {
     }
 }
 
+/// Swap the mounts at `a` and `b`
+///
+/// Both mount trees (including submounts) are copied with `open_tree`
+/// first, then each path is replaced by the copy of the other one: the
+/// copy is put beneath the current mount with `move_mount` and the
+/// current mount is lazily unmounted. This requires linux 6.5+ for
+/// `MOVE_MOUNT_BENEATH`, on older kernels it fails with `EINVAL` before
+/// anything is changed.
+///
+/// Limitations:
+///
+/// 1. Replacing each path is atomic, but the swap as a whole is not. In
+///    between the two steps both paths show the filesystem of `b`.
+/// 2. Mounts at the paths are copies, so they have new mount ids and
+///    propagation of the copied mounts is private. Processes which have
+///    files open or have current directory on the original mounts keep
+///    using them.
+/// 3. If replacing `b` fails, the original mount of `a` is restored (from
+///    a separate copy made at the start). If restoring fails too, the error
+///    is `RollbackFailed` and `a` shows the filesystem of `b`.
+pub fn bare_swap_mounts<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B)
+    -> Result<(), OSError>
+{
+    let swap = Swap {
+        a: path_to_cstring(a.as_ref()),
+        b: path_to_cstring(b.as_ref()),
+    };
+    match swap.execute(&Kernel) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(OSError::from_swap(e, Box::new(swap))),
+    }
+}
+
+/// Swap the mounts at `a` and `b` and explain the error immediately
+///
+/// See `bare_swap_mounts` for the limitations.
+pub fn swap_mounts<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B)
+    -> Result<(), Error>
+{
+    bare_swap_mounts(a, b).map_err(OSError::explain)
+}
+
+/// Replace the mount at `target` with the one made by `new`
+///
+/// This is for changes which can't be done by remount, like `mode` of a
+/// tmpfs or the set of overlay layers. The `new` operation must create a
+/// new mount at the same `target` (e.g. `Tmpfs`, `Overlay`, `BindMount`),
+/// otherwise `WrongTarget` error is returned before anything is changed.
+///
+/// The current mount tree at `target` is copied with `open_tree` (linux
+/// 5.2+) and unmounted, then `new` is performed. If it fails, the copy
+/// is mounted back, so the original filesystem (with its contents) is
+/// restored, and the error is `NewMountFailed` wrapping the error of `new`.
+/// If restoring fails too, the error is `RollbackFailed` and nothing is
+/// mounted at `target`.
+///
+/// Note: the original mount is unmounted lazily, so processes which have
+/// files open on it keep using the old filesystem. Also the restored
+/// mount is a copy, so it has a new mount id and private propagation.
+pub fn bare_recreate_with<P, M>(target: P, new: M) -> Result<(), OSError>
+    where P: AsRef<Path>, M: Mountable + Explainable
+{
+    let target = target.as_ref();
+    let recreate = Recreate {
+        target: path_to_cstring(target),
+        new: new.to_string(),
+    };
+    if !mounts_at(&new, target) {
+        let err = RecreateError::WrongTarget(recreate.new.clone());
+        return Err(OSError::from_recreate(err, Box::new(recreate)));
+    }
+    let copy = match clone_tree(&recreate.target, &Kernel) {
+        Ok(copy) => copy,
+        Err(e) => {
+            let msg = copy_error(&recreate.target);
+            return Err(OSError::from_recreate(RecreateError::Io(msg, e),
+                                              Box::new(recreate)));
+        }
+    };
+    if let Err(e) = umount2(&*recreate.target, MntFlags::MNT_DETACH) {
+        let msg = format!("Cannot unmount {:?}", target);
+        return Err(OSError::from_recreate(
+            RecreateError::Io(msg, io_error(e)), Box::new(recreate)));
+    }
+    let err = match Box::new(new).bare_perform() {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+    match move_mount(copy.as_raw_fd(), empty, AT_FDCWD, &recreate.target,
+                     MOVE_MOUNT_F_EMPTY_PATH)
+    {
+        Ok(()) => Err(OSError::from_recreate(
+            RecreateError::NewMountFailed(Box::new(err)),
+            Box::new(recreate))),
+        Err(rollback) => Err(OSError::from_recreate(
+            RecreateError::RollbackFailed(Box::new(err), io_error(rollback)),
+            Box::new(recreate))),
+    }
+}
+
+/// Checks that `new` creates a mount exactly at `target`
+///
+/// Operations which move an existing mount (they require the source to be
+/// a mount point) are rejected too, as they don't create a new filesystem.
+fn mounts_at<M: Explainable>(new: &M, target: &Path) -> bool {
+    let paths = new.paths();
+    let target = canonicalize_lenient(target);
+    new.creates_mount()
+        && !paths.iter().any(|&(role, _)| role == PathRole::MountPoint)
+        && paths.iter().any(|&(role, path)| {
+            role == PathRole::Target && canonicalize_lenient(path) == target
+        })
+}
+
+/// Replace the mount at `target` with the one made by `new` and explain
+/// the error immediately
+///
+/// See `bare_recreate_with` for the details.
+pub fn recreate_with<P, M>(target: P, new: M) -> Result<(), Error>
+    where P: AsRef<Path>, M: Mountable + Explainable
+{
+    bare_recreate_with(target, new).map_err(OSError::explain)
+}
+
+fn io_error(err: ::nix::Error) -> io::Error {
+    err.as_errno().map_or_else(
+        || io::Error::other(err),
+        io::Error::from)
+}
+
+impl Swap {
+    fn execute<C: SwapCalls>(&self, calls: &C) -> Result<(), SwapError> {
+        let copy = |path: &CStr| clone_tree(path, calls)
+            .map_err(|e| SwapError::Io(copy_error(path), e));
+        let tree_a = copy(&self.a)?;
+        let tree_b = copy(&self.b)?;
+        // `tree_a` can't be attached again if it was attached under `b`
+        // before failing, so `a` is restored from a separate copy
+        let backup_a = copy(&self.a)?;
+        replace(&self.a, &tree_b, calls)
+            .map_err(|e| SwapError::Io(format!("Cannot replace mount at {:?}",
+                                               as_path(&self.a)), e))?;
+        if let Err(e) = replace(&self.b, &tree_a, calls) {
+            let msg = format!("Cannot replace mount at {:?}",
+                              as_path(&self.b));
+            return match replace(&self.a, &backup_a, calls) {
+                Ok(()) => Err(SwapError::Io(msg, e)),
+                Err(rollback) => {
+                    Err(SwapError::RollbackFailed(msg, e, rollback))
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Makes a detached copy of the mount tree at `path`
+fn clone_tree<C: SwapCalls>(path: &CStr, calls: &C)
+    -> Result<DetachedMount, io::Error>
+{
+    calls.open_tree(AT_FDCWD, path,
+                    OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC | AT_RECURSIVE)
+        .map(|fd| DetachedMount::from_raw_fd(fd,
+            format!("copy of {:?}", as_path(path))))
+        .map_err(io_error)
+}
+
+fn copy_error(path: &CStr) -> String {
+    format!("Cannot copy mount at {:?}", as_path(path))
+}
+
+/// Replaces the top mount at `path` with the `tree`
+///
+/// If unmounting the current mount fails, the `tree` is unmounted from
+/// beneath it, so the `path` is left as it was.
+fn replace<C: SwapCalls>(path: &CStr, tree: &DetachedMount, calls: &C)
+    -> Result<(), io::Error>
+{
+    let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+    calls.move_mount(tree.as_raw_fd(), empty, AT_FDCWD, path,
+                     MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_BENEATH)
+        .map_err(io_error)?;
+    if let Err(e) = calls.umount(path) {
+        // file descriptor refers to the root of the attached copy
+        let fd_path = CString::new(
+            format!("/proc/self/fd/{}", tree.as_raw_fd())).unwrap();
+        if let Err(undo) = calls.umount(&fd_path) {
+            warn!("Can't unmount copy put beneath {:?}: {}",
+                  as_path(path), undo);
+        }
+        return Err(io_error(e));
+    }
+    Ok(())
+}
+
+impl fmt::Display for Swap {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "swap {:?} <-> {:?}",
+            as_path(&self.a), as_path(&self.b))
+    }
+}
+
+impl Explainable for Swap {
+    fn explain(&self) -> String {
+        [
+            format!("a: {}", exists(as_path(&self.a))),
+            format!("a: {}", mount_point(as_path(&self.a))),
+            format!("b: {}", exists(as_path(&self.b))),
+            format!("b: {}", mount_point(as_path(&self.b))),
+            user().to_string(),
+        ].join(", ")
+    }
+}
+
+impl fmt::Display for Recreate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "recreate {:?} with {}", as_path(&self.target), self.new)
+    }
+}
+
+impl Explainable for Recreate {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", exists(as_path(&self.target))),
+            format!("target: {}", mount_point(as_path(&self.target))),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
 impl fmt::Display for Move {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "move {:?} -> {:?}",
@@ -52,8 +371,257 @@ impl Explainable for Move {
     fn explain(&self) -> String {
         [
             format!("source: {}", exists(as_path(&self.source))),
-            format!("target: {}", exists(as_path(&self.target))),
+            format!("source: {}", mount_point(as_path(&self.source))),
+            format!("target: {}", target_exists(as_path(&self.target))),
+            format!("target: {}", dir_state(as_path(&self.target))),
         ].join(", ")
     }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![
+            (PathRole::Source, as_path(&self.source)),
+            (PathRole::MountPoint, as_path(&self.source)),
+            (PathRole::Target, as_path(&self.target)),
+        ]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
 }
 
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::ffi::{CStr, CString};
+    use std::fs::{File, create_dir, metadata};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::RawFd;
+    use std::path::Path;
+
+    use libc::c_uint;
+    use nix::Result;
+    use nix::errno::Errno;
+    use nix::unistd::getuid;
+
+    use {Error, ErrorCategory, Tmpfs, Unmount, Overlay, Remount};
+    use explain::Explainable;
+    use mountinfo::mounts_under;
+    use newapi::SwapCalls;
+    use util::test::tmp_dir;
+    use super::{Move, Swap, SwapError, swap_mounts, recreate_with};
+
+    /// Records the calls, returns fake (negative) file descriptors
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<String>>,
+        next_fd: Cell<RawFd>,
+        fail_umount: Option<&'static str>,
+    }
+
+    impl SwapCalls for Recorder {
+        fn open_tree(&self, _dfd: RawFd, path: &CStr, _flags: c_uint)
+            -> Result<RawFd>
+        {
+            let fd = self.next_fd.get() - 1;
+            self.next_fd.set(fd);
+            self.calls.borrow_mut().push(format!("open_tree({:?}) = {}",
+                path, fd));
+            Ok(fd)
+        }
+        fn move_mount(&self, from_dfd: RawFd, _from_path: &CStr,
+            _to_dfd: RawFd, to_path: &CStr, _flags: c_uint)
+            -> Result<()>
+        {
+            self.calls.borrow_mut().push(format!("move_mount({}, {:?})",
+                from_dfd, to_path));
+            Ok(())
+        }
+        fn umount(&self, path: &CStr) -> Result<()> {
+            self.calls.borrow_mut().push(format!("umount({:?})", path));
+            if self.fail_umount.map(|x| x.as_bytes()) == Some(path.to_bytes())
+            {
+                Err(::nix::Error::Sys(Errno::EPERM))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn fake_swap() -> Swap {
+        Swap {
+            a: CString::new("/a").unwrap(),
+            b: CString::new("/b").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_swap_calls() {
+        let calls = Recorder::default();
+        fake_swap().execute(&calls).unwrap();
+        assert_eq!(*calls.calls.borrow(), vec![
+            "open_tree(\"/a\") = -1",
+            "open_tree(\"/b\") = -2",
+            "open_tree(\"/a\") = -3",
+            "move_mount(-2, \"/a\")",
+            "umount(\"/a\")",
+            "move_mount(-1, \"/b\")",
+            "umount(\"/b\")",
+        ]);
+    }
+
+    #[test]
+    fn test_swap_rollback() {
+        let calls = Recorder {
+            fail_umount: Some("/b"),
+            .. Recorder::default()
+        };
+        match fake_swap().execute(&calls) {
+            Err(SwapError::Io(ref msg, _))
+            if msg == "Cannot replace mount at \"/b\"" => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(*calls.calls.borrow(), vec![
+            "open_tree(\"/a\") = -1",
+            "open_tree(\"/b\") = -2",
+            "open_tree(\"/a\") = -3",
+            "move_mount(-2, \"/a\")",
+            "umount(\"/a\")",
+            "move_mount(-1, \"/b\")",
+            "umount(\"/b\")",
+            // copy of `a` is removed from beneath `b`
+            "umount(\"/proc/self/fd/-1\")",
+            // and `a` is restored from the other copy
+            "move_mount(-3, \"/a\")",
+            "umount(\"/a\")",
+        ]);
+    }
+
+    #[test]
+    fn test_paths() {
+        let op = Move::new("/a", "/b");
+        assert_eq!(op.source_path(), Path::new("/a"));
+        assert_eq!(op.target_path(), Path::new("/b"));
+    }
+
+    #[test]
+    fn test_explain() {
+        let dir = tmp_dir("move_explain");
+        create_dir(dir.join("empty")).unwrap();
+        create_dir(dir.join("full")).unwrap();
+        File::create(dir.join("full/file")).unwrap();
+
+        assert_eq!(Move::new("/", dir.join("empty")).explain(),
+            "source: exists, source: mountpoint, \
+             target: exists, target: empty-dir");
+        assert_eq!(Move::new(&dir, dir.join("full")).explain(),
+            "source: exists, source: not-a-mountpoint, \
+             target: exists, target: non-empty-dir");
+        assert_eq!(Move::new(&dir, dir.join("full/file")).explain(),
+            "source: exists, source: not-a-mountpoint, \
+             target: exists, target: not-a-dir");
+    }
+
+    #[test]
+    fn test_swap_mounts() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("swap_mounts");
+        create_dir(dir.join("a")).unwrap();
+        create_dir(dir.join("b")).unwrap();
+        Tmpfs::new(dir.join("a")).mount().unwrap();
+        Tmpfs::new(dir.join("b")).mount().unwrap();
+        File::create(dir.join("a/from_a")).unwrap();
+        File::create(dir.join("b/from_b")).unwrap();
+
+        match swap_mounts(&dir.join("a"), &dir.join("b")) {
+            Ok(()) => {
+                assert!(dir.join("a/from_b").exists());
+                assert!(dir.join("b/from_a").exists());
+                assert!(!dir.join("a/from_a").exists());
+            }
+            // MOVE_MOUNT_BENEATH isn't supported, nothing is changed
+            Err(ref e) if e.raw_os_error() == Some(::libc::EINVAL) => {
+                assert!(dir.join("a/from_a").exists());
+                assert!(dir.join("b/from_b").exists());
+            }
+            Err(e) => panic!("{}", e),
+        }
+        Unmount::new(dir.join("a")).unmount().unwrap();
+        Unmount::new(dir.join("b")).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_swap_explain() {
+        let dir = tmp_dir("swap_explain");
+        let err = swap_mounts(&dir.join("a"), &dir).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(::libc::ENOENT));
+        assert!(err.explanation().starts_with(&format!(
+            "Cannot copy mount at {:?}, \
+             a: missing, a: not-a-mountpoint, b: exists, ",
+            dir.join("a"))));
+    }
+
+    #[test]
+    fn test_recreate_explain() {
+        let dir = tmp_dir("recreate_explain");
+        let target = dir.join("a");
+        let err = recreate_with(&target, Tmpfs::new(&target)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(::libc::ENOENT));
+        assert!(err.to_string().starts_with(&format!(
+            "recreate {:?} with tmpfs  -> {:?}: ", target, target)));
+        assert!(err.explanation().starts_with(&format!(
+            "Cannot copy mount at {:?}, \
+             target: missing, target: not-a-mountpoint, ", target)));
+    }
+
+    #[test]
+    fn test_recreate_wrong_target() {
+        let dir = tmp_dir("recreate_wrong_target");
+        let other = dir.join("other");
+        let check = |err: Error| {
+            assert_eq!(err.raw_os_error(), None);
+            assert_eq!(err.category(), ErrorCategory::InvalidConfiguration);
+            assert!(err.to_string()
+                .contains("doesn't create a new mount at the target"));
+        };
+        check(recreate_with(&dir, Tmpfs::new(&other)).unwrap_err());
+        check(recreate_with(&dir, Remount::new(&dir)).unwrap_err());
+        check(recreate_with(&dir, Move::new(&other, &dir)).unwrap_err());
+        check(recreate_with(&dir, Unmount::new(&dir)).unwrap_err());
+    }
+
+    #[test]
+    fn test_recreate_with() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("recreate_with");
+        let mode = || metadata(&dir).unwrap().permissions().mode() & 0o7777;
+        Tmpfs::new(&dir).mode(0o700).mount().unwrap();
+        assert_eq!(mode(), 0o700);
+        recreate_with(&dir, Tmpfs::new(&dir).mode(0o755)).unwrap();
+        assert_eq!(mode(), 0o755);
+        assert_eq!(mounts_under(&dir).unwrap().len(), 1);
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_recreate_rollback() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("recreate_rollback");
+        Tmpfs::new(&dir).mount().unwrap();
+        File::create(dir.join("file")).unwrap();
+        let lower = [dir.join("nonexistent1"), dir.join("nonexistent2")];
+        let err = recreate_with(&dir,
+            Overlay::readonly(lower.iter().map(|x| x.as_path()), &dir))
+            .unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "recreate {:?} with overlayfs ", dir)));
+        assert!(err.to_string().contains("original mount is restored"));
+        assert!(dir.join("file").exists());
+        assert_eq!(mounts_under(&dir).unwrap().len(), 1);
+        Unmount::new(&dir).unmount().unwrap();
+    }
+}
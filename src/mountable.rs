@@ -0,0 +1,113 @@
+use std::fmt;
+
+use {OSError, Error};
+use {BindMount, Overlay, Tmpfs, CgroupV1, ContainerMounts, Move, PivotRoot};
+use {Remount, Unmount};
+
+
+/// A common interface for all the mount operations
+///
+/// This allows keeping heterogeneous operations in a single collection,
+/// e.g. `Vec<Box<Mountable>>`. Methods take boxed `self` so that they can be
+/// called on trait objects.
+pub trait Mountable: fmt::Display + fmt::Debug {
+    /// Execute the operation
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError>;
+
+    /// Execute the operation and explain the error immediately
+    fn perform(self: Box<Self>) -> Result<(), Error> {
+        self.bare_perform().map_err(OSError::explain)
+    }
+}
+
+impl Mountable for BindMount {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_mount()
+    }
+}
+
+impl Mountable for Overlay {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_mount()
+    }
+}
+
+impl Mountable for Tmpfs {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_mount()
+    }
+}
+
+impl Mountable for CgroupV1 {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_mount()
+    }
+}
+
+impl Mountable for ContainerMounts {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_mount()
+    }
+}
+
+impl Mountable for Move {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_move_mountpoint()
+    }
+}
+
+impl Mountable for PivotRoot {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_pivot_root()
+    }
+}
+
+impl Mountable for Remount {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_remount()
+    }
+}
+
+impl Mountable for Unmount {
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        self.bare_unmount()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use {BindMount, Overlay, Tmpfs, Move, PivotRoot, Remount, Unmount};
+    use super::Mountable;
+
+    #[test]
+    fn test_heterogeneous() {
+        let ops: Vec<Box<Mountable>> = vec![
+            Box::new(BindMount::new("/nonexistent/a", "/nonexistent/b")),
+            Box::new(Overlay::readonly(
+                [Path::new("/nonexistent/a"), Path::new("/nonexistent/b")]
+                .iter().cloned(),
+                "/nonexistent/c")),
+            Box::new(Tmpfs::new("/nonexistent/a")),
+            Box::new(Move::new("/nonexistent/a", "/nonexistent/b")),
+            Box::new(PivotRoot::new("/nonexistent/a", "/nonexistent/a/b")),
+            Box::new(Remount::new("/nonexistent/a")),
+            Box::new(Unmount::new("/nonexistent/a")),
+        ];
+        assert_eq!(ops.iter().map(|x| x.to_string()).collect::<Vec<_>>(), [
+            "recursive bind mount \"/nonexistent/a\" -> \"/nonexistent/b\"",
+            "overlayfs \"/nonexistent/a\":\"/nonexistent/b\" \
+                -> \"/nonexistent/c\"",
+            "tmpfs  -> \"/nonexistent/a\"",
+            "move \"/nonexistent/a\" -> \"/nonexistent/b\"",
+            "pivot_root \"/nonexistent/a\", \
+                put old root to \"/nonexistent/a/b\"",
+            "remount \"/nonexistent/a\"",
+            "unmount \"/nonexistent/a\"",
+        ]);
+        for op in ops {
+            assert!(op.perform().is_err());
+        }
+    }
+}
@@ -1,15 +1,52 @@
 //! This module contains parser for /proc/PID/mountinfo
 //!
 use std;
+use std::io;
 use std::fmt;
-use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::ffi::{CStr, OsStr, OsString};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
 use std::borrow::Cow;
 use std::error::Error;
+use std::collections::HashMap;
 
+use nix;
+use nix::errno::Errno;
 use nix::mount::MsFlags;
+use nix::sys::statfs::statfs;
 
-use libc::c_ulong;
+use libc::{c_ulong, AT_FDCWD, AT_EMPTY_PATH};
+
+use explain::initial_user_namespace;
+use util::{canonicalize_lenient, path_to_cstring, supports_statx_mount_id};
+use flags::{flag_options, option_flag};
+use newapi::statx_mount_id;
+
+/// Flags which kernel locks when mount is propagated to a less privileged
+/// user namespace
+///
+/// These are the flags that can't be cleared by remount inside a user
+/// namespace if the mount was inherited from the parent namespace.
+pub const LOCKABLE_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_RDONLY.bits() |
+    MsFlags::MS_NODEV.bits() |
+    MsFlags::MS_NOSUID.bits() |
+    MsFlags::MS_NOEXEC.bits() |
+    MsFlags::MS_NOATIME.bits() |
+    MsFlags::MS_NODIRATIME.bits() |
+    MsFlags::MS_RELATIME.bits() |
+    MsFlags::MS_STRICTATIME.bits());
+
+/// Flags which a submount should inherit from the parent mount
+///
+/// See `inherit_flags_from_parent`.
+pub const INHERITABLE_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_NODEV.bits() |
+    MsFlags::MS_NOSUID.bits() |
+    MsFlags::MS_NOEXEC.bits());
 
 /// Error parsing a single entry of mountinfo file
 #[derive(Debug)]
@@ -36,7 +73,7 @@ pub struct ParseError {
 }
 
 impl ParseError {
-    fn new(msg: String, row_num: usize, row: String) -> ParseError {
+    pub(crate) fn new(msg: String, row_num: usize, row: String) -> ParseError {
         ParseError {
             msg: msg,
             row_num: row_num,
@@ -107,6 +144,33 @@ impl<'a> MountPoint<'a> {
         self.get_mount_flags().bits() as c_ulong
     }
 
+    /// Returns `true` if writes through the mount point are refused
+    ///
+    /// There are two read-only states. The VFS-level one is a per-mount
+    /// `ro` flag (`mount_options`), set by e.g. a read-only bind mount,
+    /// while the filesystem may still be writable via another mount point.
+    /// The superblock-level one (`ro` in `super_options`) makes the
+    /// filesystem itself read-only, e.g. a filesystem with errors remounted
+    /// `ro` by the kernel, and then all its mount points are read-only
+    /// even if their `mount_options` say `rw`. This checks both.
+    pub fn is_readonly(&self) -> bool {
+        self.get_mount_flags().contains(MsFlags::MS_RDONLY)
+            || self.is_superblock_readonly()
+    }
+
+    /// Returns `true` if the filesystem itself is read-only
+    ///
+    /// This is the `ro` in `super_options`, see `is_readonly()`.
+    pub fn is_superblock_readonly(&self) -> bool {
+        self.super_options.as_bytes().split(|c| *c == b',')
+            .any(|opt| opt == b"ro")
+    }
+
+    /// Returns per-mount (VFS-level) flags
+    ///
+    /// `MS_RDONLY` here reflects only the `ro` of `mount_options`, so the
+    /// flags can be passed back to a bind remount as is. Superblock-level
+    /// read-only state is not included, see `is_readonly()`.
     pub(crate) fn get_mount_flags(&self) -> MsFlags {
         let mut flags = MsFlags::empty();
         for opt in self.mount_options.as_bytes().split(|c| *c == b',') {
@@ -125,12 +189,861 @@ impl<'a> MountPoint<'a> {
         }
         flags
     }
+
+    /// Returns per-mount options which aren't represented in `MsFlags`
+    ///
+    /// These are options the crate doesn't model (e.g. `nosymfollow`), so
+    /// they are lost when the flags are read and passed back to remount.
+    pub fn unmodeled_mount_options(&self) -> Vec<String> {
+        self.mount_options.as_bytes().split(|c| *c == b',')
+            .filter(|opt| !opt.is_empty())
+            .filter(|&opt| std::str::from_utf8(opt).ok()
+                .and_then(option_flag).is_none())
+            .map(|opt| String::from_utf8_lossy(opt).into_owned())
+            .collect()
+    }
+
+    /// Returns flags that current process is probably unable to change
+    ///
+    /// When mount namespace is created by unprivileged user namespace, the
+    /// kernel locks the flags of all inherited mounts, so that remounting
+    /// them, for example, writable or `suid` fails with `EPERM`.
+    ///
+    /// There is no way to see which mounts are locked in mountinfo, so this
+    /// returns locked flags as if the mount was inherited from the parent
+    /// namespace. Mounts created in current namespace aren't locked. In the
+    /// initial user namespace nothing is locked and empty set is returned.
+    ///
+    /// Note: the kernel locks atime flags as a whole, so they are always
+    /// reported as locked in a user namespace.
+    pub fn locked_flags(&self) -> MsFlags {
+        // if uid_map is unavailable we're probably on an old kernel without
+        // user namespaces
+        let initial = initial_user_namespace().unwrap_or(true);
+        self.locked_flags_in(initial)
+    }
+
+    /// Returns a `mount(8)` command which would recreate this mount
+    ///
+    /// Options are the per-mount flags followed by the superblock options,
+    /// e.g. `mount -t tmpfs -o rw,nosuid,nodev,size=1024k tmpfs /tmp`.
+    /// This is best-effort: bind mounts are rendered as a mount of the
+    /// whole filesystem (`root` isn't recoverable as a path), propagation
+    /// isn't included, and some filesystems show options in
+    /// mountinfo which they don't accept back. Arguments are quoted for
+    /// the shell when needed.
+    pub fn reconstruct_command(&self) -> String {
+        let flags = self.get_mount_flags();
+        let mut options = Vec::new();
+        if !flags.contains(MsFlags::MS_RDONLY) {
+            options.push("rw".to_string());
+        }
+        options.extend(flag_options(flags).into_iter().map(String::from));
+        for opt in self.super_options.as_bytes().split(|c| *c == b',') {
+            // read-only state is already set by the per-mount flag
+            if opt.is_empty() || opt == b"rw" || opt == b"ro" {
+                continue;
+            }
+            options.push(String::from_utf8_lossy(opt).into_owned());
+        }
+        format!("mount -t {} -o {} {} {}",
+            shell_quote(&self.fstype),
+            shell_quote(OsStr::new(&options.join(","))),
+            shell_quote(&self.mount_source),
+            shell_quote(&self.mount_point))
+    }
+
+    /// Convert into a mount point which doesn't borrow the parser's buffer
+    pub fn into_owned(self) -> MountPoint<'static> {
+        MountPoint {
+            mount_id: self.mount_id,
+            parent_id: self.parent_id,
+            major: self.major,
+            minor: self.minor,
+            root: Cow::Owned(self.root.into_owned()),
+            mount_point: Cow::Owned(self.mount_point.into_owned()),
+            mount_options: Cow::Owned(self.mount_options.into_owned()),
+            optional_fields: Cow::Owned(self.optional_fields.into_owned()),
+            fstype: Cow::Owned(self.fstype.into_owned()),
+            mount_source: Cow::Owned(self.mount_source.into_owned()),
+            super_options: Cow::Owned(self.super_options.into_owned()),
+        }
+    }
+
+    /// Returns peer group id if the mount is shared (`shared:N` field)
+    pub fn peer_group(&self) -> Option<c_ulong> {
+        self.optional_field_value(b"shared:")
+    }
+
+    /// Returns peer group of the master if the mount is slave (`master:N`)
+    pub fn master_group(&self) -> Option<c_ulong> {
+        self.optional_field_value(b"master:")
+    }
+
+    /// Returns path of this mount point relative to the `parent` one
+    ///
+    /// E.g. `b/c` for `/a/b/c` under `/a`. Returns `None` if this mount
+    /// point isn't nested under the `parent`, and an empty path if they are
+    /// at the same place (i.e. stacked). Only `mount_point`s are compared,
+    /// so for bind mounts the `root` of the filesystem doesn't matter.
+    pub fn relative_to(&self, parent: &MountPoint) -> Option<PathBuf> {
+        Path::new(&self.mount_point)
+            .strip_prefix(Path::new(&parent.mount_point))
+            .ok().map(|p| p.to_path_buf())
+    }
+
+    fn optional_field_value(&self, prefix: &[u8]) -> Option<c_ulong> {
+        self.optional_fields.as_bytes()
+            .split(|c| *c == b' ')
+            .filter(|f| f.starts_with(prefix))
+            .filter_map(|f| std::str::from_utf8(&f[prefix.len()..]).ok())
+            .filter_map(|v| v.parse().ok())
+            .next()
+    }
+
+    fn locked_flags_in(&self, initial_user_namespace: bool) -> MsFlags {
+        if initial_user_namespace {
+            return MsFlags::empty();
+        }
+        (self.get_mount_flags() & LOCKABLE_FLAGS) | ATIME_FLAGS
+    }
+}
+
+fn shell_quote(value: &OsStr) -> String {
+    let value = value.to_string_lossy();
+    let safe = !value.is_empty() && value.bytes().all(|c| {
+        c.is_ascii_alphanumeric() || b"/._-=,:+@%".contains(&c)
+    });
+    if safe {
+        value.into_owned()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+const ATIME_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_NOATIME.bits() |
+    MsFlags::MS_NODIRATIME.bits() |
+    MsFlags::MS_RELATIME.bits() |
+    MsFlags::MS_STRICTATIME.bits());
+
+/// Mount propagation relationships
+///
+/// Returned by `peer_groups()`
+#[derive(Debug, Clone, Default)]
+pub struct PeerGroups {
+    /// Mount ids grouped by the peer group id (`shared:N`)
+    pub groups: HashMap<c_ulong, Vec<c_ulong>>,
+    /// Slave edges as `(mount_id, master peer group id)` pairs
+    pub slaves: Vec<(c_ulong, c_ulong)>,
+}
+
+/// Group mount points by their propagation peer groups
+///
+/// Mounts are put into `groups` in the order they are encountered.
+pub fn peer_groups<'a, 'b: 'a, I>(entries: I) -> PeerGroups
+    where I: IntoIterator<Item=&'a MountPoint<'b>>
+{
+    let mut result = PeerGroups::default();
+    for entry in entries {
+        if let Some(group) = entry.peer_group() {
+            result.groups.entry(group).or_insert_with(Vec::new)
+                .push(entry.mount_id);
+        }
+        if let Some(master) = entry.master_group() {
+            result.slaves.push((entry.mount_id, master));
+        }
+    }
+    result
+}
+
+/// Read the whole mountinfo file of the current process
+pub(crate) fn read_self_mountinfo() -> io::Result<Vec<u8>> {
+    let mut content = Vec::with_capacity(4 * 1024);
+    let mut file = File::open("/proc/self/mountinfo")?;
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Returns the number of mount points in the current mount namespace
+///
+/// This only counts the lines of `/proc/self/mountinfo`, so it's much
+/// cheaper than parsing it. Useful for detecting mount leaks, e.g. to
+/// compare with the `fs.mount-max` sysctl.
+pub fn count() -> io::Result<usize> {
+    Ok(count_from(&read_self_mountinfo()?))
+}
+
+/// Returns the number of mount points in the mount namespace of `pid`
+pub fn count_for(pid: u32) -> io::Result<usize> {
+    let mut content = Vec::with_capacity(4 * 1024);
+    File::open(format!("/proc/{}/mountinfo", pid))?
+        .read_to_end(&mut content)?;
+    Ok(count_from(&content))
+}
+
+/// Returns the maximum number of mounts per mount namespace
+///
+/// This is the `fs.mount-max` sysctl (`/proc/sys/fs/mount-max`). When the
+/// limit is reached, `mount()` fails with `ENOSPC`.
+pub fn mount_max() -> io::Result<usize> {
+    let mut content = String::with_capacity(20);
+    File::open("/proc/sys/fs/mount-max")?.read_to_string(&mut content)?;
+    mount_max_from(&content)
+}
+
+fn mount_max_from(content: &str) -> io::Result<usize> {
+    content.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData,
+            format!("invalid fs.mount-max value {:?}", content.trim()))
+    })
+}
+
+/// Returns how many more mounts can be made in the current mount namespace
+///
+/// This is `mount_max() - count()`. It's racy if other processes mount
+/// things in the same namespace, so treat it as an estimate.
+pub fn remaining_mounts() -> io::Result<usize> {
+    Ok(mount_max()?.saturating_sub(count()?))
+}
+
+fn count_from(content: &[u8]) -> usize {
+    content.split(|&c| c == b'\n')
+        .map(rstrip_cr)
+        .filter(|row| !is_comment_line(row))
+        .filter(|row| row.iter().any(|c| !c.is_ascii_whitespace()))
+        .count()
+}
+
+/// Returns all mount points at or under the `prefix` in the current mount
+/// namespace
+///
+/// Mount points are returned in the order of mountinfo file, i.e. parents
+/// go before their children. Symlinks in the prefix are resolved (it's fine
+/// if it doesn't exist).
+pub fn mounts_under<P: AsRef<Path>>(prefix: P)
+    -> io::Result<Vec<MountPoint<'static>>>
+{
+    let prefix = canonicalize_lenient(prefix.as_ref());
+    let content = read_self_mountinfo()?;
+    mounts_under_from(&content, &prefix)
+}
+
+fn mounts_under_from(content: &[u8], prefix: &Path)
+    -> io::Result<Vec<MountPoint<'static>>>
+{
+    let mut result = Vec::new();
+    for entry in Parser::new(content) {
+        let entry = entry.map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        if Path::new(&entry.mount_point).starts_with(prefix) {
+            result.push(entry.into_owned());
+        }
+    }
+    Ok(result)
+}
+
+/// Returns all bind mounts of the `source` directory (or of its subtrees)
+///
+/// Bind mounts can't be recognized by `mount_source`, which is the device
+/// of the whole filesystem. Instead, the path of `source` relative to the
+/// root of its filesystem is computed, and entries with the same device
+/// (`major:minor`) which `root` is at or under that path are returned.
+/// The mount at `source` itself (if any) isn't included. Symlinks in the
+/// path are resolved.
+pub fn binds_of<P: AsRef<Path>>(source: P)
+    -> io::Result<Vec<MountPoint<'static>>>
+{
+    let source = canonicalize_lenient(source.as_ref());
+    let content = read_self_mountinfo()?;
+    binds_of_from(&content, &source)
+}
+
+fn binds_of_from(content: &[u8], source: &Path)
+    -> io::Result<Vec<MountPoint<'static>>>
+{
+    let (major, minor, root) = match find_mount_point_from(content, source)? {
+        Some(mnt) => {
+            let relative = source.strip_prefix(&mnt.mount_point)
+                .expect("mount point is a prefix of the path");
+            (mnt.major, mnt.minor, Path::new(&mnt.root).join(relative))
+        }
+        None => return Ok(Vec::new()),
+    };
+    let mut result = Vec::new();
+    for entry in Parser::new(content) {
+        let entry = entry.map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        if entry.major == major && entry.minor == minor
+            && Path::new(&entry.root).starts_with(&root)
+            && Path::new(&entry.mount_point) != source
+        {
+            result.push(entry.into_owned());
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the mount point which contains `path` in the current mount
+/// namespace
+///
+/// This is the entry with the longest `mount_point` that is a prefix of the
+/// `path`. If several mounts are stacked at the same place, the topmost
+/// (the latest in mountinfo) is returned. Symlinks in the path are resolved
+/// (it's fine if it doesn't exist).
+pub fn find_mount_point<P: AsRef<Path>>(path: P)
+    -> io::Result<Option<MountPoint<'static>>>
+{
+    let path = canonicalize_lenient(path.as_ref());
+    let content = read_self_mountinfo()?;
+    find_mount_point_from(&content, &path)
+        .map(|x| x.map(|m| m.into_owned()))
+}
+
+/// Returns security-relevant flags of the mount which would be the parent
+/// of a new mount at `target`
+///
+/// These are `nosuid`, `nodev` and `noexec` (`INHERITABLE_FLAGS`). Kernel
+/// doesn't propagate them to submounts, so e.g. a filesystem mounted inside
+/// a `nosuid` directory may still contain suid binaries. Add the result to
+/// the flags of the new mount to prevent that. The parent is the mount
+/// containing `target` (the topmost one if `target` is a mount point
+/// already).
+pub fn inherit_flags_from_parent(target: &Path) -> io::Result<MsFlags> {
+    let target = canonicalize_lenient(target);
+    let content = read_self_mountinfo()?;
+    inherit_flags_from(&content, &target)
+}
+
+fn inherit_flags_from(content: &[u8], target: &Path) -> io::Result<MsFlags> {
+    match find_mount_point_from(content, target)? {
+        Some(parent) => Ok(parent.get_mount_flags() & INHERITABLE_FLAGS),
+        None => Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("no mount point containing {:?}", target))),
+    }
+}
+
+/// Returns true if a new mount at `path` would propagate to peer mounts
+///
+/// That is the case when the mount containing `path` is shared (has the
+/// `shared:N` field). E.g. in a container where the mount namespace wasn't
+/// made private, mounts created there also appear on the host. The parent
+/// is the topmost mount containing `path`, like in
+/// `inherit_flags_from_parent`.
+pub fn would_propagate_to_peers<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let path = canonicalize_lenient(path.as_ref());
+    let content = read_self_mountinfo()?;
+    would_propagate_from(&content, &path)
+}
+
+fn would_propagate_from(content: &[u8], path: &Path) -> io::Result<bool> {
+    match find_mount_point_from(content, path)? {
+        Some(parent) => Ok(parent.peer_group().is_some()),
+        None => Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("no mount point containing {:?}", path))),
+    }
+}
+
+/// Returns `true` if both paths are on the same mount
+///
+/// The enclosing mounts are found in mountinfo and compared by the mount
+/// id. Unlike comparing `st_dev`, this reports different bind mounts of the
+/// same filesystem as different mounts (e.g. `rename()` between them fails
+/// with `EXDEV`). Symlinks are resolved, paths don't have to exist.
+pub fn same_mount<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B)
+    -> io::Result<bool>
+{
+    let a = canonicalize_lenient(a.as_ref());
+    let b = canonicalize_lenient(b.as_ref());
+    let content = read_self_mountinfo()?;
+    same_mount_from(&content, &a, &b)
+}
+
+fn same_mount_from(content: &[u8], a: &Path, b: &Path) -> io::Result<bool> {
+    let mount_id = |path: &Path| match find_mount_point_from(content, path)? {
+        Some(mnt) => Ok(mnt.mount_id),
+        None => Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("no mount point containing {:?}", path))),
+    };
+    Ok(mount_id(a)? == mount_id(b)?)
+}
+
+/// Returns the mount point of the root directory of the current process
+///
+/// This is useful to check whether the root filesystem of a container is
+/// read-only. Note that root isn't necessarily the first entry in the
+/// mountinfo, and that there might be several mounts stacked at `/`, the
+/// topmost one is returned.
+pub fn root_mount() -> io::Result<MountPoint<'static>> {
+    let content = read_self_mountinfo()?;
+    root_mount_from(&content).map(|m| m.into_owned())
+}
+
+fn root_mount_from<'a>(content: &'a [u8]) -> io::Result<MountPoint<'a>> {
+    match find_mount_point_from(content, Path::new("/"))? {
+        Some(entry) => Ok(entry),
+        None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                   "no root mount in mountinfo")),
+    }
+}
+
+fn find_mount_point_from<'a>(content: &'a [u8], path: &Path)
+    -> io::Result<Option<MountPoint<'a>>>
+{
+    let mut result: Option<MountPoint> = None;
+    for entry in Parser::new(content) {
+        let entry = entry.map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        if contains_better(result.as_ref(), &entry, path) {
+            result = Some(entry);
+        }
+    }
+    Ok(result)
+}
+
+/// Checks whether `entry` contains `path` and is deeper than (or stacked
+/// over) the `prev` match
+fn contains_better(prev: Option<&MountPoint>, entry: &MountPoint, path: &Path)
+    -> bool
+{
+    let mount_path = Path::new(&entry.mount_point);
+    if !path.starts_with(mount_path) {
+        return false;
+    }
+    match prev {
+        Some(prev) => mount_path.components().count()
+            >= Path::new(&prev.mount_point).components().count(),
+        None => true,
+    }
+}
+
+/// A parsed snapshot of the mount table
+///
+/// Useful when many lookups are needed, as mountinfo is read and parsed
+/// only once. Note that the snapshot goes stale as soon as anything is
+/// mounted or unmounted, use `refresh()` to re-read it.
+#[derive(Debug)]
+pub struct MountTable {
+    entries: Vec<MountPoint<'static>>,
+}
+
+impl MountTable {
+    /// Read the mount table of the current process
+    pub fn read() -> io::Result<MountTable> {
+        let content = read_self_mountinfo()?;
+        MountTable::parse(&content).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })
+    }
+
+    /// Parse the contents of a mountinfo file
+    pub fn parse(content: &[u8]) -> Result<MountTable, ParseError> {
+        let entries = Parser::new(content)
+            .map(|entry| entry.map(|e| e.into_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(MountTable { entries })
+    }
+
+    /// Re-read the mount table of the current process
+    ///
+    /// Note: this reads `/proc/self/mountinfo` even if the table was
+    /// created with `parse()`. On error the old snapshot is kept.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        *self = MountTable::read()?;
+        Ok(())
+    }
+
+    /// All the entries in the order of mountinfo file
+    pub fn entries(&self) -> &[MountPoint<'static>] {
+        &self.entries
+    }
+
+    /// Returns the mount point which contains `path`
+    ///
+    /// Same as `find_mount_point()` but uses the snapshot
+    pub fn find<P: AsRef<Path>>(&self, path: P)
+        -> Option<&MountPoint<'static>>
+    {
+        let path = canonicalize_lenient(path.as_ref());
+        let mut result = None;
+        for entry in &self.entries {
+            if contains_better(result, entry, &path) {
+                result = Some(entry);
+            }
+        }
+        result
+    }
+
+    /// Returns the mount with the given `mount_id`
+    pub fn by_id(&self, mount_id: c_ulong) -> Option<&MountPoint<'static>> {
+        self.entries.iter().find(|e| e.mount_id == mount_id)
+    }
+
+    /// Returns all mounts of the device (e.g. all bind mounts of a
+    /// filesystem)
+    pub fn by_device(&self, major: c_ulong, minor: c_ulong)
+        -> Vec<&MountPoint<'static>>
+    {
+        self.entries.iter()
+            .filter(|e| e.major == major && e.minor == minor)
+            .collect()
+    }
+
+    /// Checks whether something is mounted exactly at the `path`
+    pub fn is_mountpoint<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = canonicalize_lenient(path.as_ref());
+        self.entries.iter().any(|e| Path::new(&e.mount_point) == path)
+    }
+}
+
+/// Splits comma-separated options into `(name, value)` pairs
+///
+/// E.g. `rw,data=ordered` gives `[("rw", None), ("data", Some("ordered"))]`.
+/// Only the first `=` separates the name, so values may contain `=` too.
+pub fn parse_options(options: &OsStr) -> Vec<(&OsStr, Option<&OsStr>)>
+{
+    options.as_bytes().split(|&c| c == b',')
+        .filter(|opt| !opt.is_empty())
+        .map(|opt| {
+            let mut pair = opt.splitn(2, |&c| c == b'=');
+            let name = OsStr::from_bytes(pair.next().unwrap());
+            (name, pair.next().map(OsStr::from_bytes))
+        })
+        .collect()
+}
+
+/// Filesystem-specific options of ext4 (from `super_options`)
+///
+/// Only the options which kernel shows in mountinfo can be here, in
+/// particular, options which have default values are usually omitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ext4Options {
+    /// Filesystem is read-only (`ro`)
+    pub readonly: bool,
+    /// Journaling mode (`data=`): `ordered`, `journal` or `writeback`
+    pub data: Option<String>,
+    /// Interval of committing data and metadata in seconds (`commit=`)
+    pub commit: Option<u32>,
+    /// Whether write barriers are enabled (`barrier=N` or `nobarrier`)
+    pub barrier: Option<bool>,
+    /// Behavior on errors (`errors=`): `continue`, `remount-ro` or `panic`
+    pub errors: Option<String>,
+    /// All other options, the value is `None` for options without a value
+    ///
+    /// This includes known options which value can't be parsed.
+    pub other: HashMap<String, Option<String>>,
+}
+
+/// Parses `super_options` of ext4 mount point
+///
+/// Filesystem type isn't checked, so it's up to the caller to check that
+/// `fstype` is `ext4`.
+pub fn ext4_options(mp: &MountPoint) -> Ext4Options {
+    let mut result = Ext4Options::default();
+    for (name, value) in parse_options(&mp.super_options) {
+        let name = name.to_string_lossy();
+        let value = value.map(|v| v.to_string_lossy().into_owned());
+        match (&name[..], value) {
+            ("ro", None) => result.readonly = true,
+            ("rw", None) => result.readonly = false,
+            ("data", Some(v)) => result.data = Some(v),
+            ("errors", Some(v)) => result.errors = Some(v),
+            ("commit", Some(ref v)) if v.parse::<u32>().is_ok() => {
+                result.commit = v.parse().ok();
+            }
+            ("barrier", None) => result.barrier = Some(true),
+            ("barrier", Some(ref v)) if v.parse::<u32>().is_ok() => {
+                result.barrier = v.parse::<u32>().ok().map(|x| x != 0);
+            }
+            ("nobarrier", None) => result.barrier = Some(false),
+            (_, value) => {
+                result.other.insert(name.into_owned(), value);
+            }
+        }
+    }
+    result
+}
+
+/// Layers of an overlay mount (see `overlay_mounts()`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OverlayInfo {
+    /// Mount point of the overlay
+    pub target: PathBuf,
+    /// Lower layers, the uppermost one first (`lowerdir=`)
+    ///
+    /// Data-only layers (after `::`) are included at the end.
+    pub lowerdirs: Vec<PathBuf>,
+    /// Writable layer (`upperdir=`), `None` for a read-only overlay
+    pub upperdir: Option<PathBuf>,
+    /// Work directory (`workdir=`), `None` for a read-only overlay
+    pub workdir: Option<PathBuf>,
+}
+
+impl OverlayInfo {
+    /// Parses layers from `super_options` as they are in mountinfo
+    ///
+    /// Options are split by commas before unescaping, as commas in paths
+    /// are escaped as `\054` by the kernel.
+    fn from_raw_options(target: PathBuf, options: &[u8]) -> OverlayInfo {
+        let mut info = OverlayInfo {
+            target: target,
+            .. OverlayInfo::default()
+        };
+        for opt in options.split(|&c| c == b',') {
+            let mut pair = opt.splitn(2, |&c| c == b'=');
+            let name = pair.next().unwrap();
+            let value = match pair.next() {
+                Some(value) => value,
+                None => continue,
+            };
+            let path = || PathBuf::from(
+                unescape_octals(OsStr::from_bytes(value)).into_owned());
+            match name {
+                b"lowerdir" => info.lowerdirs = split_lowerdir(value),
+                b"upperdir" => info.upperdir = Some(path()),
+                b"workdir" => info.workdir = Some(path()),
+                _ => {}
+            }
+        }
+        info
+    }
+}
+
+/// Splits escaped `lowerdir` value by colons and unescapes the paths
+///
+/// A colon in a path is either escaped as `\072`, or as `\:` (as passed
+/// to `mount()`) which older kernels show as `\134:`.
+fn split_lowerdir(value: &[u8]) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    let mut cur = Vec::new();
+    let mut i = 0;
+    while i < value.len() {
+        let rest = &value[i..];
+        if rest.starts_with(b"\\134:") {
+            cur.push(b':');
+            i += 5;
+        } else if rest.starts_with(b"\\:") {
+            cur.push(b':');
+            i += 2;
+        } else if is_octal_encoding(rest) {
+            cur.push(parse_octal(&rest[1..]));
+            i += 4;
+        } else if rest[0] == b':' {
+            // an empty item between `::` separates data-only layers
+            if !cur.is_empty() {
+                result.push(PathBuf::from(OsString::from_vec(cur)));
+                cur = Vec::new();
+            }
+            i += 1;
+        } else {
+            cur.push(rest[0]);
+            i += 1;
+        }
+    }
+    if !cur.is_empty() {
+        result.push(PathBuf::from(OsString::from_vec(cur)));
+    }
+    result
+}
+
+/// Returns all overlay mounts of the current mount namespace with their
+/// layers
+pub fn overlay_mounts() -> io::Result<Vec<OverlayInfo>> {
+    overlay_mounts_from(&read_self_mountinfo()?)
+}
+
+fn overlay_mounts_from(content: &[u8]) -> io::Result<Vec<OverlayInfo>> {
+    let mut parser = Parser::new(content);
+    let mut result = Vec::new();
+    while let Some(info) = parser.next_with(parse_overlay_row) {
+        result.push(info.map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?);
+    }
+    Ok(result)
+}
+
+fn parse_overlay_row(row: &[u8])
+    -> Result<Option<OverlayInfo>, ParseRowError>
+{
+    match parse_mount_point_raw(row)? {
+        Some((ref entry, raw_options))
+        if entry.fstype == OsStr::new("overlay") => {
+            Ok(Some(OverlayInfo::from_raw_options(
+                PathBuf::from(&entry.mount_point), raw_options)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns type of the filesystem which `path` is located on
+///
+/// Note: this is the type of the topmost mount, so for overlay it's
+/// `overlay` rather than the type of the filesystem where layers live, and
+/// for a bind mount it's the type of the filesystem it was bound from.
+pub fn fstype_at<P: AsRef<Path>>(path: P) -> io::Result<Option<OsString>> {
+    find_mount_point(path).map(|x| x.map(|m| m.fstype.into_owned()))
+}
+
+/// Filesystem types and their `statfs` magic numbers
+///
+/// Some filesystems share the magic (e.g. all of `ext2`, `ext3` and `ext4`
+/// or `tmpfs` and `devtmpfs`), the first name is the canonical one.
+const FS_MAGICS: &[(&str, u64)] = &[
+    ("tmpfs", 0x0102_1994),
+    ("devtmpfs", 0x0102_1994),
+    ("ramfs", 0x8584_58f6),
+    ("overlay", 0x794c_7630),
+    ("proc", 0x9fa0),
+    ("sysfs", 0x6265_6572),
+    ("devpts", 0x1cd1),
+    ("mqueue", 0x1980_0202),
+    ("cgroup", 0x0027_e0eb),
+    ("cgroup2", 0x6367_7270),
+    ("securityfs", 0x7363_6673),
+    ("debugfs", 0x6462_6720),
+    ("tracefs", 0x7472_6163),
+    ("bpf", 0xcafe_4a11),
+    ("hugetlbfs", 0x9584_58f6),
+    ("binfmt_misc", 0x4249_4e4d),
+    ("autofs", 0x0187),
+    ("nsfs", 0x6e73_6673),
+    ("ext4", 0xef53),
+    ("ext3", 0xef53),
+    ("ext2", 0xef53),
+    ("xfs", 0x5846_5342),
+    ("btrfs", 0x9123_683e),
+    ("f2fs", 0xf2f5_2010),
+    ("squashfs", 0x7371_7368),
+    ("iso9660", 0x9660),
+    ("vfat", 0x4d44),
+    ("fuse", 0x6573_5546),
+    ("nfs", 0x6969),
+    ("nfs4", 0x6969),
+    ("cifs", 0xff53_4d42),
+    ("smb3", 0xfe53_4d42),
+];
+
+/// Returns the canonical filesystem type name for the `statfs` magic
+pub fn fstype_by_magic(magic: u64) -> Option<&'static str> {
+    FS_MAGICS.iter().find(|&&(_, m)| m == magic).map(|&(name, _)| name)
+}
+
+/// Checks that filesystem at `path` is of the `expected` type
+///
+/// This compares the `statfs` magic number, i.e. checks what the kernel
+/// actually uses rather than what is written in mountinfo. Filesystems
+/// sharing the magic can't be distinguished, e.g. `ext3` is confirmed for
+/// the `ext4` filesystem. Fails with `InvalidInput` if `expected` isn't
+/// in the table of known filesystems.
+pub fn confirm_fstype<P: AsRef<Path>>(path: P, expected: &str)
+    -> io::Result<bool>
+{
+    let magic = match FS_MAGICS.iter().find(|&&(name, _)| name == expected) {
+        Some(&(_, magic)) => magic,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("unknown filesystem type {:?}", expected))),
+    };
+    let mut stat = unsafe { std::mem::zeroed() };
+    statfs(path.as_ref(), &mut stat).map_err(nix_to_io)?;
+    // magic numbers are 32 bit, while f_type is signed on some platforms
+    Ok(stat.f_type as u64 & 0xffff_ffff == magic)
+}
+
+/// Returns the id of the mount which `path` is located on
+///
+/// This is the same as `mount_id` field of mountinfo. Uses `statx` on
+/// linux 5.8+ and falls back to `find_mount_point` on older kernels (note
+/// that fallback doesn't detect mounts which are overmounted or out of the
+/// process' root).
+pub fn mount_id_of<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let path = path.as_ref();
+    let cpath = path_to_cstring(path);
+    mount_id_with(|| statx_mount_id(AT_FDCWD, &cpath, 0), || {
+        match find_mount_point(path)? {
+            Some(mount_point) => Ok(mount_point.mount_id),
+            None => Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("No mount point found for {:?}", path))),
+        }
+    })
+}
+
+/// Returns the id of the mount which file descriptor refers to
+///
+/// For fd returned by `fsmount` (like `DetachedMount`) this is the id of
+/// the new mount itself. Uses `statx` on linux 5.8+ and falls back to
+/// `mnt_id` in `/proc/self/fdinfo` on older kernels.
+pub fn mount_id_of_fd<F: AsRawFd>(fd: &F) -> io::Result<u64> {
+    let fd = fd.as_raw_fd();
+    let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+    mount_id_with(|| statx_mount_id(fd, empty, AT_EMPTY_PATH), || {
+        let mut content = Vec::with_capacity(256);
+        File::open(format!("/proc/self/fdinfo/{}", fd))?
+            .read_to_end(&mut content)?;
+        match parse_mount_id(&content) {
+            Some(id) => Ok(id),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("No mnt_id for file descriptor {}", fd))),
+        }
+    })
+}
+
+/// Gets mount id by `statx`, or by `fallback` if it isn't supported
+///
+/// On kernels known to be older than 5.8 `fallback` is tried first, and
+/// `statx` only if it fails (the feature may be backported).
+fn mount_id_with<S, F>(statx: S, fallback: F) -> io::Result<u64>
+    where S: Fn() -> nix::Result<Option<u64>>,
+          F: Fn() -> io::Result<u64>,
+{
+    if !supports_statx_mount_id() {
+        return fallback().or_else(|e| match statx() {
+            Ok(Some(id)) => Ok(id),
+            _ => Err(e),
+        });
+    }
+    match statx() {
+        Ok(Some(id)) => Ok(id),
+        Ok(None) | Err(nix::Error::Sys(Errno::ENOSYS)) => fallback(),
+        Err(e) => Err(nix_to_io(e)),
+    }
+}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+    err.as_errno().map_or_else(
+        || io::Error::other(err), io::Error::from)
+}
+
+/// Finds `mnt_id` field in the contents of `/proc/<pid>/fdinfo/<fd>`
+pub(crate) fn parse_mount_id(fdinfo: &[u8]) -> Option<c_ulong> {
+    fdinfo.split(|c| *c == b'\n')
+        .filter(|line| line.starts_with(b"mnt_id:"))
+        .filter_map(|line| {
+            let value = &line[b"mnt_id:".len()..];
+            let start = value.iter().position(|c| !c.is_ascii_whitespace())?;
+            parse_int(&value[start..]).ok().map(|(id, _)| id)
+        })
+        .next()
 }
 
 impl<'a> Iterator for Parser<'a> {
     type Item = Result<MountPoint<'a>, ParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(parse_mount_point)
+    }
+}
+
+impl<'a> Parser<'a> {
+    /// Parses the next row with `parse`, rows it returns `None` for are
+    /// skipped
+    fn next_with<T, F>(&mut self, parse: F)
+        -> Option<Result<T, ParseError>>
+        where F: Fn(&'a [u8]) -> Result<Option<T>, ParseRowError>,
+    {
         if self.exhausted {
             return None;
         }
@@ -141,7 +1054,7 @@ impl<'a> Iterator for Parser<'a> {
                     self.row_num += 1;
                     let row = &self.data[..ix];
                     self.data = &self.data[ix + 1..];
-                    let res = match parse_mount_point(row) {
+                    let res = match parse(row) {
                         Ok(None) => continue,
                         Ok(Some(v)) => Ok(v),
                         Err(e) => Err(ParseError::new(e.0, self.row_num,
@@ -151,7 +1064,7 @@ impl<'a> Iterator for Parser<'a> {
                 },
                 None => {
                     self.exhausted = true;
-                    let res = match parse_mount_point(self.data) {
+                    let res = match parse(self.data) {
                         Ok(None) => return None,
                         Ok(Some(v)) => Ok(v),
                         Err(e) => Err(ParseError::new(e.0, self.row_num,
@@ -166,6 +1079,14 @@ impl<'a> Iterator for Parser<'a> {
 
 pub(crate) fn parse_mount_point<'a>(row: &'a [u8])
      -> Result<Option<MountPoint<'a>>, ParseRowError>
+{
+    Ok(parse_mount_point_raw(row)?.map(|(mount_point, _)| mount_point))
+}
+
+/// Same as `parse_mount_point` but also returns `super_options` as they
+/// are in the file (i.e. escaped)
+fn parse_mount_point_raw<'a>(row: &'a [u8])
+     -> Result<Option<(MountPoint<'a>, &'a [u8])>, ParseRowError>
 {
     let row = rstrip_cr(&row);
     if is_comment_line(row) {
@@ -181,9 +1102,10 @@ pub(crate) fn parse_mount_point<'a>(row: &'a [u8])
     let (optional_fields, row) = try!(parse_optional(row));
     let (fstype, row) = try!(parse_os_str(row));
     let (mount_source, row) = try!(parse_os_str(row));
-    let (super_options, _) = try!(parse_os_str(row));
-    // TODO: should we ignore extra fields?
-    Ok(Some(MountPoint {
+    // the last field, so it takes the rest of the line including spaces
+    let raw_super_options = parse_field_tail(row)?;
+    let super_options = unescape_octals(OsStr::from_bytes(raw_super_options));
+    Ok(Some((MountPoint {
         mount_id: mount_id,
         parent_id: parent_id,
         major: major,
@@ -195,10 +1117,10 @@ pub(crate) fn parse_mount_point<'a>(row: &'a [u8])
         fstype: fstype,
         mount_source: mount_source,
         super_options: super_options,
-    }))
+    }, raw_super_options)))
 }
 
-fn is_comment_line(row: &[u8]) -> bool {
+pub(crate) fn is_comment_line(row: &[u8]) -> bool {
     if row.is_empty() {
         return true;
     }
@@ -214,7 +1136,7 @@ fn is_comment_line(row: &[u8]) -> bool {
     return false;
 }
 
-fn rstrip_cr(row: &[u8]) -> &[u8] {
+pub(crate) fn rstrip_cr(row: &[u8]) -> &[u8] {
     if let Some((&b'\r', tail)) = row.split_last() {
         tail
     } else {
@@ -232,14 +1154,22 @@ fn parse_field<'a>(data: &'a [u8], delimit: &'a [u8])
     Ok(split_by(data, delimit))
 }
 
-fn parse_os_str<'a>(data: &'a [u8])
+pub(crate) fn parse_os_str<'a>(data: &'a [u8])
     -> Result<(Cow<'a, OsStr>, &'a [u8]), ParseRowError>
 {
     let (field, tail) = try!(parse_field(data, b" "));
     Ok((unescape_octals(OsStr::from_bytes(field)), tail))
 }
 
-fn parse_int(data: &[u8])
+fn parse_field_tail(data: &[u8]) -> Result<&[u8], ParseRowError>
+{
+    if data.is_empty() {
+        return Err(ParseRowError("Expected more fields".to_string()));
+    }
+    Ok(rstrip_whitespaces(lstrip_whitespaces(data)))
+}
+
+pub(crate) fn parse_int(data: &[u8])
     -> Result<(c_ulong, &[u8]), ParseRowError>
 {
     let (field, tail) = try!(parse_field(data, b" "));
@@ -336,6 +1266,29 @@ fn unescape_octals(s: &OsStr) -> Cow<OsStr> {
     Cow::Owned(OsString::from_vec(v))
 }
 
+/// Escape the string the same way kernel does in mountinfo
+///
+/// Space, tab, newline and backslash are replaced by the octal escapes
+/// (e.g. `\040` for space). This is the reverse of what the parser does, so
+/// it's useful for generating mountinfo-like data.
+pub fn escape_octal<'a>(s: &'a OsStr) -> Cow<'a, OsStr> {
+    let needs_escape = |c: u8| {
+        c == b' ' || c == b'\t' || c == b'\n' || c == b'\\'
+    };
+    if !s.as_bytes().iter().any(|&c| needs_escape(c)) {
+        return Cow::Borrowed(s);
+    }
+    let mut v: Vec<u8> = Vec::with_capacity(s.len() + 8);
+    for &c in s.as_bytes() {
+        if needs_escape(c) {
+            v.extend(format!("\\{:03o}", c).as_bytes());
+        } else {
+            v.push(c);
+        }
+    }
+    Cow::Owned(OsString::from_vec(v))
+}
+
 fn is_octal_encoding(v: &[u8]) -> bool {
     v.len() >= 4 && v[0] == b'\\'
         && is_oct(v[1]) && is_oct(v[2]) && is_oct(v[3])
@@ -349,16 +1302,96 @@ fn parse_octal(v: &[u8]) -> u8 {
     ((v[0] & 7) << 6) + ((v[1] & 7) << 3) + (v[2] & 7)
 }
 
+/// Helpers to generate mountinfo content in tests
 #[cfg(test)]
-mod test {
-    use std::path::Path;
+pub mod fixtures {
     use std::ffi::OsStr;
     use std::os::unix::ffi::OsStrExt;
 
+    use super::escape_octal;
+
+    /// Builds mountinfo content programmatically
+    ///
+    /// Fields are escaped the same way kernel does, so paths with spaces
+    /// and other special characters can be passed as is. Empty `optional`
+    /// means no optional fields.
+    #[derive(Debug, Default)]
+    pub struct MountInfoBuilder {
+        content: Vec<u8>,
+    }
+
+    impl MountInfoBuilder {
+        pub fn new() -> MountInfoBuilder {
+            MountInfoBuilder::default()
+        }
+
+        /// Add a line (fields are in the order of mountinfo)
+        #[allow(clippy::too_many_arguments)]
+        pub fn add<R, M, S>(mut self, mount_id: u64, parent_id: u64,
+            major: u64, minor: u64, root: R, mount_point: M, options: &str,
+            optional: &str, fstype: &str, source: S, super_options: &str)
+            -> MountInfoBuilder
+            where R: AsRef<OsStr>, M: AsRef<OsStr>, S: AsRef<OsStr>,
+        {
+            let line = &mut self.content;
+            line.extend(format!("{} {} {}:{} ",
+                mount_id, parent_id, major, minor).as_bytes());
+            line.extend(escape_octal(root.as_ref()).as_bytes());
+            line.push(b' ');
+            line.extend(escape_octal(mount_point.as_ref()).as_bytes());
+            line.push(b' ');
+            line.extend(escape_octal(OsStr::new(options)).as_bytes());
+            if !optional.is_empty() {
+                line.push(b' ');
+                line.extend(optional.as_bytes());
+            }
+            line.extend(b" - ");
+            line.extend(escape_octal(OsStr::new(fstype)).as_bytes());
+            line.push(b' ');
+            line.extend(escape_octal(source.as_ref()).as_bytes());
+            line.push(b' ');
+            line.extend(escape_octal(OsStr::new(super_options)).as_bytes());
+            line.push(b'\n');
+            self
+        }
+
+        /// Returns the content as it would be read from the file
+        pub fn content(&self) -> &[u8] {
+            &self.content
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::ffi::{OsStr, OsString};
+    use std::fs::File;
+    use std::os::unix::ffi::OsStrExt;
+
     use nix::mount::MsFlags;
+    use nix::unistd::getuid;
+
+    use {Tmpfs, Unmount};
+    use util::test::tmp_dir;
 
-    use super::{Parser, ParseError};
+    use super::fixtures::MountInfoBuilder;
+
+    use super::{Parser, ParseError, MountPoint, MountTable, peer_groups};
+    use super::{mounts_under_from, find_mount_point_from, parse_mount_id};
+    use super::{find_mount_point, mounts_under, mount_id_of, mount_id_of_fd};
     use super::{is_octal_encoding, parse_octal, unescape_octals};
+    use super::{root_mount, root_mount_from, escape_octal};
+    use super::{fstype_by_magic, confirm_fstype, binds_of_from};
+    use super::{parse_options, ext4_options, Ext4Options};
+    use super::{count, count_for, count_from};
+    use super::{mount_max, mount_max_from, remaining_mounts};
+    use super::{inherit_flags_from, inherit_flags_from_parent};
+    use super::{would_propagate_from, would_propagate_to_peers};
+    use super::{same_mount, same_mount_from};
+    use super::{OverlayInfo, overlay_mounts, overlay_mounts_from};
+    use super::split_lowerdir;
 
     #[test]
     fn test_is_octal_encoding() {
@@ -388,6 +1421,143 @@ mod test {
         assert_eq!(unescape_octals(OsStr::new("test\\040data")), OsStr::new("test data"));
     }
 
+    #[test]
+    fn test_fstype_by_magic() {
+        assert_eq!(fstype_by_magic(0x01021994), Some("tmpfs"));
+        assert_eq!(fstype_by_magic(0x794c7630), Some("overlay"));
+        assert_eq!(fstype_by_magic(0x9fa0), Some("proc"));
+        assert_eq!(fstype_by_magic(0xef53), Some("ext4"));
+        assert_eq!(fstype_by_magic(0x1234), None);
+    }
+
+    #[test]
+    fn test_confirm_fstype() {
+        assert!(confirm_fstype("/proc", "proc").unwrap());
+        assert!(!confirm_fstype("/proc", "tmpfs").unwrap());
+        assert_eq!(confirm_fstype("/proc", "nonexistentfs").unwrap_err()
+                   .kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(confirm_fstype("/nonexistent", "proc").unwrap_err()
+                   .kind(), io::ErrorKind::NotFound);
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("confirm_fstype");
+        Tmpfs::new(&dir).mount().unwrap();
+        assert!(confirm_fstype(&dir, "tmpfs").unwrap());
+        assert!(confirm_fstype(&dir, "devtmpfs").unwrap());
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_fixtures_builder() {
+        let info = MountInfoBuilder::new()
+            .add(19, 1, 8, 1, "/", "/", "rw,relatime", "shared:1",
+                 "ext4", "/dev/sda1", "rw")
+            .add(20, 19, 0, 5, "/", "/mnt/my disk", "rw,nosuid", "",
+                 "tmpfs", "my\\tmpfs", "rw,size=1024k")
+            .add(21, 19, 8, 1, "/var/data", "/srv", "ro", "master:1",
+                 "ext4", "/dev/sda1", "rw");
+        assert!(info.content().starts_with(
+            b"19 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+              20 19 0:5 / /mnt/my\\040disk rw,nosuid - \
+              tmpfs my\\134tmpfs rw,size=1024k\n"));
+        let entries = Parser::new(info.content())
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].peer_group(), Some(1));
+        assert_eq!(entries[1].mount_point, OsStr::new("/mnt/my disk"));
+        assert_eq!(entries[1].mount_source, OsStr::new("my\\tmpfs"));
+        assert_eq!(entries[1].optional_fields, OsStr::new(""));
+        assert_eq!(entries[1].get_mount_flags(), MsFlags::MS_NOSUID);
+        assert_eq!(entries[2].root, OsStr::new("/var/data"));
+        assert_eq!(entries[2].master_group(), Some(1));
+        let found = find_mount_point_from(info.content(),
+                                          Path::new("/mnt/my disk/x"))
+            .unwrap().unwrap();
+        assert_eq!(found.mount_id, 20);
+    }
+
+    #[test]
+    fn test_reconstruct_command() {
+        let content = b"22 19 0:20 / /dev/shm rw,nosuid,nodev,relatime - \
+                            tmpfs tmpfs rw,size=65536k,mode=755\n\
+                        23 1 8:1 / / ro,noatime - ext4 /dev/sda1 \
+                            rw,errors=remount-ro\n\
+                        24 19 0:21 / /mnt/my\\040disk rw - \
+                            vfat /dev/sdb1 rw,fmask=0022";
+        let entries = Parser::new(&content[..])
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries[0].reconstruct_command(),
+                   "mount -t tmpfs -o rw,nosuid,nodev,relatime,\
+                    size=65536k,mode=755 tmpfs /dev/shm");
+        assert_eq!(entries[1].reconstruct_command(),
+                   "mount -t ext4 -o ro,noatime,errors=remount-ro \
+                    /dev/sda1 /");
+        assert_eq!(entries[2].reconstruct_command(),
+                   "mount -t vfat -o rw,fmask=0022 /dev/sdb1 '/mnt/my disk'");
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let content = b"19 1 8:1 / /a rw - ext4 /dev/sda1 rw\n\
+                        20 19 8:1 /x/y /a/b/c rw - ext4 /dev/sda1 rw\n\
+                        21 1 0:6 / /ab rw - tmpfs tmpfs rw\n\
+                        22 19 0:7 / /a rw - tmpfs tmpfs rw";
+        let entries = Parser::new(&content[..])
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries[1].relative_to(&entries[0]),
+                   Some(Path::new("b/c").to_path_buf()));
+        assert_eq!(entries[0].relative_to(&entries[1]), None);
+        assert_eq!(entries[2].relative_to(&entries[0]), None);
+        assert_eq!(entries[3].relative_to(&entries[0]),
+                   Some(Path::new("").to_path_buf()));
+    }
+
+    #[test]
+    fn test_escape_octal() {
+        assert_eq!(escape_octal(OsStr::new("/mnt")), OsStr::new("/mnt"));
+        assert_eq!(escape_octal(OsStr::new("a b\tc\nd\\e")),
+                   OsStr::new("a\\040b\\011c\\012d\\134e"));
+        assert_eq!(escape_octal(OsStr::new("\\040")),
+                   OsStr::new("\\134040"));
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        // xorshift, so that failures are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let special = b" \t\n\\01234567-#\r";
+        for _ in 0..1000 {
+            let len = 1 + random() % 32;
+            let path = (0..len).map(|_| {
+                let r = random();
+                if r % 2 == 0 {
+                    special[(r >> 8) as usize % special.len()]
+                } else {
+                    (r >> 8) as u8
+                }
+            }).collect::<Vec<u8>>();
+            let path = OsStr::from_bytes(&path);
+            let row = [
+                &b"36 35 98:0 "[..],
+                escape_octal(path).as_bytes(),
+                b" ",
+                escape_octal(path).as_bytes(),
+                b" rw - ext3 /dev/root rw",
+            ].concat();
+            let entry = Parser::new(&row).next().unwrap().unwrap();
+            assert_eq!(entry.root, path, "row {:?}", OsStr::from_bytes(&row));
+            assert_eq!(entry.mount_point, path);
+            assert_eq!(entry.fstype, OsStr::new("ext3"));
+        }
+    }
+
     #[test]
     fn test_mount_info_parser_proc() {
         let content = b"19 24 0:4 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw";
@@ -490,6 +1660,19 @@ mod test {
         assert!(parser.next().is_none());
     }
 
+    #[test]
+    fn test_mount_info_parser_super_options_spaces() {
+        let content = b"40 24 0:35 / /mnt rw - fuse.sshfs host:/ rw,x=a b  \n\
+                        41 24 0:36 / /tmp rw - tmpfs tmpfs rw";
+        let mut parser = Parser::new(&content[..]);
+        let mount_point = parser.next().unwrap().unwrap();
+        assert_eq!(mount_point.mount_source, OsStr::new("host:/"));
+        assert_eq!(mount_point.super_options, OsStr::new("rw,x=a b"));
+        let mount_point = parser.next().unwrap().unwrap();
+        assert_eq!(mount_point.super_options, OsStr::new("rw"));
+        assert!(parser.next().is_none());
+    }
+
     #[test]
     fn test_mount_info_parser_non_utf8() {
         let content = b"22 24 0:19 / /\xff rw shared:5 - tmpfs tmpfs rw,mode=755";
@@ -585,4 +1768,381 @@ mod test {
         assert_eq!(mount_point.mount_point, Path::new(OsStr::from_bytes(b"/proc\x00")));
         assert!(parser.next().is_none());
     }
+
+    #[test]
+    fn test_locked_flags() {
+        let content = b"19 24 0:4 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw\n\
+                        20 24 0:5 / /usr ro shared:13 - ext4 /dev/sda2 ro\n\
+                        21 24 0:6 / /tmp rw shared:14 - tmpfs tmpfs rw";
+        let mut parser = Parser::new(&content[..]);
+        let proc_mnt = parser.next().unwrap().unwrap();
+        let usr_mnt = parser.next().unwrap().unwrap();
+        let tmp_mnt = parser.next().unwrap().unwrap();
+
+        assert_eq!(proc_mnt.locked_flags_in(true), MsFlags::empty());
+        assert_eq!(usr_mnt.locked_flags_in(true), MsFlags::empty());
+
+        assert_eq!(proc_mnt.locked_flags_in(false),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC |
+            MsFlags::MS_NOATIME | MsFlags::MS_NODIRATIME |
+            MsFlags::MS_RELATIME | MsFlags::MS_STRICTATIME);
+        assert_eq!(usr_mnt.locked_flags_in(false), MsFlags::MS_RDONLY |
+            MsFlags::MS_NOATIME | MsFlags::MS_NODIRATIME |
+            MsFlags::MS_RELATIME | MsFlags::MS_STRICTATIME);
+        assert!(!tmp_mnt.locked_flags_in(false).contains(MsFlags::MS_NOSUID));
+        assert!(!tmp_mnt.locked_flags_in(false).contains(MsFlags::MS_RDONLY));
+    }
+
+    #[test]
+    fn test_peer_groups() {
+        let content = b"19 24 0:4 / /proc rw shared:5 - proc proc rw\n\
+                        20 24 0:4 / /mnt/proc rw shared:5 - proc proc rw\n\
+                        21 24 0:4 / /slave/proc rw master:5 - proc proc rw\n\
+                        22 24 0:7 / /both rw shared:7 master:5 - tmpfs tmpfs rw\n\
+                        23 24 0:8 / /private rw - tmpfs tmpfs rw";
+        let entries = Parser::new(&content[..])
+            .collect::<Result<Vec<MountPoint>, _>>().unwrap();
+        assert_eq!(entries[0].peer_group(), Some(5));
+        assert_eq!(entries[0].master_group(), None);
+        assert_eq!(entries[2].peer_group(), None);
+        assert_eq!(entries[2].master_group(), Some(5));
+        assert_eq!(entries[4].peer_group(), None);
+
+        let groups = peer_groups(&entries);
+        assert_eq!(groups.groups.len(), 2);
+        assert_eq!(groups.groups[&5], vec![19, 20]);
+        assert_eq!(groups.groups[&7], vec![22]);
+        assert_eq!(groups.slaves, vec![(21, 5), (22, 5)]);
+    }
+
+    #[test]
+    fn test_count() {
+        let content = b"\
+            # comment\n\
+            19 24 0:4 / /proc rw,relatime shared:13 - proc proc rw\n\
+            \n\
+            20 24 0:18 / /sys rw,relatime shared:2 - sysfs sysfs rw\r\n\
+              # indented comment\n\
+            \t \n\
+            21 24 0:19 / /dev rw,relatime shared:3 - devtmpfs udev rw";
+        assert_eq!(count_from(content), 3);
+        assert_eq!(count_from(b""), 0);
+        assert_eq!(count_from(b"\n\n"), 0);
+
+        // other tests may mount concurrently, so exact numbers can differ
+        assert!(count().unwrap() > 0);
+        assert!(count_for(::std::process::id()).unwrap() > 0);
+        assert!(count_for(0).is_err());
+    }
+
+    #[test]
+    fn test_mount_max() {
+        assert_eq!(mount_max_from("100000\n").unwrap(), 100000);
+        assert_eq!(mount_max_from(" 42 ").unwrap(), 42);
+        let err = mount_max_from("lots\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid fs.mount-max value \"lots\"");
+        assert!(mount_max_from("").is_err());
+        assert!(mount_max_from("-1").is_err());
+
+        // the sysctl is missing before linux 4.9 and in some sandboxes
+        let max = match mount_max() {
+            Ok(max) => max,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(max > 0);
+        assert!(remaining_mounts().unwrap() < max);
+    }
+
+    #[test]
+    fn test_mounts_under() {
+        let content = b"19 1 0:4 / / rw - ext4 /dev/sda1 rw\n\
+                        20 19 0:5 / /mnt rw - tmpfs tmpfs rw\n\
+                        21 20 0:6 / /mnt/a rw - tmpfs tmpfs rw\n\
+                        22 21 0:7 / /mnt/a/b rw - tmpfs tmpfs rw\n\
+                        23 19 0:8 / /mntx rw - tmpfs tmpfs rw";
+        let mounts = mounts_under_from(&content[..], Path::new("/mnt"))
+            .unwrap();
+        let paths = mounts.iter().map(|m| Path::new(&m.mount_point))
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec![
+            Path::new("/mnt"), Path::new("/mnt/a"), Path::new("/mnt/a/b")]);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse_options(OsStr::new("rw,data=ordered,,x=a=b")), vec![
+            (OsStr::new("rw"), None),
+            (OsStr::new("data"), Some(OsStr::new("ordered"))),
+            (OsStr::new("x"), Some(OsStr::new("a=b"))),
+        ]);
+        assert_eq!(parse_options(OsStr::new("")), vec![]);
+    }
+
+    #[test]
+    fn test_ext4_options() {
+        let content = b"76 24 8:6 / /home rw,relatime - ext4 /dev/sda1 \
+            rw,errors=remount-ro,data=ordered,commit=30,barrier=1,\
+            stripe=4,noauto_da_alloc";
+        let mp = Parser::new(&content[..]).next().unwrap().unwrap();
+        let opts = ext4_options(&mp);
+        assert_eq!(opts, Ext4Options {
+            readonly: false,
+            data: Some("ordered".to_string()),
+            commit: Some(30),
+            barrier: Some(true),
+            errors: Some("remount-ro".to_string()),
+            other: vec![
+                ("stripe".to_string(), Some("4".to_string())),
+                ("noauto_da_alloc".to_string(), None),
+            ].into_iter().collect(),
+        });
+
+        let content = b"77 24 8:7 / /data ro - ext4 /dev/sda2 \
+            ro,nobarrier,commit=x";
+        let mp = Parser::new(&content[..]).next().unwrap().unwrap();
+        let opts = ext4_options(&mp);
+        assert!(opts.readonly);
+        assert_eq!(opts.barrier, Some(false));
+        assert_eq!(opts.commit, None);
+        assert_eq!(opts.other.get("commit"), Some(&Some("x".to_string())));
+    }
+
+    #[test]
+    fn test_binds_of() {
+        let content = b"19 1 8:1 / / rw - ext4 /dev/sda1 rw\n\
+                        20 19 8:1 /srv/data /mnt/data rw - ext4 /dev/sda1 rw\n\
+                        21 19 8:1 /srv/data/sub /mnt/sub rw - ext4 /dev/sda1 rw\n\
+                        22 19 8:1 /srv/database /mnt/db rw - ext4 /dev/sda1 rw\n\
+                        23 19 8:2 /srv/data /mnt/other rw - ext4 /dev/sda2 rw";
+        let paths = |source: &str| {
+            binds_of_from(&content[..], Path::new(source)).unwrap()
+                .into_iter().map(|m| m.mount_point.into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths("/srv/data"), vec![
+            OsString::from("/mnt/data"), OsString::from("/mnt/sub")]);
+        // the same subtree found via the bind mount
+        assert_eq!(paths("/mnt/data/sub"), vec![OsString::from("/mnt/sub")]);
+        assert_eq!(paths("/srv/data/sub/x"), Vec::<OsString>::new());
+    }
+
+    #[test]
+    fn test_parse_mount_id() {
+        let fdinfo = b"pos:\t0\nflags:\t02200000\nmnt_id:\t29\nino:\t2\n";
+        assert_eq!(parse_mount_id(fdinfo), Some(29));
+        assert_eq!(parse_mount_id(b"pos:\t0\nflags:\t02200000\n"), None);
+    }
+    #[test]
+    fn test_mount_id_of() {
+        let root = find_mount_point("/").unwrap().unwrap();
+        assert_eq!(mount_id_of("/").unwrap(), root.mount_id);
+        let file = File::open("/").unwrap();
+        assert_eq!(mount_id_of_fd(&file).unwrap(), root.mount_id);
+    }
+
+    #[test]
+    fn test_mount_id_of_tmpfs() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("mount_id_of");
+        Tmpfs::new(&dir).mount().unwrap();
+        let mounts = mounts_under(&dir).unwrap();
+        assert_eq!(mount_id_of(&dir).unwrap(), mounts[0].mount_id);
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_mount_table() {
+        let content = b"19 1 8:1 / / rw - ext4 /dev/sda1 rw\n\
+                        20 19 0:5 / /proc rw - proc proc rw\n\
+                        21 19 0:6 / /nonexistent rw - tmpfs tmpfs rw\n\
+                        22 21 8:1 /data /nonexistent/data rw - ext4 /dev/sda1 rw";
+        let table = MountTable::parse(&content[..]).unwrap();
+        assert_eq!(table.entries().len(), 4);
+
+        assert_eq!(table.find("/proc/self").unwrap().mount_id, 20);
+        assert_eq!(table.find("/nonexistent/x").unwrap().mount_id, 21);
+        assert_eq!(table.find("/nonexistent/data/x").unwrap().mount_id, 22);
+        assert_eq!(table.find("/usr").unwrap().mount_id, 19);
+
+        assert_eq!(table.by_id(21).unwrap().fstype, OsStr::new("tmpfs"));
+        assert!(table.by_id(100).is_none());
+
+        let ids = table.by_device(8, 1).iter().map(|e| e.mount_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![19, 22]);
+        assert!(table.by_device(9, 1).is_empty());
+
+        assert!(table.is_mountpoint("/proc"));
+        assert!(table.is_mountpoint("/nonexistent/data"));
+        assert!(!table.is_mountpoint("/nonexistent/data/x"));
+
+        assert!(MountTable::parse(b"19 1 8:1 / /").is_err());
+    }
+
+    #[test]
+    fn test_mount_table_refresh() {
+        let mut table = MountTable::parse(b"").unwrap();
+        assert!(table.find("/").is_none());
+        table.refresh().unwrap();
+        assert!(table.is_mountpoint("/"));
+    }
+
+    #[test]
+    fn test_find_mount_point() {
+        let content = b"19 1 0:4 / / rw - ext4 /dev/sda1 rw\n\
+                        20 19 0:5 / /proc rw - proc proc rw\n\
+                        21 19 0:6 / /mnt rw - tmpfs tmpfs rw\n\
+                        22 21 0:7 / /mnt rw - overlay overlay rw";
+        let fstype = |path: &str| {
+            find_mount_point_from(&content[..], Path::new(path)).unwrap()
+                .map(|m| m.fstype.into_owned())
+        };
+        assert_eq!(fstype("/proc"), Some(OsString::from("proc")));
+        assert_eq!(fstype("/proc/self/fd"), Some(OsString::from("proc")));
+        assert_eq!(fstype("/home/user"), Some(OsString::from("ext4")));
+        assert_eq!(fstype("/procfs"), Some(OsString::from("ext4")));
+        assert_eq!(fstype("/mnt/x"), Some(OsString::from("overlay")));
+        assert_eq!(find_mount_point_from(b"", Path::new("/")).unwrap()
+                   .map(|m| m.mount_point.into_owned()), None);
+    }
+
+    #[test]
+    fn test_inherit_flags_from_parent() {
+        let content = b"19 1 0:4 / / ro,relatime - ext4 /dev/sda1 rw\n\
+                        20 19 0:5 / /home rw,nosuid,nodev - ext4 /dev/sda2 rw\n\
+                        21 19 0:6 / /tmp rw,nosuid,noexec - tmpfs tmpfs rw";
+        let flags = |path: &str| {
+            inherit_flags_from(&content[..], Path::new(path)).unwrap()
+        };
+        assert_eq!(flags("/home/user/mnt"),
+                   MsFlags::MS_NOSUID | MsFlags::MS_NODEV);
+        assert_eq!(flags("/tmp"), MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC);
+        // rdonly and atime flags aren't inherited
+        assert_eq!(flags("/mnt"), MsFlags::empty());
+        assert_eq!(inherit_flags_from(b"", Path::new("/mnt")).unwrap_err()
+                   .kind(), io::ErrorKind::NotFound);
+        inherit_flags_from_parent(Path::new("/proc/self")).unwrap();
+    }
+
+    #[test]
+    fn test_would_propagate_to_peers() {
+        let content = b"19 1 0:4 / / rw shared:1 - ext4 /dev/sda1 rw\n\
+                        20 19 0:5 / /home rw - ext4 /dev/sda2 rw\n\
+                        21 19 0:6 / /tmp rw master:1 - tmpfs tmpfs rw";
+        let check = |path: &str| {
+            would_propagate_from(&content[..], Path::new(path)).unwrap()
+        };
+        assert!(check("/mnt"));
+        assert!(check("/"));
+        assert!(!check("/home/user/mnt"));
+        // slave mounts only receive events
+        assert!(!check("/tmp/x"));
+        assert_eq!(would_propagate_from(b"", Path::new("/mnt")).unwrap_err()
+                   .kind(), io::ErrorKind::NotFound);
+        would_propagate_to_peers("/proc/self").unwrap();
+    }
+
+    #[test]
+    fn test_same_mount() {
+        let info = MountInfoBuilder::new()
+            .add(19, 1, 8, 1, "/", "/", "rw", "", "ext4", "/dev/sda1", "rw")
+            .add(20, 19, 8, 2, "/", "/data", "rw", "", "ext4", "/dev/sda2",
+                 "rw")
+            .add(21, 19, 8, 2, "/www", "/srv/www", "rw", "", "ext4",
+                 "/dev/sda2", "rw");
+        let same = |a: &str, b: &str| {
+            same_mount_from(info.content(), Path::new(a), Path::new(b))
+                .unwrap()
+        };
+        assert!(same("/data/a", "/data/b/c"));
+        assert!(same("/etc/passwd", "/srv/file"));
+        assert!(!same("/data/a", "/etc"));
+        // same device, but different mounts
+        assert!(!same("/data/www/index.html", "/srv/www/index.html"));
+        assert_eq!(same_mount_from(b"", Path::new("/a"), Path::new("/b"))
+                   .unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(same_mount("/proc/self", "/proc/1").unwrap());
+    }
+
+    #[test]
+    fn test_root_mount() {
+        let content = b"20 19 0:5 / /proc rw - proc proc rw\n\
+                        19 1 0:4 / / ro,relatime - ext4 /dev/sda1 rw\n\
+                        21 19 0:6 / /mnt rw - tmpfs tmpfs rw";
+        let root = root_mount_from(&content[..]).unwrap();
+        assert_eq!(root.mount_id, 19);
+        assert!(root.is_readonly());
+
+        let content = b"20 19 0:5 / /proc ro - proc proc rw\n\
+                        19 1 0:4 / / ro - ext4 /dev/sda1 rw\n\
+                        23 19 0:7 / / rw - overlay overlay rw";
+        let root = root_mount_from(&content[..]).unwrap();
+        assert_eq!(root.mount_id, 23);
+        assert!(!root.is_readonly());
+
+        let content = b"20 19 0:5 / /proc rw - proc proc rw";
+        assert_eq!(root_mount_from(&content[..]).unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+        root_mount().unwrap();
+    }
+
+    #[test]
+    fn test_is_readonly() {
+        let content = b"\
+            20 1 8:1 / /a rw,relatime - ext4 /dev/sda1 ro,errors=remount-ro\n\
+            21 1 8:2 / /b ro,relatime - ext4 /dev/sda2 rw\n\
+            22 1 8:3 / /c rw,relatime - ext4 /dev/sda3 rw,data=ordered\n\
+            23 1 8:4 / /d rw - ext4 /dev/sda4 errors=ro-remount";
+        let mounts = Parser::new(content).collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        // superblock is read-only, per-mount flag is not
+        assert!(mounts[0].is_readonly());
+        assert!(mounts[0].is_superblock_readonly());
+        assert!(!mounts[0].get_mount_flags().contains(MsFlags::MS_RDONLY));
+        // read-only bind of a writable filesystem
+        assert!(mounts[1].is_readonly());
+        assert!(!mounts[1].is_superblock_readonly());
+        assert!(!mounts[2].is_readonly());
+        // only a whole `ro` option counts
+        assert!(!mounts[3].is_readonly());
+    }
+
+    #[test]
+    fn test_overlay_mounts() {
+        // commas and colons in paths are escaped by overlayfs itself
+        let content = b"\
+            22 1 8:1 / / rw - ext4 /dev/sda1 rw\n\
+            40 22 0:50 / /var/lib/my\\040containers/root rw,relatime \
+            - overlay overlay rw,lowerdir=/layers/a\\040b:/layers/c\\134:d\
+            :/layers/e\\072f:/layers/foo\\054bar,\
+            upperdir=/layers/2024\\05401/upper,workdir=/layers/work,\
+            redirect_dir=on,xino=off\n\
+            41 22 0:51 / /ro ro - overlay overlay ro,lowerdir=/l1:/l2::/data";
+        let overlays = overlay_mounts_from(content).unwrap();
+        assert_eq!(overlays, vec![
+            OverlayInfo {
+                target: PathBuf::from("/var/lib/my containers/root"),
+                lowerdirs: vec![PathBuf::from("/layers/a b"),
+                                PathBuf::from("/layers/c:d"),
+                                PathBuf::from("/layers/e:f"),
+                                PathBuf::from("/layers/foo,bar")],
+                upperdir: Some(PathBuf::from("/layers/2024,01/upper")),
+                workdir: Some(PathBuf::from("/layers/work")),
+            },
+            OverlayInfo {
+                target: PathBuf::from("/ro"),
+                lowerdirs: vec![PathBuf::from("/l1"), PathBuf::from("/l2"),
+                                PathBuf::from("/data")],
+                upperdir: None,
+                workdir: None,
+            },
+        ]);
+        assert_eq!(split_lowerdir(b"/a\\\\b:/c"),
+                   vec![PathBuf::from("/a\\\\b"), PathBuf::from("/c")]);
+        overlay_mounts().unwrap();
+    }
 }
@@ -0,0 +1,146 @@
+//! This module contains parser for /proc/mounts (and /etc/mtab)
+//!
+//! It's a legacy format, which has less information than `mountinfo`. But
+//! it's still useful for old kernels and for the files in `fstab` format.
+use std::borrow::Cow;
+use std::ffi::OsStr;
+
+use libc::c_ulong;
+
+use mountinfo::{ParseError, ParseRowError};
+use mountinfo::{is_comment_line, rstrip_cr, parse_os_str, parse_int};
+
+
+/// A parser class for /proc/mounts file
+#[derive(Debug)]
+pub struct Parser<'a> {
+    data: &'a [u8],
+    row_num: usize,
+    exhausted: bool,
+}
+
+impl<'a> Parser<'a> {
+    /// Create a new parser
+    ///
+    /// `data` should contain whole contents of `/proc/mounts` file
+    pub fn new(data: &'a [u8]) -> Parser<'a> {
+        Parser {
+            data,
+            row_num: 0,
+            exhausted: false,
+        }
+    }
+}
+
+/// A single entry returned by mounts parser
+#[allow(missing_docs)]  // self-descriptive / described by man page
+#[derive(Debug)]
+pub struct MountEntry<'a> {
+    pub mount_source: Cow<'a, OsStr>,
+    pub mount_point: Cow<'a, OsStr>,
+    pub fstype: Cow<'a, OsStr>,
+    pub mount_options: Cow<'a, OsStr>,
+    pub dump: c_ulong,
+    pub pass: c_ulong,
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<MountEntry<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.exhausted {
+            let row = match self.data.iter().position(|c| *c == b'\n') {
+                Some(ix) => {
+                    let row = &self.data[..ix];
+                    self.data = &self.data[ix + 1..];
+                    row
+                }
+                None => {
+                    self.exhausted = true;
+                    self.data
+                }
+            };
+            self.row_num += 1;
+            match parse_mount_entry(row) {
+                Ok(None) => continue,
+                Ok(Some(v)) => return Some(Ok(v)),
+                Err(e) => return Some(Err(ParseError::new(e.0, self.row_num,
+                    String::from_utf8_lossy(row).into_owned()))),
+            }
+        }
+        None
+    }
+}
+
+fn parse_mount_entry<'a>(row: &'a [u8])
+     -> Result<Option<MountEntry<'a>>, ParseRowError>
+{
+    let row = rstrip_cr(row);
+    if is_comment_line(row) {
+        return Ok(None);
+    }
+    // Spaces in the fields are octal-escaped, so splitting by space and
+    // unescaping afterwards (which `parse_os_str` does) is fine
+    let (mount_source, row) = parse_os_str(row)?;
+    let (mount_point, row) = parse_os_str(row)?;
+    let (fstype, row) = parse_os_str(row)?;
+    let (mount_options, row) = parse_os_str(row)?;
+    let (dump, row) = parse_int(row)?;
+    let (pass, _) = parse_int(row)?;
+    Ok(Some(MountEntry {
+        mount_source,
+        mount_point,
+        fstype,
+        mount_options,
+        dump,
+        pass,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::ffi::OsStr;
+
+    use super::Parser;
+
+    #[test]
+    fn test_mounts_parser() {
+        let content = b"# comment\n\
+                        proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+                        /dev/sda1 / ext4 rw,relatime,data=ordered 0 1\n";
+        let mut parser = Parser::new(&content[..]);
+        let entry = parser.next().unwrap().unwrap();
+        assert_eq!(entry.mount_source, OsStr::new("proc"));
+        assert_eq!(entry.mount_point, Path::new("/proc"));
+        assert_eq!(entry.fstype, OsStr::new("proc"));
+        assert_eq!(entry.mount_options,
+                   OsStr::new("rw,nosuid,nodev,noexec,relatime"));
+        assert_eq!(entry.dump, 0);
+        assert_eq!(entry.pass, 0);
+        let entry = parser.next().unwrap().unwrap();
+        assert_eq!(entry.mount_source, OsStr::new("/dev/sda1"));
+        assert_eq!(entry.pass, 1);
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn test_mounts_parser_escaped_spaces() {
+        let content = br"/dev/my\040disk /mnt/my\040dir ext4 rw 0 0";
+        let mut parser = Parser::new(&content[..]);
+        let entry = parser.next().unwrap().unwrap();
+        assert_eq!(entry.mount_source, OsStr::new("/dev/my disk"));
+        assert_eq!(entry.mount_point, Path::new("/mnt/my dir"));
+        assert_eq!(entry.fstype, OsStr::new("ext4"));
+        assert_eq!(entry.mount_options, OsStr::new("rw"));
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn test_mounts_parser_incomplete_row() {
+        let content = b"proc /proc proc rw";
+        let mut parser = Parser::new(&content[..]);
+        assert!(parser.next().unwrap().is_err());
+        assert!(parser.next().is_none());
+    }
+}
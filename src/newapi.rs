@@ -0,0 +1,313 @@
+//! Thin wrappers around the new mount API system calls (linux 5.2+)
+//!
+//! Neither libc nor nix provide wrappers for these yet, so we call them via
+//! `syscall()`. Constants are copied from `include/uapi/linux/mount.h`.
+use std::ffi::CStr;
+use std::os::unix::io::RawFd;
+use std::ptr::null;
+
+use libc::{self, c_int, c_uint, c_long, AT_EMPTY_PATH};
+use nix::Result;
+use nix::errno::Errno;
+use nix::mount::MsFlags;
+
+use Propagation;
+use util::supports_mount_setattr;
+
+
+pub const FSOPEN_CLOEXEC: c_uint = 0x00000001;
+pub const FSMOUNT_CLOEXEC: c_uint = 0x00000001;
+
+pub const FSCONFIG_SET_FLAG: c_uint = 0;
+pub const FSCONFIG_SET_STRING: c_uint = 1;
+pub const FSCONFIG_SET_FD: c_uint = 5;
+pub const FSCONFIG_CMD_CREATE: c_uint = 6;
+
+pub const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x00000004;
+/// Mount beneath the top mount at the target (linux 6.5+)
+pub const MOVE_MOUNT_BENEATH: c_uint = 0x00000200;
+
+pub const OPEN_TREE_CLONE: c_uint = 0x00000001;
+pub const OPEN_TREE_CLOEXEC: c_uint = libc::O_CLOEXEC as c_uint;
+/// Clone the whole subtree (from `include/uapi/linux/fcntl.h`)
+pub const AT_RECURSIVE: c_uint = 0x8000;
+
+pub const MOUNT_ATTR_RDONLY: c_uint = 0x00000001;
+pub const MOUNT_ATTR_NOSUID: c_uint = 0x00000002;
+pub const MOUNT_ATTR_NODEV: c_uint = 0x00000004;
+pub const MOUNT_ATTR_NOEXEC: c_uint = 0x00000008;
+pub const MOUNT_ATTR_NOATIME: c_uint = 0x00000010;
+pub const MOUNT_ATTR_STRICTATIME: c_uint = 0x00000020;
+pub const MOUNT_ATTR_NODIRATIME: c_uint = 0x00000080;
+
+
+/// Argument of `mount_setattr` (`struct mount_attr`, version 0)
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct MountAttr {
+    pub attr_set: u64,
+    pub attr_clr: u64,
+    pub propagation: u64,
+    pub userns_fd: u64,
+}
+
+impl MountAttr {
+    pub fn propagation(propagation: Propagation) -> MountAttr {
+        MountAttr {
+            propagation: propagation.ms_flag().bits(),
+            .. MountAttr::default()
+        }
+    }
+}
+
+/// System calls used to attach detached mounts
+///
+/// This is a trait so that the sequence of calls can be checked in tests.
+pub trait AttachCalls {
+    fn move_mount(&self, from_dfd: RawFd, from_path: &CStr,
+        to_dfd: RawFd, to_path: &CStr, flags: c_uint)
+        -> Result<()>;
+    fn mount_setattr(&self, dfd: RawFd, path: &CStr, flags: c_uint,
+        attr: &MountAttr)
+        -> Result<()>;
+}
+
+/// System calls used to create detached mounts
+///
+/// This is a trait so that the sequence of calls can be checked in tests.
+pub trait CreateCalls {
+    fn fsopen(&self, fstype: &CStr, flags: c_uint) -> Result<RawFd>;
+    fn fsconfig(&self, fd: RawFd, cmd: c_uint, key: Option<&CStr>,
+        value: Option<&CStr>, aux: c_int)
+        -> Result<()>;
+    fn fsmount(&self, fd: RawFd, flags: c_uint, attr_flags: c_uint)
+        -> Result<RawFd>;
+    fn close(&self, fd: RawFd) -> Result<()>;
+}
+
+/// System calls used to swap mounts
+///
+/// This is a trait so that the rollback can be checked in tests.
+pub trait SwapCalls {
+    fn open_tree(&self, dfd: RawFd, path: &CStr, flags: c_uint)
+        -> Result<RawFd>;
+    fn move_mount(&self, from_dfd: RawFd, from_path: &CStr,
+        to_dfd: RawFd, to_path: &CStr, flags: c_uint)
+        -> Result<()>;
+    /// Lazily unmount the top mount at `path` (`MNT_DETACH`)
+    fn umount(&self, path: &CStr) -> Result<()>;
+}
+
+/// System calls used to change propagation type of a mount
+///
+/// This is a trait so that the fallback to `mount()` can be checked in tests.
+pub trait PropagationCalls {
+    fn mount_setattr(&self, dfd: RawFd, path: &CStr, flags: c_uint,
+        attr: &MountAttr)
+        -> Result<()>;
+    fn mount(&self, target: &CStr, flags: MsFlags) -> Result<()>;
+    /// Whether `mount_setattr` is expected to work, so it's tried first
+    fn has_mount_setattr(&self) -> bool;
+}
+
+/// The real system calls
+#[derive(Debug)]
+pub struct Kernel;
+
+impl CreateCalls for Kernel {
+    fn fsopen(&self, fstype: &CStr, flags: c_uint) -> Result<RawFd> {
+        fsopen(fstype, flags)
+    }
+    fn fsconfig(&self, fd: RawFd, cmd: c_uint, key: Option<&CStr>,
+        value: Option<&CStr>, aux: c_int)
+        -> Result<()>
+    {
+        fsconfig(fd, cmd, key, value, aux)
+    }
+    fn fsmount(&self, fd: RawFd, flags: c_uint, attr_flags: c_uint)
+        -> Result<RawFd>
+    {
+        fsmount(fd, flags, attr_flags)
+    }
+    fn close(&self, fd: RawFd) -> Result<()> {
+        ::nix::unistd::close(fd)
+    }
+}
+
+impl AttachCalls for Kernel {
+    fn move_mount(&self, from_dfd: RawFd, from_path: &CStr,
+        to_dfd: RawFd, to_path: &CStr, flags: c_uint)
+        -> Result<()>
+    {
+        move_mount(from_dfd, from_path, to_dfd, to_path, flags)
+    }
+    fn mount_setattr(&self, dfd: RawFd, path: &CStr, flags: c_uint,
+        attr: &MountAttr)
+        -> Result<()>
+    {
+        mount_setattr(dfd, path, flags, attr)
+    }
+}
+
+impl SwapCalls for Kernel {
+    fn open_tree(&self, dfd: RawFd, path: &CStr, flags: c_uint)
+        -> Result<RawFd>
+    {
+        open_tree(dfd, path, flags)
+    }
+    fn move_mount(&self, from_dfd: RawFd, from_path: &CStr,
+        to_dfd: RawFd, to_path: &CStr, flags: c_uint)
+        -> Result<()>
+    {
+        move_mount(from_dfd, from_path, to_dfd, to_path, flags)
+    }
+    fn umount(&self, path: &CStr) -> Result<()> {
+        ::nix::mount::umount2(path, ::nix::mount::MntFlags::MNT_DETACH)
+    }
+}
+
+impl PropagationCalls for Kernel {
+    fn mount_setattr(&self, dfd: RawFd, path: &CStr, flags: c_uint,
+        attr: &MountAttr)
+        -> Result<()>
+    {
+        mount_setattr(dfd, path, flags, attr)
+    }
+    fn mount(&self, target: &CStr, flags: MsFlags) -> Result<()> {
+        ::nix::mount::mount(None::<&CStr>, target, None::<&CStr>, flags,
+                            None::<&CStr>)
+    }
+    fn has_mount_setattr(&self) -> bool {
+        supports_mount_setattr()
+    }
+}
+
+fn fd_result(res: c_long) -> Result<RawFd> {
+    Errno::result(res).map(|fd| fd as RawFd)
+}
+
+pub fn fsopen(fstype: &CStr, flags: c_uint) -> Result<RawFd> {
+    fd_result(unsafe {
+        libc::syscall(libc::SYS_fsopen, fstype.as_ptr(), flags)
+    })
+}
+
+pub fn fsconfig(fd: RawFd, cmd: c_uint, key: Option<&CStr>,
+    value: Option<&CStr>, aux: c_int)
+    -> Result<()>
+{
+    Errno::result(unsafe {
+        libc::syscall(libc::SYS_fsconfig, fd, cmd,
+            key.map_or(null(), |k| k.as_ptr()),
+            value.map_or(null(), |v| v.as_ptr()),
+            aux)
+    }).map(|_| ())
+}
+
+pub fn fsmount(fd: RawFd, flags: c_uint, attr_flags: c_uint)
+    -> Result<RawFd>
+{
+    fd_result(unsafe {
+        libc::syscall(libc::SYS_fsmount, fd, flags, attr_flags)
+    })
+}
+
+/// Get a file descriptor referring to the mount at `path` (or its copy)
+pub fn open_tree(dfd: RawFd, path: &CStr, flags: c_uint) -> Result<RawFd> {
+    fd_result(unsafe {
+        libc::syscall(libc::SYS_open_tree, dfd, path.as_ptr(), flags)
+    })
+}
+
+pub fn move_mount(from_dfd: RawFd, from_path: &CStr,
+    to_dfd: RawFd, to_path: &CStr, flags: c_uint)
+    -> Result<()>
+{
+    Errno::result(unsafe {
+        libc::syscall(libc::SYS_move_mount,
+            from_dfd, from_path.as_ptr(), to_dfd, to_path.as_ptr(), flags)
+    }).map(|_| ())
+}
+
+/// Change properties of the mount (linux 5.12+)
+pub fn mount_setattr(dfd: RawFd, path: &CStr, flags: c_uint,
+    attr: &MountAttr)
+    -> Result<()>
+{
+    Errno::result(unsafe {
+        libc::syscall(libc::SYS_mount_setattr, dfd, path.as_ptr(), flags,
+            attr as *const MountAttr, ::std::mem::size_of::<MountAttr>())
+    }).map(|_| ())
+}
+
+/// Flags of `mount_setattr` to change the mount referred by `dfd` itself
+pub const SETATTR_EMPTY_PATH: c_uint = AT_EMPTY_PATH as c_uint;
+
+const STATX_MNT_ID: c_uint = 0x00001000;
+
+/// Part of `struct statx` we need, we don't depend on its exact layout in
+/// libc because `stx_mnt_id` was added there quite late
+#[repr(C)]
+struct StatxMountId {
+    stx_mask: u32,
+    _skip: [u32; 35],
+    stx_mnt_id: u64,
+    _rest: [u64; 13],
+}
+
+/// Returns mount id of the path using `statx` (linux 5.8+)
+///
+/// Returns `None` if kernel supports `statx` but doesn't report mount id.
+pub fn statx_mount_id(dfd: RawFd, path: &CStr, flags: c_int)
+    -> Result<Option<u64>>
+{
+    let mut buf = StatxMountId {
+        stx_mask: 0,
+        _skip: [0; 35],
+        stx_mnt_id: 0,
+        _rest: [0; 13],
+    };
+    Errno::result(unsafe {
+        libc::syscall(libc::SYS_statx, dfd, path.as_ptr(), flags,
+            STATX_MNT_ID, &mut buf as *mut StatxMountId)
+    })?;
+    if buf.stx_mask & STATX_MNT_ID != 0 {
+        Ok(Some(buf.stx_mnt_id))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Convert per-mount flags of `mount()` into `MOUNT_ATTR_*` flags
+pub fn mount_attrs(flags: MsFlags) -> c_uint {
+    let mut attrs = 0;
+    for &(flag, attr) in &[
+        (MsFlags::MS_RDONLY, MOUNT_ATTR_RDONLY),
+        (MsFlags::MS_NOSUID, MOUNT_ATTR_NOSUID),
+        (MsFlags::MS_NODEV, MOUNT_ATTR_NODEV),
+        (MsFlags::MS_NOEXEC, MOUNT_ATTR_NOEXEC),
+        (MsFlags::MS_NOATIME, MOUNT_ATTR_NOATIME),
+        (MsFlags::MS_STRICTATIME, MOUNT_ATTR_STRICTATIME),
+        (MsFlags::MS_NODIRATIME, MOUNT_ATTR_NODIRATIME),
+    ] {
+        if flags.contains(flag) {
+            attrs |= attr;
+        }
+    }
+    attrs
+}
+
+#[cfg(test)]
+mod test {
+    use nix::mount::MsFlags;
+
+    use super::{mount_attrs, MOUNT_ATTR_NOSUID, MOUNT_ATTR_NODEV};
+
+    #[test]
+    fn test_mount_attrs() {
+        assert_eq!(mount_attrs(MsFlags::MS_NOSUID | MsFlags::MS_NODEV |
+                               MsFlags::MS_REC),
+                   MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV);
+        assert_eq!(mount_attrs(MsFlags::empty()), 0);
+    }
+}
@@ -1,17 +1,61 @@
+use std::io;
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::fs::metadata;
-use std::ffi::{CStr, CString};
+use std::fs::{metadata, canonicalize};
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
+use std::ptr;
 
+use libc::{self, c_int, ENODEV};
 use nix::mount::{MsFlags, mount};
 
-use util::{path_to_cstring, as_path};
+use lock;
+use util::{path_to_cstring, as_path, format_ms_flags};
 use {OSError, Error};
-use explain::{Explainable, exists, user};
+use error::ignore_errors;
+use explain::{Explainable, PathRole, exists, target_exists, user};
+use explain::{warn_if_nonempty, fstype_state};
 
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum OverlayError {
+        DuplicateLowerdir(path: PathBuf) {
+            display("Lowerdir {:?} is specified more than once", path)
+        }
+        UpperdirIsLowerdir(path: PathBuf) {
+            display("Upperdir {:?} is also used as a lowerdir", path)
+        }
+        WorkdirIsLowerdir(path: PathBuf) {
+            display("Workdir {:?} is also used as a lowerdir", path)
+        }
+        SameLowerdirs(first: PathBuf, second: PathBuf) {
+            display("Lowerdirs {:?} and {:?} point to the same directory",
+                first, second)
+        }
+        OverlappingLayers(outer: PathBuf, inner: PathBuf) {
+            display("Layer {:?} is inside of another layer {:?}",
+                inner, outer)
+        }
+        NfsExportRequires(option: &'static str) {
+            display("Option nfs_export=on requires {}", option)
+        }
+        DifferentFilesystems(upperdir: PathBuf, workdir: PathBuf) {
+            display("Upperdir {:?} and workdir {:?} must be on the same \
+                filesystem", upperdir, workdir)
+        }
+        Canonicalize(path: PathBuf, err: io::Error) {
+            source(err)
+            display("Cannot resolve {:?}: {}", path, err)
+        }
+        VolatileLeftover(path: PathBuf) {
+            display("{:?} was used by a volatile overlay mount, \
+                upperdir and workdir must be recreated", path)
+        }
+    }
+}
+
 /// An overlay mount point
 ///
 /// This requires linux kernel of at least 3.18.
@@ -24,6 +68,12 @@ pub struct Overlay {
     lowerdirs: Vec<PathBuf>,
     upperdir: Option<PathBuf>,
     workdir: Option<PathBuf>,
+    raw_data: Option<OsString>,
+    checked: bool,
+    warn_nonempty: bool,
+    lock: Option<PathBuf>,
+    volatile: bool,
+    flags: MsFlags,
     target: CString,
 }
 
@@ -42,9 +92,46 @@ impl Overlay {
             lowerdirs: dirs.map(|x| x.to_path_buf()).collect(),
             upperdir: None,
             workdir: None,
+            raw_data: None,
+            checked: false,
+            warn_nonempty: false,
+            lock: None,
+            volatile: false,
+            flags: MsFlags::empty(),
             target: path_to_cstring(target.as_ref()),
         }
     }
+    /// A constructor for read-only overlayfs mount from any paths
+    ///
+    /// Same as `readonly` but accepts anything iterable over path-like
+    /// values, so there is no need to map items to `&Path` first.
+    ///
+    /// ```
+    /// use libmount::Overlay;
+    ///
+    /// let fs = Overlay::readonly_paths(vec!["/a", "/b"], "/mnt");
+    /// assert_eq!(fs.to_string(), r#"overlayfs "/a":"/b" -> "/mnt""#);
+    /// ```
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use libmount::Overlay;
+    ///
+    /// let dirs = vec![PathBuf::from("/a"), PathBuf::from("/b")];
+    /// let fs = Overlay::readonly_paths(&dirs, "/mnt");
+    /// assert_eq!(fs.to_string(), r#"overlayfs "/a":"/b" -> "/mnt""#);
+    /// let fs = Overlay::readonly_paths(dirs, "/mnt");
+    /// assert_eq!(fs.to_string(), r#"overlayfs "/a":"/b" -> "/mnt""#);
+    /// ```
+    pub fn readonly_paths<I, S, T>(dirs: I, target: T) -> Overlay
+        where I: IntoIterator<Item=S>, S: AsRef<Path>, T: AsRef<Path>
+    {
+        let dirs = dirs.into_iter()
+            .map(|x| x.as_ref().to_path_buf())
+            .collect::<Vec<_>>();
+        Overlay::readonly(dirs.iter().map(|x| x.as_path()), target)
+    }
+
     /// A constructor for writable overlayfs mount
     ///
     /// The upperdir and workdir must be on the same filesystem.
@@ -60,12 +147,314 @@ impl Overlay {
             lowerdirs: lowerdirs.map(|x| x.to_path_buf()).collect(),
             upperdir: Some(upperdir.as_ref().to_path_buf()),
             workdir: Some(workdir.as_ref().to_path_buf()),
+            raw_data: None,
+            checked: false,
+            warn_nonempty: false,
+            lock: None,
+            volatile: false,
+            flags: MsFlags::empty(),
             target: path_to_cstring(target.as_ref()),
         }
     }
 
-    /// Execute an overlay mount
-    pub fn bare_mount(self) -> Result<(), OSError> {
+    /// A constructor for writable overlayfs mount from a list of layers
+    ///
+    /// Unlike other constructors `layers` are ordered from the bottom to
+    /// the top, i.e. the last layer is the top-most one and it becomes the
+    /// (writable) upperdir. Other layers become lowerdirs. So
+    /// `from_layers(&[base, app, rw], ..)` is equal to
+    /// `writable([app, base], rw, ..)`.
+    ///
+    /// If `layers` is empty, the overlay has no dirs at all and mount fails.
+    pub fn from_layers<C, D>(layers: &[&Path], workdir: C, target: D)
+        -> Overlay
+        where C: AsRef<Path>, D: AsRef<Path>,
+    {
+        match layers.split_last() {
+            Some((upperdir, lowerdirs)) => {
+                Overlay::writable(lowerdirs.iter().rev().cloned(),
+                                  upperdir, workdir, target)
+            }
+            None => Overlay::readonly(layers.iter().cloned(), target),
+        }
+    }
+
+    /// Convert into a writable overlay with the same lowerdirs and target
+    ///
+    /// Note: overlayfs can't be switched between read-only and writable
+    /// mode in place, so you need to unmount the old one and mount the
+    /// new one. This is just a shortcut to avoid specifying everything again.
+    pub fn to_writable<B: AsRef<Path>, C: AsRef<Path>>(&self,
+        upperdir: B, workdir: C)
+        -> Overlay
+    {
+        Overlay {
+            upperdir: Some(upperdir.as_ref().to_path_buf()),
+            workdir: Some(workdir.as_ref().to_path_buf()),
+            ..self.clone()
+        }
+    }
+
+    /// Convert into a read-only overlay with the same lowerdirs and target
+    ///
+    /// The upperdir and workdir are dropped (if any). Note, that you need at
+    /// least two lowerdirs for read-only overlay.
+    pub fn to_readonly(&self) -> Overlay {
+        Overlay {
+            upperdir: None,
+            workdir: None,
+            ..self.clone()
+        }
+    }
+
+    /// Append raw options to the ones generated by the builder
+    ///
+    /// This is an escape hatch for the options not supported by the library
+    /// yet (like `index=on` or `metacopy=on`). Data is appended as is after
+    /// a comma, so the caller is responsible for escaping. Calling it again
+    /// replaces the data.
+    pub fn raw_data<S: AsRef<OsStr>>(mut self, data: S) -> Overlay {
+        self.set_raw_data(data);
+        self
+    }
+    /// Append raw options (in-place version of `raw_data`)
+    pub fn set_raw_data<S: AsRef<OsStr>>(&mut self, data: S) -> &mut Overlay {
+        self.raw_data = Some(data.as_ref().to_os_string());
+        self
+    }
+    /// Run additional checks before calling `mount`
+    ///
+    /// Besides the checks of `validate()` this resolves all lowerdirs (so
+    /// they must exist) and checks that no two of them point to the same
+    /// directory. For writable overlay it also checks that upperdir and
+    /// workdir are on the same filesystem (otherwise mount always fails),
+    /// and that they weren't used by a `volatile` mount before.
+    ///
+    /// Also, if lowerdirs are on different filesystems, inode numbers in
+    /// the overlay may collide. So `xino=on` is added to the options in this
+    /// case, unless `xino=` is already set in `raw_data` or the kernel
+    /// doesn't support `xino` (it's available since linux 4.17, see
+    /// `OverlayFeatures`).
+    ///
+    /// This touches filesystem, so it's off by default.
+    pub fn checked(mut self, flag: bool) -> Overlay {
+        self.set_checked(flag);
+        self
+    }
+    /// Don't sync upperdir to the disk (`volatile` option, linux 5.10+)
+    ///
+    /// This makes writes faster, but after a crash the contents of the
+    /// upperdir is undefined. Also once the overlay was mounted volatile,
+    /// kernel refuses to mount the same upperdir and workdir again (even
+    /// without this option), they must be recreated. `checked()` reports
+    /// such leftovers before mounting. Only makes sense for writable
+    /// overlay.
+    pub fn volatile(mut self, flag: bool) -> Overlay {
+        self.set_volatile(flag);
+        self
+    }
+    /// Mount the overlay read-only (`MS_RDONLY`)
+    ///
+    /// This is also valid for the overlay with an upperdir: it isn't
+    /// modified, but files copied up earlier are visible.
+    pub fn readonly_mount(mut self, flag: bool) -> Overlay {
+        self.set_readonly_mount(flag);
+        self
+    }
+    /// Ignore suid and sgid bits (`MS_NOSUID`)
+    pub fn nosuid(mut self, flag: bool) -> Overlay {
+        self.set_nosuid(flag);
+        self
+    }
+    /// Disallow access to device files (`MS_NODEV`)
+    pub fn nodev(mut self, flag: bool) -> Overlay {
+        self.set_nodev(flag);
+        self
+    }
+    /// Disallow executing programs (`MS_NOEXEC`)
+    pub fn noexec(mut self, flag: bool) -> Overlay {
+        self.set_noexec(flag);
+        self
+    }
+    /// Log a warning if target is a non-empty directory
+    ///
+    /// Its contents are hidden by the mount. This is only a `log::warn!`,
+    /// the mount is done anyway.
+    pub fn warn_nonempty_target(mut self) -> Overlay {
+        self.set_warn_nonempty_target();
+        self
+    }
+    /// Hold an exclusive `flock` on `lockfile` while mounting
+    ///
+    /// Same as `BindMount::with_lock()`. Checks of `checked()` are done
+    /// under the lock too.
+    pub fn with_lock<P: AsRef<Path>>(mut self, lockfile: P) -> Overlay {
+        self.set_lock(lockfile);
+        self
+    }
+
+    /// Run additional checks (in-place version of `checked`)
+    pub fn set_checked(&mut self, flag: bool) -> &mut Overlay {
+        self.checked = flag;
+        self
+    }
+    /// Don't sync upperdir (in-place version of `volatile`)
+    pub fn set_volatile(&mut self, flag: bool) -> &mut Overlay {
+        self.volatile = flag;
+        self
+    }
+    /// Mount read-only (in-place version of `readonly_mount`)
+    pub fn set_readonly_mount(&mut self, flag: bool) -> &mut Overlay {
+        self.flags.set(MsFlags::MS_RDONLY, flag);
+        self
+    }
+    /// Ignore suid and sgid bits (in-place version of `nosuid`)
+    pub fn set_nosuid(&mut self, flag: bool) -> &mut Overlay {
+        self.flags.set(MsFlags::MS_NOSUID, flag);
+        self
+    }
+    /// Disallow access to device files (in-place version of `nodev`)
+    pub fn set_nodev(&mut self, flag: bool) -> &mut Overlay {
+        self.flags.set(MsFlags::MS_NODEV, flag);
+        self
+    }
+    /// Disallow executing programs (in-place version of `noexec`)
+    pub fn set_noexec(&mut self, flag: bool) -> &mut Overlay {
+        self.flags.set(MsFlags::MS_NOEXEC, flag);
+        self
+    }
+    /// Log a warning if target is a non-empty directory
+    /// (in-place version of `warn_nonempty_target`)
+    pub fn set_warn_nonempty_target(&mut self) -> &mut Overlay {
+        self.warn_nonempty = true;
+        self
+    }
+    /// Hold a lock while mounting (in-place version of `with_lock`)
+    pub fn set_lock<P: AsRef<Path>>(&mut self, lockfile: P) -> &mut Overlay {
+        self.lock = Some(lockfile.as_ref().to_path_buf());
+        self
+    }
+
+    /// Check the layers for the common mistakes before mounting
+    ///
+    /// Kernel returns EINVAL for all of these, so it's hard to find out what
+    /// is wrong. This checks that there are no duplicate lowerdirs and that
+    /// neither upperdir nor workdir is used as a lowerdir. Then that no
+    /// layer (including upperdir and workdir) is inside of another one,
+    /// which kernel rejects or, on older kernels, allows with confusing
+    /// results.
+    ///
+    /// Also, if `nfs_export=on` is in `raw_data`, it checks that `index=on`
+    /// is set too and `redirect_dir` isn't `on` or `follow`.
+    ///
+    /// Paths are compared as is, no symlinks are resolved.
+    pub fn validate(&self) -> Result<(), OSError> {
+        self.check_layers()
+            .map_err(|e| OSError::from_overlay(e, Box::new(self.clone())))
+    }
+
+    fn check_layers(&self) -> Result<(), OverlayError> {
+        for (i, dir) in self.lowerdirs.iter().enumerate() {
+            if self.lowerdirs[..i].contains(dir) {
+                return Err(OverlayError::DuplicateLowerdir(dir.clone()));
+            }
+        }
+        if let Some(ref udir) = self.upperdir {
+            if self.lowerdirs.contains(udir) {
+                return Err(OverlayError::UpperdirIsLowerdir(udir.clone()));
+            }
+        }
+        if let Some(ref wdir) = self.workdir {
+            if self.lowerdirs.contains(wdir) {
+                return Err(OverlayError::WorkdirIsLowerdir(wdir.clone()));
+            }
+        }
+        check_overlapping(self.lowerdirs.iter()
+            .chain(self.upperdir.as_ref())
+            .chain(self.workdir.as_ref()))?;
+        if self.raw_option(b"nfs_export") == Some(b"on") {
+            if self.raw_option(b"index") != Some(b"on") {
+                return Err(OverlayError::NfsExportRequires("index=on"));
+            }
+            match self.raw_option(b"redirect_dir") {
+                Some(b"on") | Some(b"follow") => {
+                    return Err(OverlayError::NfsExportRequires(
+                        "redirect_dir=nofollow"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value of the last `name=value` option in `raw_data`
+    fn raw_option(&self, name: &[u8]) -> Option<&[u8]> {
+        let data = match self.raw_data {
+            Some(ref data) => data.as_bytes(),
+            None => return None,
+        };
+        data.rsplit(|&c| c == b',').find_map(|opt| {
+            if opt.starts_with(name) && opt.get(name.len()) == Some(&b'=') {
+                Some(&opt[name.len()+1..])
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns whether lowerdirs are on different devices
+    fn preflight(&self) -> Result<bool, OverlayError> {
+        self.check_layers()?;
+        let mut resolved: Vec<(PathBuf, &PathBuf)> = Vec::new();
+        let mut devices = Vec::new();
+        for dir in &self.lowerdirs {
+            let real = canonicalize(dir)
+                .map_err(|e| OverlayError::Canonicalize(dir.clone(), e))?;
+            if let Some(&(_, first)) = resolved.iter().find(|x| x.0 == real) {
+                return Err(OverlayError::SameLowerdirs(
+                    first.clone(), dir.clone()));
+            }
+            let meta = metadata(&real)
+                .map_err(|e| OverlayError::Canonicalize(dir.clone(), e))?;
+            devices.push(meta.dev());
+            resolved.push((real, dir));
+        }
+        if let (Some(udir), Some(wdir)) =
+                (self.upperdir.as_ref(), self.workdir.as_ref())
+        {
+            let udev = metadata(udir)
+                .map_err(|e| OverlayError::Canonicalize(udir.clone(), e))?
+                .dev();
+            let wdev = metadata(wdir)
+                .map_err(|e| OverlayError::Canonicalize(wdir.clone(), e))?
+                .dev();
+            check_same_fs(udir, udev, wdir, wdev)?;
+            check_volatile_leftover(udir, wdir)?;
+        }
+        Ok(multiple_devices(&devices))
+    }
+
+    fn has_xino_option(&self) -> bool {
+        self.raw_option(b"xino").is_some()
+    }
+
+    fn add_xino(&mut self) {
+        let data = match self.raw_data.take() {
+            Some(mut data) => {
+                data.push(",xino=on");
+                data
+            }
+            None => OsString::from("xino=on"),
+        };
+        self.raw_data = Some(data);
+    }
+
+    /// Returns the options string exactly as it's passed to the kernel
+    ///
+    /// Paths are escaped, so this is useful for logging or for checking
+    /// what will be mounted. Note that `checked()` may add `xino=on` at
+    /// mount time.
+    pub fn format_options(&self) -> Vec<u8> {
         let mut options = Vec::new();
         options.extend(b"lowerdir=");
         for (i, p) in self.lowerdirs.iter().enumerate() {
@@ -80,11 +469,42 @@ impl Overlay {
             options.extend(b",workdir=");
             append_escape(&mut options, w);
         }
+        if self.volatile {
+            options.extend(b",volatile");
+        }
+        if let Some(ref data) = self.raw_data {
+            options.push(b',');
+            options.extend(data.as_bytes());
+        }
+        options
+    }
+
+    /// Execute an overlay mount
+    pub fn bare_mount(mut self) -> Result<(), OSError> {
+        let _lock = match lock::acquire(&self.lock) {
+            Ok(lock) => lock,
+            Err(e) => return Err(OSError::from_io(e, Box::new(self))),
+        };
+        if self.checked {
+            match self.preflight() {
+                Ok(true) if !self.has_xino_option()
+                    && OverlayFeatures::detect().xino
+                => self.add_xino(),
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(OSError::from_overlay(e, Box::new(self)));
+                }
+            }
+        }
+        if self.warn_nonempty {
+            warn_if_nonempty(as_path(&self.target));
+        }
+        let options = self.format_options();
         mount(
             Some(CStr::from_bytes_with_nul(b"overlay\0").unwrap()),
             &*self.target,
             Some(CStr::from_bytes_with_nul(b"overlay\0").unwrap()),
-            MsFlags::empty(),
+            self.flags,
             Some(&*options),
         ).map_err(|err| OSError::from_nix(err, Box::new(self)))
     }
@@ -93,6 +513,115 @@ impl Overlay {
     pub fn mount(self) -> Result<(), Error> {
         self.bare_mount().map_err(OSError::explain)
     }
+
+    /// Mount ignoring errors of the specified kinds (like `nofail` in fstab)
+    ///
+    /// Returns `Ok(true)` if mounted and `Ok(false)` if mount failed with
+    /// one of the `kinds`.
+    pub fn mount_ignoring(self, kinds: &[io::ErrorKind]) -> Result<bool, Error> {
+        ignore_errors(self.mount(), kinds)
+    }
+}
+
+/// Overlayfs features supported by the running kernel
+///
+/// Detected by the presence of module parameters in
+/// `/sys/module/overlay/parameters`. If overlay module isn't loaded yet,
+/// nothing is detected, so all the fields are `false`. There is no module
+/// parameter for `userxattr` so it can't be detected this way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverlayFeatures {
+    /// `redirect_dir=` option (linux 4.10)
+    pub redirect_dir: bool,
+    /// `index=` option (linux 4.13)
+    pub index: bool,
+    /// `nfs_export=` option (linux 4.16)
+    pub nfs_export: bool,
+    /// `xino=` option (linux 4.17)
+    pub xino: bool,
+    /// `metacopy=` option (linux 4.19)
+    pub metacopy: bool,
+}
+
+impl OverlayFeatures {
+    /// Detect features of the running kernel
+    pub fn detect() -> OverlayFeatures {
+        OverlayFeatures::detect_in(Path::new("/sys/module/overlay/parameters"))
+    }
+
+    fn detect_in(dir: &Path) -> OverlayFeatures {
+        OverlayFeatures {
+            redirect_dir: dir.join("redirect_dir").exists(),
+            index: dir.join("index").exists(),
+            nfs_export: dir.join("nfs_export").exists(),
+            xino: dir.join("xino_auto").exists(),
+            metacopy: dir.join("metacopy").exists(),
+        }
+    }
+}
+
+/// Returns overlayfs features supported by the running kernel
+///
+/// Same as `OverlayFeatures::detect()`, use it to choose options which
+/// the kernel accepts (instead of retrying the mount on `EINVAL`).
+pub fn supported_features() -> OverlayFeatures {
+    OverlayFeatures::detect()
+}
+
+fn multiple_devices(devices: &[u64]) -> bool {
+    devices.iter().any(|&dev| dev != devices[0])
+}
+
+fn check_same_fs(upperdir: &Path, upperdev: u64, workdir: &Path, workdev: u64)
+    -> Result<(), OverlayError>
+{
+    if upperdev == workdev {
+        Ok(())
+    } else {
+        Err(OverlayError::DifferentFilesystems(
+            upperdir.to_path_buf(), workdir.to_path_buf()))
+    }
+}
+
+/// Checks that none of the `layers` is inside of another one
+fn check_overlapping<'x, I>(layers: I) -> Result<(), OverlayError>
+    where I: Iterator<Item=&'x PathBuf> + Clone
+{
+    for (i, inner) in layers.clone().enumerate() {
+        for (j, outer) in layers.clone().enumerate() {
+            if i != j && inner.starts_with(outer) {
+                return Err(OverlayError::OverlappingLayers(
+                    outer.clone(), inner.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks for the markers which kernel leaves after a volatile mount
+///
+/// The `trusted.overlay.volatile` xattr on the upperdir and the
+/// `work/incompat/volatile` directory in the workdir.
+fn check_volatile_leftover(upperdir: &Path, workdir: &Path)
+    -> Result<(), OverlayError>
+{
+    if has_xattr(upperdir, VOLATILE_XATTR) {
+        return Err(OverlayError::VolatileLeftover(upperdir.to_path_buf()));
+    }
+    if workdir.join("work/incompat/volatile").exists() {
+        return Err(OverlayError::VolatileLeftover(workdir.to_path_buf()));
+    }
+    Ok(())
+}
+
+const VOLATILE_XATTR: &[u8] = b"trusted.overlay.volatile\0";
+
+fn has_xattr(path: &Path, name: &[u8]) -> bool {
+    let path = path_to_cstring(path);
+    let name = CStr::from_bytes_with_nul(name).unwrap();
+    unsafe {
+        libc::getxattr(path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0) >= 0
+    }
 }
 
 /// Escape the path to put it into options string for overlayfs
@@ -147,23 +676,459 @@ impl Explainable for Overlay {
             info.push(format!("workdir: {}", exists(&wdir)));
 
             if let (Some(u), Some(w)) = (umeta, wmeta) {
-                info.push(format!("{}", if u.dev() == w.dev()
-                    { "same-fs" } else { "different-fs" }));
+                info.push(match check_same_fs(udir, u.dev(), wdir, w.dev()) {
+                    Ok(()) => "same-fs".to_string(),
+                    Err(_) => "different-fs".to_string(),
+                });
             }
             if udir.starts_with(wdir) {
                 info.push("upperdir-prefix-of-workdir".to_string());
             } else if wdir.starts_with(udir) {
                 info.push("workdir-prefix-of-upperdir".to_string());
             }
-            info.push(format!("target: {}", exists(as_path(&self.target))));
+            info.push(format!("target: {}", target_exists(as_path(&self.target))));
+        }
+        let devices = self.lowerdirs.iter()
+            .filter_map(|x| metadata(x).ok())
+            .map(|m| m.dev())
+            .collect::<Vec<_>>();
+        if multiple_devices(&devices) {
+            info.push("multi-device-lowerdirs".to_string());
         }
         if self.lowerdirs.len() < 1 {
             info.push("no-lowerdirs".to_string());
         } else if self.upperdir.is_none() && self.lowerdirs.len() < 2 {
             info.push("single-lowerdir".to_string());
         }
+        match self.check_layers() {
+            Ok(()) => {}
+            Err(OverlayError::DuplicateLowerdir(_)) => {
+                info.push("duplicate-lowerdir".to_string());
+            }
+            Err(OverlayError::UpperdirIsLowerdir(_)) => {
+                info.push("upperdir-is-lowerdir".to_string());
+            }
+            Err(OverlayError::WorkdirIsLowerdir(_)) => {
+                info.push("workdir-is-lowerdir".to_string());
+            }
+            Err(OverlayError::OverlappingLayers(..)) => {
+                info.push("overlapping-layers".to_string());
+            }
+            Err(_) => {}
+        }
+        if self.volatile {
+            info.push("volatile: upperdir-must-be-fresh".to_string());
+        }
+        info.push(format!("flags: {}", format_ms_flags(self.flags)));
         info.push(user().to_string());
         info.join(", ")
     }
+    fn explain_errno(&self, errno: Option<c_int>) -> String {
+        let mut info = vec![self.explain()];
+        if errno == Some(ENODEV) {
+            info.push(format!("fstype: {}", fstype_state("overlay")));
+        }
+        info.join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::ffi::CString;
+    use std::fs::{File, create_dir};
+    use std::os::unix::fs::symlink;
+
+    use nix::mount::MsFlags;
+    use nix::unistd::getuid;
+
+    use Unmount;
+    use explain::Explainable;
+    use mountinfo::find_mount_point;
+    use util::test::tmp_dir;
+    use super::{Overlay, OverlayError, OverlayFeatures, multiple_devices};
+    use super::check_same_fs;
+    use super::{check_volatile_leftover, VOLATILE_XATTR};
+    use super::{check_overlapping, supported_features};
+    use util::path_to_cstring;
+
+    #[test]
+    fn test_from_layers() {
+        let fs = Overlay::from_layers(
+            &[Path::new("/base"), Path::new("/app"), Path::new("/rw")],
+            "/work", "/mnt");
+        assert_eq!(fs.lowerdirs, vec![Path::new("/app"), Path::new("/base")]);
+        assert_eq!(fs.upperdir.as_ref().unwrap(), Path::new("/rw"));
+        assert_eq!(fs.workdir.as_ref().unwrap(), Path::new("/work"));
+        assert_eq!(fs.target, CString::new("/mnt").unwrap());
+        assert_eq!(fs.format_options(),
+            &b"lowerdir=/app:/base,upperdir=/rw,workdir=/work"[..]);
+
+        let fs = Overlay::from_layers(&[], "/work", "/mnt");
+        assert!(fs.lowerdirs.is_empty());
+        assert!(fs.upperdir.is_none());
+    }
+
+    #[test]
+    fn test_mount_flags() {
+        let fs = Overlay::from_layers(
+            &[Path::new("/nonexistent1"), Path::new("/nonexistent2")],
+            "/nonexistent-work", "/nonexistent-mnt");
+        assert!(fs.explain().contains(", flags: 0,"));
+        let fs = fs.readonly_mount(true).nosuid(true).nodev(true)
+            .noexec(true);
+        assert!(fs.explain().contains(
+            ", flags: MS_RDONLY|MS_NOSUID|MS_NODEV|MS_NOEXEC,"));
+        let mut fs = fs;
+        fs.set_nodev(false).set_readonly_mount(false);
+        assert!(fs.explain().contains(", flags: MS_NOSUID|MS_NOEXEC,"));
+    }
+
+    #[test]
+    fn test_readonly_writable_overlay() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("overlay_readonly_writable");
+        for name in &["lower", "upper", "work", "mnt"] {
+            create_dir(dir.join(name)).unwrap();
+        }
+        Overlay::writable([dir.join("lower").as_path()].iter().cloned(),
+                          dir.join("upper"), dir.join("work"), dir.join("mnt"))
+            .readonly_mount(true).nosuid(true)
+            .mount().unwrap();
+        let flags = find_mount_point(dir.join("mnt")).unwrap().unwrap()
+            .get_mount_flags();
+        assert!(flags.contains(MsFlags::MS_RDONLY | MsFlags::MS_NOSUID));
+        assert!(File::create(dir.join("mnt/file")).is_err());
+        Unmount::new(dir.join("mnt")).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_to_writable() {
+        let ro = Overlay::readonly(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(), "/mnt");
+        let rw = ro.to_writable("/upper", "/work");
+        assert_eq!(rw.lowerdirs, vec![Path::new("/a"), Path::new("/b")]);
+        assert_eq!(rw.upperdir.as_ref().unwrap(), Path::new("/upper"));
+        assert_eq!(rw.workdir.as_ref().unwrap(), Path::new("/work"));
+        assert_eq!(rw.target, CString::new("/mnt").unwrap());
+    }
+
+    #[test]
+    fn test_to_readonly() {
+        let rw = Overlay::writable(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(),
+            "/upper", "/work", "/mnt");
+        let ro = rw.to_readonly();
+        assert_eq!(ro.lowerdirs, vec![Path::new("/a"), Path::new("/b")]);
+        assert!(ro.upperdir.is_none());
+        assert!(ro.workdir.is_none());
+        assert_eq!(ro.target, CString::new("/mnt").unwrap());
+    }
+
+    #[test]
+    fn test_volatile_options() {
+        let fs = Overlay::writable(
+            [Path::new("/lower")].iter().cloned(), "/upper", "/work", "/mnt")
+            .volatile(true);
+        assert_eq!(fs.format_options(),
+            b"lowerdir=/lower,upperdir=/upper,workdir=/work,volatile"
+            .to_vec());
+        assert!(fs.explain().contains(", volatile: upperdir-must-be-fresh,"));
+        let mut fs = fs.raw_data("index=on");
+        fs.set_volatile(false);
+        assert_eq!(fs.format_options(),
+            b"lowerdir=/lower,upperdir=/upper,workdir=/work,index=on"
+            .to_vec());
+        assert!(!fs.explain().contains("volatile"));
+    }
+
+    #[test]
+    fn test_volatile_leftover_incompat_dir() {
+        let dir = tmp_dir("overlay_volatile_incompat");
+        create_dir(dir.join("upper")).unwrap();
+        create_dir(dir.join("work")).unwrap();
+        check_volatile_leftover(&dir.join("upper"), &dir.join("work"))
+            .unwrap();
+        create_dir(dir.join("work/work")).unwrap();
+        create_dir(dir.join("work/work/incompat")).unwrap();
+        create_dir(dir.join("work/work/incompat/volatile")).unwrap();
+        match check_volatile_leftover(&dir.join("upper"), &dir.join("work")) {
+            Err(OverlayError::VolatileLeftover(ref p))
+                if p == &dir.join("work") => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_volatile_leftover_xattr() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("overlay_volatile_xattr");
+        create_dir(dir.join("upper")).unwrap();
+        create_dir(dir.join("work")).unwrap();
+        let upper = path_to_cstring(&dir.join("upper"));
+        let res = unsafe {
+            ::libc::setxattr(upper.as_ptr(),
+                VOLATILE_XATTR.as_ptr() as *const _,
+                b"".as_ptr() as *const _, 0, 0)
+        };
+        if res != 0 {
+            // filesystem doesn't support trusted xattrs
+            return;
+        }
+        match check_volatile_leftover(&dir.join("upper"), &dir.join("work")) {
+            Err(OverlayError::VolatileLeftover(ref p))
+                if p == &dir.join("upper") => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_check_overlapping() {
+        let check = |paths: &[&str]| {
+            let paths = paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+            match check_overlapping(paths.iter()) {
+                Ok(()) => None,
+                Err(OverlayError::OverlappingLayers(outer, inner)) => {
+                    Some((outer, inner))
+                }
+                Err(e) => panic!("unexpected error: {:?}", e),
+            }
+        };
+        assert_eq!(check(&["/a", "/b", "/upper", "/work"]), None);
+        assert_eq!(check(&["/layers/a", "/layers/b", "/rw/upper", "/rw/work"]),
+                   None);
+        // common prefix in the name isn't an overlap
+        assert_eq!(check(&["/a", "/ab"]), None);
+        assert_eq!(check(&["/a", "/b", "/a/upper", "/work"]),
+                   Some((PathBuf::from("/a"), PathBuf::from("/a/upper"))));
+        assert_eq!(check(&["/a/b/c", "/a"]),
+                   Some((PathBuf::from("/a"), PathBuf::from("/a/b/c"))));
+        assert_eq!(check(&["/a", "/upper", "/upper/work"]),
+                   Some((PathBuf::from("/upper"),
+                         PathBuf::from("/upper/work"))));
+    }
+
+    #[test]
+    fn test_validate_overlapping() {
+        let fs = Overlay::writable(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(),
+            "/b/upper", "/work", "/mnt");
+        let err = fs.validate().unwrap_err().explain();
+        assert!(err.to_string().contains(
+            "Layer \"/b/upper\" is inside of another layer \"/b\""),
+            "{}", err);
+        assert!(err.explanation().contains("overlapping-layers"));
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        let fs = Overlay::writable(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(),
+            "/upper", "/work", "/mnt");
+        assert!(fs.check_layers().is_ok());
+        assert!(fs.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_duplicate_lowerdir() {
+        let fs = Overlay::readonly(
+            [Path::new("/a"), Path::new("/b"), Path::new("/a")]
+            .iter().cloned(),
+            "/mnt");
+        match fs.check_layers() {
+            Err(OverlayError::DuplicateLowerdir(p)) => {
+                assert_eq!(p, Path::new("/a"));
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+        let err = fs.validate().unwrap_err().explain();
+        assert!(format!("{}", err).contains(
+            "Lowerdir \"/a\" is specified more than once"));
+    }
+
+    #[test]
+    fn test_validate_upperdir_is_lowerdir() {
+        let fs = Overlay::writable(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(),
+            "/b", "/work", "/mnt");
+        match fs.check_layers() {
+            Err(OverlayError::UpperdirIsLowerdir(p)) => {
+                assert_eq!(p, Path::new("/b"));
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_validate_workdir_is_lowerdir() {
+        let fs = Overlay::writable(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(),
+            "/upper", "/a", "/mnt");
+        match fs.check_layers() {
+            Err(OverlayError::WorkdirIsLowerdir(p)) => {
+                assert_eq!(p, Path::new("/a"));
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_format_options_escaping() {
+        let fs = Overlay::writable(
+            [Path::new("/a,b"), Path::new("/c:d")].iter().cloned(),
+            "/upper", "/work\\dir", "/mnt");
+        assert_eq!(fs.format_options(),
+            &b"lowerdir=/a\\,b:/c\\:d,upperdir=/upper,workdir=/work\\\\dir"[..]);
+    }
+
+    #[test]
+    fn test_raw_data() {
+        let fs = Overlay::writable(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(),
+            "/upper", "/work", "/mnt")
+            .raw_data("index=on,metacopy=on");
+        assert_eq!(fs.format_options(),
+            &b"lowerdir=/a:/b,upperdir=/upper,workdir=/work,\
+               index=on,metacopy=on"[..]);
+    }
+
+    #[test]
+    fn test_checked_same_lowerdirs() {
+        let dir = tmp_dir("overlay_same_lowerdirs");
+        create_dir(dir.join("a")).unwrap();
+        create_dir(dir.join("b")).unwrap();
+        symlink(dir.join("a"), dir.join("c")).unwrap();
+        let lowerdirs = [dir.join("a"), dir.join("b"), dir.join("c")];
+        let fs = Overlay::readonly(lowerdirs.iter().map(|x| x.as_path()),
+                                   dir.join("mnt"));
+        assert!(fs.check_layers().is_ok());
+        match fs.preflight() {
+            Err(OverlayError::SameLowerdirs(a, c)) => {
+                assert_eq!(a, dir.join("a"));
+                assert_eq!(c, dir.join("c"));
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+        let err = fs.checked(true).mount().unwrap_err();
+        assert!(err.to_string().contains("point to the same directory"));
+
+        let lowerdirs = [dir.join("a"), dir.join("b"), dir.join("d")];
+        let fs = Overlay::readonly(lowerdirs.iter().map(|x| x.as_path()),
+                                   dir.join("mnt"));
+        match fs.preflight() {
+            Err(OverlayError::Canonicalize(d, _)) => {
+                assert_eq!(d, dir.join("d"));
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_multiple_devices() {
+        assert!(!multiple_devices(&[]));
+        assert!(!multiple_devices(&[2049]));
+        assert!(!multiple_devices(&[2049, 2049, 2049]));
+        assert!(multiple_devices(&[2049, 2049, 45]));
+    }
+
+    #[test]
+    fn test_validate_nfs_export() {
+        let fs = Overlay::readonly(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(), "/mnt");
+        let check = |data: &str| {
+            match fs.clone().raw_data(data).check_layers() {
+                Ok(()) => None,
+                Err(OverlayError::NfsExportRequires(opt)) => Some(opt),
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        };
+        assert_eq!(check("nfs_export=on"), Some("index=on"));
+        assert_eq!(check("nfs_export=on,index=off"), Some("index=on"));
+        assert_eq!(check("index=on,nfs_export=on,redirect_dir=on"),
+                   Some("redirect_dir=nofollow"));
+        assert_eq!(check("index=on,nfs_export=on,redirect_dir=follow"),
+                   Some("redirect_dir=nofollow"));
+        assert_eq!(check("index=on,nfs_export=on,redirect_dir=nofollow"),
+                   None);
+        assert_eq!(check("index=on,nfs_export=on"), None);
+        assert_eq!(check("nfs_export=off,redirect_dir=on"), None);
+        assert_eq!(check("nfs_export=on,nfs_export=off"), None);
+
+        let err = fs.clone().raw_data("nfs_export=on").validate()
+            .unwrap_err().explain();
+        assert!(err.to_string().contains(
+            "Option nfs_export=on requires index=on"));
+    }
+
+    #[test]
+    fn test_check_same_fs() {
+        let upper = Path::new("/var/upper");
+        let work = Path::new("/tmp/work");
+        assert!(check_same_fs(upper, 2049, work, 2049).is_ok());
+        match check_same_fs(upper, 2049, work, 45) {
+            Err(e @ OverlayError::DifferentFilesystems(..)) => {
+                assert_eq!(e.to_string(), "Upperdir \"/var/upper\" and \
+                    workdir \"/tmp/work\" must be on the same filesystem");
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_add_xino() {
+        let mut fs = Overlay::readonly(
+            [Path::new("/a"), Path::new("/b")].iter().cloned(), "/mnt");
+        assert!(!fs.has_xino_option());
+        fs.add_xino();
+        assert!(fs.has_xino_option());
+        assert_eq!(fs.format_options(), &b"lowerdir=/a:/b,xino=on"[..]);
+
+        let mut fs = fs.raw_data("index=on");
+        assert!(!fs.has_xino_option());
+        fs.add_xino();
+        assert_eq!(fs.format_options(),
+                   &b"lowerdir=/a:/b,index=on,xino=on"[..]);
+        assert!(fs.raw_data("xino=off").has_xino_option());
+    }
+
+    #[test]
+    fn test_explain_multi_device() {
+        let dir = tmp_dir("overlay_multi_device");
+        let fs = Overlay::readonly(
+            [dir.as_path(), Path::new("/proc")].iter().cloned(), "/mnt");
+        assert!(fs.explain().contains("multi-device-lowerdirs"));
+        let fs = Overlay::readonly(
+            [dir.as_path(), dir.as_path()].iter().cloned(), "/mnt");
+        assert!(!fs.explain().contains("multi-device-lowerdirs"));
+    }
+
+    #[test]
+    fn test_features() {
+        let dir = tmp_dir("overlay_features");
+        assert_eq!(OverlayFeatures::detect_in(&dir.join("missing")),
+                   OverlayFeatures::default());
+        for name in &["redirect_dir", "redirect_max", "index", "xino_auto"] {
+            File::create(dir.join(name)).unwrap();
+        }
+        assert_eq!(OverlayFeatures::detect_in(&dir), OverlayFeatures {
+            redirect_dir: true,
+            index: true,
+            nfs_export: false,
+            xino: true,
+            metacopy: false,
+        });
+        // the real system may have no overlay module loaded
+        assert_eq!(supported_features(), OverlayFeatures::detect());
+    }
+}
@@ -0,0 +1,272 @@
+use std::io;
+use std::fmt;
+use std::ffi::CString;
+use std::fs::create_dir;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::pivot_root;
+
+use {OSError, Error};
+use util::{path_to_cstring, as_path, canonicalize_lenient};
+use explain::{Explainable, PathRole, exists, mount_point, user};
+use mountinfo::MountTable;
+
+
+/// Name of the `put_old` directory created by `PivotRoot::prepare()`
+const PUT_OLD_NAME: &str = ".oldroot";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum PivotError {
+        NotAMountpoint(path: PathBuf) {
+            display("New root {:?} is not a mount point, \
+                bind-mount it onto itself first", path)
+        }
+        SharedMount(path: PathBuf) {
+            display("Mount {:?} has shared propagation, \
+                make it private first", path)
+        }
+    }
+}
+
+/// A pivot root operation definition
+///
+/// This is a wrapper around `pivot_root` system call. It moves the root
+/// mount of the current mount namespace to `put_old` and makes `new_root`
+/// the new root mount.
+///
+/// The usual container setup sequence is: bind-mount the new root onto
+/// itself (so it's a mount point), `pivot_root` into it and then unmount
+/// the old root (e.g. with `Unmount::recursive`).
+#[derive(Debug, Clone)]
+pub struct PivotRoot {
+    new_root: CString,
+    put_old: CString,
+}
+
+impl PivotRoot {
+    /// Create a new pivot root operation
+    ///
+    /// `put_old` must be at or under `new_root`
+    pub fn new<A: AsRef<Path>, B: AsRef<Path>>(new_root: A, put_old: B)
+        -> PivotRoot
+    {
+        PivotRoot {
+            new_root: path_to_cstring(new_root.as_ref()),
+            put_old: path_to_cstring(put_old.as_ref()),
+        }
+    }
+
+    /// Validate `new_root` and create `put_old` directory in it
+    ///
+    /// The `put_old` is `new_root/.oldroot`, it's created if doesn't exist
+    /// yet. After pivoting the old root is at `/.oldroot`, so it can be
+    /// unmounted with `Unmount::recursive("/.oldroot")`.
+    ///
+    /// This checks the preconditions of `pivot_root` that are easy to miss
+    /// (using mountinfo): `new_root` must be a mount point, and neither its
+    /// parent mount, nor the current root mount, nor the parent of the
+    /// latter may have shared propagation (see
+    /// `Propagation::make_tree_private()`). `new_root` itself may be shared.
+    pub fn bare_prepare<P: AsRef<Path>>(new_root: P)
+        -> Result<PivotRoot, OSError>
+    {
+        let new_root = new_root.as_ref();
+        let op = PivotRoot::new(new_root, default_put_old(new_root));
+        let table = match MountTable::read() {
+            Ok(table) => table,
+            Err(e) => return Err(OSError::from_io(e, Box::new(op))),
+        };
+        if let Err(e) = check_propagation(&table, new_root) {
+            return Err(OSError::from_pivot(e, Box::new(op)));
+        }
+        match create_dir(op.put_old_path()) {
+            Ok(()) => Ok(op),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(op),
+            Err(e) => Err(OSError::from_io(e, Box::new(op))),
+        }
+    }
+
+    /// Validate `new_root`, create `put_old` and explain error immediately
+    pub fn prepare<P: AsRef<Path>>(new_root: P) -> Result<PivotRoot, Error> {
+        PivotRoot::bare_prepare(new_root).map_err(OSError::explain)
+    }
+
+    /// Path which will become the new root
+    pub fn new_root_path(&self) -> &Path {
+        as_path(&self.new_root)
+    }
+
+    /// Path where old root will be put to
+    pub fn put_old_path(&self) -> &Path {
+        as_path(&self.put_old)
+    }
+
+    /// Execute a pivot root operation
+    pub fn bare_pivot_root(self) -> Result<(), OSError> {
+        pivot_root(&*self.new_root, &*self.put_old)
+            .map_err(|err| OSError::from_nix(err, Box::new(self)))
+    }
+
+    /// Execute a pivot root operation and explain the error immediately
+    pub fn pivot_root(self) -> Result<(), Error> {
+        self.bare_pivot_root().map_err(OSError::explain)
+    }
+}
+
+fn default_put_old(new_root: &Path) -> PathBuf {
+    new_root.join(PUT_OLD_NAME)
+}
+
+/// Checks that `pivot_root` into `new_root` isn't rejected because of
+/// shared propagation
+fn check_propagation(table: &MountTable, new_root: &Path)
+    -> Result<(), PivotError>
+{
+    let path = canonicalize_lenient(new_root);
+    let mnt = match table.find(&path) {
+        Some(mnt) if Path::new(&mnt.mount_point) == path => mnt,
+        _ => return Err(PivotError::NotAMountpoint(path)),
+    };
+    let parent = table.by_id(mnt.parent_id);
+    let root = table.find("/");
+    let root_parent = root.and_then(|r| table.by_id(r.parent_id));
+    for m in parent.into_iter().chain(root).chain(root_parent) {
+        if m.peer_group().is_some() {
+            return Err(PivotError::SharedMount(
+                PathBuf::from(&m.mount_point)));
+        }
+    }
+    Ok(())
+}
+
+fn put_old_state(new_root: &Path, put_old: &Path) -> &'static str {
+    let new_root = canonicalize_lenient(new_root);
+    if canonicalize_lenient(put_old).starts_with(new_root) {
+        "under-new-root"
+    } else {
+        "outside-new-root"
+    }
+}
+
+impl fmt::Display for PivotRoot {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "pivot_root {:?}, put old root to {:?}",
+            as_path(&self.new_root), as_path(&self.put_old))
+    }
+}
+
+impl Explainable for PivotRoot {
+    fn explain(&self) -> String {
+        [
+            format!("new_root: {}", exists(as_path(&self.new_root))),
+            format!("new_root: {}", mount_point(as_path(&self.new_root))),
+            format!("put_old: {}", exists(as_path(&self.put_old))),
+            format!("put_old: {}", put_old_state(
+                as_path(&self.new_root), as_path(&self.put_old))),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![
+            (PathRole::Target, as_path(&self.new_root)),
+            (PathRole::MountPoint, as_path(&self.new_root)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::create_dir;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    use mountinfo::MountTable;
+    use util::test::tmp_dir;
+    use super::{PivotRoot, PivotError, put_old_state};
+    use super::{default_put_old, check_propagation};
+
+    #[test]
+    fn test_paths() {
+        let op = PivotRoot::new("/new", "/new/old");
+        assert_eq!(op.new_root_path(), Path::new("/new"));
+        assert_eq!(op.put_old_path(), Path::new("/new/old"));
+        assert_eq!(format!("{}", op),
+                   "pivot_root \"/new\", put old root to \"/new/old\"");
+    }
+
+    #[test]
+    fn test_put_old_state() {
+        assert_eq!(put_old_state(Path::new("/new"), Path::new("/new/old")),
+                   "under-new-root");
+        assert_eq!(put_old_state(Path::new("/new"), Path::new("/new")),
+                   "under-new-root");
+        assert_eq!(put_old_state(Path::new("/new"), Path::new("/old")),
+                   "outside-new-root");
+        assert_eq!(put_old_state(Path::new("/new"), Path::new("/newer")),
+                   "outside-new-root");
+    }
+
+    #[test]
+    fn test_put_old_state_symlink() {
+        let dir = tmp_dir("pivot_put_old");
+        create_dir(dir.join("root")).unwrap();
+        create_dir(dir.join("elsewhere")).unwrap();
+        symlink(dir.join("elsewhere"), dir.join("root/old")).unwrap();
+        assert_eq!(put_old_state(&dir.join("root"), &dir.join("root/old")),
+                   "outside-new-root");
+    }
+
+    #[test]
+    fn test_default_put_old() {
+        assert_eq!(default_put_old(Path::new("/new")),
+                   Path::new("/new/.oldroot"));
+        assert_eq!(default_put_old(Path::new("/new/")),
+                   Path::new("/new/.oldroot"));
+    }
+
+    #[test]
+    fn test_check_propagation() {
+        let table = MountTable::parse(b"\
+            1 0 8:1 / / rw - ext4 /dev/sda1 rw\n\
+            2 1 0:40 / /private rw - tmpfs tmpfs rw\n\
+            3 1 0:41 / /shared rw shared:5 - tmpfs tmpfs rw\n\
+            4 3 0:42 / /shared/child rw - tmpfs tmpfs rw\n\
+            5 2 0:43 / /private/slave rw master:7 - tmpfs tmpfs rw\n")
+            .unwrap();
+        check_propagation(&table, Path::new("/private")).unwrap();
+        check_propagation(&table, Path::new("/private/slave")).unwrap();
+        // new_root itself may be shared
+        check_propagation(&table, Path::new("/shared")).unwrap();
+        // parent mount is shared
+        match check_propagation(&table, Path::new("/shared/child")) {
+            Err(PivotError::SharedMount(ref p)) if p == Path::new("/shared")
+            => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match check_propagation(&table, Path::new("/private/dir")) {
+            Err(PivotError::NotAMountpoint(ref p))
+            if p == Path::new("/private/dir") => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let table = MountTable::parse(b"\
+            1 0 8:1 / / rw shared:1 - ext4 /dev/sda1 rw\n\
+            2 1 0:40 / /private rw - tmpfs tmpfs rw\n").unwrap();
+        match check_propagation(&table, Path::new("/private")) {
+            Err(PivotError::SharedMount(ref p)) if p == Path::new("/") => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        // parent of the current root (e.g. in a chroot) is shared
+        let table = MountTable::parse(b"\
+            9 9 8:2 / /host rw shared:2 - ext4 /dev/sda2 rw\n\
+            1 9 8:1 / / rw - ext4 /dev/sda1 rw\n\
+            2 1 0:40 / /private rw - tmpfs tmpfs rw\n").unwrap();
+        match check_propagation(&table, Path::new("/private")) {
+            Err(PivotError::SharedMount(ref p)) if p == Path::new("/host")
+            => {}
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::fmt;
+use std::ffi::{CStr, CString};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use libc::c_uint;
+use nix::errno::Errno;
+use nix::mount::MsFlags;
+
+use {OSError, Error};
+use util::{path_to_cstring, as_path};
+use explain::{Explainable, exists, mount_namespace, user};
+use newapi::{PropagationCalls, Kernel, MountAttr};
+use newapi::{SETATTR_EMPTY_PATH, AT_RECURSIVE};
+
+
+/// Mount propagation type
+///
+/// See `mount_namespaces(7)` for the description of each type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// Mount and unmount events propagate to and from peers (`MS_SHARED`)
+    Shared,
+    /// No events propagate to or from this mount (`MS_PRIVATE`)
+    Private,
+    /// Events propagate from the master but not back (`MS_SLAVE`)
+    Slave,
+    /// Like private, but the mount also can't be bind-mounted
+    /// (`MS_UNBINDABLE`)
+    Unbindable,
+}
+
+/// Changes propagation type of the mount referred by a file descriptor
+///
+/// Created by `Propagation::from_fd()`. The file descriptor is usually
+/// obtained by `open_tree()` (or by opening the mount point with
+/// `O_PATH`), so unlike with path, the exact mount instance is changed even
+/// if something is mounted on top of it in the meantime.
+#[derive(Debug, Clone)]
+pub struct FdPropagation {
+    fd: RawFd,
+    recursive: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Path(CString),
+    Fd(RawFd),
+}
+
+/// An operation of changing propagation type of the mount (tree)
+#[derive(Debug, Clone)]
+struct ChangePropagation {
+    propagation: Propagation,
+    target: Target,
+    recursive: bool,
+}
+
+impl Propagation {
+    /// Returns the flag which sets this propagation type in `mount()`
+    pub fn ms_flag(&self) -> MsFlags {
+        match *self {
+            Propagation::Shared => MsFlags::MS_SHARED,
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::Unbindable => MsFlags::MS_UNBINDABLE,
+        }
+    }
+
+    /// Make all the mounts private (`mount --make-rprivate /`)
+    ///
+    /// This is usually the first thing to do after `unshare(CLONE_NEWNS)`,
+    /// because mounts of the new namespace are copies of the parent's ones
+    /// and are usually shared with them. Without this, mounts made in the
+    /// new namespace may propagate back to the host.
+    pub fn bare_make_tree_private() -> Result<(), OSError> {
+        ChangePropagation::new(Propagation::Private, Path::new("/"))
+            .bare_apply()
+    }
+
+    /// Make all the mounts private and explain error immediately
+    pub fn make_tree_private() -> Result<(), Error> {
+        Propagation::bare_make_tree_private().map_err(OSError::explain)
+    }
+
+    /// Change propagation type of the mount referred by `fd`
+    ///
+    /// This uses `mount_setattr(AT_EMPTY_PATH)` (linux 5.12+). On older
+    /// kernels it falls back to `mount()` on `/proc/self/fd/N`.
+    pub fn from_fd(fd: RawFd) -> FdPropagation {
+        FdPropagation {
+            fd,
+            recursive: false,
+        }
+    }
+}
+
+impl FdPropagation {
+    /// Also change propagation type of all the submounts
+    pub fn recursive(mut self, flag: bool) -> FdPropagation {
+        self.set_recursive(flag);
+        self
+    }
+
+    /// Change submounts too (in-place version of `recursive`)
+    pub fn set_recursive(&mut self, flag: bool) -> &mut FdPropagation {
+        self.recursive = flag;
+        self
+    }
+
+    /// Set `propagation` type on the mount
+    pub fn bare_apply(&self, propagation: Propagation)
+        -> Result<(), OSError>
+    {
+        ChangePropagation {
+            propagation,
+            target: Target::Fd(self.fd),
+            recursive: self.recursive,
+        }.bare_apply()
+    }
+
+    /// Set `propagation` type on the mount and explain error immediately
+    pub fn apply(&self, propagation: Propagation) -> Result<(), Error> {
+        self.bare_apply(propagation).map_err(OSError::explain)
+    }
+}
+
+impl ChangePropagation {
+    fn new(propagation: Propagation, target: &Path) -> ChangePropagation {
+        ChangePropagation {
+            propagation,
+            target: Target::Path(path_to_cstring(target)),
+            recursive: true,
+        }
+    }
+
+    fn flags(&self) -> MsFlags {
+        if self.recursive {
+            MsFlags::MS_REC | self.propagation.ms_flag()
+        } else {
+            self.propagation.ms_flag()
+        }
+    }
+
+    fn setattr_flags(&self) -> c_uint {
+        if self.recursive {
+            SETATTR_EMPTY_PATH | AT_RECURSIVE
+        } else {
+            SETATTR_EMPTY_PATH
+        }
+    }
+
+    fn bare_apply(self) -> Result<(), OSError> {
+        self.apply_with(&Kernel)
+            .map_err(|err| OSError::from_nix(err, Box::new(self)))
+    }
+
+    fn apply_with<C: PropagationCalls>(&self, calls: &C)
+        -> ::nix::Result<()>
+    {
+        match self.target {
+            Target::Path(ref path) => calls.mount(path, self.flags()),
+            Target::Fd(fd) => {
+                let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+                let attr = MountAttr::propagation(self.propagation);
+                let setattr = || calls.mount_setattr(fd, empty,
+                    self.setattr_flags(), &attr);
+                if calls.has_mount_setattr() {
+                    match setattr() {
+                        Err(::nix::Error::Sys(Errno::ENOSYS)) => {
+                            calls.mount(&fd_path(fd), self.flags())
+                        }
+                        res => res,
+                    }
+                } else {
+                    // Kernel looks too old, but `mount_setattr` might be
+                    // backported, so try it if `/proc` path doesn't work
+                    match calls.mount(&fd_path(fd), self.flags()) {
+                        Err(e) => match setattr() {
+                            Err(::nix::Error::Sys(Errno::ENOSYS)) => Err(e),
+                            res => res,
+                        },
+                        Ok(()) => Ok(()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fd_path(fd: RawFd) -> CString {
+    CString::new(format!("/proc/self/fd/{}", fd)).unwrap()
+}
+
+impl fmt::Display for ChangePropagation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "make ")?;
+        match self.target {
+            Target::Path(ref path) => write!(fmt, "{:?}", as_path(path))?,
+            Target::Fd(fd) => write!(fmt, "mount fd {}", fd)?,
+        }
+        if self.recursive {
+            write!(fmt, " recursively")?;
+        }
+        write!(fmt, " {}", self.propagation)
+    }
+}
+
+impl Explainable for ChangePropagation {
+    fn explain(&self) -> String {
+        let target = match self.target {
+            Target::Path(ref path) => exists(as_path(path)).to_string(),
+            Target::Fd(fd) => exists(as_path(&fd_path(fd))).to_string(),
+        };
+        [
+            format!("target: {}", target),
+            format!("mntns: {}", mount_namespace()),
+            user().to_string(),
+        ].join(", ")
+    }
+}
+
+impl fmt::Display for Propagation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            Propagation::Shared => "shared",
+            Propagation::Private => "private",
+            Propagation::Slave => "slave",
+            Propagation::Unbindable => "unbindable",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::os::unix::io::{RawFd, AsRawFd};
+    use std::path::Path;
+
+    use libc::c_uint;
+    use nix::Result;
+    use nix::errno::Errno;
+    use nix::mount::MsFlags;
+    use nix::unistd::getuid;
+
+    use {Tmpfs, Unmount};
+    use explain::Explainable;
+    use mountinfo::find_mount_point;
+    use util::test::tmp_dir;
+    use newapi::{PropagationCalls, MountAttr};
+    use newapi::{SETATTR_EMPTY_PATH, AT_RECURSIVE};
+    use super::{Propagation, ChangePropagation, Target};
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<String>>,
+        no_setattr: bool,
+        old_kernel: bool,
+        no_proc: bool,
+    }
+
+    impl PropagationCalls for Recorder {
+        fn mount_setattr(&self, dfd: RawFd, path: &CStr, flags: c_uint,
+            attr: &MountAttr)
+            -> Result<()>
+        {
+            self.calls.borrow_mut().push(format!(
+                "mount_setattr({}, {:?}, {:x}, {:x})",
+                dfd, path, flags, attr.propagation));
+            if self.no_setattr {
+                Err(::nix::Error::Sys(Errno::ENOSYS))
+            } else {
+                Ok(())
+            }
+        }
+        fn mount(&self, target: &CStr, flags: MsFlags) -> Result<()> {
+            self.calls.borrow_mut().push(format!("mount({:?}, {:x})",
+                target, flags.bits()));
+            if self.no_proc {
+                Err(::nix::Error::Sys(Errno::ENOENT))
+            } else {
+                Ok(())
+            }
+        }
+        fn has_mount_setattr(&self) -> bool {
+            !self.old_kernel
+        }
+    }
+
+    fn fd_op(propagation: Propagation, recursive: bool) -> ChangePropagation {
+        ChangePropagation {
+            propagation,
+            target: Target::Fd(7),
+            recursive,
+        }
+    }
+
+    #[test]
+    fn test_attr_translation() {
+        for &(prop, flag) in &[
+            (Propagation::Shared, MsFlags::MS_SHARED),
+            (Propagation::Private, MsFlags::MS_PRIVATE),
+            (Propagation::Slave, MsFlags::MS_SLAVE),
+            (Propagation::Unbindable, MsFlags::MS_UNBINDABLE),
+        ] {
+            assert_eq!(MountAttr::propagation(prop).propagation,
+                       flag.bits());
+            assert_eq!(fd_op(prop, false).flags(), flag);
+            assert_eq!(fd_op(prop, true).flags(), flag | MsFlags::MS_REC);
+        }
+        assert_eq!(fd_op(Propagation::Slave, false).setattr_flags(),
+                   SETATTR_EMPTY_PATH);
+        assert_eq!(fd_op(Propagation::Slave, true).setattr_flags(),
+                   SETATTR_EMPTY_PATH | AT_RECURSIVE);
+    }
+
+    #[test]
+    fn test_fd_setattr() {
+        let calls = Recorder::default();
+        fd_op(Propagation::Private, true).apply_with(&calls).unwrap();
+        assert_eq!(*calls.calls.borrow(), vec![
+            format!("mount_setattr(7, \"\", {:x}, {:x})",
+                    SETATTR_EMPTY_PATH | AT_RECURSIVE,
+                    MsFlags::MS_PRIVATE.bits()),
+        ]);
+    }
+
+    #[test]
+    fn test_fd_fallback() {
+        let calls = Recorder { no_setattr: true, .. Recorder::default() };
+        fd_op(Propagation::Shared, false).apply_with(&calls).unwrap();
+        assert_eq!(*calls.calls.borrow(), vec![
+            format!("mount_setattr(7, \"\", {:x}, {:x})",
+                    SETATTR_EMPTY_PATH, MsFlags::MS_SHARED.bits()),
+            format!("mount(\"/proc/self/fd/7\", {:x})",
+                    MsFlags::MS_SHARED.bits()),
+        ]);
+    }
+
+    #[test]
+    fn test_fd_old_kernel() {
+        let calls = Recorder { old_kernel: true, .. Recorder::default() };
+        fd_op(Propagation::Slave, false).apply_with(&calls).unwrap();
+        assert_eq!(*calls.calls.borrow(), vec![
+            format!("mount(\"/proc/self/fd/7\", {:x})",
+                    MsFlags::MS_SLAVE.bits()),
+        ]);
+    }
+
+    #[test]
+    fn test_fd_old_kernel_backport() {
+        let calls = Recorder { old_kernel: true, no_proc: true,
+                               .. Recorder::default() };
+        fd_op(Propagation::Slave, false).apply_with(&calls).unwrap();
+        assert_eq!(calls.calls.borrow().len(), 2);
+        let calls = Recorder { old_kernel: true, no_proc: true,
+                               no_setattr: true, .. Recorder::default() };
+        assert_eq!(fd_op(Propagation::Slave, false).apply_with(&calls),
+                   Err(::nix::Error::Sys(Errno::ENOENT)));
+    }
+
+    #[test]
+    fn test_fd_display() {
+        assert_eq!(fd_op(Propagation::Slave, false).to_string(),
+                   "make mount fd 7 slave");
+        assert_eq!(fd_op(Propagation::Slave, true).to_string(),
+                   "make mount fd 7 recursively slave");
+    }
+
+    #[test]
+    fn test_tree_private() {
+        let op = ChangePropagation::new(Propagation::Private, Path::new("/"));
+        assert_eq!(op.flags(), MsFlags::MS_REC | MsFlags::MS_PRIVATE);
+        assert_eq!(op.to_string(), "make \"/\" recursively private");
+        assert!(op.explain().contains(", mntns: "));
+    }
+
+    #[test]
+    fn test_from_fd() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("propagation_from_fd");
+        Tmpfs::new(&dir).mount().unwrap();
+        let file = File::open(&dir).unwrap();
+        Propagation::from_fd(file.as_raw_fd())
+            .apply(Propagation::Shared).unwrap();
+        let mnt = find_mount_point(&dir).unwrap().unwrap();
+        assert!(mnt.peer_group().is_some());
+        Propagation::from_fd(file.as_raw_fd())
+            .apply(Propagation::Private).unwrap();
+        let mnt = find_mount_point(&dir).unwrap().unwrap();
+        assert!(mnt.peer_group().is_none());
+        drop(file);
+        Unmount::new(&dir).unmount().unwrap();
+    }
+}
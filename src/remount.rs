@@ -1,29 +1,68 @@
 use std::io;
 use std::fmt;
-use std::ffi::CStr;
+use std::ffi::{CStr, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::env::current_dir;
 use std::default::Default;
+use std::sync::Arc;
 
 use nix::mount::{MsFlags, mount};
 
 use {OSError, Error};
-use util::path_to_cstring;
-use explain::{Explainable, exists, user};
-use mountinfo::{parse_mount_point};
+use util::{path_to_cstring, canonicalize_lenient, format_ms_flags};
+use explain::{Explainable, PathRole, exists, user};
+use mountinfo::{MountPoint, parse_mount_point, parse_mount_id};
+use flags::parse_option_changes;
+
+/// Flags which are used internally by the kernel
+///
+/// Kernel rejects them in a `mount()` call, so they are always masked out
+/// when flags are passed through.
+const KERNEL_ONLY_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_KERNMOUNT.bits() |
+    MsFlags::MS_ACTIVE.bits() |
+    MsFlags::MS_NOUSER.bits());
 
 /// A remount definition
 ///
 /// Usually it is used to change mount flags for a mounted filesystem.
 /// Especially to make a readonly filesystem writable or vice versa.
+///
+/// By default flags are changed relative to the current ones: current flags
+/// are read from mountinfo, flags set to `true` are added, flags set to
+/// `false` are removed and flags that weren't touched are preserved. Use
+/// `clear_all_then()` to set the flags absolutely instead.
+///
+/// If the mount point is a bind mount, `MS_BIND` is added automatically,
+/// so only this mount point is changed rather than the whole filesystem
+/// (which fails for read-only bind mounts of a writable filesystem, and
+/// with `EPERM` in user namespaces). A mount point is considered a bind
+/// mount if its `root` in mountinfo isn't `/`, or if the same device is
+/// mounted somewhere else. Use `bind(false)` to remount the filesystem
+/// itself. Detection is also skipped with `preserve_super_options()` and
+/// for filesystem-specific options of `from_options_str()`, because kernel
+/// ignores the options on bind remount.
 #[derive(Debug, Clone)]
 pub struct Remount {
     path: PathBuf,
+    fd: Option<RawFd>,
     flags: MountFlags,
+    base_flags: Option<MsFlags>,
+    flags_source: Option<PathBuf>,
+    preserve_super_options: bool,
+    data: Option<OsString>,
+    condition: Option<Condition>,
+    verify: bool,
+    strict_preserve: bool,
 }
 
+/// A predicate on current flags of the mount point (see `if_current`)
+#[derive(Clone)]
+struct Condition(Arc<dyn Fn(MsFlags) -> bool + Send + Sync>);
+
 #[derive(Debug, Clone, Default)]
 struct MountFlags {
     pub bind: Option<bool>,
@@ -41,6 +80,30 @@ struct MountFlags {
 }
 
 impl MountFlags {
+    /// Sets (or clears) the state of all the `flags` that can be remounted
+    ///
+    /// Other flags (like `MS_REC` or `MS_REMOUNT`) are ignored.
+    fn set(&mut self, flags: MsFlags, value: bool) {
+        for &mut (flag, ref mut field) in &mut [
+            (MsFlags::MS_BIND, &mut self.bind),
+            (MsFlags::MS_RDONLY, &mut self.readonly),
+            (MsFlags::MS_NODEV, &mut self.nodev),
+            (MsFlags::MS_NOEXEC, &mut self.noexec),
+            (MsFlags::MS_NOSUID, &mut self.nosuid),
+            (MsFlags::MS_NOATIME, &mut self.noatime),
+            (MsFlags::MS_NODIRATIME, &mut self.nodiratime),
+            (MsFlags::MS_RELATIME, &mut self.relatime),
+            (MsFlags::MS_STRICTATIME, &mut self.strictatime),
+            (MsFlags::MS_DIRSYNC, &mut self.dirsync),
+            (MsFlags::MS_SYNCHRONOUS, &mut self.synchronous),
+            (MsFlags::MS_MANDLOCK, &mut self.mandlock),
+        ] {
+            if flags.contains(flag) {
+                **field = Some(value);
+            }
+        }
+    }
+
     fn apply_to_flags(&self, flags: MsFlags) -> MsFlags {
         let mut flags = flags;
         flags = apply_flag(flags, MsFlags::MS_BIND, self.bind);
@@ -71,9 +134,8 @@ quick_error! {
     #[derive(Debug)]
     pub enum RemountError {
         Io(msg: String, err: io::Error) {
-            cause(err)
+            source(err)
             display("{}: {}", msg, err)
-            description(err.description())
             from(err: io::Error) -> (String::new(), err)
         }
         ParseMountInfo(err: String) {
@@ -83,6 +145,14 @@ quick_error! {
         UnknownMountPoint(path: PathBuf) {
             display("Cannot find mount point: {:?}", path)
         }
+        VerificationFailed(path: PathBuf, readonly: bool) {
+            display("Remount of {:?} succeeded but the mount point is {}",
+                path, if *readonly { "writable" } else { "read-only" })
+        }
+        UnmodeledOptions(path: PathBuf, options: Vec<String>) {
+            display("Mount point {:?} has options which would be lost \
+                on remount: {}", path, options.join(","))
+        }
     }
 }
 
@@ -91,91 +161,427 @@ impl Remount {
     ///
     /// By default it doesn't modify any flags. So is basically useless, you
     /// should set some flags to make it effective.
+    ///
+    /// Only the mount at exactly `path` is changed, never the submounts,
+    /// even if they are parts of the same recursive bind mount. Current
+    /// flags are read from the entry with exactly this mount point (the
+    /// top one if it's overmounted).
     pub fn new<A: AsRef<Path>>(path: A) -> Remount {
         Remount {
             path: path.as_ref().to_path_buf(),
+            fd: None,
+            flags: Default::default(),
+            base_flags: None,
+            flags_source: None,
+            preserve_super_options: false,
+            data: None,
+            condition: None,
+            verify: false,
+            strict_preserve: false,
+        }
+    }
+    /// Create a Remount from fstab-style options, like `rw,noexec,nosuid`
+    ///
+    /// Options are parsed by `parse_mount_options()`, but unlike with it,
+    /// options which clear a flag (e.g. `exec`, `rw`) are kept, so they
+    /// clear the flag of the mount point. Flags which aren't mentioned are
+    /// left as is. Filesystem-specific options (e.g. `size=1G` for tmpfs)
+    /// are passed as the data argument, in this case the filesystem is
+    /// remounted rather than the bind mount (unless `bind` is in options).
+    pub fn from_options_str<A: AsRef<Path>>(path: A, options: &str)
+        -> Remount
+    {
+        let (changes, data) = parse_option_changes(options);
+        let mut remount = Remount::new(path);
+        for (flags, value) in changes {
+            remount.flags.set(flags, value);
+        }
+        if !data.is_empty() {
+            remount.data = Some(data);
+        }
+        remount
+    }
+    /// Create a new Remount operation for the mount point opened as `fd`
+    ///
+    /// Unlike `new`, both looking up current flags and the remount itself
+    /// refer to the same mount, even if something is mounted over the path
+    /// or path is replaced in between. The mount is looked up by `mnt_id`
+    /// from `/proc/self/fdinfo` and remounted via `/proc/self/fd/<fd>`.
+    ///
+    /// The file descriptor must be kept open until remount is executed.
+    pub fn from_fd<F: AsRawFd>(fd: &F) -> Remount {
+        let fd = fd.as_raw_fd();
+        Remount {
+            path: PathBuf::from(format!("/proc/self/fd/{}", fd)),
+            fd: Some(fd),
             flags: Default::default(),
+            base_flags: None,
+            flags_source: None,
+            preserve_super_options: false,
+            data: None,
+            condition: None,
+            verify: false,
+            strict_preserve: false,
         }
     }
+    /// Create a Remount that makes `dst` have the same flags as `src`
+    ///
+    /// Flags of `src` are read from mountinfo at the time of remount and
+    /// replace all the flags of `dst` (like `clear_all_then()` does). Flags
+    /// set by other methods are applied on top. Both paths must be mount
+    /// points, otherwise `UnknownMountPoint` error is returned.
+    ///
+    /// Only per-mount flags are changed (i.e. `bind` is set), so the
+    /// filesystem itself isn't switched to read-only. In a user namespace
+    /// inherited mounts have their flags locked (see
+    /// `MountPoint::locked_flags`), so copying flags which clear locked ones
+    /// fails with `EPERM`.
+    pub fn copy_flags_from<A: AsRef<Path>, B: AsRef<Path>>(src: A, dst: B)
+        -> Remount
+    {
+        let mut remount = Remount::new(dst);
+        remount.flags_source = Some(src.as_ref().to_path_buf());
+        remount.set_bind(true);
+        remount
+    }
     /// Set bind flag
     /// Note: remount readonly doesn't work without MS_BIND flag
     /// inside unpriviledged user namespaces
+    ///
+    /// If neither `true` nor `false` is set, the flag is added for bind
+    /// mounts automatically.
     pub fn bind(mut self, flag: bool) -> Remount {
-        self.flags.bind = Some(flag);
+        self.set_bind(flag);
         self
     }
     /// Set readonly flag
     pub fn readonly(mut self, flag: bool) -> Remount {
-        self.flags.readonly = Some(flag);
+        self.set_readonly(flag);
         self
     }
     /// Set nodev flag
     pub fn nodev(mut self, flag: bool) -> Remount {
-        self.flags.nodev = Some(flag);
+        self.set_nodev(flag);
         self
     }
     /// Set noexec flag
     pub fn noexec(mut self, flag: bool) -> Remount {
-        self.flags.noexec = Some(flag);
+        self.set_noexec(flag);
         self
     }
     /// Set nosuid flag
     pub fn nosuid(mut self, flag: bool) -> Remount {
-        self.flags.nosuid = Some(flag);
+        self.set_nosuid(flag);
         self
     }
     /// Set noatime flag
     pub fn noatime(mut self, flag: bool) -> Remount {
-        self.flags.noatime = Some(flag);
+        self.set_noatime(flag);
         self
     }
     /// Set nodiratime flag
     pub fn nodiratime(mut self, flag: bool) -> Remount {
-        self.flags.nodiratime = Some(flag);
+        self.set_nodiratime(flag);
         self
     }
     /// Set relatime flag
     pub fn relatime(mut self, flag: bool) -> Remount {
-        self.flags.relatime = Some(flag);
+        self.set_relatime(flag);
         self
     }
     /// Set strictatime flag
     pub fn strictatime(mut self, flag: bool) -> Remount {
-        self.flags.strictatime = Some(flag);
+        self.set_strictatime(flag);
         self
     }
     /// Set dirsync flag
     pub fn dirsync(mut self, flag: bool) -> Remount {
-        self.flags.dirsync = Some(flag);
+        self.set_dirsync(flag);
         self
     }
     /// Set synchronous flag
     pub fn synchronous(mut self, flag: bool) -> Remount {
-        self.flags.synchronous = Some(flag);
+        self.set_synchronous(flag);
         self
     }
     /// Set mandlock flag
     pub fn mandlock(mut self, flag: bool) -> Remount {
+        self.set_mandlock(flag);
+        self
+    }
+    /// Pass current filesystem-specific options back to the remount
+    ///
+    /// Some filesystems reset options that are not specified on remount to
+    /// defaults. This reads `super_options` from mountinfo and passes them
+    /// as the data argument (except `ro`/`rw`, which are set by flags).
+    ///
+    /// Note: not all options can be changed on remount, but passing
+    /// unchanged value is usually fine. Also some filesystems show options
+    /// in mountinfo in a form that they don't accept, so check it's
+    /// supported by the filesystem you remount.
+    pub fn preserve_super_options(mut self, flag: bool) -> Remount {
+        self.set_preserve_super_options(flag);
+        self
+    }
+
+    /// Start from `flags` instead of the current flags of the mount point
+    ///
+    /// I.e. all the current flags are cleared, then `flags` and the flags
+    /// set by other methods are applied. Note, if no atime flags are set,
+    /// kernel uses `relatime`.
+    ///
+    /// Kernel-internal flags (`MS_KERNMOUNT`, `MS_ACTIVE`, `MS_NOUSER`) are
+    /// masked out, so it's safe to pass flags obtained elsewhere.
+    pub fn clear_all_then(mut self, flags: MsFlags) -> Remount {
+        self.set_clear_all_then(flags);
+        self
+    }
+
+    /// Only remount if `predicate` holds for the current flags
+    ///
+    /// The predicate is called with the flags of the mount point read from
+    /// mountinfo right before the remount. If it returns `false` the remount
+    /// is skipped and `Ok(())` is returned. E.g. to make the mount point
+    /// read-only only if it's writable now:
+    ///
+    /// ```ignore
+    /// Remount::new("/mnt").readonly(true)
+    ///     .if_current(|flags| !flags.contains(MsFlags::MS_RDONLY))
+    /// ```
+    pub fn if_current<F>(mut self, predicate: F) -> Remount
+        where F: Fn(MsFlags) -> bool + Send + Sync + 'static
+    {
+        self.set_if_current(predicate);
+        self
+    }
+
+    /// Check that read-only state has changed after the remount
+    ///
+    /// Mountinfo is read again after the remount and if `MS_RDONLY` of the
+    /// mount point doesn't match the flags passed to the kernel,
+    /// `VerificationFailed` error is returned. This may happen in user
+    /// namespaces and with some filesystems, where remount succeeds but
+    /// the flag isn't applied.
+    pub fn verify(mut self, flag: bool) -> Remount {
+        self.set_verify(flag);
+        self
+    }
+
+    /// Refuse to remount if some current options can't be preserved
+    ///
+    /// Only flags from `MsFlags` are read from mountinfo and passed back
+    /// to the kernel, so per-mount options the crate doesn't model (like
+    /// `nosymfollow`) would be silently dropped by remount. With this
+    /// flag set, `UnmodeledOptions` error is returned instead.
+    pub fn strict_preserve(mut self, flag: bool) -> Remount {
+        self.set_strict_preserve(flag);
+        self
+    }
+
+    /// Set bind flag (in-place version of `bind`)
+    pub fn set_bind(&mut self, flag: bool) -> &mut Remount {
+        self.flags.bind = Some(flag);
+        self
+    }
+    /// Set readonly flag (in-place version of `readonly`)
+    pub fn set_readonly(&mut self, flag: bool) -> &mut Remount {
+        self.flags.readonly = Some(flag);
+        self
+    }
+    /// Set nodev flag (in-place version of `nodev`)
+    pub fn set_nodev(&mut self, flag: bool) -> &mut Remount {
+        self.flags.nodev = Some(flag);
+        self
+    }
+    /// Set noexec flag (in-place version of `noexec`)
+    pub fn set_noexec(&mut self, flag: bool) -> &mut Remount {
+        self.flags.noexec = Some(flag);
+        self
+    }
+    /// Set nosuid flag (in-place version of `nosuid`)
+    pub fn set_nosuid(&mut self, flag: bool) -> &mut Remount {
+        self.flags.nosuid = Some(flag);
+        self
+    }
+    /// Set noatime flag (in-place version of `noatime`)
+    pub fn set_noatime(&mut self, flag: bool) -> &mut Remount {
+        self.flags.noatime = Some(flag);
+        self
+    }
+    /// Set nodiratime flag (in-place version of `nodiratime`)
+    pub fn set_nodiratime(&mut self, flag: bool) -> &mut Remount {
+        self.flags.nodiratime = Some(flag);
+        self
+    }
+    /// Set relatime flag (in-place version of `relatime`)
+    pub fn set_relatime(&mut self, flag: bool) -> &mut Remount {
+        self.flags.relatime = Some(flag);
+        self
+    }
+    /// Set strictatime flag (in-place version of `strictatime`)
+    pub fn set_strictatime(&mut self, flag: bool) -> &mut Remount {
+        self.flags.strictatime = Some(flag);
+        self
+    }
+    /// Set dirsync flag (in-place version of `dirsync`)
+    pub fn set_dirsync(&mut self, flag: bool) -> &mut Remount {
+        self.flags.dirsync = Some(flag);
+        self
+    }
+    /// Set synchronous flag (in-place version of `synchronous`)
+    pub fn set_synchronous(&mut self, flag: bool) -> &mut Remount {
+        self.flags.synchronous = Some(flag);
+        self
+    }
+    /// Set mandlock flag (in-place version of `mandlock`)
+    pub fn set_mandlock(&mut self, flag: bool) -> &mut Remount {
         self.flags.mandlock = Some(flag);
         self
     }
 
+    /// Pass current filesystem-specific options back to the remount
+    /// (in-place version of `preserve_super_options`)
+    pub fn set_preserve_super_options(&mut self, flag: bool) -> &mut Remount {
+        self.preserve_super_options = flag;
+        self
+    }
+
+    /// Start from `flags` instead of the current flags
+    /// (in-place version of `clear_all_then`)
+    pub fn set_clear_all_then(&mut self, flags: MsFlags) -> &mut Remount {
+        self.base_flags = Some(flags);
+        self
+    }
+
+    /// Only remount if `predicate` holds for the current flags
+    /// (in-place version of `if_current`)
+    pub fn set_if_current<F>(&mut self, predicate: F) -> &mut Remount
+        where F: Fn(MsFlags) -> bool + Send + Sync + 'static
+    {
+        self.condition = Some(Condition(Arc::new(predicate)));
+        self
+    }
+
+    /// Check read-only state after the remount
+    /// (in-place version of `verify`)
+    pub fn set_verify(&mut self, flag: bool) -> &mut Remount {
+        self.verify = flag;
+        self
+    }
+
+    /// Refuse to drop unmodeled options
+    /// (in-place version of `strict_preserve`)
+    pub fn set_strict_preserve(&mut self, flag: bool) -> &mut Remount {
+        self.strict_preserve = flag;
+        self
+    }
+
+    /// Set all the flags present in `flags`, others are left untouched
+    pub(crate) fn set_flags(&mut self, flags: MsFlags) -> &mut Remount {
+        self.flags.set(flags, true);
+        self
+    }
+
+    /// Flags passed to `mount()` given the current flags of the mount point
+    /// and the flags of the `copy_flags_from` source
+    fn remount_flags(&self, current: MsFlags, source: Option<MsFlags>)
+        -> MsFlags
+    {
+        let base = source.or(self.base_flags).unwrap_or(current);
+        (self.flags.apply_to_flags(base) | MsFlags::MS_REMOUNT)
+            - KERNEL_ONLY_FLAGS
+    }
+
+    /// Adds `MS_BIND` for a bind mount unless bind flag is set explicitly
+    fn auto_bind(&self, flags: MsFlags, is_bind: bool) -> MsFlags {
+        if is_bind && self.flags.bind.is_none()
+            && !self.preserve_super_options && self.data.is_none()
+        {
+            flags | MsFlags::MS_BIND
+        } else {
+            flags
+        }
+    }
+
+    /// Returns `false` if the remount should be skipped because of
+    /// `if_current` predicate
+    fn condition_holds(&self, current: MsFlags) -> bool {
+        match self.condition {
+            Some(ref cond) => (cond.0)(current),
+            None => true,
+        }
+    }
+
     /// Execute a remount
+    ///
+    /// Returns `Ok(())` without remounting if `if_current` predicate is
+    /// `false`.
     pub fn bare_remount(self) -> Result<(), OSError> {
-        let mut flags = match get_mountpoint_flags(&self.path) {
-            Ok(flags) => flags,
+        let mount_point = match self.fd {
+            Some(fd) => get_mountpoint_by_fd(fd),
+            None => get_mountpoint(&self.path),
+        };
+        let mount_point = match mount_point {
+            Ok(mount_point) => mount_point,
             Err(e) => {
                 return Err(OSError::from_remount(e, Box::new(self)));
             },
         };
-        flags = self.flags.apply_to_flags(flags) | MsFlags::MS_REMOUNT;
-        mount(
+        if self.strict_preserve {
+            if let Err(e) = check_modeled(&self.path, &mount_point) {
+                return Err(OSError::from_remount(e, Box::new(self)));
+            }
+        }
+        if !self.condition_holds(mount_point.get_mount_flags()) {
+            return Ok(());
+        }
+        let source = match self.flags_source {
+            Some(ref src) => match get_mountpoint_flags(src) {
+                Ok(flags) => Some(flags),
+                Err(e) => {
+                    return Err(OSError::from_remount(e, Box::new(self)));
+                }
+            },
+            None => None,
+        };
+        let flags = self.remount_flags(mount_point.get_mount_flags(), source);
+        let flags = self.auto_bind(flags, detect_bind_mount(&mount_point));
+        let mut data = if self.preserve_super_options {
+            Some(remount_data(&mount_point.super_options))
+        } else {
+            None
+        };
+        if let Some(ref extra) = self.data {
+            // explicit options go last, so they override preserved ones
+            let data = data.get_or_insert_with(Vec::new);
+            if !data.is_empty() {
+                data.push(b',');
+            }
+            data.extend(extra.as_bytes());
+        }
+        if let Err(err) = mount(
             None::<&CStr>,
             &*path_to_cstring(&self.path),
             None::<&CStr>,
             flags,
-            None::<&CStr>,
-        ).map_err(|err| OSError::from_nix(err, Box::new(self)))
+            data.as_ref().map(|x| &x[..]),
+        ) {
+            return Err(OSError::from_nix(err, Box::new(self)));
+        }
+        if self.verify {
+            if let Err(e) = self.verify_flags(flags) {
+                return Err(OSError::from_remount(e, Box::new(self)));
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_flags(&self, requested: MsFlags) -> Result<(), RemountError> {
+        let actual = match self.fd {
+            Some(fd) => get_mountpoint_by_fd(fd),
+            None => get_mountpoint(&self.path),
+        }?.get_mount_flags();
+        check_readonly(&self.path, requested, actual)
     }
 
     /// Execute a remount and explain the error immediately
@@ -247,40 +653,142 @@ impl fmt::Display for Remount {
     }
 }
 
+impl Remount {
+    /// Flags which `bare_remount` would pass to `mount()` now
+    fn current_remount_flags(&self) -> Result<MsFlags, RemountError> {
+        let mount_point = match self.fd {
+            Some(fd) => get_mountpoint_by_fd(fd),
+            None => get_mountpoint(&self.path),
+        }?;
+        let source = match self.flags_source {
+            Some(ref src) => Some(get_mountpoint_flags(src)?),
+            None => None,
+        };
+        let flags = self.remount_flags(mount_point.get_mount_flags(), source);
+        Ok(self.auto_bind(flags, detect_bind_mount(&mount_point)))
+    }
+}
+
+impl fmt::Debug for Condition {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Condition")
+    }
+}
+
 impl Explainable for Remount {
     fn explain(&self) -> String {
-        [
-            format!("path: {}", exists(&self.path)),
-            format!("{}", user()),
-        ].join(", ")
+        let mut info = vec![format!("path: {}", exists(&self.path))];
+        if let Ok(flags) = self.current_remount_flags() {
+            info.push(format!("flags: {}", format_ms_flags(flags)));
+        }
+        info.push(user().to_string());
+        info.join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, &self.path)]
     }
 }
 
-fn get_mountpoint_flags(path: &Path) -> Result<MsFlags, RemountError> {
-    let mount_path = if path.is_absolute() {
-        path.to_path_buf()
-    } else {
-        let mut mpath = try!(current_dir());
-        mpath.push(path);
-        mpath
-    };
-    let mut mountinfo_content = Vec::with_capacity(4 * 1024);
-    let mountinfo_path = Path::new("/proc/self/mountinfo");
-    let mut mountinfo_file = try!(File::open(mountinfo_path)
-        .map_err(|e| RemountError::Io(
-            format!("Cannot open file: {:?}", mountinfo_path), e)));
-    try!(mountinfo_file.read_to_end(&mut mountinfo_content)
-        .map_err(|e| RemountError::Io(
-            format!("Cannot read file: {:?}", mountinfo_path), e)));
-    match get_mountpoint_flags_from(&mountinfo_content, &mount_path) {
-        Ok(Some(flags)) => Ok(flags),
+pub(crate) fn get_mountpoint_flags(path: &Path) -> Result<MsFlags, RemountError> {
+    get_mountpoint(path).map(|m| m.get_mount_flags())
+}
+
+pub(crate) fn get_mountpoint(path: &Path)
+    -> Result<MountPoint<'static>, RemountError>
+{
+    let mount_path = canonicalize_lenient(path);
+    let mountinfo_content = read_file(Path::new("/proc/self/mountinfo"))?;
+    match get_mountpoint_from(&mountinfo_content, &mount_path) {
+        Ok(Some(mount_point)) => Ok(mount_point.into_owned()),
         Ok(None) => Err(RemountError::UnknownMountPoint(mount_path)),
         Err(e) => Err(e),
     }
 }
 
+fn get_mountpoint_by_fd(fd: RawFd)
+    -> Result<MountPoint<'static>, RemountError>
+{
+    let fdinfo_path = PathBuf::from(format!("/proc/self/fdinfo/{}", fd));
+    let mount_id = match parse_mount_id(&read_file(&fdinfo_path)?) {
+        Some(mount_id) => mount_id,
+        None => return Err(RemountError::ParseMountInfo(
+            format!("No mnt_id in {:?}", fdinfo_path))),
+    };
+    let mountinfo_content = read_file(Path::new("/proc/self/mountinfo"))?;
+    for line in mountinfo_content.split(|c| *c == b'\n') {
+        let entry = parse_mount_point(line)
+            .map_err(|e| RemountError::ParseMountInfo(e.0))?;
+        if let Some(mount_point) = entry {
+            if mount_point.mount_id == mount_id {
+                return Ok(mount_point.into_owned());
+            }
+        }
+    }
+    Err(RemountError::UnknownMountPoint(
+        PathBuf::from(format!("/proc/self/fd/{}", fd))))
+}
+
+fn check_modeled(path: &Path, mount_point: &MountPoint)
+    -> Result<(), RemountError>
+{
+    let options = mount_point.unmodeled_mount_options();
+    if options.is_empty() {
+        Ok(())
+    } else {
+        Err(RemountError::UnmodeledOptions(path.to_path_buf(), options))
+    }
+}
+
+fn check_readonly(path: &Path, requested: MsFlags, actual: MsFlags)
+    -> Result<(), RemountError>
+{
+    let readonly = requested.contains(MsFlags::MS_RDONLY);
+    if actual.contains(MsFlags::MS_RDONLY) != readonly {
+        return Err(RemountError::VerificationFailed(
+            path.to_path_buf(), readonly));
+    }
+    Ok(())
+}
+
+/// Checks whether the mount point is a bind mount (see `Remount` docs)
+///
+/// If mountinfo can't be read, only `root` is checked.
+fn detect_bind_mount(mount_point: &MountPoint) -> bool {
+    read_file(Path::new("/proc/self/mountinfo"))
+        .map(|content| is_bind_mount_from(&content, mount_point))
+        .unwrap_or_else(|_| mount_point.root != OsStr::new("/"))
+}
+
+fn is_bind_mount_from(content: &[u8], mount_point: &MountPoint) -> bool {
+    mount_point.root != OsStr::new("/") ||
+    content.split(|c| *c == b'\n')
+        .filter_map(|line| parse_mount_point(line).ok().and_then(|x| x))
+        .any(|m| m.major == mount_point.major &&
+                 m.minor == mount_point.minor &&
+                 m.mount_id != mount_point.mount_id)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, RemountError> {
+    let mut content = Vec::with_capacity(4 * 1024);
+    let mut file = File::open(path)
+        .map_err(|e| RemountError::Io(
+            format!("Cannot open file: {:?}", path), e))?;
+    file.read_to_end(&mut content)
+        .map_err(|e| RemountError::Io(
+            format!("Cannot read file: {:?}", path), e))?;
+    Ok(content)
+}
+
+#[cfg(test)]
 fn get_mountpoint_flags_from(content: &[u8], path: &Path)
     -> Result<Option<MsFlags>, RemountError>
+{
+    get_mountpoint_from(content, path)
+        .map(|m| m.map(|m| m.get_mount_flags()))
+}
+
+fn get_mountpoint_from<'a>(content: &'a [u8], path: &Path)
+    -> Result<Option<MountPoint<'a>>, RemountError>
 {
     // iterate from the end of the mountinfo file
     for line in content.split(|c| *c == b'\n').rev() {
@@ -288,24 +796,48 @@ fn get_mountpoint_flags_from(content: &[u8], path: &Path)
             .map_err(|e| RemountError::ParseMountInfo(e.0))?;
         if let Some(mount_point) = entry {
             if mount_point.mount_point == path {
-                return Ok(Some(mount_point.get_mount_flags()));
+                return Ok(Some(mount_point));
             }
         }
     }
     Ok(None)
 }
 
+/// Filter super options which can be passed back to remount
+///
+/// `ro` and `rw` are dropped because read-only state is controlled by flags.
+fn remount_data(super_options: &OsStr) -> Vec<u8> {
+    let mut data = Vec::new();
+    for opt in super_options.as_bytes().split(|c| *c == b',') {
+        if opt.is_empty() || opt == b"ro" || opt == b"rw" {
+            continue;
+        }
+        if !data.is_empty() {
+            data.push(b',');
+        }
+        data.extend(opt);
+    }
+    data
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
-    use std::ffi::OsStr;
+    use std::ffi::{OsStr, OsString};
+    use std::fs::{File, create_dir, create_dir_all};
     use std::os::unix::ffi::OsStrExt;
 
     use nix::mount::MsFlags;
+    use nix::unistd::getuid;
 
-    use Error;
+    use {Error, Tmpfs, Unmount, BindMount};
+    use explain::Explainable;
+    use mountinfo::{mounts_under, Parser};
+    use util::test::tmp_dir;
     use super::{Remount, RemountError, MountFlags};
     use super::{get_mountpoint_flags, get_mountpoint_flags_from};
+    use super::{get_mountpoint_from, remount_data, is_bind_mount_from};
+    use super::{check_readonly, check_modeled};
 
     #[test]
     fn test_mount_flags() {
@@ -358,6 +890,32 @@ mod test {
         assert_eq!(format!("{}", remount), "ro,nodev remount \"/\"");
     }
 
+    #[test]
+    fn test_explain_flags() {
+        let explained = Remount::new("/").readonly(true).explain();
+        assert!(explained.contains("flags: MS_RDONLY|"), "{}", explained);
+        assert!(explained.contains("MS_REMOUNT"), "{}", explained);
+        let explained = Remount::new("/").clear_all_then(MsFlags::MS_NOEXEC)
+            .explain();
+        assert!(explained.contains("flags: MS_NOEXEC|MS_REMOUNT,"),
+                "{}", explained);
+        let explained = Remount::new("/nonexistent").explain();
+        assert!(!explained.contains("flags:"), "{}", explained);
+    }
+
+    #[test]
+    fn test_remount_setters() {
+        let mut remount = Remount::new("/");
+        remount.set_readonly(true).set_nodev(true);
+        remount.set_noexec(false);
+        let chained = Remount::new("/").readonly(true).nodev(true).noexec(false);
+        assert_eq!(format!("{}", remount), format!("{}", chained));
+        assert_eq!(remount.flags.apply_to_flags(MsFlags::MS_NOEXEC),
+                   chained.flags.apply_to_flags(MsFlags::MS_NOEXEC));
+        assert_eq!(remount.flags.apply_to_flags(MsFlags::MS_NOEXEC),
+                   MsFlags::MS_RDONLY | MsFlags::MS_NODEV);
+    }
+
     #[test]
     fn test_get_mountpoint_flags_from() {
         let content = b"19 24 0:4 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw";
@@ -373,6 +931,326 @@ mod test {
         assert_eq!(flags, MsFlags::MS_NOSUID | MsFlags::MS_NODEV);
     }
 
+    #[test]
+    fn test_nested_binds() {
+        // recursive bind of /srv to /a, all entries on the same device,
+        // the submount is listed before its parent
+        let content = b"\
+            22 1 8:1 / / rw,relatime - ext4 /dev/sda1 rw\n\
+            41 42 8:1 /srv/b/c /a/b/c rw,nosuid,noexec - ext4 /dev/sda1 rw\n\
+            40 22 8:1 /srv /a rw,nosuid,noexec - ext4 /dev/sda1 rw\n\
+            42 40 8:1 /srv/b /a/b rw,nosuid - ext4 /dev/sda1 rw\n";
+        let get = |path: &str| get_mountpoint_from(&content[..],
+            Path::new(path)).unwrap().unwrap();
+        assert_eq!(get("/a").mount_id, 40);
+        assert_eq!(get("/a/b").mount_id, 42);
+        assert_eq!(get("/a/b/c").mount_id, 41);
+        assert!(get_mountpoint_from(&content[..], Path::new("/a/b/d"))
+                .unwrap().is_none());
+
+        let mount_point = get("/a/b");
+        let remount = Remount::new("/a/b").noexec(false);
+        let flags = remount.auto_bind(
+            remount.remount_flags(mount_point.get_mount_flags(), None),
+            is_bind_mount_from(&content[..], &mount_point));
+        assert_eq!(flags, MsFlags::MS_REMOUNT | MsFlags::MS_BIND |
+                          MsFlags::MS_NOSUID);
+        assert!(!flags.contains(MsFlags::MS_REC));
+    }
+
+    #[test]
+    fn test_remount_submount() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("remount_submount");
+        let src = dir.join("src");
+        let a = dir.join("a");
+        create_dir_all(src.join("b/c")).unwrap();
+        create_dir(&a).unwrap();
+        BindMount::new(&src, &src).mount().unwrap();
+        BindMount::new(src.join("b"), src.join("b")).mount().unwrap();
+        BindMount::new(src.join("b/c"), src.join("b/c")).mount().unwrap();
+        // recursive bind, the remount by with_flags() changes /a only
+        BindMount::new(&src, &a).with_flags(MsFlags::MS_NOEXEC)
+            .mount().unwrap();
+        let noexec = |path: &Path| get_mountpoint_flags(path).unwrap()
+            .contains(MsFlags::MS_NOEXEC);
+        assert!(noexec(&a));
+        assert!(!noexec(&a.join("b")));
+        Remount::new(a.join("b")).noexec(true).remount().unwrap();
+        assert!(noexec(&a));
+        assert!(noexec(&a.join("b")));
+        assert!(!noexec(&a.join("b/c")));
+        Remount::new(a.join("b")).noexec(false).remount().unwrap();
+        assert!(noexec(&a));
+        assert!(!noexec(&a.join("b")));
+        assert!(!noexec(&a.join("b/c")));
+        Unmount::recursive(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_from_options_str() {
+        let remount = Remount::from_options_str("/x",
+            "rw,exec,nosuid,defaults");
+        let current = MsFlags::MS_RDONLY | MsFlags::MS_NOEXEC |
+                      MsFlags::MS_NODEV;
+        assert_eq!(remount.remount_flags(current, None),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_NOSUID |
+                   MsFlags::MS_NODEV);
+        assert_eq!(remount.data, None);
+        assert!(remount.auto_bind(MsFlags::MS_REMOUNT, true)
+                .contains(MsFlags::MS_BIND));
+        assert_eq!(remount.to_string(), "nosuid remount \"/x\"");
+
+        // filesystem options are passed to the filesystem, not bind mount
+        let remount = Remount::from_options_str("/x",
+            "ro,size=1G,nr_inodes=0");
+        assert_eq!(remount.data, Some(OsString::from("size=1G,nr_inodes=0")));
+        assert_eq!(remount.auto_bind(MsFlags::MS_REMOUNT, true),
+                   MsFlags::MS_REMOUNT);
+        let remount = Remount::from_options_str("/x", "bind,ro");
+        assert_eq!(remount.remount_flags(MsFlags::empty(), None),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_BIND |
+                   MsFlags::MS_RDONLY);
+    }
+
+    #[test]
+    fn test_remount_options_str() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("remount_options_str");
+        Tmpfs::new(&dir).size_bytes(1 << 20).mount().unwrap();
+        Remount::from_options_str(&dir, "noexec,nodev,size=2m")
+            .remount().unwrap();
+        let mounts = mounts_under(&dir).unwrap();
+        assert!(mounts[0].get_mount_flags()
+                .contains(MsFlags::MS_NOEXEC | MsFlags::MS_NODEV));
+        assert!(mounts[0].super_options.to_str().unwrap()
+                .contains("size=2048k"));
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_auto_bind() {
+        let content = b"\
+            22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            30 22 8:2 / /home rw,relatime shared:2 - ext4 /dev/sda2 rw\n\
+            31 22 8:1 /srv/data /data rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            32 22 0:40 / /tmp rw shared:3 - tmpfs tmpfs rw\n\
+            33 22 0:40 / /var/tmp rw shared:3 - tmpfs tmpfs rw\n";
+        let bind = |path: &str| {
+            let mp = get_mountpoint_from(&content[..], Path::new(path))
+                .unwrap().unwrap();
+            is_bind_mount_from(&content[..], &mp)
+        };
+        assert!(!bind("/home"));
+        // subtree of the filesystem
+        assert!(bind("/data"));
+        // the same device mounted twice
+        assert!(bind("/tmp"));
+        assert!(bind("/var/tmp"));
+
+        let remount = Remount::new("/data").readonly(true);
+        let flags = remount.remount_flags(MsFlags::empty(), None);
+        assert_eq!(remount.auto_bind(flags, true),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY);
+        assert_eq!(remount.auto_bind(flags, false),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY);
+        let remount = remount.bind(false);
+        let flags = remount.remount_flags(MsFlags::empty(), None);
+        assert_eq!(remount.auto_bind(flags, true),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY);
+        let remount = Remount::new("/data").preserve_super_options(true);
+        let flags = remount.remount_flags(MsFlags::empty(), None);
+        assert_eq!(remount.auto_bind(flags, true), MsFlags::MS_REMOUNT);
+    }
+
+    #[test]
+    fn test_remount_data() {
+        let content = b"76 24 8:6 / /home rw,relatime shared:29 - ext4 /dev/sda1 rw,data=ordered,commit=30";
+        let mount_point = get_mountpoint_from(&content[..], Path::new("/home"))
+            .unwrap().unwrap();
+        assert_eq!(remount_data(&mount_point.super_options),
+                   b"data=ordered,commit=30");
+        assert_eq!(remount_data(OsStr::new("ro")), b"");
+    }
+
+    #[test]
+    fn test_relative_and_absolute_flags() {
+        let content = b"76 24 8:6 / /home rw,nosuid,nodev,relatime - ext4 /dev/sda1 rw";
+        let current = get_mountpoint_from(&content[..], Path::new("/home"))
+            .unwrap().unwrap().get_mount_flags();
+
+        let relative = Remount::new("/home").readonly(true).nodev(false);
+        assert_eq!(relative.remount_flags(current, None),
+            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID |
+            MsFlags::MS_RELATIME);
+
+        let absolute = Remount::new("/home").readonly(true).nodev(false)
+            .clear_all_then(MsFlags::MS_NOEXEC);
+        assert_eq!(absolute.remount_flags(current, None),
+            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_NOEXEC);
+
+        let cleared = Remount::new("/home")
+            .clear_all_then(MsFlags::empty());
+        assert_eq!(cleared.remount_flags(current, None), MsFlags::MS_REMOUNT);
+    }
+
+    #[test]
+    fn test_kernel_only_flags() {
+        let remount = Remount::new("/").clear_all_then(
+            MsFlags::MS_RDONLY | MsFlags::MS_KERNMOUNT | MsFlags::MS_ACTIVE |
+            MsFlags::MS_NOUSER);
+        assert_eq!(remount.remount_flags(MsFlags::empty(), None),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY);
+        let current = MsFlags::MS_NOSUID | MsFlags::MS_ACTIVE;
+        assert_eq!(Remount::new("/").remount_flags(current, Some(current)),
+                   MsFlags::MS_REMOUNT | MsFlags::MS_NOSUID);
+    }
+
+    #[test]
+    fn test_copy_flags_from() {
+        let content = b"\
+            76 24 8:6 / /a ro,nosuid,nodev,noatime - ext4 /dev/sda1 ro\n\
+            77 24 8:7 / /b rw,noexec,relatime - ext4 /dev/sda2 rw";
+        let src = get_mountpoint_flags_from(&content[..], Path::new("/a"))
+            .unwrap();
+        let dst = get_mountpoint_flags_from(&content[..], Path::new("/b"))
+            .unwrap().unwrap();
+        assert_eq!(
+            Remount::copy_flags_from("/a", "/b").remount_flags(dst, src),
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY |
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOATIME);
+        assert_eq!(
+            Remount::copy_flags_from("/a", "/b").readonly(false)
+                .remount_flags(dst, src),
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND |
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOATIME);
+        assert_eq!(
+            get_mountpoint_flags_from(&content[..], Path::new("/c")).unwrap(),
+            None);
+    }
+
+    #[test]
+    fn test_copy_flags_from_missing() {
+        let err = Remount::copy_flags_from("/nonexistent-src", "/")
+            .remount().unwrap_err();
+        assert!(err.to_string().contains(
+            "Cannot find mount point: \"/nonexistent-src\""), "{}", err);
+    }
+
+    #[test]
+    fn test_if_current() {
+        let rdonly = |flags: MsFlags| flags.contains(MsFlags::MS_RDONLY);
+        let remount = Remount::new("/").readonly(false).if_current(rdonly);
+        assert!(remount.condition_holds(MsFlags::MS_RDONLY));
+        assert!(!remount.condition_holds(MsFlags::MS_NOSUID));
+        assert!(Remount::new("/").condition_holds(MsFlags::empty()));
+
+        // would fail for non-root and make root read-only if not skipped
+        Remount::new("/").readonly(true).if_current(|_| false)
+            .remount().unwrap();
+    }
+
+    #[test]
+    fn test_if_current_remount() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("remount_if_current");
+        Tmpfs::new(&dir).mount().unwrap();
+        let writable = |flags: MsFlags| !flags.contains(MsFlags::MS_RDONLY);
+        Remount::new(&dir).noexec(true).if_current(|_| false)
+            .remount().unwrap();
+        assert!(!get_mountpoint_flags(&dir).unwrap()
+                .contains(MsFlags::MS_NOEXEC));
+        Remount::new(&dir).readonly(true).if_current(writable)
+            .remount().unwrap();
+        assert!(get_mountpoint_flags(&dir).unwrap()
+                .contains(MsFlags::MS_RDONLY));
+        // already read-only, so `noexec` isn't applied
+        Remount::new(&dir).readonly(true).noexec(true).if_current(writable)
+            .remount().unwrap();
+        assert!(!get_mountpoint_flags(&dir).unwrap()
+                .contains(MsFlags::MS_NOEXEC));
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_check_modeled() {
+        let content = b"19 1 0:4 / /mnt rw,nosuid,lazytime,nosymfollow \
+                        - ext4 /dev/sda1 rw\n\
+                        20 1 0:5 / /tmp ro,nodev,noexec,relatime \
+                        - tmpfs tmpfs rw";
+        let entries = Parser::new(&content[..])
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        match check_modeled(Path::new("/mnt"), &entries[0]) {
+            Err(e @ RemountError::UnmodeledOptions(..)) => {
+                assert_eq!(e.to_string(), "Mount point \"/mnt\" has options \
+                    which would be lost on remount: lazytime,nosymfollow");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        check_modeled(Path::new("/tmp"), &entries[1]).unwrap();
+    }
+
+    #[test]
+    fn test_check_readonly() {
+        let path = Path::new("/mnt");
+        let ro = MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+        let rw = MsFlags::MS_REMOUNT;
+        assert!(check_readonly(path, ro, MsFlags::MS_RDONLY).is_ok());
+        assert!(check_readonly(path, rw, MsFlags::MS_NOSUID).is_ok());
+        match check_readonly(path, ro, MsFlags::MS_NOSUID) {
+            Err(e @ RemountError::VerificationFailed(_, true)) => {
+                assert_eq!(e.to_string(), "Remount of \"/mnt\" \
+                    succeeded but the mount point is writable");
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        match check_readonly(path, rw, MsFlags::MS_RDONLY) {
+            Err(RemountError::VerificationFailed(_, false)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_verify_remount() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("remount_verify");
+        Tmpfs::new(&dir).mount().unwrap();
+        Remount::new(&dir).readonly(true).verify(true).remount().unwrap();
+        assert!(get_mountpoint_flags(&dir).unwrap()
+                .contains(MsFlags::MS_RDONLY));
+        Remount::new(&dir).readonly(false).verify(true).remount().unwrap();
+        assert!(!get_mountpoint_flags(&dir).unwrap()
+                .contains(MsFlags::MS_RDONLY));
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_remount_from_fd() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("remount_from_fd");
+        Tmpfs::new(&dir).mount().unwrap();
+        let file = File::open(&dir).unwrap();
+        // shadow the mount, so the path refers to the other one
+        Tmpfs::new(&dir).mount().unwrap();
+        Remount::from_fd(&file).readonly(true).remount().unwrap();
+        let mounts = mounts_under(&dir).unwrap();
+        assert_eq!(mounts.len(), 2);
+        assert!(mounts[0].get_mount_flags().contains(MsFlags::MS_RDONLY));
+        assert!(!mounts[1].get_mount_flags().contains(MsFlags::MS_RDONLY));
+        drop(file);
+        Unmount::recursive(&dir).unmount().unwrap();
+    }
+
     #[test]
     fn test_get_mountpoint_flags() {
         assert!(get_mountpoint_flags(Path::new("/")).is_ok());
@@ -392,7 +1270,7 @@ mod test {
     fn test_remount_unknown_mountpoint() {
         let remount = Remount::new("/non-existent");
         let error = remount.remount().unwrap_err();
-        let Error(_, e, msg) = error;
+        let Error(_, e, msg, _) = error;
         match e.get_ref() {
             Some(e) => {
                 assert_eq!(
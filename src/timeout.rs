@@ -0,0 +1,192 @@
+use std::fmt;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use {OSError, Error, Mountable};
+use explain::Explainable;
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum TimeoutError {
+        MountTimeout(timeout: Duration) {
+            display("Operation hasn't finished in {:?}, \
+                it's left running in background", timeout)
+        }
+        ThreadPanicked {
+            display("Operation has panicked in the background thread")
+        }
+    }
+}
+
+/// An operation which is executed in a separate thread with a timeout
+///
+/// This is useful for network filesystems (like NFS or CIFS): if server is
+/// unreachable, `mount()` system call may hang for a long time and it can't
+/// be interrupted. When timeout expires `MountTimeout` error is returned
+/// (its kind is `io::ErrorKind::TimedOut`). If the operation panics, the
+/// panic isn't propagated, `ThreadPanicked` error is returned instead.
+///
+/// Note: the system call can't be cancelled, so it's still blocked in the
+/// background thread after timeout. The thread is detached and the
+/// operation may eventually succeed, so you might want to unmount the target
+/// later. This also means the operation is cloned: one copy is moved to the
+/// thread and the other one is used to explain the error.
+#[derive(Debug, Clone)]
+pub struct WithTimeout<M> {
+    operation: M,
+    timeout: Duration,
+}
+
+impl<M> WithTimeout<M>
+    where M: Mountable + Explainable + Clone + Send + 'static
+{
+    /// Wrap the `operation` to fail if it doesn't finish in `timeout`
+    pub fn new(operation: M, timeout: Duration) -> WithTimeout<M> {
+        WithTimeout { operation, timeout }
+    }
+
+    /// Execute the operation
+    pub fn bare_perform(self) -> Result<(), OSError> {
+        let (tx, rx) = channel();
+        let operation = Box::new(self.operation.clone());
+        thread::spawn(move || {
+            // receiver may be gone already if timeout has expired
+            tx.send(operation.bare_perform()).ok();
+        });
+        match rx.recv_timeout(self.timeout) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(e),
+            Err(RecvTimeoutError::Timeout) => Err(OSError::from_timeout(
+                TimeoutError::MountTimeout(self.timeout),
+                Box::new(self.operation))),
+            Err(RecvTimeoutError::Disconnected) => Err(OSError::from_timeout(
+                TimeoutError::ThreadPanicked,
+                Box::new(self.operation))),
+        }
+    }
+
+    /// Execute the operation and explain the error immediately
+    pub fn perform(self) -> Result<(), Error> {
+        self.bare_perform().map_err(OSError::explain)
+    }
+}
+
+impl<M> Mountable for WithTimeout<M>
+    where M: Mountable + Explainable + Clone + Send + 'static
+{
+    fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+        (*self).bare_perform()
+    }
+}
+
+impl<M: fmt::Display> fmt::Display for WithTimeout<M> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} (timeout {:?})", self.operation, self.timeout)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fmt;
+    use std::io;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use nix::errno::Errno;
+
+    use {OSError, Mountable};
+    use explain::Explainable;
+    use super::WithTimeout;
+
+    #[derive(Debug, Clone)]
+    struct SlowMount(Duration, Option<Errno>);
+
+    impl fmt::Display for SlowMount {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "slow mount")
+        }
+    }
+
+    impl Explainable for SlowMount {
+        fn explain(&self) -> String {
+            "server: unreachable".to_string()
+        }
+    }
+
+    impl Mountable for SlowMount {
+        fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+            sleep(self.0);
+            match self.1 {
+                Some(errno) => Err(OSError::from_nix_error(
+                    ::nix::Error::Sys(errno), self)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let op = SlowMount(Duration::from_secs(10), None);
+        let err = WithTimeout::new(op, Duration::from_millis(10))
+            .perform().unwrap_err();
+        assert_eq!(err.1.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.raw_os_error(), None);
+        assert!(err.to_string().starts_with("slow mount: Operation hasn't \
+            finished in 10ms, it's left running in background ("));
+        assert!(err.to_string().ends_with(", server: unreachable)"));
+    }
+
+    #[test]
+    fn test_finished() {
+        let op = SlowMount(Duration::from_millis(1), None);
+        WithTimeout::new(op, Duration::from_secs(10)).perform().unwrap();
+
+        let op = SlowMount(Duration::from_millis(1), Some(Errno::EHOSTDOWN));
+        let err = WithTimeout::new(op, Duration::from_secs(10))
+            .perform().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EHOSTDOWN as i32));
+        assert!(err.to_string().ends_with("(server: unreachable)"));
+    }
+
+    #[derive(Debug, Clone)]
+    struct PanickingMount;
+
+    impl fmt::Display for PanickingMount {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "panicking mount")
+        }
+    }
+
+    impl Explainable for PanickingMount {
+        fn explain(&self) -> String {
+            "driver: buggy".to_string()
+        }
+    }
+
+    impl Mountable for PanickingMount {
+        fn bare_perform(self: Box<Self>) -> Result<(), OSError> {
+            panic!("injected panic");
+        }
+    }
+
+    #[test]
+    fn test_thread_panicked() {
+        let err = WithTimeout::new(PanickingMount, Duration::from_secs(10))
+            .perform().unwrap_err();
+        assert_eq!(err.1.kind(), io::ErrorKind::Other);
+        assert_eq!(err.raw_os_error(), None);
+        assert!(err.to_string().starts_with("panicking mount: Operation \
+            has panicked in the background thread ("));
+    }
+
+    #[test]
+    fn test_display() {
+        let op = SlowMount(Duration::from_secs(1), None);
+        let boxed: Box<Mountable> = Box::new(
+            WithTimeout::new(op, Duration::from_secs(5)));
+        assert_eq!(boxed.to_string(), "slow mount (timeout 5s)");
+    }
+}
@@ -1,36 +1,92 @@
+use std::io;
 use std::io::{Write, Cursor};
 use std::fmt;
-use std::str::from_utf8;
-use std::ffi::{CString, CStr};
-use std::path::Path;
+use std::ffi::{CString, CStr, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
-use libc::{uid_t, gid_t, mode_t};
+use libc::{uid_t, gid_t, mode_t, sysconf, _SC_PHYS_PAGES, _SC_PAGESIZE};
+use libc::{c_int, ENOSPC};
 use nix::mount::{MsFlags, mount};
+use nix::sys::statfs::statfs;
 
-use {OSError, Error};
-use util::{path_to_cstring, as_path};
-use explain::{Explainable, exists, user};
+use {OSError, Error, MountArgs};
+use error::ignore_errors;
+use lock;
+use util::{path_to_cstring, as_path, max_options_len, format_ms_flags};
+use util::canonicalize_lenient;
+use explain::{Explainable, PathRole, target_exists, user, warn_if_nonempty};
+use explain::mount_point;
+use detached::{DetachedMount, create_detached};
+use mountinfo::MountTable;
 
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum TmpfsError {
+        OptionsTooLong(len: usize, max: usize) {
+            display("Options string is {} bytes long, \
+                but at most {} bytes are allowed", len, max)
+        }
+        InvalidSizeFraction(fraction: f64) {
+            display("Size fraction {} is not in range (0, 1]", fraction)
+        }
+        ReadMountInfo(err: io::Error) {
+            display("Can't read mountinfo: {}", err)
+            source(err)
+        }
+        NotAMountpoint(path: PathBuf) {
+            display("Path {:?} is not a mount point", path)
+        }
+        NotTmpfs(path: PathBuf, fstype: OsString) {
+            display("Filesystem at {:?} is {:?} rather than tmpfs",
+                path, fstype)
+        }
+    }
+}
+
+/// Minimum size set by `size_fraction_of_ram`
+const MIN_FRACTION_SIZE: usize = 1 << 20;
+
 #[derive(Debug, Clone, Copy)]
 enum Size {
+    /// No `size=` option, kernel uses half of the RAM
     Auto,
     Bytes(usize),
     Blocks(usize),
 }
 
+/// Changing the size of the mounted tmpfs
+///
+/// Created by `Tmpfs::resize()`.
+#[derive(Debug, Clone)]
+pub struct TmpfsResize {
+    target: CString,
+    size: usize,
+}
+
 /// A tmpfs mount definition
 ///
 /// By default tmpfs is mounted with nosuid,nodev
+///
+/// If no size is set, no size option is passed and the kernel limits the
+/// filesystem to half of the physical memory (see
+/// `Tmpfs::effective_default_size`). Memory is allocated on write only, so
+/// the limit may be larger than the memory available.
 #[derive(Debug, Clone)]
 pub struct Tmpfs {
     target: CString,
     size: Size,
+    /// Fraction passed to `size_fraction_of_ram` if it's out of range
+    invalid_fraction: Option<f64>,
     nr_inodes: Option<usize>,
     mode: Option<mode_t>,
     uid: Option<uid_t>,
     gid: Option<gid_t>,
+    raw_data: Option<OsString>,
     flags: MsFlags,
+    warn_nonempty: bool,
+    lock: Option<PathBuf>,
 }
 
 impl Tmpfs {
@@ -39,43 +95,154 @@ impl Tmpfs {
         Tmpfs {
             target: path_to_cstring(path.as_ref()),
             size: Size::Auto,
+            invalid_fraction: None,
             nr_inodes: None,
             mode: None,
             uid: None,
             gid: None,
+            raw_data: None,
             flags: MsFlags::MS_NOSUID|MsFlags::MS_NODEV,
+            warn_nonempty: false,
+            lock: None,
         }
     }
     /// Set size in bytes
     pub fn size_bytes(mut self, size: usize) -> Tmpfs {
-        self.size = Size::Bytes(size);
+        self.set_size_bytes(size);
         self
     }
     /// Set size in blocks of PAGE_CACHE_SIZE
+    ///
+    /// Unlike `size=`, kernel doesn't accept percents in `nr_blocks=`, use
+    /// `size_fraction_of_ram` instead.
     pub fn size_blocks(mut self, size: usize) -> Tmpfs {
-        self.size = Size::Blocks(size);
+        self.set_size_blocks(size);
+        self
+    }
+    /// Set size in bytes as a fraction of physical memory
+    ///
+    /// Unlike `size=25%` option, the size is computed once here, so the
+    /// same number of bytes is passed to the kernel as shown in the logs.
+    /// The size is at least 1 MiB. Fraction must be in range `(0, 1]`,
+    /// otherwise mount fails before calling the kernel.
+    pub fn size_fraction_of_ram(mut self, fraction: f64) -> Tmpfs {
+        self.set_size_fraction_of_ram(fraction);
         self
     }
     /// Maximum number of inodes
     pub fn nr_inodes(mut self, num: usize) -> Tmpfs {
-        self.nr_inodes = Some(num);
+        self.set_nr_inodes(num);
         self
     }
     /// Set initial permissions of the root directory
     pub fn mode(mut self, mode: mode_t) -> Tmpfs {
-        self.mode = Some(mode);
+        self.set_mode(mode);
         self
     }
     /// Set initial owner of the root directory
     pub fn uid(mut self, uid: uid_t) -> Tmpfs {
-        self.uid = Some(uid);
+        self.set_uid(uid);
         self
     }
     /// Set initial group of the root directory
     pub fn gid(mut self, gid: gid_t) -> Tmpfs {
+        self.set_gid(gid);
+        self
+    }
+
+    /// Returns the size limit the kernel uses if size isn't set, in bytes
+    ///
+    /// This is half of the physical memory, rounded down to pages.
+    pub fn effective_default_size() -> u64 {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) };
+        default_size(physical_memory() as u64, page_size.max(1) as u64)
+    }
+
+    /// Append raw options to the ones generated by the builder
+    ///
+    /// This is an escape hatch for the options not supported by the library
+    /// yet. Data is appended as is after a comma, so the caller is
+    /// responsible for escaping. Calling it again replaces the data.
+    pub fn raw_data<S: AsRef<OsStr>>(mut self, data: S) -> Tmpfs {
+        self.set_raw_data(data);
+        self
+    }
+    /// Log a warning if target is a non-empty directory
+    ///
+    /// Its contents are hidden by the mount. This is only a `log::warn!`,
+    /// the mount is done anyway.
+    pub fn warn_nonempty_target(mut self) -> Tmpfs {
+        self.set_warn_nonempty_target();
+        self
+    }
+    /// Hold an exclusive `flock` on `lockfile` while mounting
+    ///
+    /// Same as `BindMount::with_lock()`.
+    pub fn with_lock<P: AsRef<Path>>(mut self, lockfile: P) -> Tmpfs {
+        self.set_lock(lockfile);
+        self
+    }
+
+    /// Set size in bytes (in-place version of `size_bytes`)
+    pub fn set_size_bytes(&mut self, size: usize) -> &mut Tmpfs {
+        self.size = Size::Bytes(size);
+        self.invalid_fraction = None;
+        self
+    }
+    /// Set size in blocks (in-place version of `size_blocks`)
+    pub fn set_size_blocks(&mut self, size: usize) -> &mut Tmpfs {
+        self.size = Size::Blocks(size);
+        self.invalid_fraction = None;
+        self
+    }
+    /// Set size as a fraction of physical memory
+    /// (in-place version of `size_fraction_of_ram`)
+    pub fn set_size_fraction_of_ram(&mut self, fraction: f64) -> &mut Tmpfs {
+        match fraction_of(physical_memory(), fraction) {
+            Some(bytes) => {
+                self.size = Size::Bytes(bytes);
+                self.invalid_fraction = None;
+            }
+            None => self.invalid_fraction = Some(fraction),
+        }
+        self
+    }
+    /// Maximum number of inodes (in-place version of `nr_inodes`)
+    pub fn set_nr_inodes(&mut self, num: usize) -> &mut Tmpfs {
+        self.nr_inodes = Some(num);
+        self
+    }
+    /// Set permissions of the root directory (in-place version of `mode`)
+    pub fn set_mode(&mut self, mode: mode_t) -> &mut Tmpfs {
+        self.mode = Some(mode);
+        self
+    }
+    /// Set owner of the root directory (in-place version of `uid`)
+    pub fn set_uid(&mut self, uid: uid_t) -> &mut Tmpfs {
+        self.uid = Some(uid);
+        self
+    }
+    /// Set group of the root directory (in-place version of `gid`)
+    pub fn set_gid(&mut self, gid: gid_t) -> &mut Tmpfs {
         self.gid = Some(gid);
         self
     }
+    /// Append raw options (in-place version of `raw_data`)
+    pub fn set_raw_data<S: AsRef<OsStr>>(&mut self, data: S) -> &mut Tmpfs {
+        self.raw_data = Some(data.as_ref().to_os_string());
+        self
+    }
+    /// Log a warning if target is a non-empty directory
+    /// (in-place version of `warn_nonempty_target`)
+    pub fn set_warn_nonempty_target(&mut self) -> &mut Tmpfs {
+        self.warn_nonempty = true;
+        self
+    }
+    /// Hold a lock while mounting (in-place version of `with_lock`)
+    pub fn set_lock<P: AsRef<Path>>(&mut self, lockfile: P) -> &mut Tmpfs {
+        self.lock = Some(lockfile.as_ref().to_path_buf());
+        self
+    }
 
     fn format_options(&self) -> Vec<u8> {
         let mut cur = Cursor::new(Vec::new());
@@ -108,48 +275,362 @@ impl Tmpfs {
             }
             write!(cur, "gid={}", gid).unwrap();
         }
+        if let Some(ref data) = self.raw_data {
+            if cur.position() != 0 {
+                cur.write_all(b",").unwrap();
+            }
+            cur.write_all(data.as_bytes()).unwrap();
+        }
         return cur.into_inner();
     }
 
+    /// Options which are checked to fit into the kernel buffer
+    ///
+    /// Otherwise the kernel would truncate them and fail with an obscure
+    /// error (or silently ignore the tail).
+    fn checked_options(&self) -> Result<Vec<u8>, TmpfsError> {
+        if let Some(fraction) = self.invalid_fraction {
+            return Err(TmpfsError::InvalidSizeFraction(fraction));
+        }
+        let options = self.format_options();
+        check_options_len(&options, max_options_len())?;
+        Ok(options)
+    }
+
+    fn args(&self) -> Result<MountArgs, TmpfsError> {
+        let options = self.checked_options()?;
+        let tmpfs = CStr::from_bytes_with_nul(b"tmpfs\0").unwrap();
+        Ok(MountArgs::new(Some(tmpfs), &self.target, Some(tmpfs),
+                          self.flags, Some(&options)))
+    }
+
     /// Mount the tmpfs
     pub fn bare_mount(self) -> Result<(), OSError> {
-        let mut options = self.format_options();
-        mount(
-            Some(CStr::from_bytes_with_nul(b"tmpfs\0").unwrap()),
-            &*self.target,
-            Some(CStr::from_bytes_with_nul(b"tmpfs\0").unwrap()),
-            self.flags,
-            Some(&*options)
-        ).map_err(|err| OSError::from_nix(err, Box::new(self)))
+        let _lock = match lock::acquire(&self.lock) {
+            Ok(lock) => lock,
+            Err(e) => return Err(OSError::from_io(e, Box::new(self))),
+        };
+        let args = match self.args() {
+            Ok(args) => args,
+            Err(e) => return Err(OSError::from_tmpfs(e, Box::new(self))),
+        };
+        if self.warn_nonempty {
+            warn_if_nonempty(as_path(&self.target));
+        }
+        args.call().map_err(|err| OSError::from_nix(err, Box::new(self)))
     }
 
     /// Mount the tmpfs and explain error immediately
     pub fn mount(self) -> Result<(), Error> {
         self.bare_mount().map_err(OSError::explain)
     }
+
+    /// Mount ignoring errors of the specified kinds (like `nofail` in fstab)
+    ///
+    /// Returns `Ok(true)` if mounted and `Ok(false)` if mount failed with
+    /// one of the `kinds`.
+    pub fn mount_ignoring(self, kinds: &[io::ErrorKind]) -> Result<bool, Error> {
+        ignore_errors(self.mount(), kinds)
+    }
+}
+
+impl Tmpfs {
+    /// Create a tmpfs which is not attached anywhere yet
+    ///
+    /// This uses new mount API, which is available in linux 5.2+, on older
+    /// kernels this fails with `ENOSYS`. The target path is ignored, use
+    /// `DetachedMount::attach_to()` to attach the filesystem.
+    pub fn bare_open_detached(self) -> Result<DetachedMount, OSError> {
+        let options = match self.checked_options() {
+            Ok(options) => options,
+            Err(e) => return Err(OSError::from_tmpfs(e, Box::new(self))),
+        };
+        create_detached(CStr::from_bytes_with_nul(b"tmpfs\0").unwrap(),
+            &options, self.flags)
+        .map_err(|err| OSError::from_nix(err, Box::new(self)))
+    }
+
+    /// Create a detached tmpfs and explain error immediately
+    pub fn open_detached(self) -> Result<DetachedMount, Error> {
+        self.bare_open_detached().map_err(OSError::explain)
+    }
+
+    /// Change the size limit of the tmpfs mounted at `target`
+    ///
+    /// This remounts the filesystem with the new `size=` (in bytes), other
+    /// options and the per-mount flags are left as is. The target is
+    /// checked to be a tmpfs mount point first. Kernel refuses to shrink
+    /// the filesystem below the space already used (`EINVAL`).
+    pub fn resize<P: AsRef<Path>>(target: P, size: usize) -> TmpfsResize {
+        TmpfsResize {
+            target: path_to_cstring(target.as_ref()),
+            size,
+        }
+    }
+}
+
+impl TmpfsResize {
+    /// Execute the resize
+    pub fn bare_resize(self) -> Result<(), OSError> {
+        let flags = match self.current_flags() {
+            Ok(flags) => flags,
+            Err(e) => return Err(OSError::from_tmpfs(e, Box::new(self))),
+        };
+        let data = format!("size={}", self.size);
+        mount(None::<&CStr>, &*self.target, None::<&CStr>,
+              MsFlags::MS_REMOUNT | flags, Some(data.as_bytes()))
+            .map_err(|err| OSError::from_nix(err, Box::new(self)))
+    }
+
+    /// Execute the resize and explain error immediately
+    pub fn resize(self) -> Result<(), Error> {
+        self.bare_resize().map_err(OSError::explain)
+    }
+
+    /// Checks that target is a tmpfs and returns its per-mount flags
+    fn current_flags(&self) -> Result<MsFlags, TmpfsError> {
+        let table = MountTable::read().map_err(TmpfsError::ReadMountInfo)?;
+        let path = canonicalize_lenient(as_path(&self.target));
+        match table.find(&path) {
+            Some(mnt) if Path::new(&mnt.mount_point) == path => {
+                if mnt.fstype == OsStr::new("tmpfs") {
+                    Ok(mnt.get_mount_flags())
+                } else {
+                    Err(TmpfsError::NotTmpfs(path,
+                        mnt.fstype.clone().into_owned()))
+                }
+            }
+            _ => Err(TmpfsError::NotAMountpoint(path)),
+        }
+    }
+}
+
+/// Total physical memory in bytes
+fn physical_memory() -> usize {
+    let (pages, page_size) = unsafe {
+        (sysconf(_SC_PHYS_PAGES), sysconf(_SC_PAGESIZE))
+    };
+    if pages > 0 && page_size > 0 {
+        (pages as usize).saturating_mul(page_size as usize)
+    } else {
+        0
+    }
+}
+
+fn default_size(memory: u64, page_size: u64) -> u64 {
+    memory / page_size / 2 * page_size
+}
+
+/// Returns size in bytes, or `None` if fraction is not in range `(0, 1]`
+fn fraction_of(memory: usize, fraction: f64) -> Option<usize> {
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return None;
+    }
+    let bytes = (memory as f64 * fraction) as usize;
+    Some(bytes.max(MIN_FRACTION_SIZE))
+}
+
+fn check_options_len(options: &[u8], max: usize) -> Result<(), TmpfsError> {
+    if options.len() > max {
+        return Err(TmpfsError::OptionsTooLong(options.len(), max));
+    }
+    Ok(())
 }
 
 impl fmt::Display for Tmpfs {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let opts = self.format_options();
-        write!(fmt, "tmpfs {} -> {:?}", from_utf8(&opts).unwrap(),
+        write!(fmt, "tmpfs ")?;
+        if let Some(fraction) = self.invalid_fraction {
+            write!(fmt, "size=<invalid fraction {}>", fraction)?;
+            if !opts.is_empty() {
+                write!(fmt, ",")?;
+            }
+        }
+        write!(fmt, "{} -> {:?}", String::from_utf8_lossy(&opts),
             as_path(&self.target))
     }
 }
 
+impl fmt::Display for TmpfsResize {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "resize tmpfs {:?} to {} bytes",
+            as_path(&self.target), self.size)
+    }
+}
+
+impl Explainable for TmpfsResize {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", mount_point(as_path(&self.target))),
+            format!("used: {}", mounted_usage(as_path(&self.target))
+                .map(|x| format!("{}%", x))
+                .unwrap_or_else(|| "unknown".to_string())),
+            user().to_string(),
+        ].join(", ")
+    }
+}
+
 impl Explainable for Tmpfs {
     fn explain(&self) -> String {
         [
-            format!("target: {}", exists(as_path(&self.target))),
+            format!("target: {}", target_exists(as_path(&self.target))),
+            format!("flags: {}", format_ms_flags(self.flags)),
             format!("{}", user()),
         ].join(", ")
     }
+    fn explain_errno(&self, errno: Option<c_int>) -> String {
+        let mut info = vec![self.explain()];
+        if errno == Some(ENOSPC) {
+            info.push(match self.size {
+                Size::Auto => "size: default".to_string(),
+                Size::Bytes(x) => format!("size: {}", x),
+                Size::Blocks(x) => format!("size: {} blocks", x),
+            });
+            if let Some(used) = mounted_usage(as_path(&self.target)) {
+                info.push(format!("used: {}%", used));
+            }
+        }
+        info.join(", ")
+    }
+    fn mount_args(&self) -> Option<MountArgs> {
+        self.args().ok()
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+    fn creates_mount(&self) -> bool {
+        true
+    }
+}
+
+/// Returns usage percent of the filesystem mounted exactly at `path`
+fn mounted_usage(path: &Path) -> Option<u64> {
+    if !MountTable::read().ok()?.is_mountpoint(path) {
+        return None;
+    }
+    let mut stat = unsafe { ::std::mem::zeroed() };
+    statfs(path, &mut stat).ok()?;
+    used_percent(stat.f_blocks as u64, stat.f_bfree as u64)
+}
+
+/// Percent of used blocks, rounded up so that full filesystem is 100%
+fn used_percent(blocks: u64, free: u64) -> Option<u64> {
+    if blocks == 0 {
+        return None;
+    }
+    let used = blocks.saturating_sub(free);
+    Some((used * 100).div_ceil(blocks))
 }
 
 
+#[cfg(test)]
 mod test {
-    #[cfg(test)]
-    use super::Tmpfs;
+    use std::ffi::OsStr;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::{Mutex, Once};
+
+    use libc::{ENOSYS, ENOSPC};
+    use log::{self, Log, Record, Metadata, LevelFilter};
+    use nix::mount::MsFlags;
+    use nix::sys::statfs::statfs;
+    use nix::unistd::getuid;
+
+    use {Unmount, Propagation};
+    use explain::Explainable;
+    use mountinfo::mounts_under;
+    use util::test::tmp_dir;
+    use super::{Tmpfs, TmpfsError, check_options_len, fraction_of};
+    use super::{used_percent, default_size};
+
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool { true }
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    static LOGS: Capture = Capture(Mutex::new(Vec::new()));
+
+    fn captured_logs() -> &'static Capture {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&LOGS).unwrap();
+            log::set_max_level(LevelFilter::Warn);
+        });
+        &LOGS
+    }
+
+    #[test]
+    fn test_warn_nonempty_target() {
+        let logs = captured_logs();
+        let empty = tmp_dir("tmpfs_warn_empty");
+        let dir = tmp_dir("tmpfs_warn_nonempty");
+        File::create(dir.join("file")).unwrap();
+        for path in &[&empty, &dir] {
+            // warning is logged regardless of whether mount succeeds
+            if Tmpfs::new(path).warn_nonempty_target().bare_mount().is_ok() {
+                Unmount::new(path).unmount().unwrap();
+            }
+        }
+        let logs = logs.0.lock().unwrap();
+        let mentions = |path: &::std::path::Path| {
+            logs.iter().filter(|x| x.contains(&format!("{:?}", path))).count()
+        };
+        assert_eq!(mentions(&empty), 0);
+        assert_eq!(mentions(&dir), 1);
+        assert!(logs.contains(&format!("Mounting over non-empty directory \
+            {:?}, its contents will be hidden", dir)));
+    }
+
+    #[test]
+    fn test_explain_flags() {
+        assert!(Tmpfs::new("/nonexistent").explain()
+                .contains("flags: MS_NOSUID|MS_NODEV"));
+    }
+
+    #[test]
+    fn test_used_percent() {
+        assert_eq!(used_percent(0, 0), None);
+        assert_eq!(used_percent(256, 256), Some(0));
+        assert_eq!(used_percent(256, 128), Some(50));
+        assert_eq!(used_percent(256, 1), Some(100));
+        assert_eq!(used_percent(256, 0), Some(100));
+    }
+
+    #[test]
+    fn test_explain_enospc() {
+        let fs = Tmpfs::new("/nonexistent").size_bytes(1 << 20);
+        let explained = fs.explain_errno(Some(ENOSPC));
+        assert!(explained.ends_with(", size: 1048576"), "{}", explained);
+        assert_eq!(fs.explain_errno(Some(ENOSYS)), fs.explain());
+        assert!(Tmpfs::new("/nonexistent").explain_errno(Some(ENOSPC))
+                .ends_with(", size: default"));
+    }
+
+    #[test]
+    fn test_explain_enospc_usage() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("tmpfs_enospc");
+        Tmpfs::new(&dir).size_bytes(64 << 10).mount().unwrap();
+        let mut file = File::create(dir.join("fill")).unwrap();
+        let err = file.write_all(&vec![0u8; 128 << 10]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ENOSPC));
+        let explained = Tmpfs::new(&dir).size_bytes(64 << 10)
+            .explain_errno(Some(ENOSPC));
+        assert!(explained.ends_with(", size: 65536, used: 100%"),
+                "{}", explained);
+        drop(file);
+        Unmount::new(&dir).unmount().unwrap();
+    }
 
     #[test]
     fn test_tmpfs_options() {
@@ -163,4 +644,170 @@ mod test {
         assert_eq!(fs.format_options(),
             "size=1048576,nr_inodes=1024,mode=01777,uid=1000,gid=1000".as_bytes())
     }
+
+    #[test]
+    fn test_tmpfs_setters() {
+        let mut fs = Tmpfs::new("/tmp");
+        fs.set_size_bytes(1 << 20)
+            .set_nr_inodes(1024)
+            .set_mode(0o1777);
+        fs.set_uid(1000);
+        fs.set_gid(1000);
+        let chained = Tmpfs::new("/tmp")
+            .size_bytes(1 << 20)
+            .nr_inodes(1024)
+            .mode(0o1777)
+            .uid(1000)
+            .gid(1000);
+        assert_eq!(fs.format_options(), chained.format_options());
+
+        fs.set_size_blocks(100);
+        assert_eq!(fs.format_options(),
+            "nr_blocks=100,nr_inodes=1024,mode=01777,uid=1000,gid=1000".as_bytes())
+    }
+
+    #[test]
+    fn test_tmpfs_raw_data() {
+        let fs = Tmpfs::new("/tmp")
+            .raw_data("huge=within_size,noswap")
+            .size_bytes(1 << 20);
+        assert_eq!(fs.format_options(),
+            "size=1048576,huge=within_size,noswap".as_bytes());
+
+        let fs = Tmpfs::new("/tmp").raw_data("huge=always");
+        assert_eq!(fs.format_options(), "huge=always".as_bytes());
+    }
+
+    #[test]
+    fn test_fraction_of_ram() {
+        let ram = 8 << 30;
+        assert_eq!(fraction_of(ram, 0.25), Some(2 << 30));
+        assert_eq!(fraction_of(ram, 1.0), Some(ram));
+        assert_eq!(fraction_of(ram, 1e-9), Some(1 << 20));
+        for &fraction in &[0.0, -0.5, 1.5, ::std::f64::NAN] {
+            assert_eq!(fraction_of(ram, fraction), None, "{}", fraction);
+        }
+    }
+
+    #[test]
+    fn test_default_size() {
+        assert_eq!(default_size(8 << 30, 4096), 4 << 30);
+        // odd number of pages is rounded down
+        assert_eq!(default_size(3 * 4096, 4096), 4096);
+        assert_eq!(default_size(4096 + 100, 4096), 0);
+        assert_eq!(default_size(0, 4096), 0);
+        let size = Tmpfs::effective_default_size();
+        assert!(size > 0);
+        assert_eq!(size % 4096, 0);
+    }
+
+    #[test]
+    fn test_size_fraction_of_ram() {
+        let fs = Tmpfs::new("/nonexistent").size_fraction_of_ram(0.5);
+        assert!(fs.format_options().starts_with(b"size="));
+        let fs = Tmpfs::new("/nonexistent").size_fraction_of_ram(2.0);
+        assert_eq!(fs.to_string(),
+            "tmpfs size=<invalid fraction 2> -> \"/nonexistent\"");
+        let err = fs.mount().unwrap_err();
+        assert!(err.to_string().contains(
+            "Size fraction 2 is not in range (0, 1]"), "{}", err);
+        // a later size overrides the invalid one
+        Tmpfs::new("/nonexistent").size_fraction_of_ram(2.0)
+            .size_bytes(1 << 20).checked_options().unwrap();
+    }
+
+    #[test]
+    fn test_options_too_long() {
+        let fs = Tmpfs::new("/nonexistent")
+            .size_bytes(1 << 20)
+            .raw_data("x".repeat(5000));
+        let len = fs.format_options().len();
+        match check_options_len(&fs.format_options(), 4095) {
+            Err(TmpfsError::OptionsTooLong(l, 4095)) => assert_eq!(l, len),
+            e => panic!("unexpected result: {:?}", e),
+        }
+        assert!(check_options_len(b"size=1048576", 4095).is_ok());
+
+        let fs = fs.raw_data("x".repeat(1 << 20));
+        let err = fs.mount().unwrap_err();
+        assert!(err.to_string().contains("Options string is 1048589 bytes"));
+        // mount() wasn't called
+        assert!(err.mount_args().is_none());
+    }
+
+    #[test]
+    fn test_mount_args() {
+        let dir = tmp_dir("tmpfs_mount_args");
+        let err = Tmpfs::new(dir.join("nonexistent"))
+            .size_bytes(1 << 20).mode(0o700)
+            .mount().unwrap_err();
+        let args = err.mount_args().unwrap();
+        assert_eq!(args.source().unwrap().to_bytes(), b"tmpfs");
+        assert_eq!(args.target().to_bytes(),
+                   dir.join("nonexistent").as_os_str().as_bytes());
+        assert_eq!(args.fstype().unwrap().to_bytes(), b"tmpfs");
+        assert_eq!(args.flags(),
+                   MsFlags::MS_NOSUID | MsFlags::MS_NODEV);
+        assert_eq!(args.data().unwrap(), b"size=1048576,mode=00700");
+        assert_eq!(args.to_string(), format!(
+            "mount(\"tmpfs\", {:?}, \"tmpfs\", MS_NOSUID|MS_NODEV, \
+             \"size=1048576,mode=00700\")", dir.join("nonexistent")));
+    }
+
+    #[test]
+    fn test_open_detached() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("tmpfs_detached");
+        let mnt = match Tmpfs::new(&dir).size_bytes(1 << 20).open_detached() {
+            Ok(mnt) => mnt,
+            Err(ref e) if e.1.raw_os_error() == Some(ENOSYS) => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+        let mount_id = mnt.mount_id().unwrap();
+        mnt.propagation(Propagation::Shared).attach_to(&dir).unwrap();
+        let mounts = mounts_under(&dir).unwrap();
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(u64::from(mounts[0].mount_id), mount_id);
+        assert!(mounts[0].peer_group().is_some());
+        assert_eq!(mounts[0].fstype, OsStr::new("tmpfs"));
+        assert!(mounts[0].super_options.to_str().unwrap()
+                .contains("size=1024k"));
+        Unmount::new(&dir).unmount().unwrap();
+    }
+
+    #[test]
+    fn test_resize_not_tmpfs() {
+        let dir = tmp_dir("tmpfs_resize_not_mountpoint");
+        let err = Tmpfs::resize(&dir, 1 << 20).bare_resize().unwrap_err();
+        assert_eq!(err.to_string(), format!("resize tmpfs {:?} to 1048576 \
+            bytes: Tmpfs(NotAMountpoint({:?}))", dir, dir));
+        match Tmpfs::resize("/proc", 1 << 20).bare_resize() {
+            Err(ref e) if e.to_string().contains("NotTmpfs(") => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("tmpfs_resize");
+        Tmpfs::new(&dir).size_bytes(1 << 20).mount().unwrap();
+        let size = || {
+            let mut stat = unsafe { ::std::mem::zeroed() };
+            statfs(&dir, &mut stat).unwrap();
+            stat.f_blocks as u64 * stat.f_bsize as u64
+        };
+        assert_eq!(size(), 1 << 20);
+        Tmpfs::resize(&dir, 2 << 20).resize().unwrap();
+        assert_eq!(size(), 2 << 20);
+        let mounts = mounts_under(&dir).unwrap();
+        assert!(mounts[0].get_mount_flags()
+                .contains(MsFlags::MS_NOSUID | MsFlags::MS_NODEV));
+        Unmount::new(&dir).unmount().unwrap();
+    }
 }
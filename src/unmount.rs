@@ -0,0 +1,469 @@
+use std::io;
+use std::fmt;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::mount::{MntFlags, umount2};
+
+use {OSError, Error};
+use util::{path_to_cstring, as_path, canonicalize_lenient};
+use explain::{Explainable, PathRole, target_exists, user};
+use mountinfo::mounts_under;
+
+
+/// An unmount definition
+///
+/// This is a wrapper around `umount2` system call
+#[derive(Debug, Clone)]
+pub struct Unmount {
+    target: CString,
+    flags: MntFlags,
+    recursive: bool,
+}
+
+/// A two-phase expiry of the mount point (`MNT_EXPIRE`)
+///
+/// This is how autofs-like tools unmount idle filesystems: the first
+/// `umount2(MNT_EXPIRE)` call marks the mount point, and the next one
+/// unmounts it if nobody has accessed it in between. If it was accessed,
+/// the call marks it again. If the mount point is busy (e.g. has open
+/// files) the call fails with `EBUSY`.
+///
+/// This keeps track of whether the mount point is marked by us, so the
+/// calls look like:
+///
+/// ```ignore
+/// let mut exp = ExpiringMount::new("/mnt/nfs");
+/// exp.mark()?;
+/// // some time later
+/// if exp.try_expire()? {
+///     // unmounted
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExpiringMount {
+    target: CString,
+    marked: bool,
+}
+
+/// Result of unmounting each mount point (see `unmount_best_effort`)
+type Outcomes = Vec<(PathBuf, Result<(), Error>)>;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum UnmountError {
+        Io(msg: String, err: io::Error) {
+            source(err)
+            display("{}: {}", msg, err)
+        }
+        Partial(failures: Vec<(PathBuf, io::Error)>) {
+            display("Failed to unmount: {}", failures.iter()
+                .map(|(p, e)| format!("{:?}: {}", p, e))
+                .collect::<Vec<_>>().join(", "))
+        }
+    }
+}
+
+impl Unmount {
+    /// Create a new unmount operation for the single mount point
+    pub fn new<P: AsRef<Path>>(target: P) -> Unmount {
+        Unmount {
+            target: path_to_cstring(target.as_ref()),
+            flags: MntFlags::empty(),
+            recursive: false,
+        }
+    }
+    /// Create an operation which unmounts everything at and under `prefix`
+    ///
+    /// Mount points are unmounted deepest first with `MNT_DETACH`. Mount
+    /// points that are already gone by the time we get to them are skipped.
+    /// Unmounting continues after a failure, and all the failures are
+    /// reported in a single error at the end.
+    pub fn recursive<P: AsRef<Path>>(prefix: P) -> Unmount {
+        Unmount {
+            target: path_to_cstring(prefix.as_ref()),
+            flags: MntFlags::MNT_DETACH,
+            recursive: true,
+        }
+    }
+    /// Lazy unmount (`MNT_DETACH`)
+    pub fn detach(mut self, flag: bool) -> Unmount {
+        self.set_detach(flag);
+        self
+    }
+    /// Force unmount (`MNT_FORCE`), makes sense for network filesystems
+    pub fn force(mut self, flag: bool) -> Unmount {
+        self.set_force(flag);
+        self
+    }
+    /// Mark the mount point as expired (`MNT_EXPIRE`)
+    ///
+    /// First call marks the mount point and fails with `EAGAIN`, the second
+    /// one unmounts it unless it was accessed in between.
+    pub fn expire(mut self, flag: bool) -> Unmount {
+        self.set_expire(flag);
+        self
+    }
+
+    /// Lazy unmount (in-place version of `detach`)
+    pub fn set_detach(&mut self, flag: bool) -> &mut Unmount {
+        self.flags.set(MntFlags::MNT_DETACH, flag);
+        self
+    }
+    /// Force unmount (in-place version of `force`)
+    pub fn set_force(&mut self, flag: bool) -> &mut Unmount {
+        self.flags.set(MntFlags::MNT_FORCE, flag);
+        self
+    }
+    /// Mark the mount point as expired (in-place version of `expire`)
+    pub fn set_expire(&mut self, flag: bool) -> &mut Unmount {
+        self.flags.set(MntFlags::MNT_EXPIRE, flag);
+        self
+    }
+
+    /// Execute an unmount
+    pub fn bare_unmount(self) -> Result<(), OSError> {
+        if self.recursive {
+            return match self.unmount_recursive() {
+                Ok(()) => Ok(()),
+                Err(e) => Err(OSError::from_unmount(e, Box::new(self))),
+            };
+        }
+        umount2(&*self.target, self.flags)
+            .map_err(|err| OSError::from_nix(err, Box::new(self)))
+    }
+
+    /// Execute an unmount and explain the error immediately
+    pub fn unmount(self) -> Result<(), Error> {
+        self.bare_unmount().map_err(OSError::explain)
+    }
+
+    /// Unmount and report the outcome for every mount point
+    ///
+    /// This is mostly useful for `recursive()` unmount in cleanup code:
+    /// `unmount()` reports only the failures, in a single error, while this
+    /// returns the result for each mount point found (in the order they
+    /// were unmounted), so the caller can see what is left. Mount points
+    /// that were already gone by the time we got to them are `Ok`. The
+    /// outer error is returned only if mountinfo can't be read.
+    ///
+    /// Non-recursive unmount reports the single target.
+    ///
+    /// This is a method rather than a builder flag because it returns
+    /// a result per mount point instead of a single error. Other operations
+    /// don't have it as they work on a single mount point, so `unmount()`
+    /// already reports everything there is.
+    pub fn unmount_best_effort(self) -> Result<Outcomes, Error> {
+        if !self.recursive {
+            let path = as_path(&self.target).to_path_buf();
+            return Ok(vec![(path, self.unmount())]);
+        }
+        let paths = match self.recursive_paths() {
+            Ok(paths) => paths,
+            Err(e) => {
+                return Err(OSError::from_unmount(e, Box::new(self))
+                    .explain());
+            }
+        };
+        let flags = self.flags;
+        Ok(unmount_each(paths, |path| umount2(path, flags))
+            .into_iter()
+            .map(|(path, result)| {
+                let result = result.map_err(|err| {
+                    let op = Unmount {
+                        target: path_to_cstring(&path),
+                        flags,
+                        recursive: false,
+                    };
+                    OSError::from_nix(err, Box::new(op)).explain()
+                });
+                (path, result)
+            })
+            .collect())
+    }
+
+    fn unmount_recursive(&self) -> Result<(), UnmountError> {
+        let paths = self.recursive_paths()?;
+        let failures = unmount_each(paths, |path| umount2(path, self.flags))
+            .into_iter()
+            .filter_map(|(path, result)| match result {
+                Ok(()) => None,
+                Err(err) => Some((path, err.as_errno()
+                    .map_or_else(
+                        || io::Error::other(err),
+                        io::Error::from))),
+            })
+            .collect::<Vec<_>>();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(UnmountError::Partial(failures))
+        }
+    }
+
+    /// Mount points under the prefix in the order of unmounting
+    fn recursive_paths(&self) -> Result<Vec<PathBuf>, UnmountError> {
+        let prefix = canonicalize_lenient(as_path(&self.target));
+        let mut paths = mounts_under(&prefix)
+            .map_err(|e| UnmountError::Io(
+                "Cannot read mountinfo".to_string(), e))?
+            .into_iter()
+            .map(|m| PathBuf::from(m.mount_point.into_owned()))
+            .collect::<Vec<_>>();
+        // Deepest first. Sort is stable, so mounts stacked at the same
+        // path are unmounted in reverse order of mounting.
+        paths.reverse();
+        paths.sort_by(|a, b| {
+            b.components().count().cmp(&a.components().count())
+        });
+        Ok(paths)
+    }
+}
+
+/// Unmounts every path with `umount`, continuing after failures
+fn unmount_each<F>(paths: Vec<PathBuf>, mut umount: F)
+    -> Vec<(PathBuf, ::nix::Result<()>)>
+    where F: FnMut(&Path) -> ::nix::Result<()>
+{
+    paths.into_iter().map(|path| {
+        let result = match umount(&path) {
+            // already unmounted, probably together with parent
+            Err(::nix::Error::Sys(Errno::EINVAL)) |
+            Err(::nix::Error::Sys(Errno::ENOENT)) => Ok(()),
+            result => result,
+        };
+        (path, result)
+    }).collect()
+}
+
+impl ExpiringMount {
+    /// Create an expiry tracker for the mount point at `target`
+    pub fn new<P: AsRef<Path>>(target: P) -> ExpiringMount {
+        ExpiringMount {
+            target: path_to_cstring(target.as_ref()),
+            marked: false,
+        }
+    }
+
+    /// Returns `true` if the mount point was marked by the last call
+    pub fn is_marked(&self) -> bool {
+        self.marked
+    }
+
+    /// Mark the mount point as expired
+    ///
+    /// Returns `true` if the mount point has been unmounted instead,
+    /// because it was already marked (e.g. by another process) and wasn't
+    /// accessed since. If it's already marked by us, does nothing and
+    /// returns `false` (use `try_expire()` to unmount).
+    pub fn bare_mark(&mut self) -> Result<bool, OSError> {
+        if self.marked {
+            return Ok(false);
+        }
+        self.expire()
+    }
+
+    /// Mark the mount point and explain error immediately
+    pub fn mark(&mut self) -> Result<bool, Error> {
+        self.bare_mark().map_err(OSError::explain)
+    }
+
+    /// Unmount the mount point if it wasn't accessed since it was marked
+    ///
+    /// Returns `true` if the mount point has been unmounted. Otherwise it's
+    /// marked again (like with `mark()`) and `false` is returned.
+    pub fn bare_try_expire(&mut self) -> Result<bool, OSError> {
+        self.expire()
+    }
+
+    /// Try to expire the mount point and explain error immediately
+    pub fn try_expire(&mut self) -> Result<bool, Error> {
+        self.bare_try_expire().map_err(OSError::explain)
+    }
+
+    fn expire(&mut self) -> Result<bool, OSError> {
+        match umount2(&*self.target, MntFlags::MNT_EXPIRE) {
+            Ok(()) => {
+                self.marked = false;
+                Ok(true)
+            }
+            Err(::nix::Error::Sys(Errno::EAGAIN)) => {
+                self.marked = true;
+                Ok(false)
+            }
+            Err(err) => {
+                self.marked = false;
+                Err(OSError::from_nix(err, Box::new(self.clone())))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Unmount {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.recursive {
+            write!(fmt, "recursive ")?;
+        }
+        if self.flags.contains(MntFlags::MNT_DETACH) {
+            write!(fmt, "lazy ")?;
+        }
+        if self.flags.contains(MntFlags::MNT_FORCE) {
+            write!(fmt, "forced ")?;
+        }
+        if self.flags.contains(MntFlags::MNT_EXPIRE) {
+            write!(fmt, "expiring ")?;
+        }
+        write!(fmt, "unmount {:?}", as_path(&self.target))
+    }
+}
+
+impl fmt::Display for ExpiringMount {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "expiring unmount {:?}", as_path(&self.target))
+    }
+}
+
+impl Explainable for ExpiringMount {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", target_exists(as_path(&self.target))),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+}
+
+impl Explainable for Unmount {
+    fn explain(&self) -> String {
+        [
+            format!("target: {}", target_exists(as_path(&self.target))),
+            user().to_string(),
+        ].join(", ")
+    }
+    fn paths(&self) -> Vec<(PathRole, &Path)> {
+        vec![(PathRole::Target, as_path(&self.target))]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, read_dir};
+    use std::path::Path;
+
+    use nix::unistd::getuid;
+
+    use Tmpfs;
+    use mountinfo::mounts_under;
+    use util::test::tmp_dir;
+    use std::path::PathBuf;
+
+    use nix::errno::Errno;
+
+    use super::{Unmount, ExpiringMount, unmount_each};
+
+    #[test]
+    fn test_display() {
+        assert_eq!(format!("{}", Unmount::new("/mnt")), "unmount \"/mnt\"");
+        assert_eq!(format!("{}", Unmount::new("/mnt").detach(true)),
+                   "lazy unmount \"/mnt\"");
+        assert_eq!(format!("{}", Unmount::recursive("/mnt")),
+                   "recursive lazy unmount \"/mnt\"");
+    }
+
+    #[test]
+    fn test_recursive_unmount() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("recursive_unmount");
+        Tmpfs::new(&dir).mount().unwrap();
+        create_dir(dir.join("a")).unwrap();
+        Tmpfs::new(dir.join("a")).mount().unwrap();
+        create_dir(dir.join("a/b")).unwrap();
+        Tmpfs::new(dir.join("a/b")).mount().unwrap();
+        Tmpfs::new(dir.join("a/b")).mount().unwrap();
+        assert_eq!(mounts_under(&dir).unwrap().len(), 4);
+
+        Unmount::recursive(&dir).unmount().unwrap();
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+        assert!(!Path::new(&dir).join("a").exists());
+    }
+
+    #[test]
+    fn test_unmount_each() {
+        let paths = vec!["/a/b", "/a/c", "/a/d", "/a"].into_iter()
+            .map(PathBuf::from).collect::<Vec<_>>();
+        let mut called = Vec::new();
+        let results = unmount_each(paths, |path| {
+            called.push(path.to_path_buf());
+            match path.to_str().unwrap() {
+                "/a/b" => Err(::nix::Error::Sys(Errno::EBUSY)),
+                "/a/c" => Err(::nix::Error::Sys(Errno::EINVAL)),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(called.len(), 4);
+        assert_eq!(results.iter()
+            .map(|&(ref p, ref r)| (p.to_str().unwrap(), r.is_ok()))
+            .collect::<Vec<_>>(),
+            vec![("/a/b", false), ("/a/c", true), ("/a/d", true),
+                 ("/a", true)]);
+    }
+
+    #[test]
+    fn test_unmount_best_effort() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("unmount_best_effort");
+        Tmpfs::new(&dir).mount().unwrap();
+        create_dir(dir.join("a")).unwrap();
+        Tmpfs::new(dir.join("a")).mount().unwrap();
+        let results = Unmount::recursive(&dir).unmount_best_effort()
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, dir.join("a"));
+        assert_eq!(results[1].0, dir);
+        assert!(results.iter().all(|x| x.1.is_ok()));
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+
+        let results = Unmount::new(&dir).unmount_best_effort().unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_err());
+    }
+
+    #[test]
+    fn test_expiring_mount() {
+        if !getuid().is_root() {
+            return;
+        }
+        let dir = tmp_dir("expiring_mount");
+        Tmpfs::new(&dir).mount().unwrap();
+        let mut exp = ExpiringMount::new(&dir);
+        assert!(!exp.is_marked());
+        assert_eq!(exp.mark().unwrap(), false);
+        assert!(exp.is_marked());
+        // marking twice doesn't unmount
+        assert_eq!(exp.mark().unwrap(), false);
+        assert!(exp.is_marked());
+        assert_eq!(exp.try_expire().unwrap(), true);
+        assert!(!exp.is_marked());
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+
+        // access in between clears the mark
+        Tmpfs::new(&dir).mount().unwrap();
+        assert_eq!(exp.mark().unwrap(), false);
+        read_dir(&dir).unwrap().count();
+        assert_eq!(exp.try_expire().unwrap(), false);
+        assert!(exp.is_marked());
+        assert_eq!(exp.try_expire().unwrap(), true);
+        assert_eq!(mounts_under(&dir).unwrap().len(), 0);
+
+        let err = exp.try_expire().unwrap_err();
+        assert!(!exp.is_marked());
+        assert!(err.to_string().starts_with("expiring unmount "));
+    }
+}
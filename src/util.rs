@@ -1,7 +1,45 @@
-use std::path::Path;
+use std::io;
+use std::env::current_dir;
+use std::path::{Path, PathBuf};
 use std::ffi::{CStr, CString, OsStr};
+use std::fs::canonicalize;
 use std::os::unix::ffi::OsStrExt;
 
+use libc::{mode_t, sysconf, _SC_PAGESIZE};
+use nix::mount::MsFlags;
+use nix::sys::stat::{Mode, umask};
+use nix::sys::utsname::uname;
+
+
+/// Names of the mount flags in the order of bits
+const MS_FLAG_NAMES: &[(MsFlags, &str)] = &[
+    (MsFlags::MS_RDONLY, "MS_RDONLY"),
+    (MsFlags::MS_NOSUID, "MS_NOSUID"),
+    (MsFlags::MS_NODEV, "MS_NODEV"),
+    (MsFlags::MS_NOEXEC, "MS_NOEXEC"),
+    (MsFlags::MS_SYNCHRONOUS, "MS_SYNCHRONOUS"),
+    (MsFlags::MS_REMOUNT, "MS_REMOUNT"),
+    (MsFlags::MS_MANDLOCK, "MS_MANDLOCK"),
+    (MsFlags::MS_DIRSYNC, "MS_DIRSYNC"),
+    (MsFlags::MS_NOATIME, "MS_NOATIME"),
+    (MsFlags::MS_NODIRATIME, "MS_NODIRATIME"),
+    (MsFlags::MS_BIND, "MS_BIND"),
+    (MsFlags::MS_MOVE, "MS_MOVE"),
+    (MsFlags::MS_REC, "MS_REC"),
+    (MsFlags::MS_SILENT, "MS_SILENT"),
+    (MsFlags::MS_POSIXACL, "MS_POSIXACL"),
+    (MsFlags::MS_UNBINDABLE, "MS_UNBINDABLE"),
+    (MsFlags::MS_PRIVATE, "MS_PRIVATE"),
+    (MsFlags::MS_SLAVE, "MS_SLAVE"),
+    (MsFlags::MS_SHARED, "MS_SHARED"),
+    (MsFlags::MS_RELATIME, "MS_RELATIME"),
+    (MsFlags::MS_KERNMOUNT, "MS_KERNMOUNT"),
+    (MsFlags::MS_I_VERSION, "MS_I_VERSION"),
+    (MsFlags::MS_STRICTATIME, "MS_STRICTATIME"),
+    (MsFlags::MS_ACTIVE, "MS_ACTIVE"),
+    (MsFlags::MS_NOUSER, "MS_NOUSER"),
+];
+
 
 pub fn path_to_cstring(path: &Path) -> CString {
     return CString::new(path.as_os_str().as_bytes()).unwrap()
@@ -10,3 +48,277 @@ pub fn path_to_cstring(path: &Path) -> CString {
 pub fn as_path(cstring: &CStr) -> &Path {
     OsStr::from_bytes(cstring.to_bytes()).as_ref()
 }
+
+/// Format mount flags like `MS_BIND|MS_REC`
+///
+/// Empty set is formatted as `0`, bits without a name are appended in hex.
+pub fn format_ms_flags(flags: MsFlags) -> String {
+    let mut names = Vec::new();
+    let mut rest = flags.bits();
+    for &(flag, name) in MS_FLAG_NAMES {
+        if flags.contains(flag) {
+            names.push(name.to_string());
+            rest &= !flag.bits();
+        }
+    }
+    if rest != 0 {
+        names.push(format!("{:#x}", rest));
+    }
+    if names.is_empty() {
+        return "0".to_string();
+    }
+    names.join("|")
+}
+
+/// Maximum length of the data (options) argument of the mount system call
+///
+/// Kernel copies at most a single page of data, and the options string must
+/// be nul-terminated within that page.
+pub fn max_options_len() -> usize {
+    let page_size = unsafe { sysconf(_SC_PAGESIZE) };
+    if page_size > 0 { page_size as usize - 1 } else { 4095 }
+}
+
+/// Resolve symlinks in the existing part of the path
+///
+/// Unlike `fs::canonicalize` this doesn't fail if path doesn't exist (yet):
+/// the longest existing prefix is canonicalized and the rest of the path is
+/// appended as is. Relative path is resolved against current directory. If
+/// nothing can be resolved, the path is returned unchanged.
+pub fn canonicalize_lenient(path: &Path) -> PathBuf {
+    let path = match current_dir() {
+        Ok(cur) => cur.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(mut real) = canonicalize(existing) {
+            for name in rest.iter().rev() {
+                real.push(name);
+            }
+            return real;
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.clone(),
+        }
+    }
+}
+
+/// Run `f` with umask set to `mask`, the previous umask is restored after
+///
+/// Kernel doesn't apply umask to the `mode` option of a filesystem, so
+/// this isn't needed for the mount itself. It's useful for populating a
+/// fresh filesystem, e.g. `with_umask(0, || create_dir_all(...))` creates
+/// directories with exactly the modes requested.
+///
+/// The umask is restored even if `f` panics. Note: umask is per-process,
+/// so this affects all the threads.
+pub fn with_umask<T, F: FnOnce() -> T>(mask: mode_t, f: F) -> T {
+    swap_umask(umask, mask, f)
+}
+
+/// Restores the umask when dropped
+struct UmaskGuard<S: FnMut(Mode) -> Mode> {
+    set: S,
+    old: Mode,
+}
+
+impl<S: FnMut(Mode) -> Mode> Drop for UmaskGuard<S> {
+    fn drop(&mut self) {
+        (self.set)(self.old);
+    }
+}
+
+fn swap_umask<T, S, F>(mut set: S, mask: mode_t, f: F) -> T
+    where S: FnMut(Mode) -> Mode,
+          F: FnOnce() -> T,
+{
+    let old = set(Mode::from_bits_truncate(mask));
+    let _guard = UmaskGuard { set, old };
+    f()
+}
+
+/// Returns `(major, minor, patch)` version of the running kernel
+///
+/// Parsed from the release string of `uname`, distro suffixes like
+/// `-generic` or `-rc1` are ignored.
+pub fn kernel_version() -> io::Result<(u32, u32, u32)> {
+    let uts = uname();
+    parse_kernel_version(uts.release()).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData,
+            format!("Can't parse kernel release {:?}", uts.release()))
+    })
+}
+
+fn parse_kernel_version(release: &str) -> Option<(u32, u32, u32)> {
+    let end = release.find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(release.len());
+    let mut parts = release[..end].split('.').map(|x| x.parse().ok());
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = match parts.next() {
+        Some(patch) => patch?,
+        None => 0,
+    };
+    Some((major, minor, patch))
+}
+
+/// Returns `false` if the kernel is known to be older than `version`
+///
+/// If the version can't be determined, returns `true`. This is only a hint
+/// for which system call to try first, `ENOSYS` is the final answer
+/// (distributions backport features to older kernels and seccomp filters
+/// may block newer calls).
+fn kernel_at_least(version: (u32, u32, u32)) -> bool {
+    kernel_version().map(|current| current >= version).unwrap_or(true)
+}
+
+/// New mount API: `open_tree`, `move_mount`, `fsopen`, `fsmount` (linux 5.2)
+pub fn supports_open_tree() -> bool {
+    kernel_at_least((5, 2, 0))
+}
+
+/// Mount id reported by `statx` (linux 5.8)
+pub fn supports_statx_mount_id() -> bool {
+    kernel_at_least((5, 8, 0))
+}
+
+/// The `mount_setattr` system call (linux 5.12)
+pub fn supports_mount_setattr() -> bool {
+    kernel_at_least((5, 12, 0))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Create a fresh (empty) directory for the test
+    pub fn tmp_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("libmount-test-{}-{}",
+                                          process::id(), name));
+        remove_dir_all(&dir).ok();
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+}
+
+#[cfg(test)]
+mod test_format_flags {
+    use nix::mount::MsFlags;
+
+    use super::format_ms_flags;
+
+    #[test]
+    fn test_format_ms_flags() {
+        assert_eq!(format_ms_flags(MsFlags::empty()), "0");
+        assert_eq!(format_ms_flags(MsFlags::MS_REC | MsFlags::MS_BIND),
+                   "MS_BIND|MS_REC");
+        assert_eq!(format_ms_flags(MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY |
+                                   MsFlags::MS_RELATIME),
+                   "MS_RDONLY|MS_REMOUNT|MS_RELATIME");
+        assert_eq!(format_ms_flags(MsFlags::MS_SHARED | MsFlags::MS_REC),
+                   "MS_REC|MS_SHARED");
+    }
+}
+
+#[cfg(test)]
+mod test_canonicalize {
+    use std::fs::create_dir;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    use super::canonicalize_lenient;
+    use super::test::tmp_dir;
+
+    #[test]
+    fn test_missing_last_component() {
+        let dir = tmp_dir("canonicalize_missing");
+        create_dir(dir.join("real")).unwrap();
+        symlink(dir.join("real"), dir.join("link")).unwrap();
+        assert_eq!(canonicalize_lenient(&dir.join("link/missing")),
+                   dir.join("real/missing"));
+        assert_eq!(canonicalize_lenient(&dir.join("link/missing/deeper")),
+                   dir.join("real/missing/deeper"));
+        assert_eq!(canonicalize_lenient(&dir.join("link")), dir.join("real"));
+    }
+
+    #[test]
+    fn test_nothing_exists() {
+        assert_eq!(canonicalize_lenient(Path::new("/")), Path::new("/"));
+        assert_eq!(canonicalize_lenient(Path::new("/nonexistent/x")),
+                   Path::new("/nonexistent/x"));
+    }
+}
+
+#[cfg(test)]
+mod test_umask {
+    use std::cell::Cell;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use nix::sys::stat::Mode;
+
+    use super::swap_umask;
+
+    // Real umask is per-process, so tests use a fake one to not affect
+    // files created by the tests running in parallel
+    #[test]
+    fn test_with_umask() {
+        let current = Cell::new(Mode::from_bits_truncate(0o027));
+        let set = |mask| current.replace(mask);
+        assert_eq!(swap_umask(set, 0, || current.get().bits()), 0);
+        assert_eq!(current.get().bits(), 0o027);
+    }
+
+    #[test]
+    fn test_with_umask_panic() {
+        let current = Cell::new(Mode::from_bits_truncate(0o027));
+        let set = |mask| current.replace(mask);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            swap_umask(set, 0, || panic!("injected panic"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(current.get().bits(), 0o027);
+    }
+}
+
+#[cfg(test)]
+mod test_kernel_version {
+    use super::{parse_kernel_version, kernel_version};
+    use super::{supports_open_tree, supports_statx_mount_id};
+    use super::supports_mount_setattr;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_kernel_version("5.15.0-generic"), Some((5, 15, 0)));
+        assert_eq!(parse_kernel_version("6.1.0"), Some((6, 1, 0)));
+        assert_eq!(parse_kernel_version("4.19.0-rc1"), Some((4, 19, 0)));
+        assert_eq!(parse_kernel_version("6.8"), Some((6, 8, 0)));
+        assert_eq!(parse_kernel_version("6.9-rc3"), Some((6, 9, 0)));
+        assert_eq!(parse_kernel_version("5.10.0+"), Some((5, 10, 0)));
+        assert_eq!(parse_kernel_version("3.10.0-1160.el7.x86_64"),
+                   Some((3, 10, 0)));
+        assert_eq!(parse_kernel_version("6.18.44-fc-v139"),
+                   Some((6, 18, 44)));
+        assert_eq!(parse_kernel_version("6"), None);
+        assert_eq!(parse_kernel_version("6..1"), None);
+        assert_eq!(parse_kernel_version("linux"), None);
+        assert_eq!(parse_kernel_version(""), None);
+    }
+
+    #[test]
+    fn test_running_kernel() {
+        let version = kernel_version().unwrap();
+        assert!(version >= (2, 6, 0));
+        assert_eq!(supports_open_tree(), version >= (5, 2, 0));
+        assert_eq!(supports_statx_mount_id(), version >= (5, 8, 0));
+        assert_eq!(supports_mount_setattr(), version >= (5, 12, 0));
+    }
+}